@@ -0,0 +1,1557 @@
+use crate::debug_info::{DebugInfo, LineInfo, Scope};
+use crate::diagnostic::{location, Diagnostic};
+use crate::expression::Expr;
+use crate::object::{ObjectFile, RelocatableObject, Relocation, Segment};
+use crate::symbols::{Symbol, SymbolKind, SymbolTable};
+use crate::vm::{Op, Register};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Register(Register),
+    Immediate(i32),
+    Expression(Expr),
+    String(Vec<u8>),
+}
+
+struct Statement<'a> {
+    line: usize,
+    /// The instruction text and each operand's text, for pointing errors at them.
+    text: &'a str,
+    operand_texts: Vec<&'a str>,
+    address: u16,
+    mnemonic: String,
+    operands: Vec<Operand>,
+}
+
+/// Assembles a program written one instruction per line, e.g. `PUSH 2`, `POP A`, `ADDS`.
+/// Operands are separated by commas and `;` starts a comment. A line may start with a
+/// `label:` whose address can be used as an operand anywhere in the program; `JR` turns a
+/// label into the matching relative offset. Labels starting with a dot, such as `.loop`, are
+/// local to the global label above them and can be reused in other routines; elsewhere they
+/// can be referred to as `routine.loop`.
+///
+/// Numeric operands may be expressions over numbers, labels and constants, e.g.
+/// `PUSH BUFFER_BASE+2`, using `+ - * / % & | ^ << >> ~` and parentheses. `.equ NAME value`
+/// defines a constant; its value must be computable from the symbols defined above it.
+///
+/// Data is laid out with directives: `.org addr` moves the location counter forward,
+/// `.word` and `.byte` emit comma separated values and `.ascii "text"` emits the bytes of a
+/// string, which may contain the escapes `\n`, `\r`, `\t`, `\0`, `\\`, `\"` and `\xHH`.
+///
+/// The result is the little endian byte image of the program starting at address 0, with any
+/// gaps left by `.org` filled with zeros.
+///
+/// `.entry addr` records where execution starts, for `Assembly::to_object`.
+///
+/// `.include "file.asm"` pastes another source file in place; relative paths are resolved
+/// against the current directory.
+///
+/// Errors are returned as a `Diagnostic` carrying the line, column and span they refer to.
+pub fn assemble(source: &str) -> Result<Vec<u8>, Diagnostic> {
+    Assembly::from_source(source).map(|assembly| assembly.bytes)
+}
+
+/// Assembles the file at `path`. Included files are resolved relative to the file that
+/// includes them and errors name the file and the chain of includes that led to it.
+pub fn assemble_file(path: impl AsRef<Path>) -> Result<Vec<u8>, Diagnostic> {
+    Assembly::from_file(path).map(|assembly| assembly.bytes)
+}
+
+/// An assembled program together with its listing and symbol table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assembly {
+    pub bytes: Vec<u8>,
+    pub listing: Vec<ListingLine>,
+    pub symbols: SymbolTable,
+    /// Address given by `.entry`, if the program has one.
+    pub entry: Option<u16>,
+    /// Labels exported with `.global`.
+    pub globals: Vec<String>,
+    /// Words to patch at link time; only filled in when assembling a relocatable module.
+    pub relocations: Vec<Relocation>,
+    /// Source line and label scope of every address that holds code or data.
+    pub debug_info: DebugInfo,
+}
+
+/// One source line of the listing. `address` is set for lines that define a label or emit
+/// code or data, and `bytes` holds what the line emitted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListingLine {
+    pub address: Option<u16>,
+    pub bytes: Vec<u8>,
+    pub source: String,
+}
+
+impl Assembly {
+    /// Same as `assemble`, keeping the listing and symbol table.
+    pub fn from_source(source: &str) -> Result<Assembly, Diagnostic> {
+        assemble_lines(&expand_source(source)?, false)
+    }
+
+    /// Same as `assemble_file`, keeping the listing and symbol table.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Assembly, Diagnostic> {
+        assemble_lines(&expand_file(path.as_ref())?, false)
+    }
+
+    /// Assembles a module to be linked with others, see `to_relocatable`. Symbols that are not
+    /// defined are taken to be external, and every 16-bit operand that depends on a label or
+    /// an external symbol gets a relocation.
+    pub fn from_source_relocatable(source: &str) -> Result<Assembly, Diagnostic> {
+        assemble_lines(&expand_source(source)?, true)
+    }
+
+    /// File version of `from_source_relocatable`.
+    pub fn from_file_relocatable(path: impl AsRef<Path>) -> Result<Assembly, Diagnostic> {
+        assemble_lines(&expand_file(path.as_ref())?, true)
+    }
+
+    /// Packs a module assembled with `from_source_relocatable` for the linker.
+    pub fn to_relocatable(&self) -> RelocatableObject {
+        let symbols = self
+            .globals
+            .iter()
+            .filter_map(|name| Some((name.clone(), self.symbols.get(name)?.value)))
+            .collect();
+        RelocatableObject {
+            code: self.bytes.clone(),
+            entry: self.entry,
+            symbols,
+            relocations: self.relocations.clone(),
+        }
+    }
+
+    /// Packs the program into an object file: one segment per run of contiguous code and
+    /// data, so gaps left by `.org` are not stored. Execution starts at the `.entry` address,
+    /// or at the first segment when there is none.
+    pub fn to_object(&self) -> ObjectFile {
+        let mut segments: Vec<Segment> = Vec::new();
+        for line in &self.listing {
+            let (Some(address), false) = (line.address, line.bytes.is_empty()) else {
+                continue;
+            };
+            match segments.last_mut() {
+                Some(last) if last.address as usize + last.bytes.len() == address as usize => {
+                    last.bytes.extend_from_slice(&line.bytes)
+                }
+                _ => segments.push(Segment {
+                    address,
+                    bytes: line.bytes.clone(),
+                }),
+            }
+        }
+        let entry = self
+            .entry
+            .or(segments.first().map(|segment| segment.address))
+            .unwrap_or(0);
+        ObjectFile { entry, segments }
+    }
+
+    /// Renders the listing as text: address, emitted words and the source line.
+    /// Code and data are shown as little endian words, a trailing odd byte on its own.
+    pub fn render_listing(&self) -> String {
+        let mut out = String::new();
+        for line in &self.listing {
+            let address = match line.address {
+                Some(address) => format!("{:04X}", address),
+                None => String::new(),
+            };
+            let words: Vec<String> = line
+                .bytes
+                .chunks(2)
+                .map(|chunk| match chunk {
+                    [lo, hi] => format!("{:04X}", u16::from_le_bytes([*lo, *hi])),
+                    _ => format!("{:02X}", chunk[0]),
+                })
+                .collect();
+            let text = format!("{:4}  {:14}  {}", address, words.join(" "), line.source);
+            out.push_str(text.trim_end());
+            out.push('\n');
+        }
+        out
+    }
+}
+
+fn expand_source(source: &str) -> Result<Vec<SourceLine>, Diagnostic> {
+    let mut lines = Vec::new();
+    expand_includes(
+        source,
+        None,
+        Path::new("."),
+        &mut Vec::new(),
+        "",
+        &mut lines,
+    )?;
+    Ok(lines)
+}
+
+fn expand_file(path: &Path) -> Result<Vec<SourceLine>, Diagnostic> {
+    let source = read_source(path).map_err(Diagnostic::new)?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut chain = vec![canonical(path).map_err(Diagnostic::new)?];
+    let mut lines = Vec::new();
+    expand_includes(&source, Some(path), dir, &mut chain, "", &mut lines)?;
+    Ok(lines)
+}
+
+/// A line of source after includes have been expanded, with where it came from.
+struct SourceLine {
+    file: Option<PathBuf>,
+    line: usize,
+    included_from: String,
+    text: String,
+}
+
+impl SourceLine {
+    fn location(&self) -> String {
+        location(self.file.as_deref(), self.line, &self.included_from)
+    }
+
+    /// An error about `span`, which must be a slice of this line's text; anything else
+    /// points at the whole line.
+    fn error(&self, span: &str, message: String) -> Diagnostic {
+        let start = (span.as_ptr() as usize).wrapping_sub(self.text.as_ptr() as usize);
+        let (start, end) = if start + span.len() <= self.text.len() {
+            (start, start + span.len())
+        } else {
+            (0, self.text.len())
+        };
+        Diagnostic {
+            message,
+            file: self.file.clone(),
+            line: self.line,
+            column: self.text[..start].chars().count() + 1,
+            span: self.text[start..end].chars().count(),
+            source_line: self.text.clone(),
+            included_from: self.included_from.clone(),
+        }
+    }
+}
+
+fn expand_includes(
+    source: &str,
+    file: Option<&Path>,
+    dir: &Path,
+    chain: &mut Vec<PathBuf>,
+    included_from: &str,
+    lines: &mut Vec<SourceLine>,
+) -> Result<(), Diagnostic> {
+    for (n, text) in source.lines().enumerate() {
+        let line = SourceLine {
+            file: file.map(Path::to_path_buf),
+            line: n + 1,
+            included_from: included_from.to_string(),
+            text: text.to_string(),
+        };
+        let (_, rest) = split_label(strip_comment(text).trim());
+        let include = parse_include(rest).map_err(|e| line.error(rest, e))?;
+        let Some(name) = include else {
+            lines.push(line);
+            continue;
+        };
+        let path = dir.join(name);
+        let at = |e: String| line.error(name, e);
+        let key = canonical(&path).map_err(at)?;
+        if let Some(start) = chain.iter().position(|p| *p == key) {
+            let cycle: Vec<String> = chain[start..]
+                .iter()
+                .chain([&key])
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(at(format!("Include cycle: {}", cycle.join(" -> "))));
+        }
+        let included = read_source(&path).map_err(at)?;
+        let location = line.location();
+        // The include line stays in the output so a label on it is still defined.
+        lines.push(line);
+        chain.push(key);
+        let dir = path.parent().unwrap_or(Path::new("."));
+        expand_includes(&included, Some(&path), dir, chain, &location, lines)?;
+        chain.pop();
+    }
+    Ok(())
+}
+
+fn parse_include(line: &str) -> Result<Option<&str>, String> {
+    let (directive, rest) = match line.find(char::is_whitespace) {
+        Some(i) => line.split_at(i),
+        None => (line, ""),
+    };
+    if !directive.eq_ignore_ascii_case(".include") {
+        return Ok(None);
+    }
+    match rest
+        .trim()
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+    {
+        Some(path) if !path.is_empty() => Ok(Some(path)),
+        _ => Err(".include expects a quoted file name".to_string()),
+    }
+}
+
+fn read_source(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("Cannot read '{}': {}", path.display(), e))
+}
+
+fn canonical(path: &Path) -> Result<PathBuf, String> {
+    fs::canonicalize(path).map_err(|e| format!("Cannot read '{}': {}", path.display(), e))
+}
+
+fn assemble_lines(lines: &[SourceLine], relocatable: bool) -> Result<Assembly, Diagnostic> {
+    // First pass: parse every line and assign addresses to labels.
+    let mut symbols: HashMap<String, i32> = HashMap::new();
+    let mut labels = Vec::new();
+    let mut listing = Vec::new();
+    let mut statements = Vec::new();
+    let mut address: u16 = 0;
+    let mut scope = None;
+    let mut entry = None;
+    let mut globals = Vec::new();
+    for (index, source) in lines.iter().enumerate() {
+        let (label, line) = split_label(strip_comment(&source.text).trim());
+        listing.push(ListingLine {
+            address: None,
+            bytes: Vec::new(),
+            source: source.text.trim_end().to_string(),
+        });
+        if let Some(label) = label {
+            let name = qualify(label, scope).map_err(|e| source.error(label, e))?;
+            if !label.contains('.') {
+                scope = Some(label);
+            }
+            define_symbol(&mut symbols, &name, address as i32)
+                .map_err(|e| source.error(label, e))?;
+            labels.push(name);
+            listing[index].address = Some(address);
+        }
+        if line.is_empty() || matches!(parse_include(line), Ok(Some(_))) {
+            continue;
+        }
+        if let Some((name, value)) = parse_equ(line, &symbols).map_err(|e| source.error(line, e))? {
+            define_symbol(&mut symbols, name, value).map_err(|e| source.error(name, e))?;
+            continue;
+        }
+        let (mnemonic, operand_texts) = split_line(line);
+        let operands = operand_texts
+            .iter()
+            .map(|text| parse_scoped_operand(text, scope).map_err(|e| source.error(text, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if mnemonic == ".GLOBAL" {
+            for (operand, text) in operands.iter().zip(&operand_texts) {
+                match operand {
+                    Operand::Expression(Expr::Symbol(name)) => {
+                        globals.push((source, *text, name.clone()))
+                    }
+                    _ => return Err(source.error(text, ".global expects label names".to_string())),
+                }
+            }
+            if operands.is_empty() {
+                return Err(source.error(line, ".global expects label names".to_string()));
+            }
+            continue;
+        }
+        if mnemonic == ".ENTRY" {
+            let [operand @ (Operand::Immediate(_) | Operand::Expression(_))] = operands.as_slice()
+            else {
+                return Err(source.error(line, ".entry expects an address".to_string()));
+            };
+            if entry.is_some() {
+                return Err(source.error(line, "Duplicate .entry".to_string()));
+            }
+            entry = Some((source, operand_texts[0], operand.clone()));
+            continue;
+        }
+        if mnemonic == ".ORG" {
+            let target = match operands.as_slice() {
+                [Operand::Immediate(v)] => imm16(*v),
+                [Operand::Expression(expr)] => {
+                    match expr
+                        .symbols()
+                        .into_iter()
+                        .find(|s| !symbols.contains_key(*s))
+                    {
+                        Some(name) => Err(format!("Symbol '{}' must be defined before .org", name)),
+                        None => expr.evaluate(&symbols).and_then(imm16),
+                    }
+                }
+                _ => Err(".org expects an address".to_string()),
+            }
+            .map_err(|e| source.error(operand_texts.first().unwrap_or(&line), e))?;
+            if target < address {
+                return Err(source.error(
+                    operand_texts[0],
+                    format!(
+                        ".org 0x{:X} is behind the current address 0x{:X}",
+                        target, address
+                    ),
+                ));
+            }
+            address = target;
+            continue;
+        }
+        let placeholders: Vec<Operand> = operands
+            .iter()
+            .map(|operand| match operand {
+                Operand::Expression(_) => Operand::Immediate(0),
+                other => other.clone(),
+            })
+            .collect();
+        let size = encode_statement(&mnemonic, &placeholders)
+            .map_err(|e| source.error(line, e))?
+            .len() as u16;
+        listing[index].address = Some(address);
+        statements.push(Statement {
+            line: index,
+            text: line,
+            operand_texts,
+            address,
+            mnemonic,
+            operands,
+        });
+        address = address
+            .checked_add(size)
+            .ok_or_else(|| source.error(line, "Program does not fit in memory".to_string()))?;
+    }
+
+    // Second pass: resolve symbols and encode.
+    let mut bytes = Vec::new();
+    let mut unresolved: Vec<String> = Vec::new();
+    let mut first_unresolved = None;
+    let mut relocations = Vec::new();
+    let mut debug_info = DebugInfo::new();
+    for statement in &statements {
+        let source = &lines[statement.line];
+        let mut operands = Vec::new();
+        let mut pending = Vec::new();
+        for (index, (operand, text)) in statement
+            .operands
+            .iter()
+            .zip(&statement.operand_texts)
+            .enumerate()
+        {
+            let resolved = match operand {
+                Operand::Expression(expr) if relocatable => {
+                    let (value, dependence) = classify(expr, statement, &symbols, &labels)
+                        .map_err(|e| source.error(text, e))?;
+                    match dependence {
+                        Moves::Never => {}
+                        Moves::WithModule => pending.push((index, *text, None, value)),
+                        Moves::WithSymbol(name) => pending.push((index, *text, Some(name), value)),
+                    }
+                    Operand::Immediate(value)
+                }
+                Operand::Expression(expr) => {
+                    let missing: Vec<&str> = expr
+                        .symbols()
+                        .into_iter()
+                        .filter(|name| !symbols.contains_key(*name))
+                        .collect();
+                    if missing.is_empty() {
+                        let target = expr.evaluate(&symbols).map_err(|e| source.error(text, e))?;
+                        Operand::Immediate(symbol_value(statement, target))
+                    } else {
+                        first_unresolved.get_or_insert((source, *text));
+                        for name in missing {
+                            if !unresolved.iter().any(|u| u == name) {
+                                unresolved.push(name.to_string());
+                            }
+                        }
+                        Operand::Immediate(0)
+                    }
+                }
+                other => other.clone(),
+            };
+            operands.push(resolved);
+        }
+        let encoded = encode_statement(&statement.mnemonic, &operands)
+            .map_err(|e| source.error(statement.text, e))?;
+        for (index, text, symbol, value) in pending {
+            let offset = relocated_field(&statement.mnemonic, &operands, index)
+                .map_err(|e| source.error(text, e))?;
+            relocations.push(Relocation {
+                offset: statement.address + offset as u16,
+                symbol,
+                addend: value as u16,
+            });
+        }
+        if !encoded.is_empty() {
+            debug_info.lines.push(LineInfo {
+                address: statement.address,
+                size: encoded.len() as u16,
+                file: source
+                    .file
+                    .as_ref()
+                    .map_or(String::new(), |f| f.display().to_string()),
+                line: source.line,
+            });
+        }
+        bytes.resize(statement.address as usize, 0);
+        bytes.extend_from_slice(&encoded);
+        listing[statement.line].bytes = encoded;
+    }
+    if let Some((source, text)) = first_unresolved {
+        let message = format!("Unresolved symbols: {}", unresolved.join(", "));
+        return Err(source.error(text, message));
+    }
+    for (source, text, name) in &globals {
+        if !labels.contains(name) {
+            return Err(source.error(text, format!("Global symbol '{}' is not a label", name)));
+        }
+    }
+    let entry = match entry {
+        Some((source, text, operand)) => {
+            let value = match operand {
+                Operand::Expression(expr) => {
+                    let missing: Vec<&str> = expr
+                        .symbols()
+                        .into_iter()
+                        .filter(|name| !symbols.contains_key(*name))
+                        .collect();
+                    if !missing.is_empty() {
+                        let message = format!("Unresolved symbols: {}", missing.join(", "));
+                        return Err(source.error(text, message));
+                    }
+                    expr.evaluate(&symbols)
+                }
+                Operand::Immediate(v) => Ok(v),
+                _ => unreachable!(),
+            };
+            Some(value.and_then(imm16).map_err(|e| source.error(text, e))?)
+        }
+        None => None,
+    };
+
+    let mut table = SymbolTable::new();
+    for (name, &value) in &symbols {
+        let kind = if labels.contains(name) {
+            SymbolKind::Label
+        } else {
+            SymbolKind::Constant
+        };
+        table.insert(name, value as u16, kind);
+    }
+    // Each non-local label scopes the code up to the next one; of several labels at one
+    // address only the last is kept.
+    let scope_starts: Vec<&Symbol> = table
+        .iter()
+        .filter(|s| s.kind == SymbolKind::Label && !s.name.contains('.'))
+        .collect();
+    for (i, symbol) in scope_starts.iter().enumerate() {
+        let end = match scope_starts.get(i + 1) {
+            Some(next) => next.value,
+            None => bytes.len() as u16,
+        };
+        if symbol.value < end {
+            debug_info.scopes.push(Scope {
+                name: symbol.name.clone(),
+                start: symbol.value,
+                end,
+            });
+        }
+    }
+    Ok(Assembly {
+        bytes,
+        listing,
+        symbols: table,
+        entry,
+        globals: globals.into_iter().map(|(_, _, name)| name).collect(),
+        relocations,
+        debug_info,
+    })
+}
+
+fn encode_statement(mnemonic: &str, operands: &[Operand]) -> Result<Vec<u8>, String> {
+    if mnemonic.starts_with('.') {
+        return encode_directive(mnemonic, operands);
+    }
+    let op = build_op(mnemonic, operands)?;
+    Ok(op
+        .encode()
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect())
+}
+
+fn encode_directive(directive: &str, operands: &[Operand]) -> Result<Vec<u8>, String> {
+    if operands.is_empty() {
+        return Err(format!("{} expects at least one operand", directive));
+    }
+    let mut bytes = Vec::new();
+    for operand in operands {
+        match (directive, operand) {
+            (".WORD", Operand::Immediate(v)) => bytes.extend_from_slice(&imm16(*v)?.to_le_bytes()),
+            (".BYTE", Operand::Immediate(v)) => bytes.push(imm8(*v)?),
+            (".ASCII", Operand::String(text)) => bytes.extend_from_slice(text),
+            (".WORD" | ".BYTE" | ".ASCII", _) => {
+                return Err(format!("Invalid operand for {}", directive))
+            }
+            _ => return Err(format!("Unknown directive '{}'", directive)),
+        }
+    }
+    Ok(bytes)
+}
+
+fn symbol_value(statement: &Statement, target: i32) -> i32 {
+    if statement.mnemonic == "JR" {
+        target - (statement.address as i32 + 2)
+    } else {
+        target
+    }
+}
+
+/// How a value in a relocatable module changes at link time.
+enum Moves {
+    Never,
+    WithModule,
+    WithSymbol(String),
+}
+
+/// Works out how an operand of a relocatable module depends on where things end up, by
+/// evaluating it again with every label moved and with each undefined symbol moved in turn.
+/// Returns the value as if the module was at address 0 with external symbols at 0.
+fn classify(
+    expr: &Expr,
+    statement: &Statement,
+    symbols: &HashMap<String, i32>,
+    labels: &[String],
+) -> Result<(i32, Moves), String> {
+    const SHIFT: i32 = 0x1000;
+    let cannot = || "Expression cannot be relocated".to_string();
+    let mut externs: Vec<&str> = expr
+        .symbols()
+        .into_iter()
+        .filter(|name| !symbols.contains_key(*name))
+        .collect();
+    externs.dedup();
+    let mut env = symbols.clone();
+    for name in &externs {
+        env.insert(name.to_string(), 0);
+    }
+    let value = symbol_value(statement, expr.evaluate(&env)?);
+
+    let mut moved = env.clone();
+    for label in labels {
+        if let Some(address) = moved.get_mut(label) {
+            *address += SHIFT;
+        }
+    }
+    // The distance a JR jumps does not change when the whole module moves.
+    let jr_shift = if statement.mnemonic == "JR" { SHIFT } else { 0 };
+    let mut dependence = match symbol_value(statement, expr.evaluate(&moved)?) - jr_shift - value {
+        0 => Moves::Never,
+        SHIFT => Moves::WithModule,
+        _ => return Err(cannot()),
+    };
+    for name in externs {
+        let mut moved = env.clone();
+        moved.insert(name.to_string(), SHIFT);
+        match (
+            symbol_value(statement, expr.evaluate(&moved)?) - value,
+            &dependence,
+        ) {
+            (0, _) => {}
+            (SHIFT, Moves::Never) => dependence = Moves::WithSymbol(name.to_string()),
+            _ => return Err(cannot()),
+        }
+    }
+    Ok((value, dependence))
+}
+
+/// Byte offset of the 16-bit field that operand `index` is encoded into.
+fn relocated_field(mnemonic: &str, operands: &[Operand], index: usize) -> Result<usize, String> {
+    let mut probe = operands.to_vec();
+    probe[index] = Operand::Immediate(0);
+    let low = encode_statement(mnemonic, &probe);
+    probe[index] = Operand::Immediate(0xffff);
+    let high = encode_statement(mnemonic, &probe);
+    if let (Ok(low), Ok(high)) = (low, high) {
+        let changed: Vec<usize> = (0..low.len()).filter(|&i| low[i] != high[i]).collect();
+        if let [first, second] = changed[..] {
+            if second == first + 1 {
+                return Ok(first);
+            }
+        }
+    }
+    Err(
+        "Only 16-bit operands can refer to labels or external symbols in a relocatable module"
+            .to_string(),
+    )
+}
+
+/// Turns a local label such as `.loop` into `main.loop`, where `main` is the enclosing
+/// global label.
+fn qualify(name: &str, scope: Option<&str>) -> Result<String, String> {
+    if !name.starts_with('.') {
+        return Ok(name.to_string());
+    }
+    scope
+        .map(|scope| format!("{}{}", scope, name))
+        .ok_or(format!("Local label '{}' has no enclosing label", name))
+}
+
+fn parse_scoped_operand(s: &str, scope: Option<&str>) -> Result<Operand, String> {
+    let mut operand = parse_operand(s)?;
+    if let Operand::Expression(expr) = &mut operand {
+        expr.rename_symbols(&mut |name| qualify(name, scope))?;
+    }
+    Ok(operand)
+}
+
+fn define_symbol(symbols: &mut HashMap<String, i32>, name: &str, value: i32) -> Result<(), String> {
+    if parse_register_name(name).is_some() {
+        return Err(format!("Label '{}' is a register name", name));
+    }
+    if symbols.insert(name.to_string(), value).is_some() {
+        return Err(format!("Duplicate label '{}'", name));
+    }
+    Ok(())
+}
+
+/// Parses `.equ NAME value` (or `.equ NAME, value`), evaluating the value right away.
+fn parse_equ<'a>(
+    line: &'a str,
+    symbols: &HashMap<String, i32>,
+) -> Result<Option<(&'a str, i32)>, String> {
+    let (directive, rest) = match line.find(char::is_whitespace) {
+        Some(i) => line.split_at(i),
+        None => (line, ""),
+    };
+    if !directive.eq_ignore_ascii_case(".equ") {
+        return Ok(None);
+    }
+    let rest = rest.trim();
+    let end = rest
+        .find(|c: char| c.is_whitespace() || c == ',')
+        .unwrap_or(rest.len());
+    let (name, value) = rest.split_at(end);
+    let value = value.trim_start().trim_start_matches(',').trim();
+    if !is_identifier(name) || value.is_empty() {
+        return Err(".equ expects a name and a value".to_string());
+    }
+    let value = Expr::parse(value)?.evaluate(symbols)?;
+    Ok(Some((name, value)))
+}
+
+/// Byte offset of the first `target` that is not inside a string literal.
+fn find_unquoted(s: &str, target: char) -> Option<usize> {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            c if c == target && !in_string => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn strip_comment(line: &str) -> &str {
+    match find_unquoted(line, ';') {
+        Some(i) => &line[..i],
+        None => line,
+    }
+}
+
+fn split_operands(mut s: &str) -> Vec<&str> {
+    let mut operands = Vec::new();
+    while let Some(i) = find_unquoted(s, ',') {
+        operands.push(s[..i].trim());
+        s = &s[i + 1..];
+    }
+    operands.push(s.trim());
+    operands
+}
+
+/// Decodes the escapes `\n`, `\r`, `\t`, `\0`, `\\`, `\"` and `\xHH` in a string literal.
+fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        let byte = match chars.next() {
+            Some('n') => b'\n',
+            Some('r') => b'\r',
+            Some('t') => b'\t',
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            Some('"') => b'"',
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                u8::from_str_radix(&hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == 2)
+                    .ok_or(format!("Invalid escape '\\x{}'", hex))?
+            }
+            Some(c) => return Err(format!("Invalid escape '\\{}'", c)),
+            None => return Err("Unterminated string".to_string()),
+        };
+        bytes.push(byte);
+    }
+    Ok(bytes)
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+fn split_label(line: &str) -> (Option<&str>, &str) {
+    if let Some(i) = line.find(':') {
+        let label = line[..i].trim();
+        // `.loop` is local to the label above it, `main.loop` names a local label in full.
+        let mut parts = label.strip_prefix('.').unwrap_or(label).split('.');
+        if parts.next().is_some_and(is_identifier) && parts.all(is_identifier) {
+            return (Some(label), line[i + 1..].trim());
+        }
+    }
+    (None, line)
+}
+
+/// Splits a line into its upper-cased mnemonic and the text of each operand.
+fn split_line(line: &str) -> (String, Vec<&str>) {
+    let (mnemonic, rest) = match line.find(char::is_whitespace) {
+        Some(i) => line.split_at(i),
+        None => (line, ""),
+    };
+    let operands = if rest.trim().is_empty() {
+        Vec::new()
+    } else {
+        split_operands(rest)
+    };
+    (mnemonic.to_uppercase(), operands)
+}
+
+pub(crate) fn parse_register_name(s: &str) -> Option<Register> {
+    let reg = match s.to_uppercase().as_str() {
+        "A" => Register::A,
+        "B" => Register::B,
+        "C" => Register::C,
+        "D" => Register::D,
+        "E" => Register::E,
+        "F" => Register::F,
+        "G" => Register::G,
+        "H" => Register::H,
+        "M" => Register::M,
+        "SP" => Register::SP,
+        "PC" => Register::PC,
+        "BP" => Register::BP,
+        "FLAGS" => Register::FLAGS,
+        _ => return None,
+    };
+    Some(reg)
+}
+
+fn parse_operand(s: &str) -> Result<Operand, String> {
+    if let Some(reg) = parse_register_name(s) {
+        return Ok(Operand::Register(reg));
+    }
+    if let Some(text) = s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        return unescape(text).map(Operand::String);
+    }
+    let expr = Expr::parse(s)?;
+    if expr.symbols().is_empty() {
+        expr.evaluate(&HashMap::new()).map(Operand::Immediate)
+    } else {
+        Ok(Operand::Expression(expr))
+    }
+}
+
+fn imm4(value: i32) -> Result<u8, String> {
+    if (0..16).contains(&value) {
+        Ok(value as u8)
+    } else {
+        Err(format!("Value {} does not fit in 4 bits", value))
+    }
+}
+
+fn imm8(value: i32) -> Result<u8, String> {
+    if (0..=0xff).contains(&value) {
+        Ok(value as u8)
+    } else {
+        Err(format!("Value {} does not fit in 8 bits", value))
+    }
+}
+
+fn simm8(value: i32) -> Result<i8, String> {
+    if (-0x80..0x80).contains(&value) {
+        Ok(value as i8)
+    } else {
+        Err(format!("Offset {} does not fit in a signed byte", value))
+    }
+}
+
+fn imm16(value: i32) -> Result<u16, String> {
+    if (-0x8000..=0xffff).contains(&value) {
+        Ok(value as u16)
+    } else {
+        Err(format!("Value {} does not fit in 16 bits", value))
+    }
+}
+
+fn build_op(mnemonic: &str, operands: &[Operand]) -> Result<Op, String> {
+    use Operand::{Immediate as I, Register as R};
+    let op = match (mnemonic, operands) {
+        ("NOP", []) => Op::Nop,
+        ("PUSH", [I(v)]) => Op::Push(imm8(*v)?),
+        ("PUSH", [R(r)]) => Op::PushRegister(*r),
+        ("PUSHW", [I(v)]) => Op::Push16(imm16(*v)?),
+        ("POP", [R(r)]) => Op::PopRegister(*r),
+        ("ADDS", []) => Op::AddStack,
+        ("SUBS", []) => Op::SubStack,
+        ("MULS", []) => Op::MulStack,
+        ("DIVS", []) => Op::DivStack,
+        ("ADD", [R(r1), R(r2)]) => Op::AddRegister(*r1, *r2),
+        ("SUB", [R(r1), R(r2)]) => Op::SubRegister(*r1, *r2),
+        ("MUL", [R(r1), R(r2)]) => Op::MulRegister(*r1, *r2),
+        ("DIV", [R(r1), R(r2)]) => Op::DivRegister(*r1, *r2),
+        ("MULW", [R(r1), R(r2)]) if r1 == r2 => {
+            return Err(format!(
+                "MULW needs two different registers, not {} twice",
+                r1
+            ))
+        }
+        ("MULW", [R(r1), R(r2)]) => Op::MulWide(*r1, *r2),
+        ("ADC", [R(r1), R(r2)]) => Op::Adc(*r1, *r2),
+        ("SBB", [R(r1), R(r2)]) => Op::Sbb(*r1, *r2),
+        ("ADDSAT", [R(r1), R(r2)]) => Op::AddSat(*r1, *r2),
+        ("SUBSAT", [R(r1), R(r2)]) => Op::SubSat(*r1, *r2),
+        ("MAC", [R(r1), R(r2), R(r3)]) => Op::Mac(*r1, *r2, *r3),
+        ("ADDI", [R(r), I(v)]) => Op::AddImm(*r, imm8(*v)?),
+        ("SUBI", [R(r), I(v)]) => Op::SubImm(*r, imm8(*v)?),
+        ("INC", [R(r)]) => Op::Inc(*r),
+        ("DEC", [R(r)]) => Op::Dec(*r),
+        ("NEG", [R(r)]) => Op::Neg(*r),
+        ("MOV", [R(r1), R(r2)]) => Op::Mov(*r1, *r2),
+        ("SWAP", [R(r1), R(r2)]) => Op::Swap(*r1, *r2),
+        ("CMOVZ", [R(r1), R(r2)]) => Op::CmovZ(*r1, *r2),
+        ("CMOVNZ", [R(r1), R(r2)]) => Op::CmovNz(*r1, *r2),
+        ("LDI", [R(r), I(v)]) => Op::LoadImm(*r, imm8(*v)?),
+        ("LDIW", [R(r), I(v)]) => Op::LoadImm16(*r, imm16(*v)?),
+        ("LOAD", [R(r1), R(r2)]) => Op::Load(*r1, *r2),
+        ("STORE", [R(r1), R(r2)]) => Op::Store(*r1, *r2),
+        ("LOADB", [R(r1), R(r2)]) => Op::LoadByte(*r1, *r2),
+        ("STOREB", [R(r1), R(r2)]) => Op::StoreByte(*r1, *r2),
+        ("SEXT8", [R(r)]) => Op::Sext8(*r),
+        ("ZEXT8", [R(r)]) => Op::Zext8(*r),
+        ("SHL", [R(r), I(v)]) => Op::Shl(*r, imm4(*v)?),
+        ("SHR", [R(r), I(v)]) => Op::Shr(*r, imm4(*v)?),
+        ("SAR", [R(r), I(v)]) => Op::Sar(*r, imm4(*v)?),
+        ("SHL", [R(r1), R(r2)]) => Op::ShlRegister(*r1, *r2),
+        ("SHR", [R(r1), R(r2)]) => Op::ShrRegister(*r1, *r2),
+        ("SAR", [R(r1), R(r2)]) => Op::SarRegister(*r1, *r2),
+        ("ROL", [R(r), I(v)]) => Op::Rol(*r, imm4(*v)?),
+        ("ROR", [R(r), I(v)]) => Op::Ror(*r, imm4(*v)?),
+        ("RCL", [R(r), I(v)]) => Op::Rcl(*r, imm4(*v)?),
+        ("RCR", [R(r), I(v)]) => Op::Rcr(*r, imm4(*v)?),
+        ("BTST", [R(r), I(v)]) => Op::BitTest(*r, imm4(*v)?),
+        ("BSET", [R(r), I(v)]) => Op::BitSet(*r, imm4(*v)?),
+        ("BCLR", [R(r), I(v)]) => Op::BitClear(*r, imm4(*v)?),
+        ("BTGL", [R(r), I(v)]) => Op::BitToggle(*r, imm4(*v)?),
+        ("CLZ", [R(r)]) => Op::Clz(*r),
+        ("POPCNT", [R(r)]) => Op::Popcnt(*r),
+        ("MIN", [R(r1), R(r2)]) => Op::Min(*r1, *r2),
+        ("MAX", [R(r1), R(r2)]) => Op::Max(*r1, *r2),
+        ("MINS", [R(r1), R(r2)]) => Op::MinSigned(*r1, *r2),
+        ("MAXS", [R(r1), R(r2)]) => Op::MaxSigned(*r1, *r2),
+        ("CMP", [R(r1), R(r2)]) => Op::Cmp(*r1, *r2),
+        ("TEST", [R(r1), R(r2)]) => Op::Test(*r1, *r2),
+        ("JMP", [I(v)]) => Op::Jmp(imm16(*v)?),
+        ("JMP", [R(r)]) => Op::JmpRegister(*r),
+        ("JR", [I(v)]) => Op::JmpRelative(simm8(*v)?),
+        ("JZ", [I(v)]) => Op::Jz(imm16(*v)?),
+        ("JNZ", [I(v)]) => Op::Jnz(imm16(*v)?),
+        ("JLT", [I(v)]) => Op::Jlt(imm16(*v)?),
+        ("JGT", [I(v)]) => Op::Jgt(imm16(*v)?),
+        ("JLE", [I(v)]) => Op::Jle(imm16(*v)?),
+        ("JGE", [I(v)]) => Op::Jge(imm16(*v)?),
+        ("LOOP", [R(r), I(v)]) => Op::Loop(*r, imm16(*v)?),
+        ("CALL", [I(v)]) => Op::Call(imm16(*v)?),
+        ("RET", []) => Op::Ret,
+        ("INT", [I(v)]) => Op::Int(imm8(*v)?),
+        ("IRET", []) => Op::Iret,
+        ("SYSCALL", [I(v)]) => Op::Syscall(imm8(*v)?),
+        ("PUSHA", []) => Op::PushAll,
+        ("POPA", []) => Op::PopAll,
+        ("PUSHF", []) => Op::PushFlags,
+        ("POPF", []) => Op::PopFlags,
+        ("LCS", [R(r)]) => Op::LoadCodeSegment(*r),
+        ("LDS", [R(r)]) => Op::LoadDataSegment(*r),
+        ("MEMCPY", [R(r1), R(r2), R(r3)]) => Op::MemCpy(*r1, *r2, *r3),
+        ("MEMSET", [R(r1), R(r2), R(r3)]) => Op::MemSet(*r1, *r2, *r3),
+        ("CAS", [R(r1), R(r2), R(r3)]) => Op::Cas(*r1, *r2, *r3),
+        ("HALT", []) => Op::Halt,
+        _ => {
+            return Err(format!(
+                "Invalid instruction or operands for '{}'",
+                mnemonic
+            ))
+        }
+    };
+    Ok(op)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Machine;
+
+    fn assemble(source: &str) -> Result<Vec<u8>, String> {
+        super::assemble(source).map_err(|e| e.to_string())
+    }
+
+    fn assemble_file(path: impl AsRef<Path>) -> Result<Vec<u8>, String> {
+        super::assemble_file(path).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_assemble_bytes() {
+        let bytes = assemble("PUSH 2\nPOP A\nADDS").unwrap();
+        assert_eq!(bytes, vec![0x01, 0x02, 0x02, 0x00, 0x03, 0x00]);
+    }
+
+    #[test]
+    fn test_assemble_operands() {
+        let bytes = assemble("mov c, m ; copy\n\n  LDIW h, 0xbeef\nJR -2").unwrap();
+        let mut expected = Vec::new();
+        for op in [
+            Op::Mov(Register::C, Register::M),
+            Op::LoadImm16(Register::H, 0xbeef),
+            Op::JmpRelative(-2),
+        ] {
+            for word in op.encode() {
+                expected.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn test_assemble_and_run() {
+        let source = "
+            LDI SP, 0x80  ; stack away from the code
+            PUSH 2
+            PUSH 6
+            ADDS
+            POP A
+            HALT
+        ";
+        let bytes = assemble(source).unwrap();
+        let mut m = Machine::new();
+        for (i, byte) in bytes.iter().enumerate() {
+            m.memory.write(i as u16, *byte);
+        }
+        m.run().unwrap();
+        assert_eq!(m.get_register(Register::A), 8);
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert_eq!(
+            assemble("NOP\nFOO A"),
+            Err("line 2: Invalid instruction or operands for 'FOO'".to_string())
+        );
+        assert_eq!(
+            assemble("PUSH 256"),
+            Err("line 1: Value 256 does not fit in 8 bits".to_string())
+        );
+        assert_eq!(
+            assemble("SHL A, B, C"),
+            Err("line 1: Invalid instruction or operands for 'SHL'".to_string())
+        );
+        assert_eq!(
+            assemble("PUSH $"),
+            Err("line 1: Unexpected character '$' in expression".to_string())
+        );
+        assert_eq!(
+            assemble("MULW A, A"),
+            Err("line 1: MULW needs two different registers, not A twice".to_string())
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        let source = "
+                JMP start      ; forward reference
+            data: NOP
+            start:
+                LDI C, 3
+            loop: INC A
+                LOOP C, loop
+                JR end
+                NOP
+            end: HALT
+        ";
+        let bytes = assemble(source).unwrap();
+        let mut m = Machine::new();
+        for (i, byte) in bytes.iter().enumerate() {
+            m.memory.write(i as u16, *byte);
+        }
+        assert_eq!(m.memory.read2(2), Some(6));
+        m.run().unwrap();
+        assert_eq!(m.get_register(Register::A), 3);
+        assert_eq!(m.get_register(Register::PC), bytes.len() as u16);
+    }
+
+    #[test]
+    fn test_label_errors() {
+        assert_eq!(
+            assemble("JMP foo\nCALL bar\nJZ foo"),
+            Err("line 1: Unresolved symbols: foo, bar".to_string())
+        );
+        assert_eq!(
+            assemble("x: NOP\nx: NOP"),
+            Err("line 2: Duplicate label 'x'".to_string())
+        );
+        assert_eq!(
+            assemble("NOP\nsp: NOP"),
+            Err("line 2: Label 'sp' is a register name".to_string())
+        );
+    }
+
+    #[test]
+    fn test_data_directives() {
+        let source = "
+                LDIW M, message
+                HALT
+            table: .word 0x1234, table, -1
+                .byte 1, 2, 0xff
+            .org 0x20
+            message: .ascii \"Hi; there, you\"  ; a comment
+        ";
+        let bytes = assemble(source).unwrap();
+        assert_eq!(&bytes[..4], &[0x21, 0x03, 0x20, 0x00]);
+        assert_eq!(&bytes[6..12], &[0x34, 0x12, 0x06, 0x00, 0xff, 0xff]);
+        assert_eq!(&bytes[12..15], &[1, 2, 0xff]);
+        assert!(bytes[15..0x20].iter().all(|&b| b == 0));
+        assert_eq!(&bytes[0x20..], b"Hi; there, you");
+    }
+
+    #[test]
+    fn test_directive_errors() {
+        assert_eq!(
+            assemble(".org 0x10\n.org 0x8"),
+            Err("line 2: .org 0x8 is behind the current address 0x10".to_string())
+        );
+        assert_eq!(
+            assemble(".byte 300"),
+            Err("line 1: Value 300 does not fit in 8 bits".to_string())
+        );
+        assert_eq!(
+            assemble(".word \"text\""),
+            Err("line 1: Invalid operand for .WORD".to_string())
+        );
+        assert_eq!(
+            assemble(".space 4"),
+            Err("line 1: Unknown directive '.SPACE'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_expressions() {
+        let source = "
+            .equ UART 0xFF00
+            .equ BUFFER_BASE, 0x40
+            .equ SIZE BUFFER_BASE * 2
+                LDIW M, UART + 1
+                PUSH BUFFER_BASE+2
+                LDI A, (SIZE >> 4) | 1
+            data: .word data + SIZE, ~0
+        ";
+        let expected = "
+                LDIW M, 0xFF01
+                PUSH 0x42
+                LDI A, 9
+                .word 0x88, 0xffff
+        ";
+        assert_eq!(assemble(source), assemble(expected));
+    }
+
+    #[test]
+    fn test_expression_errors() {
+        assert_eq!(
+            assemble(".equ X Y + 1"),
+            Err("line 1: Unresolved symbol 'Y'".to_string())
+        );
+        assert_eq!(
+            assemble(".equ X 1\n.equ X 2"),
+            Err("line 2: Duplicate label 'X'".to_string())
+        );
+        assert_eq!(
+            assemble(".equ A 1"),
+            Err("line 1: Label 'A' is a register name".to_string())
+        );
+        assert_eq!(
+            assemble(".equ X"),
+            Err("line 1: .equ expects a name and a value".to_string())
+        );
+        assert_eq!(
+            assemble("PUSH 1 / 0"),
+            Err("line 1: Division by zero".to_string())
+        );
+        assert_eq!(
+            assemble("PUSH foo + bar\nPUSH bar"),
+            Err("line 1: Unresolved symbols: foo, bar".to_string())
+        );
+        assert_eq!(
+            assemble(".org end\nend: HALT"),
+            Err("line 1: Symbol 'end' must be defined before .org".to_string())
+        );
+    }
+
+    fn write_sources(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tinyvm-{}-{}", name, std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        for (file, source) in files {
+            fs::write(dir.join(file), source).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = write_sources(
+            "include",
+            &[
+                (
+                    "main.asm",
+                    ".include \"lib/consts.asm\"\nstart: PUSH VALUE\nHALT",
+                ),
+                (
+                    "lib/consts.asm",
+                    ".include \"base.asm\"\n.equ VALUE BASE + 1",
+                ),
+                ("lib/base.asm", ".equ BASE 41 ; shared header"),
+            ],
+        );
+        assert_eq!(
+            assemble_file(dir.join("main.asm")),
+            assemble("PUSH 42\nHALT")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let dir = write_sources(
+            "include-errors",
+            &[
+                ("a.asm", "NOP\n.include \"b.asm\""),
+                ("b.asm", ".include \"a.asm\""),
+                ("bad.asm", "NOP\n.include \"lib/bad.asm\""),
+                ("lib/bad.asm", "\nPUSH 300"),
+            ],
+        );
+        let a = dir.join("a.asm").canonicalize().unwrap();
+        let b = dir.join("b.asm").canonicalize().unwrap();
+        assert_eq!(
+            assemble_file(dir.join("a.asm")),
+            Err(format!(
+                "{} line 1, included from {} line 2: Include cycle: {} -> {} -> {}",
+                dir.join("b.asm").display(),
+                dir.join("a.asm").display(),
+                a.display(),
+                b.display(),
+                a.display()
+            ))
+        );
+        assert_eq!(
+            assemble_file(dir.join("bad.asm")),
+            Err(format!(
+                "{} line 2, included from {} line 2: Value 300 does not fit in 8 bits",
+                dir.join("lib/bad.asm").display(),
+                dir.join("bad.asm").display()
+            ))
+        );
+        assert_eq!(
+            assemble(".include"),
+            Err("line 1: .include expects a quoted file name".to_string())
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_listing_and_symbols() {
+        let source = "\
+.equ UART 0xFF00
+start:  LDIW M, UART ; load the port
+        JMP start
+
+msg:    .byte 1, 2, 3";
+        let assembly = Assembly::from_source(source).unwrap();
+        assert_eq!(
+            assembly.render_listing(),
+            concat!(
+                "                      .equ UART 0xFF00\n",
+                "0000  0321 FF00       start:  LDIW M, UART ; load the port\n",
+                "0004  0013 0000               JMP start\n",
+                "\n",
+                "0008  0201 03         msg:    .byte 1, 2, 3\n",
+            )
+        );
+        assert_eq!(
+            assembly.symbols.to_string(),
+            "label 0x0000 start\nlabel 0x0008 msg\nconst 0xFF00 UART\n"
+        );
+        assert_eq!(assembly.symbols.label_at(8), Some("msg"));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let error = super::assemble("NOP\n  LDI A, 1 +  300 ; too big").unwrap_err();
+        assert_eq!((error.line, error.column, error.span), (2, 3, 15));
+        assert_eq!(error.message, "Value 301 does not fit in 8 bits");
+        let error = super::assemble("PUSH 1\nPUSH 2, (1 +\nHALT").unwrap_err();
+        assert_eq!((error.line, error.column, error.span), (2, 9, 4));
+        assert_eq!(
+            error.render(),
+            concat!(
+                "error: Unexpected end of expression\n",
+                "  --> line 2, column 9\n",
+                "  |\n",
+                "2 | PUSH 2, (1 +\n",
+                "  |         ^^^^\n",
+            )
+        );
+        let error = super::assemble("start: NOP\nstart: NOP").unwrap_err();
+        assert_eq!((error.line, error.column, error.span), (2, 1, 5));
+        let error = super::assemble("JMP nowhere").unwrap_err();
+        assert_eq!((error.line, error.column, error.span), (1, 5, 7));
+    }
+
+    #[test]
+    fn test_local_labels() {
+        let source = "
+            first:  LDI C, 2
+            .loop:  DEC C
+                    JNZ .loop
+            second: LDI C, 3
+            .loop:  DEC C
+                    JNZ .loop
+                    JR .done
+            .done:  JMP first.loop
+        ";
+        let expected = "
+                    LDI C, 2
+                    DEC C
+                    JNZ 2
+                    LDI C, 3
+                    DEC C
+                    JNZ 10
+                    JR 0
+                    JMP 2
+        ";
+        assert_eq!(assemble(source), assemble(expected));
+        let assembly = Assembly::from_source(source).unwrap();
+        assert_eq!(assembly.symbols.label_at(10), Some("second.loop"));
+
+        assert_eq!(
+            assemble(".loop: NOP"),
+            Err("line 1: Local label '.loop' has no enclosing label".to_string())
+        );
+        assert_eq!(
+            assemble("JMP .loop"),
+            Err("line 1: Local label '.loop' has no enclosing label".to_string())
+        );
+        assert_eq!(
+            assemble("a1: NOP\n.x: NOP\n.x: NOP"),
+            Err("line 3: Duplicate label 'a1.x'".to_string())
+        );
+        assert_eq!(
+            assemble("a1: NOP\nb1: NOP\na1.x: JMP a1.x"),
+            assemble("NOP\nNOP\nJMP 4")
+        );
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        assert_eq!(
+            assemble(r#".ascii "Hi\n\0", "\t\"q\";\x7f\xFF" ; comment"#),
+            Ok(b"Hi\n\0\t\"q\";\x7f\xff".to_vec())
+        );
+        assert_eq!(
+            assemble(r#".ascii "\q""#),
+            Err(r"line 1: Invalid escape '\q'".to_string())
+        );
+        assert_eq!(
+            assemble(r#".ascii "\x4""#),
+            Err(r"line 1: Invalid escape '\x4'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_object() {
+        let source = "
+                .entry main
+                .word 0xbeef
+                .org 0x20
+        main:   PUSH 1
+                HALT
+        ";
+        let assembly = Assembly::from_source(source).unwrap();
+        assert_eq!(assembly.entry, Some(0x20));
+        let object = assembly.to_object();
+        assert_eq!(object.entry, 0x20);
+        assert_eq!(
+            object.segments,
+            vec![
+                Segment {
+                    address: 0,
+                    bytes: vec![0xef, 0xbe],
+                },
+                Segment {
+                    address: 0x20,
+                    bytes: vec![0x01, 0x01, 0x22, 0x00],
+                },
+            ]
+        );
+        let object = Assembly::from_source(".org 0x10\nHALT")
+            .unwrap()
+            .to_object();
+        assert_eq!(object.entry, 0x10);
+
+        assert_eq!(
+            assemble(".entry nowhere"),
+            Err("line 1: Unresolved symbols: nowhere".to_string())
+        );
+        assert_eq!(
+            assemble(".entry 1\n.entry 2"),
+            Err("line 2: Duplicate .entry".to_string())
+        );
+        assert_eq!(
+            assemble(".entry A"),
+            Err("line 1: .entry expects an address".to_string())
+        );
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let dir = write_sources(
+            "debug-info",
+            &[
+                (
+                    "main.asm",
+                    "main:   CALL double\n        HALT\n.include \"lib.asm\"",
+                ),
+                ("lib.asm", "\n.equ TWO 2\ndouble: LDI B, TWO\n.done:  RET"),
+            ],
+        );
+        let assembly = Assembly::from_file(dir.join("main.asm")).unwrap();
+        let info = &assembly.debug_info;
+        let main = dir.join("main.asm").display().to_string();
+        let lib = dir.join("lib.asm").canonicalize().unwrap();
+        let lib = lib.display().to_string();
+        let lines: Vec<(u16, u16, &str, usize)> = info
+            .lines
+            .iter()
+            .map(|l| (l.address, l.size, l.file.as_str(), l.line))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (0, 4, main.as_str(), 1),
+                (4, 2, main.as_str(), 2),
+                (6, 2, lib.as_str(), 3),
+                (8, 2, lib.as_str(), 4),
+            ]
+        );
+        assert_eq!(
+            info.scopes,
+            vec![
+                Scope {
+                    name: "main".to_string(),
+                    start: 0,
+                    end: 6,
+                },
+                Scope {
+                    name: "double".to_string(),
+                    start: 6,
+                    end: 10,
+                },
+            ]
+        );
+        assert_eq!(info.describe(8), format!("double+0x2 at {} line 4", lib));
+        fs::remove_dir_all(dir).unwrap();
+
+        let info = Assembly::from_source("one:\ntwo: NOP\n.word 1")
+            .unwrap()
+            .debug_info;
+        assert_eq!(info.describe(2), "two+0x2 at line 3");
+    }
+
+    #[test]
+    fn test_relocatable() {
+        let source = "
+                .global main
+        main:   LDIW A, data + 1
+                CALL print
+                JMP main
+        data:   .word print - 2, 7
+        ";
+        let module = Assembly::from_source_relocatable(source)
+            .unwrap()
+            .to_relocatable();
+        assert_eq!(module.symbols, vec![("main".to_string(), 0)]);
+        assert_eq!(
+            module.relocations,
+            vec![
+                Relocation {
+                    offset: 2,
+                    symbol: None,
+                    addend: 13,
+                },
+                Relocation {
+                    offset: 6,
+                    symbol: Some("print".to_string()),
+                    addend: 0,
+                },
+                Relocation {
+                    offset: 10,
+                    symbol: None,
+                    addend: 0,
+                },
+                Relocation {
+                    offset: 12,
+                    symbol: Some("print".to_string()),
+                    addend: 0xfffe,
+                },
+            ]
+        );
+
+        let error = |source| {
+            Assembly::from_source_relocatable(source)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(
+            error("here: LDI A, here"),
+            Err("line 1: Only 16-bit operands can refer to labels or external symbols in a relocatable module".to_string())
+        );
+        assert_eq!(
+            error("LDIW A, print * 2"),
+            Err("line 1: Expression cannot be relocated".to_string())
+        );
+        assert_eq!(
+            error(".global BUFFER\n.equ BUFFER 10"),
+            Err("line 1: Global symbol 'BUFFER' is not a label".to_string())
+        );
+        assert_eq!(
+            assemble("CALL print"),
+            Err("line 1: Unresolved symbols: print".to_string())
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use crate::symbols::SymbolTable;
+use crate::vm::{Machine, Register};
+use std::fmt;
+
+/// Frames followed before giving up on a chain that does not end.
+const MAX_FRAMES: usize = 256;
+
+/// One call on the stack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// PC for the innermost frame, the return address for the others.
+    pub pc: u16,
+    /// The frame's BP.
+    pub bp: u16,
+    /// The routine `pc` is in and the offset into it, when symbols are known.
+    pub function: Option<(String, u16)>,
+}
+
+/// `0x0012 in double+0x2`, or just `0x0012` without a symbol.
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:04X}", self.pc)?;
+        match &self.function {
+            Some((name, 0)) => write!(f, " in {}", name),
+            Some((name, offset)) => write!(f, " in {}+0x{:X}", name, offset),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Machine {
+    /// The call stack, innermost frame first, found by following the BP chain.
+    ///
+    /// This assumes the usual frame layout: `CALL` pushes the return address and the routine
+    /// starts with `PUSH BP` / `MOV BP, SP` (and ends with `MOV SP, BP` / `POP BP` / `RET`).
+    /// The stack grows upwards, so the caller's BP is at BP-2 and the return address at
+    /// BP-4. The chain ends at a frame whose saved BP is 0, as BP is when a program starts.
+    /// Inside a routine that has not set up its frame yet, its caller is missing. Frames are
+    /// read with `peek_word`, in the machine's byte order and without side effects.
+    pub fn backtrace(&self, symbols: Option<&SymbolTable>) -> Vec<Frame> {
+        let frame = |pc: u16, bp: u16| Frame {
+            pc,
+            bp,
+            function: symbols
+                .and_then(|symbols| symbols.label_before(pc))
+                .map(|label| (label.name.clone(), pc - label.value)),
+        };
+        let mut bp = self.get_register(Register::BP);
+        let mut frames = vec![frame(self.get_register(Register::PC), bp)];
+        while bp >= 4 && frames.len() < MAX_FRAMES {
+            let (Some(saved), Some(ret)) = (self.peek_word(bp - 2), self.peek_word(bp - 4)) else {
+                break;
+            };
+            // Callers' frames lie lower on the stack, anything else is not a frame.
+            if saved == 0 || saved >= bp {
+                break;
+            }
+            frames.push(frame(ret, saved));
+            bp = saved;
+        }
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembly;
+    use crate::memory::{Endianness, WatchKind};
+
+    #[test]
+    fn test_backtrace() {
+        let assembly = Assembly::from_source(
+            "
+            start:  LDIW SP, 0x1000
+            main:   PUSH BP
+                    MOV BP, SP
+                    CALL outer
+                    HALT
+            outer:  PUSH BP
+                    MOV BP, SP
+                    LDI A, 1
+                    CALL inner
+                    MOV SP, BP
+                    POP BP
+                    RET
+            inner:  PUSH BP
+                    MOV BP, SP
+                    HALT
+            ",
+        )
+        .unwrap();
+        let mut m = Machine::new();
+        m.load_program(&assembly.bytes, 0).unwrap();
+        m.run().unwrap();
+        let frames = m.backtrace(Some(&assembly.symbols));
+        let lines: Vec<String> = frames.iter().map(Frame::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "0x0024 in inner+0x6",
+                "0x0018 in outer+0xA",
+                "0x000C in main+0x8"
+            ]
+        );
+        assert_eq!(frames[2].bp, 0x1002);
+
+        assert_eq!(m.backtrace(None)[1].to_string(), "0x0018");
+        m.set_register(Register::BP, 0);
+        assert_eq!(m.backtrace(None).len(), 1);
+    }
+
+    #[test]
+    fn test_backtrace_big_endian() {
+        let assembly = Assembly::from_source(
+            "
+                    LDIW SP, 0x1000
+                    PUSH BP
+                    MOV BP, SP
+                    CALL inner
+                    HALT
+            inner:  PUSH BP
+                    MOV BP, SP
+                    HALT
+            ",
+        )
+        .unwrap();
+        let big: Vec<u8> = assembly
+            .bytes
+            .chunks(2)
+            .flat_map(|w| [w[1], w[0]])
+            .collect();
+        let mut m = Machine::new();
+        m.set_endianness(Endianness::Big);
+        m.load_program(&big, 0).unwrap();
+        m.run().unwrap();
+        m.add_watchpoint(0x1000, 0x100F, WatchKind::Read);
+        let lines: Vec<String> = m.backtrace(None).iter().map(Frame::to_string).collect();
+        assert_eq!(lines, ["0x0014", "0x000C"]);
+        assert!(m.watch_hits().is_empty());
+    }
+}
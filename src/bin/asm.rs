@@ -0,0 +1,154 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use tiny_vm::{Assembly, ImageFormat};
+
+const USAGE: &str =
+    "usage: tvm-asm <input.asm> [-o output] [-l listing.lst] [-s symbols.sym] [-g debug.dbg] [--object | -c | --hex | --srec | --json]";
+
+struct Options {
+    input: PathBuf,
+    output: PathBuf,
+    listing: Option<PathBuf>,
+    symbols: Option<PathBuf>,
+    debug: Option<PathBuf>,
+    format: Format,
+}
+
+enum Format {
+    Image(ImageFormat),
+    /// A relocatable object for tvm-link.
+    Relocatable,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut input = None;
+    let mut output = None;
+    let mut listing = None;
+    let mut symbols = None;
+    let mut debug = None;
+    let mut format = None;
+    while let Some(arg) = args.next() {
+        let target = match arg.as_str() {
+            "-o" | "--output" => &mut output,
+            "-l" | "--listing" => &mut listing,
+            "-s" | "--symbols" => &mut symbols,
+            "-g" | "--debug" => &mut debug,
+            "--object" => {
+                format = Some(Format::Image(ImageFormat::Object));
+                continue;
+            }
+            "-c" | "--relocatable" => {
+                format = Some(Format::Relocatable);
+                continue;
+            }
+            "--hex" => {
+                format = Some(Format::Image(ImageFormat::IntelHex));
+                continue;
+            }
+            "--srec" => {
+                format = Some(Format::Image(ImageFormat::SRecord));
+                continue;
+            }
+            "--json" => {
+                format = Some(Format::Image(ImageFormat::Json));
+                continue;
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            flag if flag.starts_with('-') => return Err(format!("Unknown option '{}'", flag)),
+            _ if input.is_none() => {
+                input = Some(PathBuf::from(arg));
+                continue;
+            }
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        };
+        let value = args
+            .next()
+            .ok_or(format!("Option '{}' expects a file name", arg))?;
+        *target = Some(PathBuf::from(value));
+    }
+    let input = input.ok_or(USAGE.to_string())?;
+    // Without a flag the format follows the extension of the output file.
+    let format = format
+        .or_else(|| {
+            let output = output.as_deref()?;
+            if output.extension()? == "o" {
+                Some(Format::Relocatable)
+            } else {
+                ImageFormat::from_extension(output).map(Format::Image)
+            }
+        })
+        .unwrap_or(Format::Image(ImageFormat::Raw));
+    let extension = match format {
+        Format::Image(ImageFormat::Raw) => "bin",
+        Format::Image(ImageFormat::Object) => "tvm",
+        Format::Image(ImageFormat::IntelHex) => "hex",
+        Format::Image(ImageFormat::SRecord) => "s19",
+        Format::Image(ImageFormat::Json) => "json",
+        Format::Relocatable => "o",
+    };
+    let output = output.unwrap_or_else(|| input.with_extension(extension));
+    Ok(Options {
+        input,
+        output,
+        listing,
+        symbols,
+        debug,
+        format,
+    })
+}
+
+fn write(path: &Path, contents: impl AsRef<[u8]>) -> Result<(), String> {
+    fs::write(path, contents).map_err(|e| format!("Cannot write '{}': {}", path.display(), e))
+}
+
+pub fn main() -> Result<(), String> {
+    let options = parse_args(env::args().skip(1))?;
+    let assembled = if let Format::Relocatable = options.format {
+        Assembly::from_file_relocatable(&options.input)
+    } else {
+        Assembly::from_file(&options.input)
+    };
+    let assembly = match assembled {
+        Ok(assembly) => assembly,
+        Err(diagnostic) => {
+            eprint!("{}", diagnostic.render());
+            process::exit(1);
+        }
+    };
+
+    let output = match options.format {
+        Format::Image(ImageFormat::Raw) => assembly.bytes.clone(),
+        Format::Image(ImageFormat::Object) => assembly.to_object().to_bytes(),
+        Format::Image(ImageFormat::IntelHex) => assembly.to_object().to_intel_hex().into_bytes(),
+        Format::Image(ImageFormat::SRecord) => assembly.to_object().to_srecord().into_bytes(),
+        #[cfg(feature = "json")]
+        Format::Image(ImageFormat::Json) => {
+            assembly.to_object().to_json(&assembly.symbols).into_bytes()
+        }
+        #[cfg(not(feature = "json"))]
+        Format::Image(ImageFormat::Json) => {
+            return Err("tvm-asm was built without the json feature".to_string())
+        }
+        Format::Relocatable => assembly.to_relocatable().to_bytes(),
+    };
+    write(&options.output, &output)?;
+    if let Some(path) = &options.listing {
+        write(path, assembly.render_listing())?;
+    }
+    if let Some(path) = &options.symbols {
+        write(path, assembly.symbols.to_string())?;
+    }
+    if let Some(path) = &options.debug {
+        write(path, assembly.debug_info.to_string())?;
+    }
+    println!(
+        "{} -> {} ({} bytes)",
+        options.input.display(),
+        options.output.display(),
+        output.len()
+    );
+
+    Ok(())
+}
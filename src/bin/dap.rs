@@ -0,0 +1,17 @@
+use std::env;
+use std::io::{self, BufReader};
+use tiny_vm::DapServer;
+
+const USAGE: &str = "usage: tvm-dap";
+
+/// Speaks the Debug Adapter Protocol on stdin and stdout; editors start it and send `launch`
+/// with the program to debug.
+pub fn main() -> Result<(), String> {
+    if let Some(arg) = env::args().nth(1) {
+        return Err(match arg.as_str() {
+            "-h" | "--help" => USAGE.to_string(),
+            _ => format!("Unexpected argument '{}'", arg),
+        });
+    }
+    DapServer::new().serve(BufReader::new(io::stdin()), io::stdout())
+}
@@ -0,0 +1,112 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use tiny_vm::{
+    CoreDump, DebugInfo, Debugger, GdbStub, ImageFormat, Machine, ObjectFile, SymbolTable,
+};
+
+const USAGE: &str =
+    "usage: tvm-dbg <image> [-f raw|tvm|ihex|srec|json] [-s symbols.sym] [-g debug.dbg] [--gdb port]
+       tvm-dbg --core core_file [-s symbols.sym] [-g debug.dbg] [--gdb port]";
+
+/// Loads a program image, or a core dump for post-mortem inspection, and reads debugger
+/// commands from stdin until `quit` or end of input. With `--gdb` it waits for gdb to connect
+/// on that port instead.
+pub fn main() -> Result<(), String> {
+    let mut path = None;
+    let mut format = None;
+    let mut symbols_path = None;
+    let mut debug_path = None;
+    let mut core_path = None;
+    let mut gdb_port = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-f" | "--format" => {
+                let value = args.next().ok_or("Option '-f' expects a format")?;
+                format = Some(ImageFormat::from_name(&value)?);
+            }
+            "-s" | "--symbols" => {
+                symbols_path = Some(args.next().ok_or("Option '-s' expects a file name")?);
+            }
+            "-g" | "--debug" => {
+                debug_path = Some(args.next().ok_or("Option '-g' expects a file name")?);
+            }
+            "--core" => {
+                core_path = Some(args.next().ok_or("Option '--core' expects a file name")?);
+            }
+            "--gdb" => {
+                let value = args.next().ok_or("Option '--gdb' expects a port")?;
+                let port = value
+                    .parse::<u16>()
+                    .map_err(|_| format!("Invalid port '{}'", value))?;
+                gdb_port = Some(port);
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            flag if flag.starts_with('-') => return Err(format!("Unknown option '{}'", flag)),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+
+    let mut vm = Machine::new();
+    let mut symbols = SymbolTable::new();
+    match (path, core_path) {
+        (None, Some(core_path)) => {
+            let core = CoreDump::from_file(&core_path)?;
+            vm.load_core(&core)?;
+            println!("{}", core);
+        }
+        (Some(path), None) => {
+            let bytes = fs::read(&path).map_err(|e| format!("Cannot read '{}': {}", path, e))?;
+            let format = format
+                .or_else(|| ImageFormat::from_extension(Path::new(&path)))
+                .unwrap_or_else(|| ImageFormat::detect(&bytes));
+            let (object, image_symbols) = ObjectFile::from_image_with_symbols(&bytes, format, 0)?;
+            vm.load_object(&object)?;
+            symbols = image_symbols;
+        }
+        _ => return Err(USAGE.to_string()),
+    }
+    if let Some(symbols_path) = symbols_path {
+        let text = fs::read_to_string(&symbols_path)
+            .map_err(|e| format!("Cannot read '{}': {}", symbols_path, e))?;
+        symbols = SymbolTable::parse(&text)?;
+    }
+
+    let mut debugger = Debugger::new(vm, symbols);
+    if let Some(debug_path) = debug_path {
+        debugger.debug_info = DebugInfo::from_file(debug_path)?;
+    }
+    if let Some(port) = gdb_port {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .map_err(|e| format!("Cannot listen on port {}: {}", port, e))?;
+        println!("Waiting for gdb on 127.0.0.1:{}", port);
+        let (stream, peer) = listener.accept().map_err(|e| e.to_string())?;
+        println!("gdb connected from {}", peer);
+        return GdbStub::new(debugger).serve(stream);
+    }
+    println!("{}", debugger.execute("disas")?);
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("(tvm) ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let Some(line) = lines.next() else {
+            println!();
+            break;
+        };
+        let line = line.map_err(|e| e.to_string())?;
+        if matches!(line.trim(), "q" | "quit") {
+            break;
+        }
+        match debugger.execute(&line) {
+            Ok(output) if output.is_empty() => {}
+            Ok(output) => println!("{}", output),
+            Err(error) => println!("error: {}", error),
+        }
+    }
+    Ok(())
+}
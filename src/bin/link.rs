@@ -0,0 +1,90 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tiny_vm::{link, RelocatableObject};
+
+const USAGE: &str =
+    "usage: tvm-link <module.o>... [-o output.tvm] [-b base_address] [-s symbols.sym]";
+
+struct Options {
+    inputs: Vec<PathBuf>,
+    output: PathBuf,
+    base: u16,
+    symbols: Option<PathBuf>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut inputs = Vec::new();
+    let mut output = None;
+    let mut base = 0;
+    let mut symbols = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => {
+                let value = args.next().ok_or("Option '-o' expects a file name")?;
+                output = Some(PathBuf::from(value));
+            }
+            "-b" | "--base" => {
+                let value = args.next().ok_or("Option '-b' expects an address")?;
+                base = parse_address(&value)?;
+            }
+            "-s" | "--symbols" => {
+                let value = args.next().ok_or("Option '-s' expects a file name")?;
+                symbols = Some(PathBuf::from(value));
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            flag if flag.starts_with('-') => return Err(format!("Unknown option '{}'", flag)),
+            _ => inputs.push(PathBuf::from(arg)),
+        }
+    }
+    if inputs.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    let output = output.unwrap_or_else(|| inputs[0].with_extension("tvm"));
+    Ok(Options {
+        inputs,
+        output,
+        base,
+        symbols,
+    })
+}
+
+fn parse_address(s: &str) -> Result<u16, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("Invalid address '{}'", s))
+}
+
+fn write(path: &Path, contents: impl AsRef<[u8]>) -> Result<(), String> {
+    fs::write(path, contents).map_err(|e| format!("Cannot write '{}': {}", path.display(), e))
+}
+
+pub fn main() -> Result<(), String> {
+    let options = parse_args(env::args().skip(1))?;
+    let mut modules = Vec::new();
+    for path in &options.inputs {
+        let bytes =
+            fs::read(path).map_err(|e| format!("Cannot read '{}': {}", path.display(), e))?;
+        let module = RelocatableObject::from_bytes(&bytes)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        modules.push(module);
+    }
+
+    let (object, symbols) = link(&modules, options.base)?;
+    let output = object.to_bytes();
+    write(&options.output, &output)?;
+    if let Some(path) = &options.symbols {
+        write(path, symbols.to_string())?;
+    }
+    println!(
+        "{} modules -> {} ({} bytes, entry point 0x{:04X})",
+        modules.len(),
+        options.output.display(),
+        output.len(),
+        object.entry
+    );
+
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use tiny_vm::{
+    disassemble_with_symbols, hex_dump, DebugInfo, ImageFormat, ObjectFile, SymbolTable,
+};
+
+const USAGE: &str =
+    "usage: tvm-objdump <image> [-f raw|tvm|ihex|srec|json] [-b base_address] [-s symbols.sym] [-g debug.dbg]";
+
+struct Options {
+    input: PathBuf,
+    format: Option<ImageFormat>,
+    base: u16,
+    symbols: Option<PathBuf>,
+    debug: Option<PathBuf>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut input = None;
+    let mut format = None;
+    let mut base = 0;
+    let mut symbols = None;
+    let mut debug = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-f" | "--format" => {
+                let value = args.next().ok_or("Option '-f' expects a format")?;
+                format = Some(ImageFormat::from_name(&value)?);
+            }
+            "-b" | "--base" => {
+                let value = args.next().ok_or("Option '-b' expects an address")?;
+                base = parse_address(&value)?;
+            }
+            "-s" | "--symbols" => {
+                let value = args.next().ok_or("Option '-s' expects a file name")?;
+                symbols = Some(PathBuf::from(value));
+            }
+            "-g" | "--debug" => {
+                let value = args.next().ok_or("Option '-g' expects a file name")?;
+                debug = Some(PathBuf::from(value));
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            flag if flag.starts_with('-') => return Err(format!("Unknown option '{}'", flag)),
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+    Ok(Options {
+        input: input.ok_or(USAGE.to_string())?,
+        format,
+        base,
+        symbols,
+        debug,
+    })
+}
+
+fn parse_address(s: &str) -> Result<u16, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("Invalid address '{}'", s))
+}
+
+pub fn main() -> Result<(), String> {
+    let options = parse_args(env::args().skip(1))?;
+    let bytes = fs::read(&options.input)
+        .map_err(|e| format!("Cannot read '{}': {}", options.input.display(), e))?;
+    let format = options
+        .format
+        .or_else(|| ImageFormat::from_extension(&options.input))
+        .unwrap_or_else(|| ImageFormat::detect(&bytes));
+    // A JSON program carries its own symbols.
+    let (object, mut symbols) = ObjectFile::from_image_with_symbols(&bytes, format, options.base)?;
+    if let Some(path) = &options.symbols {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read '{}': {}", path.display(), e))?;
+        symbols = SymbolTable::parse(&text)?;
+    }
+    if format == ImageFormat::Raw {
+        println!(
+            "{}: raw image, {} bytes, loaded at 0x{:04X}",
+            options.input.display(),
+            bytes.len(),
+            options.base
+        );
+    } else {
+        println!(
+            "{}: {}, entry point 0x{:04X}, {} segments",
+            options.input.display(),
+            format,
+            object.entry,
+            object.segments.len()
+        );
+        println!("\nSegments:");
+        for segment in &object.segments {
+            println!(
+                "  0x{:04X}-0x{:04X}  {} bytes",
+                segment.address,
+                segment.address as usize + segment.bytes.len(),
+                segment.bytes.len()
+            );
+        }
+    }
+    let segments = object.segments;
+
+    for segment in &segments {
+        println!("\nContents of 0x{:04X}:", segment.address);
+        print!("{}", hex_dump(&segment.bytes, segment.address));
+    }
+    let debug = match &options.debug {
+        Some(path) => DebugInfo::from_file(path)?,
+        None => DebugInfo::new(),
+    };
+    for segment in &segments {
+        println!("\nDisassembly of 0x{:04X}:", segment.address);
+        for line in disassemble_with_symbols(&segment.bytes, segment.address, &symbols) {
+            if let Some(label) = &line.label {
+                println!("\n{:04X} <{}>:", line.address, label);
+            }
+            let hex: Vec<String> = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let text = format!("{:04X}  {:12}  {}", line.address, hex.join(" "), line.text);
+            match debug.line_at(line.address) {
+                Some(source) if source.address == line.address => {
+                    println!("{:40}; {}", text, source.location())
+                }
+                _ => println!("{}", text),
+            }
+        }
+    }
+
+    Ok(())
+}
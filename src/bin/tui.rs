@@ -0,0 +1,105 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use tiny_vm::{Debugger, ImageFormat, Machine, ObjectFile, SymbolTable, Tui};
+
+const USAGE: &str = "usage: tvm-tui <image> [-f raw|tvm|ihex|srec|json] [-s symbols.sym]";
+
+/// Runs `stty` on the terminal, returning what it prints.
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Raw mode on the alternate screen, put back as it was when dropped, however `main` ends.
+struct Terminal {
+    saved: Option<String>,
+}
+
+impl Terminal {
+    fn enter() -> Self {
+        // Keys arrive one at a time in raw mode; without stty they still work followed by
+        // Enter.
+        let saved = stty(&["-g"]);
+        stty(&["raw", "-echo"]);
+        // Alternate screen, hidden cursor.
+        print!("\x1b[?1049h\x1b[?25l");
+        Self { saved }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        if let Some(saved) = &self.saved {
+            stty(&[saved]);
+        }
+    }
+}
+
+/// Loads a program image and debugs it full screen, one key press per command.
+pub fn main() -> Result<(), String> {
+    let mut path = None;
+    let mut format = None;
+    let mut symbols_path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-f" | "--format" => {
+                let value = args.next().ok_or("Option '-f' expects a format")?;
+                format = Some(ImageFormat::from_name(&value)?);
+            }
+            "-s" | "--symbols" => {
+                symbols_path = Some(args.next().ok_or("Option '-s' expects a file name")?);
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            flag if flag.starts_with('-') => return Err(format!("Unknown option '{}'", flag)),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+    let path = path.ok_or(USAGE)?;
+
+    let mut vm = Machine::new();
+    let bytes = fs::read(&path).map_err(|e| format!("Cannot read '{}': {}", path, e))?;
+    let format = format
+        .or_else(|| ImageFormat::from_extension(Path::new(&path)))
+        .unwrap_or_else(|| ImageFormat::detect(&bytes));
+    let (object, mut symbols) = ObjectFile::from_image_with_symbols(&bytes, format, 0)?;
+    vm.load_object(&object)?;
+    if let Some(symbols_path) = symbols_path {
+        let text = fs::read_to_string(&symbols_path)
+            .map_err(|e| format!("Cannot read '{}': {}", symbols_path, e))?;
+        symbols = SymbolTable::parse(&text)?;
+    }
+
+    let mut tui = Tui::new(Debugger::new(vm, symbols));
+    let _terminal = Terminal::enter();
+    let mut stdout = io::stdout();
+    let mut key = [0];
+    loop {
+        print!("\x1b[H\x1b[2J{}", tui.render());
+        stdout.flush().map_err(|e| e.to_string())?;
+        if io::stdin().read(&mut key).map_err(|e| e.to_string())? == 0 {
+            break;
+        }
+        if matches!(key[0], b'\r' | b'\n') {
+            continue;
+        }
+        // Ctrl-C and Ctrl-D quit like q, since raw mode swallows the signal.
+        if matches!(key[0], 0x03 | 0x04) || !tui.key(key[0] as char) {
+            break;
+        }
+    }
+    Ok(())
+}
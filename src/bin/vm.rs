@@ -1,25 +1,142 @@
-use tiny_vm::{Machine, Register};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tiny_vm::{
+    assemble, Console, Disk, Endianness, Framebuffer, ImageFormat, Keyboard, Machine, ObjectFile,
+    Register, Rng, Timer, Uart, CONSOLE_ADDRESS, DISK_ADDRESS, FRAMEBUFFER_ADDRESS,
+    FRAMEBUFFER_HEIGHT, FRAMEBUFFER_WIDTH, KEYBOARD_ADDRESS, RNG_ADDRESS, TIMER_ADDRESS,
+    UART_ADDRESS,
+};
+
+const USAGE: &str = concat!(
+    "usage: vm [image] [-f raw|tvm|ihex|srec|json] [--core core_file]",
+    " [--strict-alignment] [--big-endian] [--seed n]",
+    " [--keyboard] [--display] [--disk file]"
+);
+
+/// Runs `stty` on the terminal, returning what it prints.
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Runs the program image given on the command line, or a small demo program. The image
+/// format comes from `-f`, else the file extension, else the file contents. With `--core` a
+/// fault writes a core dump, `--strict-alignment` makes word accesses at odd addresses
+/// fault, and `--big-endian` runs images that store words high byte first. Bytes stored to
+/// `CONSOLE_ADDRESS` are printed, a `Uart` at `UART_ADDRESS` connects the program to
+/// standard input and output, and a `Timer` at `TIMER_ADDRESS` can interrupt it. An `Rng` at
+/// `RNG_ADDRESS` gives random bytes, the same ones every run with `--seed`. With `--keyboard`
+/// standard input goes to a `Keyboard` at `KEYBOARD_ADDRESS` instead of the `Uart`, a key at a
+/// time. `--display` maps a `Framebuffer` at `FRAMEBUFFER_ADDRESS` and draws each frame
+/// presented on the terminal. `--disk` attaches a `Disk` on a host file at `DISK_ADDRESS`.
 pub fn main() -> Result<(), String> {
     let mut vm = Machine::new();
+    vm.map(CONSOLE_ADDRESS, 1, Console::stdout())?;
+    vm.add_device(TIMER_ADDRESS, 6, Timer::new())?;
+
+    let mut path = None;
+    let mut format = None;
+    let mut seed = None;
+    let mut keyboard = false;
+    let mut display = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-f" | "--format" => {
+                let value = args.next().ok_or("Option '-f' expects a format")?;
+                format = Some(ImageFormat::from_name(&value)?);
+            }
+            "--core" => {
+                let value = args.next().ok_or("Option '--core' expects a file name")?;
+                vm.set_core_dump_path(Some(PathBuf::from(value)));
+            }
+            "--strict-alignment" => vm.set_alignment_checks(true),
+            "--big-endian" => vm.set_endianness(Endianness::Big),
+            "--seed" => {
+                let value = args.next().ok_or("Option '--seed' expects a number")?;
+                let value = value
+                    .parse()
+                    .map_err(|_| format!("Invalid seed '{}'", value))?;
+                seed = Some(value);
+            }
+            "--keyboard" => keyboard = true,
+            "--display" => display = true,
+            "--disk" => {
+                let value = args.next().ok_or("Option '--disk' expects a file name")?;
+                vm.add_device(DISK_ADDRESS, 6, Disk::open(value)?)?;
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            flag if flag.starts_with('-') => return Err(format!("Unknown option '{}'", flag)),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+
+    let seed = seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64)
+    });
+    vm.map(RNG_ADDRESS, 4, Rng::new(seed))?;
+    if keyboard {
+        vm.map(UART_ADDRESS, 2, Uart::new(io::empty(), io::stdout()))?;
+        vm.map(KEYBOARD_ADDRESS, 2, Keyboard::stdin())?;
+    } else {
+        vm.map(UART_ADDRESS, 2, Uart::stdio())?;
+    }
+    if display {
+        let mut framebuffer = Framebuffer::new(FRAMEBUFFER_WIDTH, FRAMEBUFFER_HEIGHT);
+        framebuffer.on_present(|frame| {
+            let mut stdout = io::stdout();
+            let _ = write!(stdout, "\x1b[H\x1b[2J{}", frame.to_ansi());
+            let _ = stdout.flush();
+        });
+        vm.map(FRAMEBUFFER_ADDRESS, framebuffer.mapped_len(), framebuffer)?;
+    }
+
+    match path {
+        Some(path) => {
+            let bytes = fs::read(&path).map_err(|e| format!("Cannot read '{}': {}", path, e))?;
+            let format = format
+                .or_else(|| ImageFormat::from_extension(Path::new(&path)))
+                .unwrap_or_else(|| ImageFormat::detect(&bytes));
+            vm.load_object(&ObjectFile::from_image(&bytes, format, 0)?)?;
+        }
+        None => {
+            let program = assemble(
+                "
+                PUSH 2
+                PUSH 6
+                ADDS
+                POP A
+                HALT
+                ",
+            )?;
+            vm.load_program(&program, 0)?;
+        }
+    }
 
-    /*
-    PUSH 2
-    PUSH 6
-    ADDSTACK
-    POP A
-    */
-    vm.memory.write(0, 0x1);
-    vm.memory.write(1, 2);
-    vm.memory.write(2, 0x1);
-    vm.memory.write(3, 6);
-    vm.memory.write(4, 0x3);
-    vm.memory.write(6, 0x2);
-    vm.memory.write(7, 0);
-
-    vm.step()?;
-    vm.step()?;
-    vm.step()?;
-    vm.step()?;
+    // Without line buffering keys arrive as they are pressed; without stty, after Enter.
+    let saved = keyboard.then(|| stty(&["-g"])).flatten();
+    if keyboard {
+        stty(&["-icanon", "-echo", "min", "1"]);
+    }
+    let result = vm.run();
+    if let Some(saved) = saved {
+        stty(&[&saved]);
+    }
+    result?;
 
     println!("A = {}", vm.get_register(Register::A));
 
@@ -0,0 +1,268 @@
+use crate::memory::{Access, Addressable};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Which line of a full set a miss evicts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replacement {
+    /// The least recently used one.
+    Lru,
+    /// The one brought in first.
+    Fifo,
+}
+
+/// The shape of a simulated cache: `sets` sets of `ways` lines of `line_size` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    pub line_size: usize,
+    pub sets: usize,
+    pub ways: usize,
+    pub replacement: Replacement,
+}
+
+impl Default for CacheConfig {
+    /// 1 KiB: 16 sets of 4 ways of 16-byte lines, LRU.
+    fn default() -> Self {
+        Self {
+            line_size: 16,
+            sets: 16,
+            ways: 4,
+            replacement: Replacement::Lru,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheCounts {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheCounts {
+    /// Hits as a fraction of all accesses, 0 if there were none.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+/// Hits and misses by kind of access, counted per byte.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub read: CacheCounts,
+    pub write: CacheCounts,
+    pub fetch: CacheCounts,
+}
+
+impl CacheStats {
+    pub fn total(&self) -> CacheCounts {
+        CacheCounts {
+            hits: self.read.hits + self.write.hits + self.fetch.hits,
+            misses: self.read.misses + self.write.misses + self.fetch.misses,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Line {
+    tag: usize,
+    /// When the line was last used (LRU) or filled (FIFO).
+    stamp: u64,
+}
+
+/// The tags of a simulated cache and its statistics. Only tags are kept: data always comes
+/// from memory, and writes allocate a line like reads do.
+#[derive(Debug)]
+pub struct Cache {
+    config: CacheConfig,
+    sets: Vec<Vec<Line>>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Result<Self, String> {
+        if !config.line_size.is_power_of_two() || !config.sets.is_power_of_two() {
+            return Err(format!(
+                "Cache line size {} and set count {} must be powers of two",
+                config.line_size, config.sets
+            ));
+        }
+        if config.ways == 0 {
+            return Err("A cache needs at least one way".to_string());
+        }
+        Ok(Self {
+            config,
+            sets: vec![Vec::new(); config.sets],
+            clock: 0,
+            stats: CacheStats::default(),
+        })
+    }
+
+    pub fn config(&self) -> CacheConfig {
+        self.config
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+    }
+
+    /// Empties every line, keeping the statistics.
+    pub fn invalidate(&mut self) {
+        self.sets.iter_mut().for_each(Vec::clear);
+    }
+
+    /// Looks `address` up, filling its line on a miss, and counts the access. Returns
+    /// whether it hit.
+    pub fn access(&mut self, address: u16, access: Access) -> bool {
+        self.clock += 1;
+        let line = address as usize / self.config.line_size;
+        let tag = line / self.config.sets;
+        let set = &mut self.sets[line % self.config.sets];
+        let hit = match set.iter_mut().find(|l| l.tag == tag) {
+            Some(line) => {
+                if self.config.replacement == Replacement::Lru {
+                    line.stamp = self.clock;
+                }
+                true
+            }
+            None => {
+                let line = Line {
+                    tag,
+                    stamp: self.clock,
+                };
+                if set.len() < self.config.ways {
+                    set.push(line);
+                } else if let Some(victim) = set.iter_mut().min_by_key(|l| l.stamp) {
+                    *victim = line;
+                }
+                false
+            }
+        };
+        let counts = match access {
+            Access::Read => &mut self.stats.read,
+            Access::Write => &mut self.stats.write,
+            Access::Execute => &mut self.stats.fetch,
+        };
+        if hit {
+            counts.hits += 1;
+        } else {
+            counts.misses += 1;
+        }
+        hit
+    }
+}
+
+/// Wraps any memory and runs every successful access through a shared `Cache`.
+pub struct CachedMemory {
+    inner: Box<dyn Addressable>,
+    cache: Rc<RefCell<Cache>>,
+}
+
+impl CachedMemory {
+    pub fn new(inner: Box<dyn Addressable>, cache: Rc<RefCell<Cache>>) -> Self {
+        Self { inner, cache }
+    }
+
+    pub fn into_inner(self) -> Box<dyn Addressable> {
+        self.inner
+    }
+}
+
+impl Addressable for CachedMemory {
+    fn read(&self, address: u16) -> Option<u8> {
+        let value = self.inner.read(address)?;
+        self.cache.borrow_mut().access(address, Access::Read);
+        Some(value)
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        let written = self.inner.write(address, value);
+        if written {
+            self.cache.borrow_mut().access(address, Access::Write);
+        }
+        written
+    }
+
+    fn fetch(&self, address: u16) -> Option<u8> {
+        let value = self.inner.fetch(address)?;
+        self.cache.borrow_mut().access(address, Access::Execute);
+        Some(value)
+    }
+
+    fn peek(&self, address: u16) -> Option<u8> {
+        self.inner.peek(address)
+    }
+
+    fn poke(&mut self, address: u16, value: u8) -> bool {
+        self.inner.poke(address, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::LinearMemory;
+
+    #[test]
+    fn test_cache() {
+        let config = CacheConfig {
+            line_size: 4,
+            sets: 2,
+            ways: 2,
+            replacement: Replacement::Lru,
+        };
+        let mut cache = Cache::new(config).unwrap();
+        assert!(!cache.access(0x00, Access::Read));
+        assert!(cache.access(0x03, Access::Read));
+        // Lines 0, 2 and 4 all fall in set 0.
+        assert!(!cache.access(0x08, Access::Write));
+        assert!(cache.access(0x00, Access::Read));
+        assert!(!cache.access(0x10, Access::Read));
+        assert!(cache.access(0x00, Access::Read));
+        assert!(!cache.access(0x08, Access::Read));
+        assert!(!cache.access(0x04, Access::Execute));
+        let stats = cache.stats();
+        assert_eq!((stats.read.hits, stats.read.misses), (3, 3));
+        assert_eq!((stats.write.hits, stats.write.misses), (0, 1));
+        assert_eq!(stats.total().hit_rate(), 3.0 / 8.0);
+
+        let mut fifo = Cache::new(CacheConfig {
+            replacement: Replacement::Fifo,
+            ..config
+        })
+        .unwrap();
+        for address in [0x00, 0x08, 0x00, 0x10] {
+            fifo.access(address, Access::Read);
+        }
+        assert!(!fifo.access(0x00, Access::Read));
+        fifo.invalidate();
+        assert!(!fifo.access(0x10, Access::Read));
+
+        assert!(Cache::new(CacheConfig {
+            line_size: 3,
+            ..config
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_cached_memory() {
+        let cache = Rc::new(RefCell::new(Cache::new(CacheConfig::default()).unwrap()));
+        let mut memory = CachedMemory::new(Box::new(LinearMemory::new(0x100)), cache.clone());
+        assert!(memory.write2(0x10, 0xBEEF));
+        assert_eq!(memory.read2(0x10), Some(0xBEEF));
+        assert_eq!(memory.read(0x100), None);
+        let stats = cache.borrow().stats();
+        assert_eq!((stats.write.hits, stats.write.misses), (1, 1));
+        assert_eq!((stats.read.hits, stats.read.misses), (2, 0));
+    }
+}
@@ -0,0 +1,87 @@
+use crate::memory::Addressable;
+use std::io::{self, Stdout, Write};
+
+/// Where `vm` maps its `Console`.
+pub const CONSOLE_ADDRESS: u16 = 0xFF00;
+
+/// An output device one byte wide: each byte stored to it is written to a sink, the host's
+/// standard output unless given another. It reads as 0. A failed write to the sink makes
+/// the store fail.
+pub struct Console<W: Write> {
+    sink: W,
+}
+
+impl Console<Stdout> {
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+}
+
+impl<W: Write> Console<W> {
+    pub fn new(sink: W) -> Self {
+        Self { sink }
+    }
+
+    pub fn sink(&self) -> &W {
+        &self.sink
+    }
+
+    pub fn into_inner(self) -> W {
+        self.sink
+    }
+}
+
+impl<W: Write> Addressable for Console<W> {
+    fn read(&self, address: u16) -> Option<u8> {
+        (address == 0).then_some(0)
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        address == 0
+            && self
+                .sink
+                .write_all(&[value])
+                .and_then(|_| self.sink.flush())
+                .is_ok()
+    }
+
+    fn peek(&self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    fn poke(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::vm::Machine;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_console() {
+        let console = Rc::new(RefCell::new(Console::new(Vec::new())));
+        let mut m = Machine::new();
+        m.map(CONSOLE_ADDRESS, 1, console.clone()).unwrap();
+        let program = assemble(
+            "
+            LDIW M, 0xFF00
+            LDI A, 0x48
+            STOREB M, A
+            LDI A, 0x69
+            STOREB M, A
+            LOADB B, M
+            HALT
+            ",
+        )
+        .unwrap();
+        m.load_program(&program, 0).unwrap();
+        m.run().unwrap();
+        assert_eq!(console.borrow().sink(), b"Hi");
+        assert_eq!(console.borrow().read(1), None);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::object::Reader;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Magic bytes at the start of a core dump.
+pub const CORE_MAGIC: [u8; 4] = *b"TVMC";
+pub const CORE_VERSION: u16 = 1;
+
+/// The state of a machine whose `step` failed: where it failed, the bytes of the faulting
+/// instruction, the error, and a snapshot of registers (FLAGS included) and memory as they
+/// were left by the fault. Reopen it with `Machine::load_core`.
+///
+/// Layout, all numbers little endian:
+///
+/// ```text
+/// magic "TVMC" | version: u16 | pc: u16 | instruction length: u8 | instruction
+/// error length: u16 | error (UTF-8) | snapshot, as written by `Machine::save_snapshot`
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoreDump {
+    /// Address of the instruction that faulted.
+    pub pc: u16,
+    /// Up to four bytes read from `pc`; fewer if memory ends first.
+    pub instruction: Vec<u8>,
+    pub error: String,
+    pub snapshot: Vec<u8>,
+}
+
+impl CoreDump {
+    pub fn is_core_dump(bytes: &[u8]) -> bool {
+        bytes.starts_with(&CORE_MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = CORE_MAGIC.to_vec();
+        bytes.extend_from_slice(&CORE_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.pc.to_le_bytes());
+        bytes.push(self.instruction.len() as u8);
+        bytes.extend_from_slice(&self.instruction);
+        bytes.extend_from_slice(&(self.error.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(self.error.as_bytes());
+        bytes.extend_from_slice(&self.snapshot);
+        bytes
+    }
+
+    /// Reads a core dump. The snapshot is only checked when it is loaded into a machine.
+    pub fn from_bytes(bytes: &[u8]) -> Result<CoreDump, String> {
+        if !Self::is_core_dump(bytes) {
+            return Err("Not a TinyVM core dump".to_string());
+        }
+        let mut reader = Reader {
+            bytes,
+            offset: CORE_MAGIC.len(),
+            what: "core dump",
+        };
+        let version = reader.u16()?;
+        if version != CORE_VERSION {
+            return Err(format!(
+                "Unsupported core dump version {} (expected version {})",
+                version, CORE_VERSION
+            ));
+        }
+        let pc = reader.u16()?;
+        let len = reader.take(1)?[0] as usize;
+        let instruction = reader.take(len)?.to_vec();
+        let len = reader.u16()? as usize;
+        let error = String::from_utf8(reader.take(len)?.to_vec())
+            .map_err(|_| "Invalid error message in core dump".to_string())?;
+        Ok(CoreDump {
+            pc,
+            instruction,
+            error,
+            snapshot: bytes[reader.offset..].to_vec(),
+        })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<CoreDump, String> {
+        let path = path.as_ref();
+        let bytes =
+            fs::read(path).map_err(|e| format!("Cannot read '{}': {}", path.display(), e))?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        fs::write(path, self.to_bytes())
+            .map_err(|e| format!("Cannot write '{}': {}", path.display(), e))
+    }
+}
+
+/// `Fault at 0x0010 (FF 00): Unknown instruction 0xFF`
+impl fmt::Display for CoreDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex: Vec<String> = self
+            .instruction
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        write!(
+            f,
+            "Fault at 0x{:04X} ({}): {}",
+            self.pc,
+            hex.join(" "),
+            self.error
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let core = CoreDump {
+            pc: 0x10,
+            instruction: vec![0xff, 0x00, 0x01, 0x02],
+            error: "Unknown instruction 0xFF".to_string(),
+            snapshot: b"TVMS...".to_vec(),
+        };
+        let bytes = core.to_bytes();
+        assert_eq!(&bytes[..13], b"TVMC\x01\x00\x10\x00\x04\xff\x00\x01\x02");
+        assert_eq!(CoreDump::from_bytes(&bytes), Ok(core.clone()));
+        assert_eq!(
+            core.to_string(),
+            "Fault at 0x0010 (FF 00 01 02): Unknown instruction 0xFF"
+        );
+
+        assert_eq!(
+            CoreDump::from_bytes(b"TVMS"),
+            Err("Not a TinyVM core dump".to_string())
+        );
+        assert_eq!(
+            CoreDump::from_bytes(&bytes[..15]),
+            Err("Truncated core dump".to_string())
+        );
+    }
+}
@@ -0,0 +1,985 @@
+use crate::debug_info::DebugInfo;
+use crate::debugger::{Debugger, StopReason};
+use crate::object::{ImageFormat, ObjectFile};
+use crate::symbols::SymbolTable;
+use crate::vm::{Machine, Register, REGISTER_COUNT};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+
+/// Instructions run between checks for new requests, such as `pause`, while running.
+const RUN_BATCH: usize = 4096;
+/// The machine has a single thread of execution.
+const THREAD_ID: i64 = 1;
+/// `variablesReference` of the register scope.
+const REGISTERS: i64 = 1;
+
+/// How the program is running between requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Run {
+    Continue,
+    /// Run to the next source line.
+    Line,
+    /// Run until PC reaches this return address.
+    StepOut(u16),
+}
+
+/// A Debug Adapter Protocol server, so VS Code and other editors can debug tinyvm programs.
+///
+/// `launch` takes `program` (a path), and optionally `format` (as for `-f`), `symbols` (a
+/// symbol table file), `debugInfo` (a file from `tvm-asm -g`) and `stopOnEntry`. Breakpoints
+/// are set on source lines, functions (labels) or instruction addresses; with debug info the
+/// editor shows the source and steps by line. The disassembly, memory and register views
+/// work, and so do `stepBack` and `reverseContinue`. Expressions typed in the debug console
+/// are `tvm-dbg` commands.
+pub struct DapServer {
+    debugger: Option<Debugger>,
+    seq: i64,
+    /// Messages ready to be sent.
+    outgoing: Vec<String>,
+    /// Events to send after the response to the current request.
+    events: Vec<String>,
+    launched: bool,
+    configured: bool,
+    started: bool,
+    stop_on_entry: bool,
+    running: Option<Run>,
+    /// Breakpoint addresses by what set them, `function`, `instruction` or a source path,
+    /// since each set request replaces only its own.
+    breakpoints: HashMap<String, Vec<u16>>,
+    done: bool,
+}
+
+impl Default for DapServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DapServer {
+    pub fn new() -> Self {
+        Self {
+            debugger: None,
+            seq: 0,
+            outgoing: Vec::new(),
+            events: Vec::new(),
+            launched: false,
+            configured: false,
+            started: false,
+            stop_on_entry: false,
+            running: None,
+            breakpoints: HashMap::new(),
+            done: false,
+        }
+    }
+
+    /// Serves one session, reading requests from `input` and writing responses and events to
+    /// `output`, until the client disconnects.
+    pub fn serve(
+        &mut self,
+        input: impl BufRead + Send + 'static,
+        mut output: impl Write,
+    ) -> Result<(), String> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut input = input;
+            while let Ok(Some(message)) = read_message(&mut input) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        loop {
+            for message in self.outgoing.drain(..) {
+                write_message(&mut output, &message).map_err(|e| e.to_string())?;
+            }
+            if self.done {
+                return Ok(());
+            }
+            let message = if self.running.is_some() {
+                match receiver.try_recv() {
+                    Ok(message) => Some(message),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            } else {
+                match receiver.recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => return Ok(()),
+                }
+            };
+            if let Some(message) = message {
+                self.handle(&message);
+            }
+            self.run_batch();
+        }
+    }
+
+    fn handle(&mut self, message: &str) {
+        let request: Value = match serde_json::from_str(message) {
+            Ok(request) => request,
+            Err(error) => {
+                self.output("stderr", &format!("{}\n", error));
+                return;
+            }
+        };
+        let command = request
+            .get("command")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let arguments = request.get("arguments").cloned().unwrap_or(Value::Null);
+        let result = self.dispatch(&command, &arguments);
+        let mut response = json!({
+            "seq": self.next_seq(),
+            "type": "response",
+            "request_seq": request.get("seq").and_then(Value::as_i64).unwrap_or(0),
+            "success": result.is_ok(),
+            "command": command,
+        });
+        match result {
+            Ok(Value::Null) => {}
+            Ok(body) => response["body"] = body,
+            Err(error) => response["message"] = error.into(),
+        }
+        self.outgoing.push(response.to_string());
+        self.outgoing.append(&mut self.events);
+    }
+
+    fn dispatch(&mut self, command: &str, args: &Value) -> Result<Value, String> {
+        match command {
+            "initialize" => {
+                let capabilities: serde_json::Map<String, Value> = [
+                    "supportsConfigurationDoneRequest",
+                    "supportsFunctionBreakpoints",
+                    "supportsInstructionBreakpoints",
+                    "supportsStepBack",
+                    "supportsSetVariable",
+                    "supportsReadMemoryRequest",
+                    "supportsWriteMemoryRequest",
+                    "supportsDisassembleRequest",
+                    "supportsTerminateRequest",
+                ]
+                .iter()
+                .map(|name| (name.to_string(), true.into()))
+                .collect();
+                return Ok(capabilities.into());
+            }
+            "launch" => {
+                self.debugger = Some(load(args)?);
+                self.stop_on_entry = args
+                    .get("stopOnEntry")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false);
+                self.launched = true;
+                // Breakpoints can be resolved from here on, so ask for them.
+                self.event("initialized", Value::Null);
+                self.start();
+                return Ok(Value::Null);
+            }
+            "configurationDone" => {
+                self.configured = true;
+                self.start();
+                return Ok(Value::Null);
+            }
+            "disconnect" | "terminate" => {
+                self.done = true;
+                return Ok(Value::Null);
+            }
+            "threads" => {
+                let thread = json!({"id": THREAD_ID, "name": "main"});
+                return Ok(json!({"threads": [thread]}));
+            }
+            _ => {}
+        }
+
+        let debugger = self
+            .debugger
+            .as_mut()
+            .ok_or("No program has been launched")?;
+        match command {
+            "setBreakpoints" | "setFunctionBreakpoints" | "setInstructionBreakpoints" => {
+                let path = args
+                    .get("source")
+                    .and_then(|s| s.get("path"))
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                let key = match command {
+                    "setFunctionBreakpoints" => "function".to_string(),
+                    "setInstructionBreakpoints" => "instruction".to_string(),
+                    _ => format!("source {}", path),
+                };
+                let mut addresses = Vec::new();
+                let mut results = Vec::new();
+                for breakpoint in args
+                    .get("breakpoints")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                {
+                    // The address, and the line it is on when set by line.
+                    let resolved = match command {
+                        "setFunctionBreakpoints" => {
+                            let name = breakpoint.get("name").and_then(Value::as_str);
+                            debugger
+                                .address(name.unwrap_or_default())
+                                .map(|a| (a, None))
+                        }
+                        "setInstructionBreakpoints" => {
+                            let reference = breakpoint.get("instructionReference");
+                            let offset = breakpoint.get("offset").and_then(Value::as_i64);
+                            debugger
+                                .address(reference.and_then(Value::as_str).unwrap_or_default())
+                                .map(|a| (a.wrapping_add(offset.unwrap_or(0) as u16), None))
+                        }
+                        _ => {
+                            let line = breakpoint.get("line").and_then(Value::as_i64);
+                            let line = line.unwrap_or(0).max(0) as usize;
+                            match debugger.debug_info.line_address(path, line) {
+                                Some(info) => Ok((info.address, Some(info.line))),
+                                None if debugger.debug_info.lines.is_empty() => {
+                                    Err("No debug info is loaded".to_string())
+                                }
+                                None => Err(format!("No code at or after line {}", line)),
+                            }
+                        }
+                    };
+                    results.push(match resolved {
+                        Ok((address, line)) => {
+                            addresses.push(address);
+                            let mut result = json!({
+                                "verified": true,
+                                "instructionReference": address_reference(address),
+                            });
+                            if let Some(line) = line {
+                                result["line"] = line.into();
+                            }
+                            result
+                        }
+                        Err(error) => json!({"verified": false, "message": error}),
+                    });
+                }
+                for &address in &addresses {
+                    debugger.add_breakpoint(address);
+                }
+                let replaced = self.breakpoints.insert(key, addresses).unwrap_or_default();
+                for address in replaced {
+                    if !self.breakpoints.values().any(|set| set.contains(&address)) {
+                        debugger.remove_breakpoint(address);
+                    }
+                }
+                Ok(json!({"breakpoints": results}))
+            }
+            "stackTrace" => {
+                let frames: Vec<Value> = debugger
+                    .machine
+                    .backtrace(Some(&debugger.symbols))
+                    .iter()
+                    .enumerate()
+                    .map(|(id, frame)| {
+                        let name = match &frame.function {
+                            Some((name, 0)) => name.clone(),
+                            Some((name, offset)) => format!("{}+0x{:X}", name, offset),
+                            None => format!("0x{:04X}", frame.pc),
+                        };
+                        // Line and column are 0 for frames without a source line.
+                        let line = debugger
+                            .debug_info
+                            .line_at(frame.pc)
+                            .filter(|l| !l.file.is_empty());
+                        let mut result = json!({
+                            "id": id,
+                            "name": name,
+                            "line": line.map_or(0, |l| l.line),
+                            "column": line.is_some() as i64,
+                            "instructionPointerReference": address_reference(frame.pc),
+                        });
+                        if let Some(line) = line {
+                            result["source"] = source(&line.file);
+                        }
+                        result
+                    })
+                    .collect();
+                let total = frames.len();
+                Ok(json!({"stackFrames": frames, "totalFrames": total}))
+            }
+            "scopes" => {
+                let scope = json!({
+                    "name": "Registers",
+                    "presentationHint": "registers",
+                    "variablesReference": REGISTERS,
+                    "expensive": false,
+                });
+                Ok(json!({"scopes": [scope]}))
+            }
+            "variables" => {
+                let variables: Vec<Value> = (0..REGISTER_COUNT as u8)
+                    .filter_map(Register::from_u8)
+                    .map(|register| {
+                        let value = debugger.machine.get_register(register);
+                        json!({
+                            "name": register.to_string(),
+                            "value": format!("0x{:04X}", value),
+                            "variablesReference": 0,
+                            "memoryReference": address_reference(value),
+                        })
+                    })
+                    .collect();
+                Ok(json!({"variables": variables}))
+            }
+            "setVariable" => {
+                let name = args.get("name").and_then(Value::as_str).unwrap_or_default();
+                let register = (0..REGISTER_COUNT as u8)
+                    .filter_map(Register::from_u8)
+                    .find(|r| r.to_string() == name)
+                    .ok_or(format!("Unknown register '{}'", name))?;
+                let text = args
+                    .get("value")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                let value = debugger
+                    .address(text.trim())
+                    .map_err(|_| format!("Invalid value '{}'", text))?;
+                debugger.machine.set_register(register, value);
+                Ok(json!({"value": format!("0x{:04X}", value)}))
+            }
+            "continue" | "next" | "stepIn" | "stepOut" if debugger.machine.is_halted() => {
+                Err("The program has halted".to_string())
+            }
+            "continue" => {
+                self.running = Some(Run::Continue);
+                Ok(json!({"allThreadsContinued": true}))
+            }
+            "next" | "stepIn" => {
+                let pc = debugger.machine.get_register(Register::PC);
+                let granularity = args.get("granularity").and_then(Value::as_str);
+                if granularity != Some("instruction") && debugger.debug_info.line_at(pc).is_some() {
+                    self.running = Some(Run::Line);
+                } else {
+                    let result = debugger.step();
+                    self.stopped(result);
+                }
+                Ok(Value::Null)
+            }
+            "stepOut" => {
+                let frames = debugger.machine.backtrace(None);
+                self.running = Some(match frames.get(1) {
+                    Some(caller) => Run::StepOut(caller.pc),
+                    None => Run::Continue,
+                });
+                Ok(Value::Null)
+            }
+            "stepBack" => {
+                let result = debugger.step_back();
+                self.stopped(result);
+                Ok(Value::Null)
+            }
+            "reverseContinue" => {
+                let result = debugger.reverse();
+                self.stopped(result);
+                Ok(Value::Null)
+            }
+            "pause" => {
+                if self.running.take().is_some() {
+                    self.stopped_event("pause", None);
+                }
+                Ok(Value::Null)
+            }
+            "evaluate" => {
+                let expression = args
+                    .get("expression")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                let before = debugger.machine.save_snapshot();
+                let result = debugger.execute(expression)?;
+                // Commands such as `step` move the program; have the editor refresh.
+                if debugger.machine.save_snapshot() != before {
+                    self.stopped_event("step", None);
+                }
+                Ok(json!({"result": result, "variablesReference": 0}))
+            }
+            "readMemory" => {
+                let address = memory_reference(debugger, args)?;
+                let count = args
+                    .get("count")
+                    .and_then(Value::as_i64)
+                    .unwrap_or(0)
+                    .max(0);
+                let bytes = debugger.read_memory(address, count as usize);
+                Ok(json!({
+                    "address": address_reference(address),
+                    "data": base64_encode(&bytes),
+                    "unreadableBytes": count - bytes.len() as i64,
+                }))
+            }
+            "writeMemory" => {
+                let address = memory_reference(debugger, args)?;
+                let data = args.get("data").and_then(Value::as_str).unwrap_or_default();
+                let bytes = base64_decode(data).ok_or("Invalid base64 data")?;
+                debugger.write_memory(address, &bytes)?;
+                Ok(json!({"bytesWritten": bytes.len()}))
+            }
+            "disassemble" => {
+                let address = memory_reference(debugger, args)?;
+                let offset = args
+                    .get("instructionOffset")
+                    .and_then(Value::as_i64)
+                    .unwrap_or(0);
+                let count = args
+                    .get("instructionCount")
+                    .and_then(Value::as_i64)
+                    .unwrap_or(0)
+                    .clamp(0, 4096);
+                let instructions = disassemble(debugger, address, offset, count as usize);
+                Ok(json!({"instructions": instructions}))
+            }
+            _ => Err(format!("Unsupported request '{}'", command)),
+        }
+    }
+
+    /// Starts the program once it is launched and the editor has sent its configuration.
+    fn start(&mut self) {
+        if self.started || !self.launched || !self.configured {
+            return;
+        }
+        self.started = true;
+        if self.stop_on_entry {
+            self.stopped_event("entry", None);
+        } else {
+            self.running = Some(Run::Continue);
+        }
+    }
+
+    fn run_batch(&mut self) {
+        let (Some(run), Some(debugger)) = (self.running, self.debugger.as_mut()) else {
+            return;
+        };
+        let result = match run {
+            Run::Continue => debugger.resume_for(RUN_BATCH),
+            Run::Line => debugger.next_line_for(RUN_BATCH),
+            Run::StepOut(target) => (|| {
+                for _ in 0..RUN_BATCH {
+                    let reason = debugger.step()?;
+                    let pc = debugger.machine.get_register(Register::PC);
+                    if reason != StopReason::Step || pc == target {
+                        return Ok(Some(reason));
+                    }
+                    if debugger.breakpoints().any(|b| b == pc) {
+                        return Ok(Some(StopReason::Breakpoint(pc)));
+                    }
+                }
+                Ok(None)
+            })(),
+        };
+        match result {
+            Ok(None) => {}
+            Ok(Some(reason)) => {
+                self.running = None;
+                self.stopped(Ok(reason));
+            }
+            Err(error) => {
+                self.running = None;
+                self.stopped(Err(error));
+            }
+        }
+        self.outgoing.append(&mut self.events);
+    }
+
+    /// Reports where execution stopped, or that the program ended.
+    fn stopped(&mut self, result: Result<StopReason, String>) {
+        match result {
+            Ok(StopReason::Step) => self.stopped_event("step", None),
+            Ok(StopReason::Breakpoint(_)) => self.stopped_event("breakpoint", None),
+            Ok(StopReason::Watchpoint(_)) => self.stopped_event("data breakpoint", None),
+            Ok(StopReason::StartOfHistory) => {
+                self.stopped_event("step", Some("Start of recorded history"))
+            }
+            Ok(StopReason::Halted) => {
+                self.event("exited", json!({"exitCode": 0}));
+                self.event("terminated", Value::Null);
+            }
+            Err(error) => {
+                self.output("stderr", &format!("{}\n", error));
+                self.stopped_event("exception", Some(&error));
+            }
+        }
+    }
+
+    fn stopped_event(&mut self, reason: &str, text: Option<&str>) {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(text) = text {
+            body["text"] = text.into();
+        }
+        self.event("stopped", body);
+    }
+
+    fn output(&mut self, category: &str, text: &str) {
+        self.event("output", json!({"category": category, "output": text}));
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        let mut message = json!({"seq": self.next_seq(), "type": "event", "event": event});
+        if !body.is_null() {
+            message["body"] = body;
+        }
+        self.events.push(message.to_string());
+    }
+
+    fn next_seq(&mut self) -> Value {
+        self.seq += 1;
+        self.seq.into()
+    }
+}
+
+/// Loads the program named by the `launch` arguments.
+fn load(args: &Value) -> Result<Debugger, String> {
+    let path = args
+        .get("program")
+        .and_then(Value::as_str)
+        .ok_or("Launch needs a \"program\"")?;
+    let bytes = fs::read(path).map_err(|e| format!("Cannot read '{}': {}", path, e))?;
+    let format = match args.get("format").and_then(Value::as_str) {
+        Some(name) => ImageFormat::from_name(name)?,
+        None => ImageFormat::from_extension(Path::new(path))
+            .unwrap_or_else(|| ImageFormat::detect(&bytes)),
+    };
+    let mut machine = Machine::new();
+    let (object, mut symbols) = ObjectFile::from_image_with_symbols(&bytes, format, 0)?;
+    machine.load_object(&object)?;
+    if let Some(path) = args.get("symbols").and_then(Value::as_str) {
+        let text =
+            fs::read_to_string(path).map_err(|e| format!("Cannot read '{}': {}", path, e))?;
+        symbols = SymbolTable::parse(&text)?;
+    }
+    let mut debugger = Debugger::new(machine, symbols);
+    if let Some(path) = args.get("debugInfo").and_then(Value::as_str) {
+        debugger.debug_info = DebugInfo::from_file(path)?;
+    }
+    Ok(debugger)
+}
+
+/// A `Source` for a file named in debug info; editors want an absolute path.
+fn source(file: &str) -> Value {
+    let path = fs::canonicalize(file).unwrap_or_else(|_| file.into());
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned());
+    json!({
+        "name": name.unwrap_or_default(),
+        "path": path.display().to_string(),
+    })
+}
+
+fn address_reference(address: u16) -> Value {
+    format!("0x{:04X}", address).into()
+}
+
+/// `memoryReference` plus `offset`.
+fn memory_reference(debugger: &Debugger, args: &Value) -> Result<u16, String> {
+    let reference = args
+        .get("memoryReference")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let address = debugger.address(reference)?;
+    let offset = args.get("offset").and_then(Value::as_i64).unwrap_or(0);
+    Ok(address.wrapping_add(offset as u16))
+}
+
+/// `count` instructions starting `offset` instructions away from `address`, which may be
+/// negative. Where there is nothing to decode, placeholders keep the count.
+fn disassemble(debugger: &Debugger, address: u16, offset: i64, count: usize) -> Vec<Value> {
+    let before = offset.min(0).unsigned_abs() as usize;
+    // Instructions are 2 or 4 bytes; look back far enough and pick a start that decodes
+    // into `address`.
+    let lowest = address.saturating_sub((before * 4).min(u16::MAX as usize) as u16);
+    let len = (address - lowest) as usize + (offset.max(0) as usize + count) * 4;
+    let bytes_from = |base: u16| debugger.read_memory(base, len - (base - lowest) as usize);
+    let base = (lowest..=address)
+        .step_by(2)
+        .find(|&base| {
+            debugger
+                .disassemble(&bytes_from(base), base)
+                .iter()
+                .any(|line| line.address == address)
+        })
+        .unwrap_or(address);
+    let lines = debugger.disassemble(&bytes_from(base), base);
+    let index = lines
+        .iter()
+        .position(|line| line.address == address)
+        .unwrap_or(0) as i64;
+    (0..count as i64)
+        .map(|i| {
+            let n = index + offset + i;
+            match usize::try_from(n).ok().and_then(|n| lines.get(n)) {
+                Some(line) => {
+                    let hex: Vec<String> =
+                        line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                    let mut instruction = json!({
+                        "address": address_reference(line.address),
+                        "instructionBytes": hex.join(" "),
+                        "instruction": line.text,
+                    });
+                    if let Some(label) = &line.label {
+                        instruction["symbol"] = label.as_str().into();
+                    }
+                    instruction
+                }
+                None => {
+                    // Two bytes per placeholder, before the first or after the last line.
+                    let placeholder = match (lines.first(), lines.last()) {
+                        (Some(first), _) if n < 0 => first.address.wrapping_sub((-n * 2) as u16),
+                        (_, Some(last)) => {
+                            let end = last.address.wrapping_add(last.bytes.len() as u16);
+                            end.wrapping_add(((n - lines.len() as i64) * 2) as u16)
+                        }
+                        _ => address.wrapping_add((n * 2) as u16),
+                    };
+                    json!({
+                        "address": address_reference(placeholder),
+                        "instruction": "??",
+                        "presentationHint": "invalid",
+                    })
+                }
+            }
+        })
+        .collect()
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=');
+    let mut out = Vec::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text.bytes() {
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        bits = bits << 6 | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Reads one `Content-Length` framed message, or `None` at the end of the input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
+
+fn write_message(output: &mut impl Write, message: &str) -> io::Result<()> {
+    write!(
+        output,
+        "Content-Length: {}\r\n\r\n{}",
+        message.len(),
+        message
+    )?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembly;
+    use std::io::Cursor;
+
+    const SOURCE: &str = "
+        main:   LDI A, 3
+                LDIW M, 0x200
+        loop:   STORE M, A
+                DEC A
+                JNZ loop
+                HALT
+        ";
+
+    /// Sends a request, lets the program run, and returns what the server sent back.
+    fn request(server: &mut DapServer, command: &str, arguments: Value) -> Vec<Value> {
+        let message = json!({
+            "seq": 1,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        });
+        server.handle(&message.to_string());
+        while server.running.is_some() {
+            server.run_batch();
+        }
+        server
+            .outgoing
+            .drain(..)
+            .map(|m| serde_json::from_str(&m).unwrap())
+            .collect()
+    }
+
+    fn kinds(messages: &[Value]) -> Vec<String> {
+        messages
+            .iter()
+            .map(|m| {
+                let field = m.get("event").or_else(|| m.get("command"));
+                let reason = m.get("body").and_then(|b| b.get("reason"));
+                [field, reason]
+                    .iter()
+                    .flatten()
+                    .filter_map(|v| v.as_str())
+                    .collect::<Vec<_>>()
+                    .join(":")
+            })
+            .collect()
+    }
+
+    fn launched() -> DapServer {
+        let assembly = Assembly::from_source(SOURCE).unwrap();
+        let mut machine = Machine::new();
+        machine.load_program(&assembly.bytes, 0).unwrap();
+        let mut server = DapServer::new();
+        server.debugger = Some(Debugger::new(machine, assembly.symbols));
+        server.started = true;
+        server
+    }
+
+    fn body<'a>(messages: &'a [Value], key: &str) -> &'a Value {
+        messages[0].get("body").and_then(|b| b.get(key)).unwrap()
+    }
+
+    #[test]
+    fn test_session() {
+        let path = std::env::temp_dir().join(format!("tvm-dap-{}.bin", std::process::id()));
+        fs::write(&path, Assembly::from_source(SOURCE).unwrap().bytes).unwrap();
+        let mut server = DapServer::new();
+        let messages = request(&mut server, "initialize", Value::Null);
+        assert_eq!(kinds(&messages), vec!["initialize"]);
+        assert_eq!(body(&messages, "supportsStepBack").as_bool(), Some(true));
+        assert_eq!(
+            request(&mut server, "threads", Value::Null)[0].get("success"),
+            Some(&Value::from(true))
+        );
+
+        let launch = json!({
+            "program": path.to_str().unwrap(),
+            "format": "raw",
+            "stopOnEntry": true,
+        });
+        assert_eq!(
+            kinds(&request(&mut server, "launch", launch)),
+            vec!["launch", "initialized"]
+        );
+        fs::remove_file(&path).unwrap();
+        let messages = request(&mut server, "configurationDone", Value::Null);
+        assert_eq!(kinds(&messages), vec!["configurationDone", "stopped:entry"]);
+
+        // A raw image has no symbols, so break on the address of `loop`.
+        let breakpoints = json!({"breakpoints": [{"instructionReference": "0x0006"}]});
+        let messages = request(&mut server, "setInstructionBreakpoints", breakpoints);
+        assert_eq!(
+            body(&messages, "breakpoints").as_array().unwrap()[0]
+                .get("verified")
+                .and_then(Value::as_bool),
+            Some(true)
+        );
+        let messages = request(&mut server, "continue", Value::Null);
+        assert_eq!(kinds(&messages), vec!["continue", "stopped:breakpoint"]);
+        let messages = request(&mut server, "next", Value::Null);
+        assert_eq!(kinds(&messages), vec!["next", "stopped:step"]);
+        let messages = request(&mut server, "stepBack", Value::Null);
+        assert_eq!(kinds(&messages), vec!["stepBack", "stopped:step"]);
+
+        let messages = request(&mut server, "variables", Value::Null);
+        let a = &body(&messages, "variables").as_array().unwrap()[0];
+        assert_eq!(a.get("value").and_then(Value::as_str), Some("0x0003"));
+        let set = json!({"name": "A", "value": "1"});
+        let messages = request(&mut server, "setVariable", set);
+        assert_eq!(body(&messages, "value").as_str(), Some("0x0001"));
+
+        let clear = json!({"breakpoints": []});
+        request(&mut server, "setInstructionBreakpoints", clear);
+        let messages = request(&mut server, "continue", Value::Null);
+        assert_eq!(kinds(&messages), vec!["continue", "exited", "terminated"]);
+        let messages = request(&mut server, "next", Value::Null);
+        assert_eq!(
+            messages[0].get("message").and_then(Value::as_str),
+            Some("The program has halted")
+        );
+    }
+
+    #[test]
+    fn test_functions_and_stepping() {
+        let mut server = launched();
+        let breakpoints = json!({"breakpoints": [{"name": "loop"}, {"name": "nowhere"}]});
+        let messages = request(&mut server, "setFunctionBreakpoints", breakpoints);
+        let results = body(&messages, "breakpoints").as_array().unwrap();
+        assert_eq!(
+            results[0]
+                .get("instructionReference")
+                .and_then(Value::as_str),
+            Some("0x0006")
+        );
+        assert_eq!(
+            results[1].get("message").and_then(Value::as_str),
+            Some("Unknown address 'nowhere'")
+        );
+        request(&mut server, "continue", Value::Null);
+        let messages = request(&mut server, "stackTrace", Value::Null);
+        let frame = &body(&messages, "stackFrames").as_array().unwrap()[0];
+        assert_eq!(frame.get("name").and_then(Value::as_str), Some("loop"));
+
+        let messages = request(&mut server, "reverseContinue", Value::Null);
+        assert_eq!(kinds(&messages), vec!["reverseContinue", "stopped:step"]);
+        let messages = request(&mut server, "evaluate", json!({"expression": "s"}));
+        assert_eq!(kinds(&messages), vec!["evaluate", "stopped:step"]);
+        let messages = request(&mut server, "evaluate", json!({"expression": "r"}));
+        assert_eq!(kinds(&messages), vec!["evaluate"]);
+        assert!(body(&messages, "result")
+            .as_str()
+            .unwrap()
+            .starts_with("A  0x0003"));
+
+        let messages = request(&mut server, "pause", Value::Null);
+        assert_eq!(kinds(&messages), vec!["pause"]);
+        let messages = request(&mut server, "scopes", Value::Null);
+        assert_eq!(
+            body(&messages, "scopes").as_array().unwrap()[0].get("variablesReference"),
+            Some(&Value::from(REGISTERS))
+        );
+    }
+
+    #[test]
+    fn test_memory_and_disassembly() {
+        let mut server = launched();
+        let read = json!({"memoryReference": "main", "offset": 2, "count": 4});
+        let messages = request(&mut server, "readMemory", read.clone());
+        assert_eq!(body(&messages, "address").as_str(), Some("0x0002"));
+        assert_eq!(body(&messages, "data").as_str(), Some("IQMAAg=="));
+
+        let write = json!({"memoryReference": "0x0002", "data": "AQID"});
+        let messages = request(&mut server, "writeMemory", write);
+        assert_eq!(body(&messages, "bytesWritten").as_i64(), Some(3));
+        let messages = request(&mut server, "readMemory", read);
+        assert_eq!(body(&messages, "data").as_str(), Some("AQIDAg=="));
+
+        let mut server = launched();
+        let disassemble = json!({
+            "memoryReference": "loop",
+            "instructionOffset": -3,
+            "instructionCount": 5,
+        });
+        let messages = request(&mut server, "disassemble", disassemble);
+        let instructions = body(&messages, "instructions").as_array().unwrap();
+        let field = |i: usize, key: &str| {
+            instructions[i]
+                .get(key)
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+        let addresses: Vec<String> = (0..5).map(|i| field(i, "address")).collect();
+        assert_eq!(
+            addresses,
+            ["0xFFFE", "0x0000", "0x0002", "0x0006", "0x0008"]
+        );
+        assert_eq!(field(0, "presentationHint"), "invalid");
+        assert_eq!(field(3, "instruction"), "STORE M, A");
+        assert_eq!(field(3, "symbol"), "loop");
+    }
+
+    #[test]
+    fn test_framing_and_base64() {
+        let mut input = Cursor::new("Content-Length: 2\r\n\r\n{}Content-Length: 4\r\n\r\nnull");
+        assert_eq!(read_message(&mut input).unwrap(), Some("{}".to_string()));
+        assert_eq!(read_message(&mut input).unwrap(), Some("null".to_string()));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+        let mut output = Vec::new();
+        write_message(&mut output, "{}").unwrap();
+        assert_eq!(output, b"Content-Length: 2\r\n\r\n{}");
+
+        for bytes in [&b""[..], b"a", b"ab", b"abc", b"\xFF\x00\x10\x80"] {
+            assert_eq!(base64_decode(&base64_encode(bytes)).as_deref(), Some(bytes));
+        }
+        assert_eq!(base64_encode(b"ab"), "YWI=");
+        assert_eq!(base64_decode("a*"), None);
+    }
+
+    #[test]
+    fn test_source_lines() {
+        let dir = std::env::temp_dir().join(format!("tvm-dap-source-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("main.asm");
+        fs::write(&path, SOURCE.trim_start_matches('\n')).unwrap();
+        let assembly = Assembly::from_file(&path).unwrap();
+        let mut server = launched();
+        server.debugger.as_mut().unwrap().debug_info = assembly.debug_info;
+
+        let breakpoints = json!({
+            "source": {"path": path.to_str().unwrap()},
+            "breakpoints": [{"line": 3}, {"line": 9}],
+        });
+        let messages = request(&mut server, "setBreakpoints", breakpoints);
+        let results = body(&messages, "breakpoints").as_array().unwrap();
+        assert_eq!(results[0].get("line").and_then(Value::as_i64), Some(3));
+        assert_eq!(
+            results[1].get("message").and_then(Value::as_str),
+            Some("No code at or after line 9")
+        );
+        let messages = request(&mut server, "next", Value::Null);
+        assert_eq!(kinds(&messages), vec!["next", "stopped:step"]);
+        let messages = request(&mut server, "stackTrace", Value::Null);
+        let frame = &body(&messages, "stackFrames").as_array().unwrap()[0];
+        assert_eq!(frame.get("line").and_then(Value::as_i64), Some(2));
+        let source = frame.get("source").and_then(|s| s.get("name"));
+        assert_eq!(source.and_then(Value::as_str), Some("main.asm"));
+
+        request(&mut server, "continue", Value::Null);
+        let messages = request(&mut server, "next", Value::Null);
+        assert_eq!(kinds(&messages), vec!["next", "stopped:step"]);
+        let step = json!({"granularity": "instruction"});
+        request(&mut server, "next", step);
+        let debugger = server.debugger.as_ref().unwrap();
+        assert_eq!(debugger.machine.get_register(Register::PC), 0x0A);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// The source line that produced the `size` bytes at `address`. `file` is empty for source
+/// that was not read from a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineInfo {
+    pub address: u16,
+    pub size: u16,
+    pub file: String,
+    pub line: usize,
+}
+
+impl LineInfo {
+    /// `file line N`, or `line N` without a file, like diagnostics.
+    pub fn location(&self) -> String {
+        if self.file.is_empty() {
+            format!("line {}", self.line)
+        } else {
+            format!("{} line {}", self.file, self.line)
+        }
+    }
+}
+
+/// The addresses from a non-local label up to, but not including, the next one; local labels
+/// inside belong to this scope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scope {
+    pub name: String,
+    pub start: u16,
+    pub end: u16,
+}
+
+/// Maps addresses back to source lines and label scopes so tools can show `main+0x4 at
+/// prog.asm line 12` instead of a bare PC.
+///
+/// The text form has one entry per line, `line <address> <size> <line> <file>` or
+/// `scope <start> <end> <name>`; it is what `to_string` writes and `parse` reads back.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    /// Sorted by address.
+    pub lines: Vec<LineInfo>,
+    /// Sorted by start address.
+    pub scopes: Vec<Scope>,
+}
+
+impl DebugInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The line whose bytes include `address`.
+    pub fn line_at(&self, address: u16) -> Option<&LineInfo> {
+        let index = self.lines.partition_point(|l| l.address <= address);
+        let line = &self.lines[..index].last()?;
+        (address - line.address < line.size).then_some(line)
+    }
+
+    /// The first line at or after `line` in `file` that produced bytes, which is where a
+    /// breakpoint on that line goes. `file` matches the recorded path when either ends with
+    /// the other, so both `math.inc` and an editor's absolute path find `lib/math.inc`.
+    pub fn line_address(&self, file: &str, line: usize) -> Option<&LineInfo> {
+        let file = Path::new(file);
+        self.lines
+            .iter()
+            .filter(|l| {
+                let recorded = Path::new(&l.file);
+                !l.file.is_empty() && (recorded.ends_with(file) || file.ends_with(recorded))
+            })
+            .filter(|l| l.line >= line)
+            .min_by_key(|l| (l.line, l.address))
+    }
+
+    /// The scope that contains `address`.
+    pub fn scope_at(&self, address: u16) -> Option<&Scope> {
+        let index = self.scopes.partition_point(|s| s.start <= address);
+        let scope = &self.scopes[..index].last()?;
+        (address < scope.end).then_some(scope)
+    }
+
+    /// A description of `address` such as `main+0x4 at prog.asm line 12`, falling back to
+    /// `0x0012` for addresses without debug info.
+    pub fn describe(&self, address: u16) -> String {
+        let mut out = match self.scope_at(address) {
+            Some(scope) if scope.start == address => scope.name.clone(),
+            Some(scope) => format!("{}+0x{:X}", scope.name, address - scope.start),
+            None => format!("0x{:04X}", address),
+        };
+        if let Some(line) = self.line_at(address) {
+            out.push_str(" at ");
+            out.push_str(&line.location());
+        }
+        out
+    }
+
+    pub fn parse(text: &str) -> Result<DebugInfo, String> {
+        let mut info = DebugInfo::new();
+        for (n, line) in text.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with(';') {
+                continue;
+            }
+            let invalid = || format!("line {}: Invalid debug info entry '{}'", n + 1, trimmed);
+            let mut fields = trimmed.splitn(5, ' ');
+            let kind = fields.next().unwrap_or_default();
+            let mut number = || {
+                let field = fields.next().ok_or_else(invalid)?;
+                match field.strip_prefix("0x") {
+                    Some(hex) => u16::from_str_radix(hex, 16).map(|v| v as usize),
+                    None => field.parse(),
+                }
+                .map_err(|_| invalid())
+            };
+            match kind {
+                "line" => {
+                    let address = number()? as u16;
+                    let size = number()? as u16;
+                    let line = number()?;
+                    let file = fields.next().unwrap_or_default().to_string();
+                    info.lines.push(LineInfo {
+                        address,
+                        size,
+                        file,
+                        line,
+                    });
+                }
+                "scope" => {
+                    let start = number()? as u16;
+                    let end = number()? as u16;
+                    let name = fields
+                        .next()
+                        .filter(|n| !n.is_empty())
+                        .ok_or_else(invalid)?;
+                    info.scopes.push(Scope {
+                        name: name.to_string(),
+                        start,
+                        end,
+                    });
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        info.lines.sort_by_key(|l| l.address);
+        info.scopes.sort_by_key(|s| s.start);
+        Ok(info)
+    }
+
+    /// Reads debug info written by `tvm-asm -g`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<DebugInfo, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read '{}': {}", path.display(), e))?;
+        Self::parse(&text)
+    }
+}
+
+impl fmt::Display for DebugInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for scope in &self.scopes {
+            writeln!(
+                f,
+                "scope 0x{:04X} 0x{:04X} {}",
+                scope.start, scope.end, scope.name
+            )?;
+        }
+        for line in &self.lines {
+            write!(f, "line 0x{:04X} {} {}", line.address, line.size, line.line)?;
+            if line.file.is_empty() {
+                writeln!(f)?;
+            } else {
+                writeln!(f, " {}", line.file)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> DebugInfo {
+        DebugInfo {
+            lines: vec![
+                LineInfo {
+                    address: 0,
+                    size: 2,
+                    file: String::new(),
+                    line: 2,
+                },
+                LineInfo {
+                    address: 4,
+                    size: 4,
+                    file: "lib/math.inc".to_string(),
+                    line: 10,
+                },
+            ],
+            scopes: vec![Scope {
+                name: "main".to_string(),
+                start: 0,
+                end: 8,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        let info = info();
+        assert_eq!(info.line_at(1).map(|l| l.line), Some(2));
+        assert_eq!(info.line_at(2), None);
+        assert_eq!(info.line_at(7).map(|l| l.line), Some(10));
+        assert_eq!(info.scope_at(7).map(|s| s.name.as_str()), Some("main"));
+        assert_eq!(info.scope_at(8), None);
+        assert_eq!(info.describe(0), "main at line 2");
+        assert_eq!(info.describe(6), "main+0x6 at lib/math.inc line 10");
+        assert_eq!(info.describe(2), "main+0x2");
+        assert_eq!(info.describe(0x20), "0x0020");
+        assert_eq!(info.line_address("math.inc", 3).map(|l| l.address), Some(4));
+        assert_eq!(
+            info.line_address("lib/math.inc", 10).map(|l| l.address),
+            Some(4)
+        );
+        assert_eq!(
+            info.line_address("/src/lib/math.inc", 1).map(|l| l.line),
+            Some(10)
+        );
+        assert_eq!(info.line_address("math.inc", 11), None);
+        assert_eq!(info.line_address("ath.inc", 10), None);
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let text = info().to_string();
+        assert_eq!(
+            text,
+            "scope 0x0000 0x0008 main\n\
+             line 0x0000 2 2\n\
+             line 0x0004 4 10 lib/math.inc\n"
+        );
+        assert_eq!(DebugInfo::parse(&text), Ok(info()));
+        assert_eq!(
+            DebugInfo::parse("line 0x0000 two 2"),
+            Err("line 1: Invalid debug info entry 'line 0x0000 two 2'".to_string())
+        );
+        assert_eq!(
+            DebugInfo::parse("\nscope 0 4"),
+            Err("line 2: Invalid debug info entry 'scope 0 4'".to_string())
+        );
+    }
+}
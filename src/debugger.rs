@@ -0,0 +1,906 @@
+use crate::assembler::parse_register_name;
+use crate::debug_info::DebugInfo;
+use crate::disassembler::{disassemble_in, DisassembledLine};
+use crate::expression::parse_number;
+use crate::memory::{WatchHit, WatchKind};
+use crate::symbols::SymbolTable;
+use crate::vm::{Flag, Machine, Register, REGISTER_COUNT};
+use std::collections::BTreeSet;
+use std::fs;
+
+/// Steps recorded for reverse execution.
+pub const HISTORY_STEPS: usize = 100_000;
+
+const HELP: &str = "\
+step [n]              (s)  execute n instructions, default 1
+next [n]              (n)  run to the next source line, n times, default 1
+continue              (c)  run until a breakpoint, a watchpoint or HALT
+rstep [n]             (rs) undo the last n instructions, default 1
+rcontinue             (rc) run backwards until a breakpoint or the oldest recorded step
+break [addr]          (b)  set a breakpoint, or list them
+delete addr                remove a breakpoint
+watch addr [end] [r|w|rw]  stop when memory is read and/or written, default w
+unwatch id                 remove a watchpoint
+regs                  (r)  show the registers
+set reg name value         change a register
+set mem addr byte...       change memory, one byte per value
+backtrace             (bt) show the call stack, following the BP chain
+mem addr [len]        (x)  hex dump len bytes of memory, default 64
+disas [addr] [n]           disassemble n instructions, default around PC
+list [addr]           (l)  show the source lines around addr, default PC
+help                  (h)  this text
+quit                  (q)  leave the debugger
+
+Addresses are numbers (0x10, 0b1010, 16), labels, register names or, with debug info,
+source lines (main.asm:12).
+An empty line repeats the last command.";
+
+/// Why execution stopped and control came back to the debugger.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// A single step finished.
+    Step,
+    Breakpoint(u16),
+    Watchpoint(Vec<WatchHit>),
+    Halted,
+    /// Running backwards reached the oldest recorded step.
+    StartOfHistory,
+}
+
+/// Runs a `Machine` under control: breakpoints, stepping and inspection, either through
+/// methods or through the text commands of `execute`, which is what `tvm-dbg` reads. The
+/// last `HISTORY_STEPS` steps are recorded so they can be undone.
+pub struct Debugger {
+    pub machine: Machine,
+    /// Used to resolve label names in commands and to label addresses in the output.
+    pub symbols: SymbolTable,
+    /// Source lines for `file:line` addresses, `list` and `next`; empty unless set.
+    pub debug_info: DebugInfo,
+    breakpoints: BTreeSet<u16>,
+    last_command: String,
+}
+
+impl Debugger {
+    /// Starts recording history, which wraps the machine's memory; see
+    /// `Machine::record_history`.
+    pub fn new(mut machine: Machine, symbols: SymbolTable) -> Self {
+        machine.record_history(HISTORY_STEPS);
+        Self {
+            machine,
+            symbols,
+            debug_info: DebugInfo::new(),
+            breakpoints: BTreeSet::new(),
+            last_command: String::new(),
+        }
+    }
+
+    /// Returns false if there already was a breakpoint at `address`.
+    pub fn add_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.insert(address)
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Executes one instruction.
+    pub fn step(&mut self) -> Result<StopReason, String> {
+        if self.machine.is_halted() {
+            return Err("The program has halted".to_string());
+        }
+        self.machine.step()?;
+        let hits = self.machine.watch_hits();
+        Ok(if self.machine.is_halted() {
+            StopReason::Halted
+        } else if !hits.is_empty() {
+            StopReason::Watchpoint(hits)
+        } else {
+            StopReason::Step
+        })
+    }
+
+    /// Executes instructions until one halts the machine or hits a watchpoint, or PC reaches
+    /// a breakpoint. The first instruction always runs, so this moves on from a breakpoint.
+    pub fn resume(&mut self) -> Result<StopReason, String> {
+        loop {
+            if let Some(reason) = self.resume_for(usize::MAX)? {
+                return Ok(reason);
+            }
+        }
+    }
+
+    /// Like `resume`, but gives up after `limit` instructions and returns `None`, so callers
+    /// can check for an interrupt between batches.
+    pub fn resume_for(&mut self, limit: usize) -> Result<Option<StopReason>, String> {
+        for _ in 0..limit {
+            let reason = self.step()?;
+            if reason != StopReason::Step {
+                return Ok(Some(reason));
+            }
+            let pc = self.machine.get_register(Register::PC);
+            if self.breakpoints.contains(&pc) {
+                return Ok(Some(StopReason::Breakpoint(pc)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Executes instructions until PC reaches a different source line, so a line with
+    /// several instructions, or a loop, runs as one step. Calls are stepped into. Stops early
+    /// like `resume`.
+    pub fn next_line(&mut self) -> Result<StopReason, String> {
+        loop {
+            if let Some(reason) = self.next_line_for(usize::MAX)? {
+                return Ok(reason);
+            }
+        }
+    }
+
+    /// Like `next_line`, but gives up after `limit` instructions and returns `None`.
+    pub fn next_line_for(&mut self, limit: usize) -> Result<Option<StopReason>, String> {
+        if self.debug_info.lines.is_empty() {
+            return Err("No debug info is loaded, use 'step'".to_string());
+        }
+        let pc = self.machine.get_register(Register::PC);
+        let start = self
+            .debug_info
+            .line_at(pc)
+            .map(|l| (l.file.clone(), l.line));
+        for _ in 0..limit {
+            let reason = self.step()?;
+            if reason != StopReason::Step {
+                return Ok(Some(reason));
+            }
+            let pc = self.machine.get_register(Register::PC);
+            if self.breakpoints.contains(&pc) {
+                return Ok(Some(StopReason::Breakpoint(pc)));
+            }
+            let line = self.debug_info.line_at(pc);
+            if line.is_some_and(|l| start.as_ref() != Some(&(l.file.clone(), l.line))) {
+                return Ok(Some(StopReason::Step));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Undoes one instruction.
+    pub fn step_back(&mut self) -> Result<StopReason, String> {
+        self.machine
+            .step_back()
+            .map_err(|_| "No earlier steps were recorded".to_string())?;
+        Ok(match self.machine.history().map_or(0, |h| h.len()) {
+            0 => StopReason::StartOfHistory,
+            _ => StopReason::Step,
+        })
+    }
+
+    /// Undoes instructions until PC reaches a breakpoint or no recorded steps are left.
+    /// Watchpoints do not stop it.
+    pub fn reverse(&mut self) -> Result<StopReason, String> {
+        loop {
+            let reason = self.step_back()?;
+            let pc = self.machine.get_register(Register::PC);
+            if reason != StopReason::Step {
+                return Ok(reason);
+            }
+            if self.breakpoints.contains(&pc) {
+                return Ok(StopReason::Breakpoint(pc));
+            }
+        }
+    }
+
+    /// Runs one text command and returns what it prints. An empty line repeats the last
+    /// command. `quit` is left to the caller.
+    pub fn execute(&mut self, line: &str) -> Result<String, String> {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => {
+                self.last_command = line.to_string();
+                line.to_string()
+            }
+        };
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(String::new());
+        };
+        let args: Vec<&str> = words.collect();
+        match (command, args.as_slice()) {
+            ("s" | "step", []) => self.step_command(1),
+            ("s" | "step", [n]) => {
+                let n = parse_number(n)
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or(format!("Invalid step count '{}'", n))?;
+                self.step_command(n as usize)
+            }
+            ("n" | "next", []) => self.next_command(1),
+            ("n" | "next", [n]) => {
+                let n = parse_number(n)
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or(format!("Invalid step count '{}'", n))?;
+                self.next_command(n as usize)
+            }
+            ("c" | "continue", []) => {
+                let reason = self.resume()?;
+                Ok(self.stop_message(&reason, false))
+            }
+            ("rs" | "rstep", []) => self.step_back_command(1),
+            ("rs" | "rstep", [n]) => {
+                let n = parse_number(n)
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or(format!("Invalid step count '{}'", n))?;
+                self.step_back_command(n as usize)
+            }
+            ("rc" | "rcontinue", []) => {
+                let reason = self.reverse()?;
+                Ok(self.stop_message(&reason, false))
+            }
+            ("b" | "break", []) => {
+                let list: Vec<String> = self.breakpoints().map(|a| self.describe(a)).collect();
+                Ok(if list.is_empty() {
+                    "No breakpoints".to_string()
+                } else {
+                    format!("Breakpoints: {}", list.join(", "))
+                })
+            }
+            ("b" | "break", [address]) => {
+                let address = self.address(address)?;
+                self.add_breakpoint(address);
+                Ok(format!("Breakpoint at {}", self.describe(address)))
+            }
+            ("delete", [address]) => {
+                let address = self.address(address)?;
+                if !self.remove_breakpoint(address) {
+                    return Err(format!("No breakpoint at {}", self.describe(address)));
+                }
+                Ok(format!("Deleted breakpoint at {}", self.describe(address)))
+            }
+            ("watch", [start, rest @ ..]) if rest.len() <= 2 => {
+                let start = self.address(start)?;
+                let (kind, range) = match rest.last().map(|k| k.to_ascii_lowercase()) {
+                    Some(k) if k == "r" => (WatchKind::Read, &rest[..rest.len() - 1]),
+                    Some(k) if k == "w" => (WatchKind::Write, &rest[..rest.len() - 1]),
+                    Some(k) if k == "rw" => (WatchKind::ReadWrite, &rest[..rest.len() - 1]),
+                    _ => (WatchKind::Write, rest),
+                };
+                let end = match range {
+                    [] => start,
+                    [end] => self.address(end)?,
+                    _ => return Err("Usage: watch addr [end] [r|w|rw]".to_string()),
+                };
+                if end < start {
+                    return Err("The end of a watched range comes before its start".to_string());
+                }
+                let id = self.machine.add_watchpoint(start, end, kind);
+                Ok(format!(
+                    "Watchpoint {} on 0x{:04X}-0x{:04X}",
+                    id, start, end
+                ))
+            }
+            ("unwatch", [id]) => {
+                let id = parse_number(id).map_err(|_| format!("Invalid watchpoint '{}'", id))?;
+                if id < 0 || !self.machine.remove_watchpoint(id as usize) {
+                    return Err(format!("No watchpoint {}", id));
+                }
+                Ok(format!("Deleted watchpoint {}", id))
+            }
+            ("r" | "regs", []) => Ok(self.registers()),
+            ("set", ["reg", name, value]) => {
+                let register =
+                    parse_register_name(name).ok_or(format!("Unknown register '{}'", name))?;
+                let value = self.value(value)?;
+                self.machine.set_register(register, value);
+                Ok(format!("{} = 0x{:04X}", register, value))
+            }
+            ("set", ["mem", address, values @ ..]) if !values.is_empty() => {
+                let address = self.address(address)?;
+                let bytes = values
+                    .iter()
+                    .map(|v| {
+                        let value = self.value(v)?;
+                        u8::try_from(value)
+                            .map_err(|_| format!("Value 0x{:X} does not fit in a byte", value))
+                    })
+                    .collect::<Result<Vec<u8>, String>>()?;
+                self.write_memory(address, &bytes)?;
+                let end = address + (bytes.len() - 1) as u16;
+                Ok(self
+                    .machine
+                    .dump_memory(address..=end)
+                    .trim_end()
+                    .to_string())
+            }
+            ("bt" | "backtrace", []) => {
+                let frames = self.machine.backtrace(Some(&self.symbols));
+                let lines: Vec<String> = frames
+                    .iter()
+                    .enumerate()
+                    .map(|(n, frame)| format!("#{:<2} {}", n, frame))
+                    .collect();
+                Ok(lines.join("\n"))
+            }
+            ("x" | "mem", [address, rest @ ..]) if rest.len() <= 1 => {
+                let address = self.address(address)?;
+                let len = match rest {
+                    [len] => parse_number(len)
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .ok_or(format!("Invalid length '{}'", len))?
+                        as usize,
+                    _ => 64,
+                };
+                let end = address.saturating_add(u16::try_from(len - 1).unwrap_or(u16::MAX));
+                let dump = self.machine.dump_memory(address..=end);
+                if dump.is_empty() {
+                    return Err(format!("Cannot read memory at 0x{:04X}", address));
+                }
+                Ok(dump.trim_end().to_string())
+            }
+            ("disas", []) => Ok(self.disassembly(None, 8)),
+            ("disas", [address, rest @ ..]) if rest.len() <= 1 => {
+                let address = self.address(address)?;
+                let count = match rest {
+                    [n] => parse_number(n)
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .ok_or(format!("Invalid count '{}'", n))?
+                        as usize,
+                    _ => 8,
+                };
+                Ok(self.disassembly(Some(address), count))
+            }
+            ("l" | "list", []) => self.source(self.machine.get_register(Register::PC), 5),
+            ("l" | "list", [address]) => self.source(self.address(address)?, 5),
+            ("h" | "help", []) => Ok(HELP.to_string()),
+            (
+                "s" | "step" | "n" | "next" | "c" | "continue" | "rs" | "rstep" | "rc"
+                | "rcontinue" | "b" | "break" | "delete" | "watch" | "unwatch" | "r" | "regs"
+                | "set" | "bt" | "backtrace" | "x" | "mem" | "disas" | "l" | "list" | "h" | "help",
+                _,
+            ) => Err(format!("Wrong arguments for '{}', try 'help'", command)),
+            _ => Err(format!("Unknown command '{}', try 'help'", command)),
+        }
+    }
+
+    fn step_command(&mut self, n: usize) -> Result<String, String> {
+        let mut reason = StopReason::Step;
+        for _ in 0..n {
+            reason = self.step()?;
+            if reason != StopReason::Step {
+                break;
+            }
+        }
+        Ok(self.stop_message(&reason, false))
+    }
+
+    fn next_command(&mut self, n: usize) -> Result<String, String> {
+        let mut reason = StopReason::Step;
+        for _ in 0..n {
+            reason = self.next_line()?;
+            if reason != StopReason::Step {
+                break;
+            }
+        }
+        Ok(self.stop_message(&reason, true))
+    }
+
+    fn step_back_command(&mut self, n: usize) -> Result<String, String> {
+        let mut reason = StopReason::Step;
+        for _ in 0..n {
+            reason = self.step_back()?;
+            if reason != StopReason::Step {
+                break;
+            }
+        }
+        Ok(self.stop_message(&reason, false))
+    }
+
+    /// What stopped execution, followed by the next instruction, or by its source line when
+    /// `source` is set and the line can be read.
+    fn stop_message(&self, reason: &StopReason, source: bool) -> String {
+        let mut lines = Vec::new();
+        match reason {
+            StopReason::Step => {}
+            StopReason::Breakpoint(address) => {
+                lines.push(format!("Breakpoint at {}", self.describe(*address)))
+            }
+            StopReason::Watchpoint(hits) => {
+                for hit in hits {
+                    let (verb, preposition) = if hit.write {
+                        ("wrote", "to")
+                    } else {
+                        ("read", "from")
+                    };
+                    lines.push(format!(
+                        "Watchpoint {}: {} 0x{:02X} {} 0x{:04X}",
+                        hit.id, verb, hit.value, preposition, hit.address
+                    ));
+                }
+            }
+            StopReason::Halted => return "Program halted".to_string(),
+            StopReason::StartOfHistory => lines.push("Start of recorded history".to_string()),
+        }
+        let pc = self.machine.get_register(Register::PC);
+        match self.source(pc, 0) {
+            Ok(line) if source => lines.push(line),
+            _ => lines.extend(self.disassembly(Some(pc), 1).lines().map(str::to_string)),
+        }
+        lines.join("\n")
+    }
+
+    /// `0x0010 <main>`, or just `0x0010` when no label is defined there.
+    pub fn describe(&self, address: u16) -> String {
+        match self.symbols.label_at(address) {
+            Some(label) => format!("0x{:04X} <{}>", address, label),
+            None => format!("0x{:04X}", address),
+        }
+    }
+
+    /// Resolves a number, a label, a register name or a `file:line` source line to an
+    /// address.
+    pub fn address(&self, text: &str) -> Result<u16, String> {
+        if let Some((file, line)) = text.rsplit_once(':') {
+            if let Ok(line) = line.parse::<usize>() {
+                return self
+                    .debug_info
+                    .line_address(file, line)
+                    .map(|l| l.address)
+                    .ok_or(format!("No code at {}", text));
+            }
+        }
+        if let Some(symbol) = self.symbols.get(text) {
+            return Ok(symbol.value);
+        }
+        if let Some(register) = parse_register_name(text) {
+            return Ok(self.machine.get_register(register));
+        }
+        parse_number(text)
+            .ok()
+            .and_then(|n| u16::try_from(n).ok())
+            .ok_or(format!("Unknown address '{}'", text))
+    }
+
+    /// Writes `bytes` from `address` on, failing at the first byte memory does not accept.
+    /// Bytes are written with `poke`: devices refuse them, and the writes are not journaled,
+    /// so stepping back does not undo them.
+    pub fn write_memory(&mut self, address: u16, bytes: &[u8]) -> Result<(), String> {
+        for (i, &byte) in bytes.iter().enumerate() {
+            let target = u16::try_from(i).ok().and_then(|i| address.checked_add(i));
+            if !target.is_some_and(|target| self.machine.memory.poke(target, byte)) {
+                return Err(format!(
+                    "Cannot write memory at 0x{:04X}",
+                    address as usize + i
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// A value for `set`: a number, a label's value or a register's contents.
+    fn value(&self, text: &str) -> Result<u16, String> {
+        self.address(text)
+            .map_err(|_| format!("Invalid value '{}'", text))
+    }
+
+    /// Up to `len` bytes from `address`, stopping early where memory ends or is a device.
+    pub fn read_memory(&self, address: u16, len: usize) -> Vec<u8> {
+        match len {
+            0 => Vec::new(),
+            _ => {
+                let end = address.saturating_add(u16::try_from(len - 1).unwrap_or(u16::MAX));
+                self.machine.read_memory(address..=end)
+            }
+        }
+    }
+
+    /// The numbered source lines within `context` of the one at `address`, with PC's line
+    /// marked by `=>` and lines holding a breakpoint by `*`.
+    pub(crate) fn source(&self, address: u16, context: usize) -> Result<String, String> {
+        let info = self
+            .debug_info
+            .line_at(address)
+            .filter(|l| !l.file.is_empty())
+            .ok_or(format!("No source for 0x{:04X}", address))?;
+        let text = fs::read_to_string(&info.file)
+            .map_err(|e| format!("Cannot read '{}': {}", info.file, e))?;
+        let line_of = |address: u16| {
+            self.debug_info
+                .line_at(address)
+                .filter(|l| l.file == info.file)
+                .map(|l| l.line)
+        };
+        let pc = line_of(self.machine.get_register(Register::PC));
+        let breakpoints: Vec<usize> = self.breakpoints().filter_map(line_of).collect();
+        let first = info.line.saturating_sub(context).max(1);
+        let lines: Vec<String> = text
+            .lines()
+            .enumerate()
+            .map(|(i, text)| (i + 1, text))
+            .skip(first - 1)
+            .take(info.line + context + 1 - first)
+            .map(|(n, text)| {
+                let marker = if pc == Some(n) {
+                    "=>"
+                } else if breakpoints.contains(&n) {
+                    " *"
+                } else {
+                    "  "
+                };
+                format!("{}{:>4}  {}", marker, n, text.trim_end())
+            })
+            .collect();
+        Ok(lines.join("\n"))
+    }
+
+    pub(crate) fn registers(&self) -> String {
+        let mut out = String::new();
+        for (i, register) in (0..REGISTER_COUNT as u8)
+            .filter_map(Register::from_u8)
+            .filter(|r| *r != Register::FLAGS)
+            .enumerate()
+        {
+            let separator = if i % 4 == 3 { "\n" } else { "  " };
+            out.push_str(&format!(
+                "{:<3}0x{:04X}{}",
+                register.to_string(),
+                self.machine.get_register(register),
+                separator
+            ));
+        }
+        let flags = self.machine.get_register(Register::FLAGS);
+        let names: Vec<&str> = [
+            (Flag::Zero, "Z"),
+            (Flag::Carry, "C"),
+            (Flag::Negative, "N"),
+            (Flag::Overflow, "V"),
+            (Flag::Interrupt, "I"),
+        ]
+        .iter()
+        .filter(|(flag, _)| flags & flag.mask() != 0)
+        .map(|(_, name)| *name)
+        .collect();
+        out.push_str(&format!("FLAGS 0x{:04X} [{}]", flags, names.join(" ")));
+        out
+    }
+
+    /// Decodes `bytes` found at `base` with the symbols and byte order of the machine.
+    pub(crate) fn disassemble(&self, bytes: &[u8], base: u16) -> Vec<DisassembledLine> {
+        disassemble_in(bytes, base, &self.symbols, self.machine.endianness())
+    }
+
+    /// `count` instructions from `start`, or around PC when `start` is `None`, with PC marked
+    /// by `=>` and breakpoints by `*`.
+    pub(crate) fn disassembly(&self, start: Option<u16>, count: usize) -> String {
+        let pc = self.machine.get_register(Register::PC);
+        let (base, skip) = match start {
+            Some(start) => (start, 0),
+            None => {
+                // Instructions have no fixed start, so look back for a decoding that lands
+                // on PC and show a few instructions before it.
+                let base = (1..=3)
+                    .rev()
+                    .map(|n| pc.saturating_sub(n * 4))
+                    .find(|&base| {
+                        let bytes = self.read_memory(base, (pc - base) as usize + 4);
+                        self.disassemble(&bytes, base)
+                            .iter()
+                            .any(|line| line.address == pc)
+                    })
+                    .unwrap_or(pc);
+                let bytes = self.read_memory(base, (pc - base) as usize);
+                let before = self.disassemble(&bytes, base).len();
+                (base, before.saturating_sub(3))
+            }
+        };
+        let bytes = self.read_memory(base, (skip + count) * 4);
+        let lines: Vec<String> = self
+            .disassemble(&bytes, base)
+            .into_iter()
+            .skip(skip)
+            .take(count)
+            .map(|line| {
+                let marker = if line.address == pc {
+                    "=>"
+                } else if self.breakpoints.contains(&line.address) {
+                    " *"
+                } else {
+                    "  "
+                };
+                let label = line
+                    .label
+                    .map(|label| format!("{}:\n", label))
+                    .unwrap_or_default();
+                let hex: Vec<String> = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                format!(
+                    "{}{} {:04X}  {:12} {}",
+                    label,
+                    marker,
+                    line.address,
+                    hex.join(" "),
+                    line.text
+                )
+            })
+            .collect();
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembly;
+
+    fn debugger() -> Debugger {
+        let assembly = Assembly::from_source(
+            "
+            main:   LDI A, 3
+                    LDIW M, 0x200
+            loop:   STORE M, A
+                    DEC A
+                    JNZ loop
+                    HALT
+            ",
+        )
+        .unwrap();
+        let mut machine = Machine::new();
+        machine.load_program(&assembly.bytes, 0).unwrap();
+        Debugger::new(machine, assembly.symbols)
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut dbg = debugger();
+        assert_eq!(dbg.execute("break"), Ok("No breakpoints".to_string()));
+        assert_eq!(
+            dbg.execute("b loop"),
+            Ok("Breakpoint at 0x0006 <loop>".to_string())
+        );
+        assert_eq!(
+            dbg.execute("c"),
+            Ok("Breakpoint at 0x0006 <loop>\nloop:\n=> 0006  20 03        STORE M, A".to_string())
+        );
+        assert_eq!(dbg.machine.get_register(Register::A), 3);
+        assert!(dbg.execute("").unwrap().starts_with("Breakpoint"));
+        assert_eq!(dbg.machine.get_register(Register::A), 2);
+        assert_eq!(
+            dbg.execute("delete 6"),
+            Ok("Deleted breakpoint at 0x0006 <loop>".to_string())
+        );
+        assert_eq!(dbg.execute("continue"), Ok("Program halted".to_string()));
+        assert_eq!(dbg.machine.get_register(Register::A), 0);
+        assert_eq!(dbg.execute("s"), Err("The program has halted".to_string()));
+    }
+
+    #[test]
+    fn test_step_and_inspect() {
+        let mut dbg = debugger();
+        assert_eq!(
+            dbg.execute("step 2"),
+            Ok("loop:\n=> 0006  20 03        STORE M, A".to_string())
+        );
+        assert_eq!(
+            dbg.execute("regs"),
+            Ok("A  0x0003  B  0x0000  C  0x0000  M  0x0200\n\
+                SP 0x0000  PC 0x0006  BP 0x0000  D  0x0000\n\
+                E  0x0000  F  0x0000  G  0x0000  H  0x0000\n\
+                FLAGS 0x0000 []"
+                .to_string())
+        );
+        assert_eq!(
+            dbg.execute("disas"),
+            Ok("main:\n   0000  F0 03        LDI A, 3\n   \
+                0002  21 03 00 02  LDIW M, 0x0200\n\
+                loop:\n=> 0006  20 03        STORE M, A\n   \
+                0008  24 00        DEC A\n   \
+                000A  16 00 06 00  JNZ loop\n   \
+                000E  22 00        HALT\n   \
+                0010  00 00        NOP\n   \
+                0012  00 00        NOP"
+                .to_string())
+        );
+        dbg.execute("s").unwrap();
+        assert_eq!(
+            dbg.execute("x M 4"),
+            Ok("0200  03 00 00 00                                      |....|".to_string())
+        );
+    }
+
+    #[test]
+    fn test_watch() {
+        let mut dbg = debugger();
+        assert_eq!(
+            dbg.execute("watch 0x200 0x201"),
+            Ok("Watchpoint 1 on 0x0200-0x0201".to_string())
+        );
+        assert_eq!(
+            dbg.execute("c"),
+            Ok("Watchpoint 1: wrote 0x03 to 0x0200\n\
+                Watchpoint 1: wrote 0x00 to 0x0201\n\
+                => 0008  24 00        DEC A"
+                .to_string())
+        );
+        assert_eq!(
+            dbg.execute("unwatch 1"),
+            Ok("Deleted watchpoint 1".to_string())
+        );
+        assert_eq!(dbg.execute("unwatch 1"), Err("No watchpoint 1".to_string()));
+    }
+
+    #[test]
+    fn test_reverse() {
+        let mut dbg = debugger();
+        assert_eq!(
+            dbg.execute("rs"),
+            Err("No earlier steps were recorded".to_string())
+        );
+        dbg.execute("b loop").unwrap();
+        dbg.execute("c").unwrap();
+        dbg.execute("c").unwrap();
+        assert_eq!(
+            dbg.execute("s 3"),
+            Ok("loop:\n=> 0006  20 03        STORE M, A".to_string())
+        );
+        assert_eq!(dbg.machine.get_register(Register::A), 1);
+        assert_eq!(
+            dbg.execute("x 0x200 1"),
+            Ok("0200  02                                               |.|".to_string())
+        );
+        assert_eq!(
+            dbg.execute("rs 3"),
+            Ok("loop:\n=> 0006  20 03        STORE M, A".to_string())
+        );
+        assert_eq!(dbg.machine.get_register(Register::A), 2);
+        assert_eq!(
+            dbg.execute("rc"),
+            Ok("Breakpoint at 0x0006 <loop>\nloop:\n=> 0006  20 03        STORE M, A".to_string())
+        );
+        assert_eq!(dbg.machine.get_register(Register::A), 3);
+        assert_eq!(
+            dbg.execute("x 0x200 1"),
+            Ok("0200  00                                               |.|".to_string())
+        );
+        assert_eq!(
+            dbg.execute("rcontinue"),
+            Ok("Start of recorded history\nmain:\n=> 0000  F0 03        LDI A, 3".to_string())
+        );
+        dbg.execute("c").unwrap();
+        assert_eq!(dbg.machine.get_register(Register::A), 3);
+    }
+
+    #[test]
+    fn test_set() {
+        let mut dbg = debugger();
+        assert_eq!(dbg.execute("set reg a 0x10"), Ok("A = 0x0010".to_string()));
+        assert_eq!(
+            dbg.execute("set reg PC loop"),
+            Ok("PC = 0x0006".to_string())
+        );
+        assert_eq!(dbg.execute("set reg M 0x300"), Ok("M = 0x0300".to_string()));
+        dbg.execute("s").unwrap();
+        assert_eq!(dbg.read_memory(0x300, 2), [0x10, 0]);
+        dbg.machine
+            .add_watchpoint(0x300, 0x302, crate::memory::WatchKind::Write);
+        assert_eq!(
+            dbg.execute("set mem M 0xFF 1 A"),
+            Ok("0300  FF 01 10                                         |...|".to_string())
+        );
+        assert!(dbg.machine.watch_hits().is_empty());
+        assert_eq!(
+            dbg.execute("set reg X 1"),
+            Err("Unknown register 'X'".to_string())
+        );
+        assert_eq!(
+            dbg.execute("set reg A -1"),
+            Err("Invalid value '-1'".to_string())
+        );
+        assert_eq!(
+            dbg.execute("set mem 0x200 0x100"),
+            Err("Value 0x100 does not fit in a byte".to_string())
+        );
+        assert_eq!(
+            dbg.execute("set mem 0x1FFF 1 2"),
+            Err("Cannot write memory at 0x2000".to_string())
+        );
+        assert_eq!(
+            dbg.execute("set mem 0x200"),
+            Err("Wrong arguments for 'set', try 'help'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut dbg = debugger();
+        assert_eq!(
+            dbg.execute("frobnicate"),
+            Err("Unknown command 'frobnicate', try 'help'".to_string())
+        );
+        assert_eq!(
+            dbg.execute("b nowhere"),
+            Err("Unknown address 'nowhere'".to_string())
+        );
+        assert_eq!(
+            dbg.execute("b 1 2"),
+            Err("Wrong arguments for 'b', try 'help'".to_string())
+        );
+        assert_eq!(
+            dbg.execute("delete main"),
+            Err("No breakpoint at 0x0000 <main>".to_string())
+        );
+        assert_eq!(
+            dbg.execute("step 0"),
+            Err("Invalid step count '0'".to_string())
+        );
+        assert_eq!(
+            dbg.execute("x 0x3000"),
+            Err("Cannot read memory at 0x3000".to_string())
+        );
+        assert!(dbg.execute("help").unwrap().contains("continue"));
+        assert_eq!(dbg.execute("bt"), Ok("#0  0x0000 in main".to_string()));
+    }
+
+    #[test]
+    fn test_source() {
+        let dir = std::env::temp_dir().join(format!("tvm-debugger-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("main.asm");
+        fs::write(
+            &path,
+            "main:   LDI A, 3\n        LDIW M, 0x200\nloop:   STORE M, A\n        DEC A\n        \
+             JNZ loop\n        HALT\n",
+        )
+        .unwrap();
+        let assembly = Assembly::from_file(&path).unwrap();
+        let mut machine = Machine::new();
+        machine.load_program(&assembly.bytes, 0).unwrap();
+        let mut dbg = Debugger::new(machine, assembly.symbols);
+        assert_eq!(
+            dbg.execute("next"),
+            Err("No debug info is loaded, use 'step'".to_string())
+        );
+        dbg.debug_info = assembly.debug_info;
+
+        assert_eq!(
+            dbg.execute("b main.asm:4"),
+            Ok("Breakpoint at 0x0008".to_string())
+        );
+        assert_eq!(
+            dbg.execute("b main.asm:7"),
+            Err("No code at main.asm:7".to_string())
+        );
+        assert_eq!(
+            dbg.execute("list"),
+            Ok("=>   1  main:   LDI A, 3\n     \
+                2          LDIW M, 0x200\n     \
+                3  loop:   STORE M, A\n \
+                *   4          DEC A\n     \
+                5          JNZ loop\n     \
+                6          HALT"
+                .to_string())
+        );
+        assert_eq!(
+            dbg.execute("n"),
+            Ok("=>   2          LDIW M, 0x200".to_string())
+        );
+        assert_eq!(
+            dbg.execute("n 2"),
+            Ok("Breakpoint at 0x0008\n=>   4          DEC A".to_string())
+        );
+        assert_eq!(
+            dbg.execute("list main.asm:6").map(|l| l.lines().count()),
+            Ok(6)
+        );
+        assert_eq!(
+            dbg.execute("delete main.asm:4"),
+            Ok("Deleted breakpoint at 0x0008".to_string())
+        );
+        dbg.execute("n 2").unwrap();
+        assert_eq!(dbg.execute("n"), Ok("=>   4          DEC A".to_string()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
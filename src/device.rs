@@ -0,0 +1,10 @@
+use crate::memory::Addressable;
+
+/// A memory-mapped device that also acts on its own, like a timer counting down.
+/// `Machine::add_device` maps it and calls `tick` after every instruction.
+pub trait Device: Addressable {
+    /// Advances the device by one instruction; returns an interrupt to raise, if any.
+    /// `memory` is the bus, for devices that copy to and from memory themselves (DMA).
+    /// DMA skips the MMU, watchpoints, traces and the cache; `Machine::step_back` undoes it.
+    fn tick(&mut self, memory: &mut dyn Addressable) -> Option<u8>;
+}
@@ -0,0 +1,146 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// An assembler error pointing at the part of the source it is about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    /// File the error is in, `None` for source passed in as a string.
+    pub file: Option<PathBuf>,
+    /// 1-based line, 0 when the error is not tied to a line (e.g. an unreadable input file).
+    pub line: usize,
+    /// 1-based column of the first character of the span.
+    pub column: usize,
+    /// Number of characters the error covers.
+    pub span: usize,
+    /// The offending source line.
+    pub source_line: String,
+    /// Where the file was included from, e.g. `main.asm line 3`; empty for the top level.
+    pub included_from: String,
+}
+
+impl Diagnostic {
+    /// An error that is not about any particular source line.
+    pub fn new(message: String) -> Self {
+        Diagnostic {
+            message,
+            file: None,
+            line: 0,
+            column: 0,
+            span: 0,
+            source_line: String::new(),
+            included_from: String::new(),
+        }
+    }
+
+    pub fn location(&self) -> String {
+        location(self.file.as_deref(), self.line, &self.included_from)
+    }
+
+    /// Renders the error with the offending line and its span underlined:
+    ///
+    /// ```text
+    /// error: Value 300 does not fit in 8 bits
+    ///  --> line 2, column 6
+    ///   |
+    /// 2 | PUSH 300
+    ///   |      ^^^
+    /// ```
+    pub fn render(&self) -> String {
+        if self.line == 0 {
+            return format!("error: {}\n", self.message);
+        }
+        let mut arrow = match &self.file {
+            Some(file) => format!("{} line {}", file.display(), self.line),
+            None => format!("line {}", self.line),
+        };
+        arrow = format!("{}, column {}", arrow, self.column);
+        if !self.included_from.is_empty() {
+            arrow = format!("{}, included from {}", arrow, self.included_from);
+        }
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        // Keep tabs so the marker lines up with the source in a terminal.
+        let indent: String = self
+            .source_line
+            .chars()
+            .take(self.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        format!(
+            "error: {}\n{} --> {}\n{} |\n{} | {}\n{} | {}{}\n",
+            self.message,
+            gutter,
+            arrow,
+            gutter,
+            number,
+            self.source_line,
+            gutter,
+            indent,
+            "^".repeat(self.span.max(1))
+        )
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.location(), self.message)
+        }
+    }
+}
+
+impl From<Diagnostic> for String {
+    fn from(diagnostic: Diagnostic) -> String {
+        diagnostic.to_string()
+    }
+}
+
+/// Describes a source line as `file line N, included from ...`.
+pub(crate) fn location(file: Option<&Path>, line: usize, included_from: &str) -> String {
+    let location = match file {
+        Some(file) => format!("{} line {}", file.display(), line),
+        None => format!("line {}", line),
+    };
+    if included_from.is_empty() {
+        location
+    } else {
+        format!("{}, included from {}", location, included_from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let diagnostic = Diagnostic {
+            message: "Value 300 does not fit in 8 bits".to_string(),
+            file: Some(PathBuf::from("lib.asm")),
+            line: 12,
+            column: 7,
+            span: 3,
+            source_line: "\tPUSH 300".to_string(),
+            included_from: "main.asm line 1".to_string(),
+        };
+        assert_eq!(
+            diagnostic.to_string(),
+            "lib.asm line 12, included from main.asm line 1: Value 300 does not fit in 8 bits"
+        );
+        assert_eq!(
+            diagnostic.render(),
+            concat!(
+                "error: Value 300 does not fit in 8 bits\n",
+                "   --> lib.asm line 12, column 7, included from main.asm line 1\n",
+                "   |\n",
+                "12 | \tPUSH 300\n",
+                "   | \t     ^^^\n",
+            )
+        );
+        let diagnostic = Diagnostic::new("Cannot read 'x.asm'".to_string());
+        assert_eq!(diagnostic.render(), "error: Cannot read 'x.asm'\n");
+    }
+}
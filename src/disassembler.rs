@@ -0,0 +1,373 @@
+use crate::memory::Endianness;
+use crate::symbols::SymbolTable;
+use crate::vm::{parse_instruction, Op, Register};
+use std::fmt;
+
+/// One decoded instruction, or a data directive for bytes that are not a valid instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisassembledLine {
+    /// Label defined at this address, when disassembling with a symbol table.
+    pub label: Option<String>,
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub op: Option<Op>,
+    pub text: String,
+}
+
+/// Decodes a program image loaded at `base` into one line per instruction. Words that do not
+/// decode, or that only decode by ignoring bits the encoder would never set, are shown as
+/// `.word` data; a trailing odd byte is shown as `.byte`.
+pub fn disassemble(bytes: &[u8], base: u16) -> Vec<DisassembledLine> {
+    disassemble_with_symbols(bytes, base, &SymbolTable::new())
+}
+
+/// Like `disassemble`, but jump, call and loop targets that have a label are printed by name,
+/// e.g. `CALL print_char`, and each line carries the label defined at its address.
+pub fn disassemble_with_symbols(
+    bytes: &[u8],
+    base: u16,
+    symbols: &SymbolTable,
+) -> Vec<DisassembledLine> {
+    disassemble_in(bytes, base, symbols, Endianness::Little)
+}
+
+/// Like `disassemble_with_symbols`, for memory whose words are stored in `endianness`, such
+/// as that of a machine running big-endian images.
+pub fn disassemble_in(
+    bytes: &[u8],
+    base: u16,
+    symbols: &SymbolTable,
+    endianness: Endianness,
+) -> Vec<DisassembledLine> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let address = base.wrapping_add(offset as u16);
+        let Some(ins) = word_at(bytes, offset, endianness) else {
+            lines.push(DisassembledLine {
+                label: symbols.label_at(address).map(str::to_string),
+                address,
+                bytes: bytes[offset..].to_vec(),
+                op: None,
+                text: format!(".byte 0x{:02X}", bytes[offset]),
+            });
+            break;
+        };
+        let mut next = offset + 2;
+        let decoded = parse_instruction(ins, || {
+            let word =
+                word_at(bytes, next, endianness).ok_or("Truncated instruction".to_string())?;
+            next += 2;
+            Ok(word)
+        });
+        let line = match decoded {
+            Ok(op) if encodes_to(&op, &bytes[offset..next], endianness) => DisassembledLine {
+                label: symbols.label_at(address).map(str::to_string),
+                address,
+                bytes: bytes[offset..next].to_vec(),
+                op: Some(op),
+                text: symbolize(op, address, symbols),
+            },
+            _ => DisassembledLine {
+                label: symbols.label_at(address).map(str::to_string),
+                address,
+                bytes: bytes[offset..offset + 2].to_vec(),
+                op: None,
+                text: format!(".word 0x{:04X}", ins),
+            },
+        };
+        offset += line.bytes.len();
+        lines.push(line);
+    }
+    lines
+}
+
+/// Renders a disassembly as source text that `assemble` turns back into the same bytes: each
+/// label on its own line followed by the instructions and data. A non-zero `base` becomes a
+/// leading `.org`, so the image is then reproduced at that offset, after zeros.
+pub fn disassemble_to_source(bytes: &[u8], base: u16, symbols: &SymbolTable) -> String {
+    let mut source = String::new();
+    if base != 0 {
+        source.push_str(&format!("    .org 0x{:04X}\n", base));
+    }
+    for line in disassemble_with_symbols(bytes, base, symbols) {
+        if let Some(label) = &line.label {
+            source.push_str(&format!("{}:\n", label));
+        }
+        source.push_str(&format!("    {}\n", line.text));
+    }
+    source
+}
+
+/// Address a jump, call or loop instruction at `address` transfers control to.
+fn branch_target(op: Op, address: u16) -> Option<u16> {
+    match op {
+        Op::Jmp(a)
+        | Op::Jz(a)
+        | Op::Jnz(a)
+        | Op::Jlt(a)
+        | Op::Jgt(a)
+        | Op::Jle(a)
+        | Op::Jge(a)
+        | Op::Call(a)
+        | Op::Loop(_, a) => Some(a),
+        Op::JmpRelative(offset) => Some(address.wrapping_add(2).wrapping_add(offset as u16)),
+        _ => None,
+    }
+}
+
+/// Formats `op`, replacing its target operand with a label name when there is one. The
+/// target is always the last operand, so it is swapped out of the plain text.
+fn symbolize(op: Op, address: u16, symbols: &SymbolTable) -> String {
+    let text = op.to_string();
+    let name = branch_target(op, address).and_then(|target| symbols.label_at(target));
+    match (name, text.rsplit_once(' ')) {
+        (Some(name), Some((prefix, _))) => format!("{} {}", prefix, name),
+        _ => text,
+    }
+}
+
+fn word_at(bytes: &[u8], offset: usize, endianness: Endianness) -> Option<u16> {
+    let pair = bytes.get(offset..offset + 2)?;
+    Some(endianness.from_bytes([pair[0], pair[1]]))
+}
+
+/// Whether encoding `op` gives back exactly `bytes`.
+fn encodes_to(op: &Op, bytes: &[u8], endianness: Endianness) -> bool {
+    let encoded: Vec<u8> = op
+        .encode()
+        .iter()
+        .flat_map(|&w| endianness.to_bytes(w))
+        .collect();
+    encoded == bytes
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Formats an instruction in the syntax accepted by the assembler.
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Op::Nop => write!(f, "NOP"),
+            Op::Push(v) => write!(f, "PUSH {}", v),
+            Op::PushRegister(r) => write!(f, "PUSH {}", r),
+            Op::Push16(v) => write!(f, "PUSHW 0x{:04X}", v),
+            Op::PopRegister(r) => write!(f, "POP {}", r),
+            Op::AddStack => write!(f, "ADDS"),
+            Op::SubStack => write!(f, "SUBS"),
+            Op::MulStack => write!(f, "MULS"),
+            Op::DivStack => write!(f, "DIVS"),
+            Op::AddRegister(r1, r2) => write!(f, "ADD {}, {}", r1, r2),
+            Op::SubRegister(r1, r2) => write!(f, "SUB {}, {}", r1, r2),
+            Op::MulRegister(r1, r2) => write!(f, "MUL {}, {}", r1, r2),
+            Op::DivRegister(r1, r2) => write!(f, "DIV {}, {}", r1, r2),
+            Op::MulWide(r1, r2) => write!(f, "MULW {}, {}", r1, r2),
+            Op::Adc(r1, r2) => write!(f, "ADC {}, {}", r1, r2),
+            Op::Sbb(r1, r2) => write!(f, "SBB {}, {}", r1, r2),
+            Op::AddSat(r1, r2) => write!(f, "ADDSAT {}, {}", r1, r2),
+            Op::SubSat(r1, r2) => write!(f, "SUBSAT {}, {}", r1, r2),
+            Op::Mac(r1, r2, r3) => write!(f, "MAC {}, {}, {}", r1, r2, r3),
+            Op::AddImm(r, v) => write!(f, "ADDI {}, {}", r, v),
+            Op::SubImm(r, v) => write!(f, "SUBI {}, {}", r, v),
+            Op::Inc(r) => write!(f, "INC {}", r),
+            Op::Dec(r) => write!(f, "DEC {}", r),
+            Op::Neg(r) => write!(f, "NEG {}", r),
+            Op::Mov(r1, r2) => write!(f, "MOV {}, {}", r1, r2),
+            Op::Swap(r1, r2) => write!(f, "SWAP {}, {}", r1, r2),
+            Op::CmovZ(r1, r2) => write!(f, "CMOVZ {}, {}", r1, r2),
+            Op::CmovNz(r1, r2) => write!(f, "CMOVNZ {}, {}", r1, r2),
+            Op::LoadImm(r, v) => write!(f, "LDI {}, {}", r, v),
+            Op::LoadImm16(r, v) => write!(f, "LDIW {}, 0x{:04X}", r, v),
+            Op::Load(r1, r2) => write!(f, "LOAD {}, {}", r1, r2),
+            Op::Store(r1, r2) => write!(f, "STORE {}, {}", r1, r2),
+            Op::LoadByte(r1, r2) => write!(f, "LOADB {}, {}", r1, r2),
+            Op::StoreByte(r1, r2) => write!(f, "STOREB {}, {}", r1, r2),
+            Op::Sext8(r) => write!(f, "SEXT8 {}", r),
+            Op::Zext8(r) => write!(f, "ZEXT8 {}", r),
+            Op::Shl(r, n) => write!(f, "SHL {}, {}", r, n),
+            Op::Shr(r, n) => write!(f, "SHR {}, {}", r, n),
+            Op::Sar(r, n) => write!(f, "SAR {}, {}", r, n),
+            Op::ShlRegister(r1, r2) => write!(f, "SHL {}, {}", r1, r2),
+            Op::ShrRegister(r1, r2) => write!(f, "SHR {}, {}", r1, r2),
+            Op::SarRegister(r1, r2) => write!(f, "SAR {}, {}", r1, r2),
+            Op::Rol(r, n) => write!(f, "ROL {}, {}", r, n),
+            Op::Ror(r, n) => write!(f, "ROR {}, {}", r, n),
+            Op::Rcl(r, n) => write!(f, "RCL {}, {}", r, n),
+            Op::Rcr(r, n) => write!(f, "RCR {}, {}", r, n),
+            Op::BitTest(r, n) => write!(f, "BTST {}, {}", r, n),
+            Op::BitSet(r, n) => write!(f, "BSET {}, {}", r, n),
+            Op::BitClear(r, n) => write!(f, "BCLR {}, {}", r, n),
+            Op::BitToggle(r, n) => write!(f, "BTGL {}, {}", r, n),
+            Op::Clz(r) => write!(f, "CLZ {}", r),
+            Op::Popcnt(r) => write!(f, "POPCNT {}", r),
+            Op::Min(r1, r2) => write!(f, "MIN {}, {}", r1, r2),
+            Op::Max(r1, r2) => write!(f, "MAX {}, {}", r1, r2),
+            Op::MinSigned(r1, r2) => write!(f, "MINS {}, {}", r1, r2),
+            Op::MaxSigned(r1, r2) => write!(f, "MAXS {}, {}", r1, r2),
+            Op::Cmp(r1, r2) => write!(f, "CMP {}, {}", r1, r2),
+            Op::Test(r1, r2) => write!(f, "TEST {}, {}", r1, r2),
+            Op::Jmp(a) => write!(f, "JMP 0x{:04X}", a),
+            Op::JmpRegister(r) => write!(f, "JMP {}", r),
+            Op::JmpRelative(offset) => write!(f, "JR {}", offset),
+            Op::Jz(a) => write!(f, "JZ 0x{:04X}", a),
+            Op::Jnz(a) => write!(f, "JNZ 0x{:04X}", a),
+            Op::Jlt(a) => write!(f, "JLT 0x{:04X}", a),
+            Op::Jgt(a) => write!(f, "JGT 0x{:04X}", a),
+            Op::Jle(a) => write!(f, "JLE 0x{:04X}", a),
+            Op::Jge(a) => write!(f, "JGE 0x{:04X}", a),
+            Op::Loop(r, a) => write!(f, "LOOP {}, 0x{:04X}", r, a),
+            Op::Call(a) => write!(f, "CALL 0x{:04X}", a),
+            Op::Ret => write!(f, "RET"),
+            Op::Int(n) => write!(f, "INT {}", n),
+            Op::Iret => write!(f, "IRET"),
+            Op::Syscall(n) => write!(f, "SYSCALL {}", n),
+            Op::PushAll => write!(f, "PUSHA"),
+            Op::PopAll => write!(f, "POPA"),
+            Op::PushFlags => write!(f, "PUSHF"),
+            Op::PopFlags => write!(f, "POPF"),
+            Op::LoadCodeSegment(r) => write!(f, "LCS {}", r),
+            Op::LoadDataSegment(r) => write!(f, "LDS {}", r),
+            Op::MemCpy(r1, r2, r3) => write!(f, "MEMCPY {}, {}, {}", r1, r2, r3),
+            Op::MemSet(r1, r2, r3) => write!(f, "MEMSET {}, {}, {}", r1, r2, r3),
+            Op::Cas(r1, r2, r3) => write!(f, "CAS {}, {}, {}", r1, r2, r3),
+            Op::Halt => write!(f, "HALT"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble, Assembly};
+
+    fn texts(bytes: &[u8], base: u16) -> Vec<String> {
+        disassemble(bytes, base)
+            .into_iter()
+            .map(|line| format!("{:04X} {}", line.address, line.text))
+            .collect()
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let bytes =
+            assemble("PUSH 2\nLDIW M, 0x1234\nstart: ADD A, B\nJR -4\nJMP start\nHALT").unwrap();
+        assert_eq!(
+            texts(&bytes, 0x100),
+            vec![
+                "0100 PUSH 2",
+                "0102 LDIW M, 0x1234",
+                "0106 ADD A, B",
+                "0108 JR -4",
+                "010A JMP 0x0006",
+                "010E HALT",
+            ]
+        );
+        let lines = disassemble(&bytes, 0x100);
+        assert_eq!(lines[1].bytes, bytes[2..6].to_vec());
+        assert_eq!(lines[1].op, Some(Op::LoadImm16(Register::M, 0x1234)));
+    }
+
+    #[test]
+    fn test_big_endian() {
+        let little = assemble("LDIW M, 0x1234\nHALT").unwrap();
+        let big: Vec<u8> = little.chunks(2).flat_map(|w| [w[1], w[0]]).collect();
+        let lines = disassemble_in(&big, 0, &SymbolTable::new(), Endianness::Big);
+        let texts: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, ["LDIW M, 0x1234", "HALT"]);
+        assert_eq!(lines[0].bytes, big[..4].to_vec());
+    }
+
+    #[test]
+    fn test_data() {
+        // 0xff is not an opcode, NOP with a non-zero high byte never comes out of the
+        // encoder, JMP is missing its address word and the last byte is odd.
+        let bytes = [0xff, 0xff, 0x00, 0x12, 0x01, 0x07, 0x13, 0x00, 0x42];
+        assert_eq!(
+            texts(&bytes, 0),
+            vec![
+                "0000 .word 0xFFFF",
+                "0002 .word 0x1200",
+                "0004 PUSH 7",
+                "0006 .word 0x0013",
+                "0008 .byte 0x42",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_symbols() {
+        let assembly = Assembly::from_source(
+            "start: LDI C, 3\n.loop: CALL print\nLOOP C, .loop\nJR start\nprint: RET\nJMP 0x40",
+        )
+        .unwrap();
+        let lines = disassemble_with_symbols(&assembly.bytes, 0, &assembly.symbols);
+        let texts: Vec<(Option<&str>, &str)> = lines
+            .iter()
+            .map(|line| (line.label.as_deref(), line.text.as_str()))
+            .collect();
+        assert_eq!(
+            texts,
+            vec![
+                (Some("start"), "LDI C, 3"),
+                (Some("start.loop"), "CALL print"),
+                (None, "LOOP C, start.loop"),
+                (None, "JR start"),
+                (Some("print"), "RET"),
+                (None, "JMP 0x0040"),
+            ]
+        );
+    }
+
+    const CORPUS: [&str; 4] = [
+        "PUSH 2\nPUSH 6\nADDS\nPOP A\nHALT",
+        "
+        start:  LDI C, 3
+                LDIW M, msg
+        .loop:  LOADB A, M
+                SYSCALL 1
+                INC M
+                LOOP C, .loop
+                CALL done
+                JR start
+        done:   RET
+        msg:    .ascii \"Hi!\\n\"
+                .byte 7
+        ",
+        "
+                JMP main
+                .org 0x20
+        table:  .word 0x1234, 0xffff, table
+        main:   PUSHW table
+                POP M
+                LOAD A, M
+                ROL A, 3
+                MAC A, B, C
+                CAS M, A, B
+                MEMCPY M, A, C
+                MEMSET M, B, D
+                JNZ main
+                HALT
+        ",
+        ".word 0x00ff, 0x1200, 0x0013\n.byte 0x42",
+    ];
+
+    #[test]
+    fn test_round_trip() {
+        for program in CORPUS {
+            let assembly = Assembly::from_source(program).unwrap();
+            for symbols in [SymbolTable::new(), assembly.symbols.clone()] {
+                let source = disassemble_to_source(&assembly.bytes, 0, &symbols);
+                assert_eq!(assemble(&source), Ok(assembly.bytes.clone()), "{}", source);
+            }
+        }
+        let bytes = assemble("JMP 0x100").unwrap();
+        let source = disassemble_to_source(&bytes, 0x100, &SymbolTable::new());
+        let mut expected = vec![0; 0x100];
+        expected.extend_from_slice(&bytes);
+        assert_eq!(assemble(&source), Ok(expected));
+    }
+}
@@ -0,0 +1,303 @@
+use crate::device::Device;
+use crate::memory::Addressable;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Where `vm --disk` maps its `Disk`.
+pub const DISK_ADDRESS: u16 = 0xFF50;
+/// The bytes in a sector, the unit the disk reads and writes.
+pub const DISK_SECTOR_SIZE: usize = 256;
+/// Offset of the sector number, a little-endian word.
+pub const DISK_SECTOR: u16 = 0;
+/// Offset of the address in memory to copy a sector to or from, a little-endian word.
+pub const DISK_BUFFER: u16 = 2;
+/// Offset of the command register: storing `DISK_READ` or `DISK_WRITE` starts a transfer.
+pub const DISK_COMMAND: u16 = 4;
+/// Offset of the status register, a combination of `DISK_BUSY` and `DISK_ERROR`.
+pub const DISK_STATUS: u16 = 5;
+/// Command: copy the sector into memory at the buffer address.
+pub const DISK_READ: u8 = 1;
+/// Command: copy a sector's worth of memory at the buffer address to the sector.
+pub const DISK_WRITE: u8 = 2;
+/// Status bit: a transfer has been started and not finished.
+pub const DISK_BUSY: u8 = 0x01;
+/// Status bit: the last command failed, because it was unknown, its sector was past the end
+/// of the disk, its buffer was not all memory or the host file could not be used.
+pub const DISK_ERROR: u8 = 0x02;
+
+/// A disk controller over host storage, usually a file, seen as `DISK_SECTOR_SIZE`-byte
+/// sectors. Guest code sets the sector and buffer registers and stores a command; the
+/// transfer happens by DMA before the next instruction, and the status register tells when
+/// it is done and whether it worked. The disk does not grow: a partial last sector is
+/// left out.
+pub struct Disk<S: Read + Write + Seek> {
+    storage: S,
+    sectors: u16,
+    sector: u16,
+    buffer: u16,
+    /// The command started and not yet run by `tick`.
+    command: Option<u8>,
+    status: u8,
+    /// Why the last command failed, if it did.
+    error: Option<String>,
+}
+
+impl Disk<File> {
+    /// A disk on the existing file at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| format!("Cannot open disk '{}': {}", path.display(), e))?;
+        Self::new(file)
+    }
+}
+
+impl<S: Read + Write + Seek> Disk<S> {
+    pub fn new(mut storage: S) -> Result<Self, String> {
+        let len = storage.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+        let sectors = len / DISK_SECTOR_SIZE as u64;
+        let sectors = u16::try_from(sectors)
+            .map_err(|_| format!("A disk holds at most 65535 sectors, not {}", sectors))?;
+        Ok(Self {
+            storage,
+            sectors,
+            sector: 0,
+            buffer: 0,
+            command: None,
+            status: 0,
+            error: None,
+        })
+    }
+
+    pub fn sectors(&self) -> u16 {
+        self.sectors
+    }
+
+    /// Why the last command failed, for the host; guest code only sees `DISK_ERROR`.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+
+    /// Runs `command` between the current sector and memory.
+    fn transfer(&mut self, command: u8, memory: &mut dyn Addressable) -> Result<(), String> {
+        if self.sector >= self.sectors {
+            return Err(format!(
+                "Sector {} is past the end of the disk",
+                self.sector
+            ));
+        }
+        let offset = self.sector as u64 * DISK_SECTOR_SIZE as u64;
+        self.storage
+            .seek(SeekFrom::Start(offset))
+            .map_err(|e| e.to_string())?;
+        let mut data = [0; DISK_SECTOR_SIZE];
+        match command {
+            DISK_READ => {
+                self.storage
+                    .read_exact(&mut data)
+                    .map_err(|e| e.to_string())?;
+                if !memory.write_slice(self.buffer, &data) {
+                    return Err(format!("Cannot write the sector to 0x{:04X}", self.buffer));
+                }
+            }
+            DISK_WRITE => {
+                if !memory.read_slice(self.buffer, &mut data) {
+                    return Err(format!("Cannot read a sector from 0x{:04X}", self.buffer));
+                }
+                self.storage
+                    .write_all(&data)
+                    .and_then(|_| self.storage.flush())
+                    .map_err(|e| e.to_string())?;
+            }
+            _ => return Err(format!("Unknown disk command {}", command)),
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read + Write + Seek> Addressable for Disk<S> {
+    fn read(&self, address: u16) -> Option<u8> {
+        match address {
+            0..=1 => Some(self.sector.to_le_bytes()[address as usize]),
+            2..=3 => Some(self.buffer.to_le_bytes()[address as usize - 2]),
+            DISK_COMMAND => Some(self.command.unwrap_or(0)),
+            DISK_STATUS => Some(self.status),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        let set_byte = |word: &mut u16, i: usize| {
+            let mut bytes = word.to_le_bytes();
+            bytes[i] = value;
+            *word = u16::from_le_bytes(bytes);
+        };
+        match address {
+            0..=1 => set_byte(&mut self.sector, address as usize),
+            2..=3 => set_byte(&mut self.buffer, address as usize - 2),
+            DISK_COMMAND => {
+                self.command = Some(value);
+                self.status = DISK_BUSY;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn peek(&self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    fn poke(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+}
+
+impl<S: Read + Write + Seek> Device for Disk<S> {
+    fn tick(&mut self, memory: &mut dyn Addressable) -> Option<u8> {
+        let command = self.command.take()?;
+        self.error = self.transfer(command, memory).err();
+        self.status = if self.error.is_some() { DISK_ERROR } else { 0 };
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::memory::{LinearMemory, WatchKind};
+    use crate::vm::{Machine, Register};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_disk() {
+        let mut image = vec![0; DISK_SECTOR_SIZE * 2 + 10];
+        image[DISK_SECTOR_SIZE..DISK_SECTOR_SIZE * 2].fill(0xAB);
+        let mut disk = Disk::new(Cursor::new(image)).unwrap();
+        assert_eq!(disk.sectors(), 2);
+        let mut memory = LinearMemory::new(0x400);
+
+        assert!(disk.write2(DISK_SECTOR, 1));
+        assert!(disk.write2(DISK_BUFFER, 0x100));
+        assert!(disk.write(DISK_COMMAND, DISK_READ));
+        assert_eq!(disk.read(DISK_STATUS), Some(DISK_BUSY));
+        assert_eq!(disk.tick(&mut memory), None);
+        assert_eq!(disk.read(DISK_STATUS), Some(0));
+        assert_eq!(disk.error(), None);
+        assert_eq!(memory.read(0x100), Some(0xAB));
+        assert_eq!(memory.read(0x1FF), Some(0xAB));
+        assert_eq!(memory.read(0x200), Some(0));
+
+        memory.fill(0x300, 0x100, 0x5A);
+        disk.write2(DISK_SECTOR, 0);
+        disk.write2(DISK_BUFFER, 0x300);
+        disk.write(DISK_COMMAND, DISK_WRITE);
+        disk.tick(&mut memory);
+        assert_eq!(disk.read(DISK_STATUS), Some(0));
+        assert_eq!(disk.storage().get_ref()[..DISK_SECTOR_SIZE], [0x5A; 256]);
+
+        disk.write2(DISK_SECTOR, 2);
+        disk.write(DISK_COMMAND, DISK_READ);
+        disk.tick(&mut memory);
+        assert_eq!(disk.read(DISK_STATUS), Some(DISK_ERROR));
+        disk.write2(DISK_SECTOR, 0);
+        disk.write2(DISK_BUFFER, 0x380);
+        disk.write(DISK_COMMAND, DISK_READ);
+        disk.tick(&mut memory);
+        assert_eq!(disk.read(DISK_STATUS), Some(DISK_ERROR));
+        assert_eq!(disk.error(), Some("Cannot write the sector to 0x0380"));
+        disk.write(DISK_COMMAND, 9);
+        disk.tick(&mut memory);
+        assert_eq!(disk.read(DISK_STATUS), Some(DISK_ERROR));
+        assert_eq!(disk.error(), Some("Unknown disk command 9"));
+        assert!(!disk.write(DISK_STATUS, 0));
+    }
+
+    #[test]
+    fn test_copy_sector() {
+        let mut image = vec![0; DISK_SECTOR_SIZE * 4];
+        image[..5].copy_from_slice(b"tiny!");
+        let mut m = Machine::new();
+        let disk = m
+            .add_device(DISK_ADDRESS, 6, Disk::new(Cursor::new(image)).unwrap())
+            .unwrap();
+        // Reads sector 0 into 0x1000, then writes it back out as sector 3.
+        let program = assemble(
+            "
+            LDIW M, 0xFF52
+            LDIW A, 0x1000
+            STORE M, A          ; buffer
+            LDIW M, 0xFF54
+            LDI A, 1
+            STOREB M, A         ; read sector 0
+            LDIW D, 0xFF55
+    wait:   LOADB B, D
+            BTST B, 0
+            JNZ wait
+            LDIW M, 0xFF50
+            LDIW A, 3
+            STORE M, A          ; sector
+            LDIW M, 0xFF54
+            LDI A, 2
+            STOREB M, A         ; write sector 3
+    wait2:  LOADB B, D
+            BTST B, 0
+            JNZ wait2
+            HALT
+            ",
+        )
+        .unwrap();
+        m.load_program(&program, 0).unwrap();
+        m.run().unwrap();
+        assert_eq!(m.get_register(Register::B), 0);
+        assert_eq!(m.read_memory(0x1000..0x1005), b"tiny!");
+        let disk = disk.borrow();
+        let image = disk.storage().get_ref();
+        assert_eq!(&image[DISK_SECTOR_SIZE * 3..][..5], b"tiny!");
+    }
+
+    #[test]
+    fn test_step_back_read() {
+        let mut image = vec![0; DISK_SECTOR_SIZE];
+        image[..5].copy_from_slice(b"tiny!");
+        let mut m = Machine::new();
+        m.add_device(DISK_ADDRESS, 6, Disk::new(Cursor::new(image)).unwrap())
+            .unwrap();
+        let program = assemble(
+            "
+            LDIW M, 0xFF52
+            LDIW A, 0x1000
+            STORE M, A          ; buffer
+            LDIW M, 0xFF54
+            LDI A, 1
+            STOREB M, A         ; read sector 0
+            HALT
+            ",
+        )
+        .unwrap();
+        m.load_program(&program, 0).unwrap();
+        m.record_history(8);
+        m.add_watchpoint(0x1000, 0x10FF, WatchKind::Write);
+        m.run().unwrap();
+        assert_eq!(m.read_memory(0x1000..0x1005), b"tiny!");
+        // DMA is not a watched write, but history undoes it with the step that started it.
+        assert!(m.watch_hits().is_empty());
+        m.step_back().unwrap();
+        assert_eq!(m.read_memory(0x1000..0x1005), b"tiny!");
+        m.step_back().unwrap();
+        assert_eq!(m.read_memory(0x1000..0x1005), [0; 5]);
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+
+/// An assembly-time expression over numbers and symbols, e.g. `BUFFER_BASE + 2`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i32),
+    Symbol(String),
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i32),
+    Identifier(String),
+    Operator(&'static str),
+}
+
+const OPERATORS: [&str; 13] = [
+    "<<", ">>", "+", "-", "*", "/", "%", "&", "|", "^", "~", "(", ")",
+];
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Expr, String> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.parse_binary(0)?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some(_) => Err(format!("Invalid expression '{}'", s)),
+        }
+    }
+
+    /// Names of all symbols referenced by the expression, in order of appearance.
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Expr::Number(_) => Vec::new(),
+            Expr::Symbol(name) => vec![name.as_str()],
+            Expr::Negate(e) | Expr::Not(e) => e.symbols(),
+            Expr::Binary(_, lhs, rhs) => {
+                let mut symbols = lhs.symbols();
+                symbols.extend(rhs.symbols());
+                symbols
+            }
+        }
+    }
+
+    /// Replaces every symbol name with `f(name)`.
+    pub fn rename_symbols(
+        &mut self,
+        f: &mut impl FnMut(&str) -> Result<String, String>,
+    ) -> Result<(), String> {
+        match self {
+            Expr::Number(_) => Ok(()),
+            Expr::Symbol(name) => {
+                *name = f(name)?;
+                Ok(())
+            }
+            Expr::Negate(e) | Expr::Not(e) => e.rename_symbols(f),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.rename_symbols(f)?;
+                rhs.rename_symbols(f)
+            }
+        }
+    }
+
+    pub fn evaluate(&self, symbols: &HashMap<String, i32>) -> Result<i32, String> {
+        match self {
+            Expr::Number(n) => Ok(*n),
+            Expr::Symbol(name) => symbols
+                .get(name)
+                .copied()
+                .ok_or(format!("Unresolved symbol '{}'", name)),
+            Expr::Negate(e) => Ok(e.evaluate(symbols)?.wrapping_neg()),
+            Expr::Not(e) => Ok(!e.evaluate(symbols)?),
+            Expr::Binary(op, lhs, rhs) => {
+                let a = lhs.evaluate(symbols)?;
+                let b = rhs.evaluate(symbols)?;
+                match op {
+                    BinaryOp::Add => Ok(a.wrapping_add(b)),
+                    BinaryOp::Sub => Ok(a.wrapping_sub(b)),
+                    BinaryOp::Mul => Ok(a.wrapping_mul(b)),
+                    BinaryOp::Div => a.checked_div(b).ok_or("Division by zero".to_string()),
+                    BinaryOp::Rem => a.checked_rem(b).ok_or("Division by zero".to_string()),
+                    BinaryOp::And => Ok(a & b),
+                    BinaryOp::Or => Ok(a | b),
+                    BinaryOp::Xor => Ok(a ^ b),
+                    BinaryOp::Shl => Ok(a.wrapping_shl(b as u32)),
+                    BinaryOp::Shr => Ok(a.wrapping_shr(b as u32)),
+                }
+            }
+        }
+    }
+}
+
+pub fn parse_number(s: &str) -> Result<i32, String> {
+    let lower = s.to_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i32::from_str_radix(hex, 16)
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        i32::from_str_radix(bin, 2)
+    } else {
+        lower.parse::<i32>()
+    }
+    .map_err(|_| format!("Invalid number '{}'", s))
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    while let Some(c) = rest.chars().next() {
+        // Symbols may contain dots, e.g. `.loop` or `main.loop` for local labels.
+        let local =
+            c == '.' && rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '_');
+        if is_word(c) || local {
+            let end = rest
+                .find(|c: char| !(is_word(c) || c == '.'))
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            if c.is_ascii_digit() {
+                tokens.push(Token::Number(parse_number(word)?));
+            } else {
+                tokens.push(Token::Identifier(word.to_string()));
+            }
+            rest = &rest[end..];
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Operator(op));
+            rest = &rest[op.len()..];
+        } else {
+            return Err(format!("Unexpected character '{}' in expression", c));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+/// Binary operators grouped by precedence, loosest first.
+const PRECEDENCE: [&[(&str, BinaryOp)]; 6] = [
+    &[("|", BinaryOp::Or)],
+    &[("^", BinaryOp::Xor)],
+    &[("&", BinaryOp::And)],
+    &[("<<", BinaryOp::Shl), (">>", BinaryOp::Shr)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+    &[
+        ("*", BinaryOp::Mul),
+        ("/", BinaryOp::Div),
+        ("%", BinaryOp::Rem),
+    ],
+];
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn peek_operator(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Operator(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.parse_unary();
+        }
+        let mut lhs = self.parse_binary(level + 1)?;
+        while let Some(op) = self.peek_operator() {
+            let Some(&(_, binary)) = PRECEDENCE[level].iter().find(|(s, _)| *s == op) else {
+                break;
+            };
+            self.pos += 1;
+            let rhs = self.parse_binary(level + 1)?;
+            lhs = Expr::Binary(binary, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Identifier(name)) => Ok(Expr::Symbol(name)),
+            Some(Token::Operator("-")) => Ok(Expr::Negate(Box::new(self.parse_unary()?))),
+            Some(Token::Operator("~")) => Ok(Expr::Not(Box::new(self.parse_unary()?))),
+            Some(Token::Operator("(")) => {
+                let expr = self.parse_binary(0)?;
+                match self.next() {
+                    Some(Token::Operator(")")) => Ok(expr),
+                    _ => Err("Missing ')' in expression".to_string()),
+                }
+            }
+            Some(Token::Operator(op)) => Err(format!("Unexpected '{}' in expression", op)),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(s: &str) -> Result<i32, String> {
+        let symbols = HashMap::from([("BASE".to_string(), 0x200), ("N".to_string(), 4)]);
+        Expr::parse(s)?.evaluate(&symbols)
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(eval("42"), Ok(42));
+        assert_eq!(eval("-0x10"), Ok(-16));
+        assert_eq!(eval("BASE+2"), Ok(0x202));
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("BASE >> 8 | 1 << N"), Ok(0x12));
+        assert_eq!(eval("0xff & ~1"), Ok(0xfe));
+        assert_eq!(eval("10 - 2 - 3"), Ok(5));
+        assert_eq!(eval("7 % 4 ^ 0b10"), Ok(1));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(eval("1 +"), Err("Unexpected end of expression".to_string()));
+        assert_eq!(eval("(1"), Err("Missing ')' in expression".to_string()));
+        assert_eq!(eval("1 2"), Err("Invalid expression '1 2'".to_string()));
+        assert_eq!(eval("4 / 0"), Err("Division by zero".to_string()));
+        assert_eq!(eval("X + 1"), Err("Unresolved symbol 'X'".to_string()));
+        assert_eq!(eval("0xzz"), Err("Invalid number '0xzz'".to_string()));
+        assert_eq!(
+            eval("1 @ 2"),
+            Err("Unexpected character '@' in expression".to_string())
+        );
+    }
+
+    #[test]
+    fn test_symbols() {
+        let expr = Expr::parse("A + B * (C - A)").unwrap();
+        assert_eq!(expr.symbols(), vec!["A", "B", "C", "A"]);
+        let mut expr = Expr::parse(".loop - main.end").unwrap();
+        expr.rename_symbols(&mut |name| Ok(name.replace('.', "_")))
+            .unwrap();
+        assert_eq!(expr.symbols(), vec!["_loop", "main_end"]);
+    }
+}
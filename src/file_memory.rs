@@ -0,0 +1,133 @@
+use crate::memory::Addressable;
+use std::ffi::c_void;
+use std::fs::{File, OpenOptions};
+use std::os::raw::c_int;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+// Declared by hand rather than through a bindings crate; the values and the 64-bit `off_t`
+// hold on the 64-bit Linux and macOS targets this module is built for.
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const MAP_SHARED: c_int = 1;
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: i64,
+    ) -> *mut c_void;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+}
+
+/// Memory backed by a host file mapped into the process: writes land in the file as they
+/// happen, so its contents outlive the machine and other tools can open it while it runs.
+pub struct FileBackedMemory {
+    data: *mut u8,
+    len: usize,
+    _file: File,
+}
+
+impl FileBackedMemory {
+    /// Maps the first `len` bytes of the file at `path`, creating it and padding it with
+    /// zeroes as needed. A longer file is left as it is.
+    pub fn open(path: impl AsRef<Path>, len: usize) -> Result<Self, String> {
+        let path = path.as_ref();
+        let error = |e: std::io::Error| format!("Cannot map '{}': {}", path.display(), e);
+        if len == 0 || len > 0x10000 {
+            return Err(format!("Cannot map 0x{:X} bytes of memory", len));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(error)?;
+        if file.metadata().map_err(error)?.len() < len as u64 {
+            file.set_len(len as u64).map_err(error)?;
+        }
+        // SAFETY: the file is open read-write and at least `len` bytes long; the mapping is
+        // only touched within `len` and is unmapped on drop.
+        let data = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if data as isize == -1 {
+            return Err(error(std::io::Error::last_os_error()));
+        }
+        Ok(Self {
+            data: data.cast(),
+            len,
+            _file: file,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Drop for FileBackedMemory {
+    fn drop(&mut self) {
+        // SAFETY: `data` was returned by `mmap` for `len` bytes and is not used again.
+        unsafe { munmap(self.data.cast(), self.len) };
+    }
+}
+
+impl Addressable for FileBackedMemory {
+    fn read(&self, address: u16) -> Option<u8> {
+        let offset = address as usize;
+        // SAFETY: `offset` is inside the mapping.
+        (offset < self.len).then(|| unsafe { *self.data.add(offset) })
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        let offset = address as usize;
+        if offset >= self.len {
+            return false;
+        }
+        // SAFETY: `offset` is inside the mapping.
+        unsafe { *self.data.add(offset) = value };
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_file_backed_memory() {
+        let path = std::env::temp_dir().join(format!("tvm-mmap-{}.bin", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut memory = FileBackedMemory::open(&path, 0x100).unwrap();
+        assert_eq!(memory.read(0xFF), Some(0));
+        assert_eq!(memory.read(0x100), None);
+        assert!(memory.write2(0x10, 0xBEEF));
+        assert!(!memory.write(0x100, 1));
+        assert_eq!(fs::read(&path).unwrap()[0x10..0x12], [0xEF, 0xBE]);
+        drop(memory);
+
+        let memory = FileBackedMemory::open(&path, 0x20).unwrap();
+        assert_eq!(memory.read2(0x10), Some(0xBEEF));
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0x100);
+        drop(memory);
+        fs::remove_file(&path).unwrap();
+        assert!(FileBackedMemory::open(&path, 0).is_err());
+    }
+}
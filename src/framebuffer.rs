@@ -0,0 +1,234 @@
+use crate::memory::{read_bytes, write_bytes, Addressable};
+
+/// Where `vm --display` maps its `Framebuffer`.
+pub const FRAMEBUFFER_ADDRESS: u16 = 0xE000;
+/// The width in pixels of the framebuffer `vm --display` maps.
+pub const FRAMEBUFFER_WIDTH: usize = 64;
+/// Its height in pixels.
+pub const FRAMEBUFFER_HEIGHT: usize = 48;
+
+/// Called with the framebuffer when a frame is presented.
+type PresentHook = Box<dyn FnMut(&Framebuffer)>;
+
+/// A display of `width` by `height` pixels, one byte each, row by row from the top left.
+/// Pixels are RGB332: red in bits 5-7, green in bits 2-4 and blue in bits 0-1. The byte
+/// after the last pixel is the present register: storing to it hands the frame to the
+/// `on_present` hook, so guest code draws a whole frame before it shows.
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+    frames: u64,
+    hook: Option<PresentHook>,
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; width * height],
+            frames: 0,
+            hook: None,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// How many bytes to map: the pixels and the present register.
+    pub fn mapped_len(&self) -> usize {
+        self.pixels.len() + 1
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    /// How many frames have been presented.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Calls `hook` with the framebuffer each time a frame is presented, replacing any
+    /// earlier hook.
+    pub fn on_present(&mut self, hook: impl FnMut(&Framebuffer) + 'static) {
+        self.hook = Some(Box::new(hook));
+    }
+
+    /// Presents the current frame, as a store to the present register does.
+    pub fn present(&mut self) {
+        self.frames += 1;
+        if let Some(mut hook) = self.hook.take() {
+            hook(self);
+            self.hook.get_or_insert(hook);
+        }
+    }
+
+    /// The pixels as 0x00RRGGBB words, the layout most window libraries take.
+    pub fn to_rgb(&self) -> Vec<u32> {
+        self.pixels.iter().map(|&pixel| rgb(pixel)).collect()
+    }
+
+    /// The frame drawn with ANSI 24-bit colour, two pixel rows per line of half blocks.
+    pub fn to_ansi(&self) -> String {
+        let mut out = String::new();
+        for y in (0..self.height).step_by(2) {
+            for x in 0..self.width {
+                let top = rgb(self.pixels[y * self.width + x]);
+                let bottom = if y + 1 < self.height {
+                    rgb(self.pixels[(y + 1) * self.width + x])
+                } else {
+                    0
+                };
+                out.push_str(&format!(
+                    "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}",
+                    top >> 16,
+                    (top >> 8) & 0xFF,
+                    top & 0xFF,
+                    bottom >> 16,
+                    (bottom >> 8) & 0xFF,
+                    bottom & 0xFF
+                ));
+            }
+            out.push_str("\x1b[0m\n");
+        }
+        out
+    }
+}
+
+/// Expands an RGB332 pixel to 0x00RRGGBB.
+fn rgb(pixel: u8) -> u32 {
+    let red = (pixel >> 5) as u32 * 255 / 7;
+    let green = ((pixel >> 2) & 7) as u32 * 255 / 7;
+    let blue = (pixel & 3) as u32 * 255 / 3;
+    red << 16 | green << 8 | blue
+}
+
+impl Addressable for Framebuffer {
+    fn read(&self, address: u16) -> Option<u8> {
+        match self.pixels.get(address as usize) {
+            Some(&pixel) => Some(pixel),
+            None => (address as usize == self.pixels.len()).then_some(0),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        if let Some(pixel) = self.pixels.get_mut(address as usize) {
+            *pixel = value;
+        } else if address as usize == self.pixels.len() {
+            self.present();
+        } else {
+            return false;
+        }
+        true
+    }
+
+    fn read_slice(&self, address: u16, buf: &mut [u8]) -> bool {
+        let start = address as usize;
+        match self.pixels.get(start..start + buf.len()) {
+            Some(pixels) => {
+                buf.copy_from_slice(pixels);
+                true
+            }
+            None => read_bytes(self, address, buf),
+        }
+    }
+
+    fn write_slice(&mut self, address: u16, data: &[u8]) -> bool {
+        let start = address as usize;
+        match self.pixels.get_mut(start..start + data.len()) {
+            Some(pixels) => {
+                pixels.copy_from_slice(data);
+                true
+            }
+            None => write_bytes(self, address, data),
+        }
+    }
+
+    /// Pixels are plain memory; the present register is not.
+    fn peek(&self, address: u16) -> Option<u8> {
+        self.pixels.get(address as usize).copied()
+    }
+
+    fn poke(&mut self, address: u16, value: u8) -> bool {
+        self.pixels
+            .get_mut(address as usize)
+            .map(|pixel| *pixel = value)
+            .is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::vm::Machine;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_framebuffer() {
+        let mut fb = Framebuffer::new(4, 3);
+        assert_eq!(fb.mapped_len(), 13);
+        assert!(fb.write(5, 0xE0));
+        assert!(fb.write_slice(8, &[0x1C, 0x03, 0xFF, 0x00]));
+        assert_eq!(fb.read(5), Some(0xE0));
+        assert_eq!(fb.read(12), Some(0));
+        assert_eq!(fb.read(13), None);
+        assert!(!fb.write(13, 0));
+        let rgb = fb.to_rgb();
+        assert_eq!(rgb[5], 0xFF0000);
+        assert_eq!(rgb[8], 0x00FF00);
+        assert_eq!(rgb[9], 0x0000FF);
+        assert_eq!(rgb[10], 0xFFFFFF);
+        assert_eq!(fb.to_ansi().lines().count(), 2);
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let log = seen.clone();
+        fb.on_present(move |fb| log.borrow_mut().push(fb.pixels()[5]));
+        assert!(fb.write(12, 1));
+        fb.present();
+        assert_eq!(*seen.borrow(), vec![0xE0, 0xE0]);
+        assert_eq!(fb.frames(), 2);
+    }
+
+    #[test]
+    fn test_draw() {
+        let fb = Rc::new(RefCell::new(Framebuffer::new(8, 8)));
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        let log = frames.clone();
+        fb.borrow_mut()
+            .on_present(move |fb| log.borrow_mut().push(fb.pixels().to_vec()));
+        let mut m = Machine::new();
+        m.map(FRAMEBUFFER_ADDRESS, fb.borrow().mapped_len(), fb.clone())
+            .unwrap();
+        // A white diagonal, then present.
+        let program = assemble(
+            "
+            LDIW M, 0xE000
+            LDI A, 0xFF
+            LDIW C, 8
+    draw:   STOREB M, A
+            ADDI M, 9
+            LOOP C, draw
+            LDIW M, 0xE040
+            STOREB M, A
+            HALT
+            ",
+        )
+        .unwrap();
+        m.load_program(&program, 0).unwrap();
+        m.run().unwrap();
+        let frames = frames.borrow();
+        assert_eq!(frames.len(), 1);
+        for (i, &pixel) in frames[0].iter().enumerate() {
+            assert_eq!(pixel, if i % 9 == 0 { 0xFF } else { 0 }, "pixel {}", i);
+        }
+    }
+}
@@ -0,0 +1,535 @@
+use crate::debugger::{Debugger, StopReason};
+use crate::memory::WatchKind;
+use crate::vm::{Register, REGISTER_COUNT};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+/// Instructions run between checks for a Ctrl-C from gdb while continuing.
+const INTERRUPT_POLL: usize = 4096;
+
+/// What the serving loop does after a packet.
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Reply(String),
+    /// Step once, or continue, then send a stop reply.
+    Resume {
+        step: bool,
+    },
+    /// Undo one step, or run backwards, then send a stop reply.
+    Reverse {
+        step: bool,
+    },
+    /// Reply, then end the session.
+    Close(Option<String>),
+}
+
+/// A GDB remote serial protocol server around a `Debugger`. gdb reads the register layout
+/// from the target description this sends (`qXfer:features:read`), so it can connect with
+/// `target remote`, read and write registers and memory, set breakpoints and watchpoints
+/// (`Z0`–`Z4`), step and continue; Ctrl-C interrupts a running program.
+pub struct GdbStub {
+    pub debugger: Debugger,
+    /// Machine watchpoint ids by `Z` type, address and length.
+    watches: HashMap<(u8, u16, u16), usize>,
+}
+
+impl GdbStub {
+    pub fn new(debugger: Debugger) -> Self {
+        Self {
+            debugger,
+            watches: HashMap::new(),
+        }
+    }
+
+    /// Serves one gdb connection until gdb detaches, kills the program or disconnects.
+    pub fn serve(&mut self, mut stream: TcpStream) -> Result<(), String> {
+        let error = |e: std::io::Error| format!("gdb connection: {}", e);
+        while let Some(packet) = read_packet(&mut stream).map_err(error)? {
+            match self.handle(&packet) {
+                Action::Reply(reply) => send_packet(&mut stream, &reply).map_err(error)?,
+                Action::Resume { step } => {
+                    let stop = self.resume(step, &mut || interrupted(&stream))?;
+                    for reply in stop {
+                        send_packet(&mut stream, &reply).map_err(error)?;
+                    }
+                }
+                Action::Reverse { step } => {
+                    let stop = self.reverse(step);
+                    send_packet(&mut stream, &stop).map_err(error)?;
+                }
+                Action::Close(reply) => {
+                    if let Some(reply) = reply {
+                        send_packet(&mut stream, &reply).map_err(error)?;
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, packet: &str) -> Action {
+        let machine = &mut self.debugger.machine;
+        let reply = match packet.split_at(packet.len().min(1)) {
+            ("?", _) | ("\x03", _) => {
+                if machine.is_halted() {
+                    "W00".to_string()
+                } else {
+                    "S05".to_string()
+                }
+            }
+            ("g", "") => registers()
+                .map(|r| hex(&machine.get_register(r).to_le_bytes()))
+                .collect(),
+            ("G", values) => {
+                let Some(bytes) = unhex(values).filter(|b| b.len() == REGISTER_COUNT * 2) else {
+                    return Action::Reply("E01".to_string());
+                };
+                for (register, value) in registers().zip(bytes.chunks(2)) {
+                    machine.set_register(register, u16::from_le_bytes([value[0], value[1]]));
+                }
+                "OK".to_string()
+            }
+            ("p", n) => match number(n).and_then(register) {
+                Some(r) => hex(&machine.get_register(r).to_le_bytes()),
+                None => "E01".to_string(),
+            },
+            ("P", assignment) => {
+                let parsed = assignment.split_once('=').and_then(|(n, value)| {
+                    let value = unhex(value).filter(|b| b.len() == 2)?;
+                    Some((
+                        register(number(n)?)?,
+                        u16::from_le_bytes([value[0], value[1]]),
+                    ))
+                });
+                match parsed {
+                    Some((register, value)) => {
+                        machine.set_register(register, value);
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            ("m", range) => match address_range(range) {
+                Some((address, len)) => {
+                    let bytes = self.debugger.read_memory(address, len as usize);
+                    if bytes.is_empty() && len > 0 {
+                        "E01".to_string()
+                    } else {
+                        hex(&bytes)
+                    }
+                }
+                None => "E01".to_string(),
+            },
+            ("M", write) => {
+                let parsed = write.split_once(':').and_then(|(range, data)| {
+                    let (address, len) = address_range(range)?;
+                    Some((address, unhex(data).filter(|b| b.len() == len as usize)?))
+                });
+                let Some((address, data)) = parsed else {
+                    return Action::Reply("E01".to_string());
+                };
+                // Written with `poke`, like the debugger's `set mem`: devices refuse the bytes.
+                match self.debugger.write_memory(address, &data) {
+                    Ok(()) => "OK".to_string(),
+                    Err(_) => "E01".to_string(),
+                }
+            }
+            ("s" | "c", at) => {
+                if !at.is_empty() {
+                    match number(at) {
+                        Some(pc) => machine.set_register(Register::PC, pc as u16),
+                        None => return Action::Reply("E01".to_string()),
+                    }
+                }
+                return Action::Resume {
+                    step: packet.starts_with('s'),
+                };
+            }
+            ("b", "s" | "c") => {
+                return Action::Reverse {
+                    step: packet == "bs",
+                }
+            }
+            ("Z" | "z", point) => self.breakpoint(packet.starts_with('Z'), point),
+            ("H" | "T", _) => "OK".to_string(),
+            ("D", _) => return Action::Close(Some("OK".to_string())),
+            ("k", _) => return Action::Close(None),
+            ("v", _) if packet.starts_with("vKill") => {
+                return Action::Close(Some("OK".to_string()))
+            }
+            _ => self.query(packet),
+        };
+        Action::Reply(reply)
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=1000;qXfer:features:read+;ReverseStep+;ReverseContinue+"
+                .to_string();
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let xml = target_description();
+            return match address_range(range) {
+                Some((offset, len)) => {
+                    let start = (offset as usize).min(xml.len());
+                    let end = (start + len as usize).min(xml.len());
+                    let marker = if end == xml.len() { 'l' } else { 'm' };
+                    format!("{}{}", marker, &xml[start..end])
+                }
+                None => "E01".to_string(),
+            };
+        }
+        match packet {
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => "",
+        }
+        .to_string()
+    }
+
+    /// `Z`/`z` packets: type 0 and 1 are breakpoints, 2 to 4 write, read and access
+    /// watchpoints.
+    fn breakpoint(&mut self, insert: bool, point: &str) -> String {
+        let mut fields = point.split(',');
+        let (Some(kind), Some(address), Some(len)) = (
+            fields.next().and_then(number),
+            fields.next().and_then(number),
+            fields.next().and_then(number),
+        ) else {
+            return "E01".to_string();
+        };
+        let (kind, address, len) = (kind as u8, address as u16, len as u16);
+        match kind {
+            0 | 1 if insert => {
+                self.debugger.add_breakpoint(address);
+            }
+            0 | 1 => {
+                self.debugger.remove_breakpoint(address);
+            }
+            2..=4 if insert => {
+                let watch = match kind {
+                    2 => WatchKind::Write,
+                    3 => WatchKind::Read,
+                    _ => WatchKind::ReadWrite,
+                };
+                let end = address.saturating_add(len.max(1) - 1);
+                let id = self.debugger.machine.add_watchpoint(address, end, watch);
+                self.watches.insert((kind, address, len), id);
+            }
+            2..=4 => {
+                if let Some(id) = self.watches.remove(&(kind, address, len)) {
+                    self.debugger.machine.remove_watchpoint(id);
+                }
+            }
+            _ => return String::new(),
+        }
+        "OK".to_string()
+    }
+
+    /// Steps or continues, polling `interrupted` between batches, and returns the packets to
+    /// send: a stop reply, preceded by the error as console output if an instruction faulted.
+    fn resume(
+        &mut self,
+        step: bool,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> Result<Vec<String>, String> {
+        if self.debugger.machine.is_halted() {
+            return Ok(vec!["W00".to_string()]);
+        }
+        let result = if step {
+            self.debugger.step()
+        } else {
+            loop {
+                match self.debugger.resume_for(INTERRUPT_POLL) {
+                    Ok(None) if interrupted() => return Ok(vec!["S02".to_string()]),
+                    Ok(None) => {}
+                    Ok(Some(reason)) => break Ok(reason),
+                    Err(error) => break Err(error),
+                }
+            }
+        };
+        Ok(match result {
+            Ok(reason) => vec![stop_reply(reason)],
+            Err(error) => vec![
+                format!("O{}", hex(format!("{}\n", error).as_bytes())),
+                "S0B".to_string(),
+            ],
+        })
+    }
+
+    /// Steps back or runs backwards. Having nothing left to undo is reported to gdb as
+    /// reaching the start of the replay log, not as an error.
+    fn reverse(&mut self, step: bool) -> String {
+        let result = if step {
+            self.debugger.step_back()
+        } else {
+            self.debugger.reverse()
+        };
+        stop_reply(result.unwrap_or(StopReason::StartOfHistory))
+    }
+}
+
+fn stop_reply(reason: StopReason) -> String {
+    match reason {
+        StopReason::Step | StopReason::Breakpoint(_) => "S05".to_string(),
+        StopReason::Watchpoint(hits) => {
+            let hit = &hits[0];
+            let kind = if hit.write { "watch" } else { "rwatch" };
+            format!("T05{}:{:x};", kind, hit.address)
+        }
+        StopReason::Halted => "W00".to_string(),
+        StopReason::StartOfHistory => "T05replaylog:begin;".to_string(),
+    }
+}
+
+/// The register numbers gdb uses are the machine's own.
+fn registers() -> impl Iterator<Item = Register> {
+    (0..REGISTER_COUNT as u8).filter_map(Register::from_u8)
+}
+
+fn register(n: u32) -> Option<Register> {
+    u8::try_from(n).ok().and_then(Register::from_u8)
+}
+
+fn target_description() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n  <feature name=\"org.tinyvm.core\">\n",
+    );
+    for (n, register) in registers().enumerate() {
+        let kind = match register {
+            Register::PC => " type=\"code_ptr\"",
+            Register::SP | Register::BP => " type=\"data_ptr\"",
+            _ => "",
+        };
+        xml.push_str(&format!(
+            "    <reg name=\"{}\" bitsize=\"16\" regnum=\"{}\"{}/>\n",
+            register.to_string().to_lowercase(),
+            n,
+            kind
+        ));
+    }
+    xml.push_str("  </feature>\n</target>\n");
+    xml
+}
+
+fn number(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+/// `addr,length` in hex, both limited to 16 bits.
+fn address_range(text: &str) -> Option<(u16, u16)> {
+    let (address, len) = text.split_once(',')?;
+    Some((
+        u16::try_from(number(address)?).ok()?,
+        u16::try_from(number(len)?).ok()?,
+    ))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, b| sum.wrapping_add(b))
+}
+
+fn read_byte(stream: &mut impl Read) -> std::io::Result<Option<u8>> {
+    let mut byte = [0];
+    Ok((stream.read(&mut byte)? == 1).then_some(byte[0]))
+}
+
+/// Reads the next `$data#cs` packet, acknowledging it, or a bare Ctrl-C as `"\x03"`. Returns
+/// `None` when the connection closes.
+fn read_packet(stream: &mut (impl Read + Write)) -> std::io::Result<Option<String>> {
+    loop {
+        match read_byte(stream)? {
+            None => return Ok(None),
+            Some(0x03) => return Ok(Some("\x03".to_string())),
+            Some(b'$') => {}
+            // Acks for our own packets, and noise between packets.
+            Some(_) => continue,
+        }
+        let mut data = Vec::new();
+        loop {
+            match read_byte(stream)? {
+                None => return Ok(None),
+                Some(b'#') => break,
+                Some(byte) => data.push(byte),
+            }
+        }
+        let (Some(high), Some(low)) = (read_byte(stream)?, read_byte(stream)?) else {
+            return Ok(None);
+        };
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let sum = std::str::from_utf8(&[high, low])
+            .ok()
+            .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+        if sum == Some(checksum(&data)) {
+            stream.write_all(b"+")?;
+            return Ok(Some(data));
+        }
+        stream.write_all(b"-")?;
+    }
+}
+
+fn send_packet(stream: &mut impl Write, data: &str) -> std::io::Result<()> {
+    write!(stream, "${}#{:02x}", data, checksum(data))?;
+    stream.flush()
+}
+
+/// Whether gdb sent a Ctrl-C while the program was running.
+fn interrupted(stream: &TcpStream) -> bool {
+    let mut byte = [0];
+    let _ = stream.set_nonblocking(true);
+    let interrupted = match stream.peek(&mut byte) {
+        Ok(1) if byte[0] == 0x03 => (&*stream).read(&mut byte).is_ok(),
+        Ok(_) => false,
+        Err(e) => e.kind() != ErrorKind::WouldBlock,
+    };
+    let _ = stream.set_nonblocking(false);
+    interrupted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembly;
+    use crate::vm::Machine;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn program() -> GdbStub {
+        let assembly = Assembly::from_source(
+            "
+            main:   LDI A, 3
+                    LDIW M, 0x200
+            loop:   STORE M, A
+                    DEC A
+                    JNZ loop
+                    HALT
+            ",
+        )
+        .unwrap();
+        let mut machine = Machine::new();
+        machine.load_program(&assembly.bytes, 0).unwrap();
+        GdbStub::new(Debugger::new(machine, assembly.symbols))
+    }
+
+    fn reply(stub: &mut GdbStub, packet: &str) -> String {
+        match stub.handle(packet) {
+            Action::Reply(reply) => reply,
+            Action::Resume { step } => stub.resume(step, &mut || false).unwrap().join("|"),
+            Action::Reverse { step } => stub.reverse(step),
+            Action::Close(reply) => reply.unwrap_or_default(),
+        }
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut stub = program();
+        assert_eq!(reply(&mut stub, "P0=3412"), "OK");
+        assert_eq!(reply(&mut stub, "p0"), "3412");
+        assert_eq!(reply(&mut stub, "p5"), "0000");
+        assert_eq!(reply(&mut stub, "p20"), "E01");
+        assert_eq!(reply(&mut stub, "g"), format!("3412{}", "0000".repeat(12)));
+        assert_eq!(reply(&mut stub, &format!("G{}", "0100".repeat(13))), "OK");
+        assert_eq!(stub.debugger.machine.get_register(Register::H), 1);
+        assert_eq!(reply(&mut stub, "G0100"), "E01");
+
+        assert_eq!(reply(&mut stub, "m0,6"), "f00321030002");
+        assert_eq!(reply(&mut stub, "M200,2:aa55"), "OK");
+        assert_eq!(reply(&mut stub, "m1ff,4"), "00aa5500");
+        assert_eq!(reply(&mut stub, "M200,2:aa"), "E01");
+        assert_eq!(reply(&mut stub, "m3000,2"), "E01");
+
+        stub.debugger.machine.map_registers().unwrap();
+        assert_eq!(reply(&mut stub, "MFFE0,2:0500"), "E01");
+        assert_eq!(stub.debugger.machine.get_register(Register::A), 1);
+    }
+
+    #[test]
+    fn test_execution() {
+        let mut stub = program();
+        assert_eq!(reply(&mut stub, "?"), "S05");
+        assert_eq!(reply(&mut stub, "s"), "S05");
+        assert_eq!(reply(&mut stub, "p5"), "0200");
+        assert_eq!(reply(&mut stub, "Z0,8,2"), "OK");
+        assert_eq!(reply(&mut stub, "c"), "S05");
+        assert_eq!(reply(&mut stub, "p5"), "0800");
+        assert_eq!(reply(&mut stub, "z0,8,2"), "OK");
+        assert_eq!(reply(&mut stub, "Z2,200,1"), "OK");
+        assert_eq!(reply(&mut stub, "c"), "T05watch:200;");
+        assert_eq!(reply(&mut stub, "z2,200,1"), "OK");
+        assert_eq!(reply(&mut stub, "c"), "W00");
+        assert_eq!(reply(&mut stub, "?"), "W00");
+        assert_eq!(reply(&mut stub, "bs"), "S05");
+        assert_eq!(reply(&mut stub, "p5"), "0e00");
+        assert_eq!(reply(&mut stub, "Z0,6,2"), "OK");
+        assert_eq!(reply(&mut stub, "bc"), "S05");
+        assert_eq!(reply(&mut stub, "p5"), "0600");
+        assert_eq!(reply(&mut stub, "z0,6,2"), "OK");
+        assert_eq!(reply(&mut stub, "bc"), "T05replaylog:begin;");
+        assert_eq!(reply(&mut stub, "bs"), "T05replaylog:begin;");
+        assert_eq!(reply(&mut stub, "p5"), "0000");
+
+        let mut stub = program();
+        assert_eq!(reply(&mut stub, "M10,2:ff00"), "OK");
+        assert_eq!(
+            reply(&mut stub, "c10"),
+            format!("O{}|S0B", hex(b"Unknown register 0xF\n"))
+        );
+    }
+
+    #[test]
+    fn test_target_description() {
+        let stub = program();
+        assert_eq!(
+            stub.query("qSupported:swbreak+"),
+            "PacketSize=1000;qXfer:features:read+;ReverseStep+;ReverseContinue+"
+        );
+        let xml = stub.query("qXfer:features:read:target.xml:0,1000");
+        assert!(xml.starts_with("l<?xml"));
+        assert!(xml.contains("<reg name=\"pc\" bitsize=\"16\" regnum=\"5\" type=\"code_ptr\"/>"));
+        assert_eq!(stub.query("qXfer:features:read:target.xml:0,5"), "m<?xml");
+        assert_eq!(stub.query("qUnknown"), "");
+    }
+
+    #[test]
+    fn test_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let mut received = Vec::new();
+            for (packet, len) in [("$p0#a0", 9), ("$p0#00", 1), ("$s#73", 8), ("$D#44", 7)] {
+                stream.write_all(packet.as_bytes()).unwrap();
+                let mut buffer = vec![0; len];
+                stream.read_exact(&mut buffer).unwrap();
+                received.push(String::from_utf8(buffer).unwrap());
+            }
+            received
+        });
+        let (stream, _) = listener.accept().unwrap();
+        program().serve(stream).unwrap();
+        assert_eq!(
+            client.join().unwrap(),
+            ["+$0000#c0", "-", "+$S05#b8", "+$OK#9a"]
+        );
+    }
+}
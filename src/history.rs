@@ -0,0 +1,83 @@
+use crate::vm::REGISTER_COUNT;
+use std::collections::VecDeque;
+
+/// What one step changed, enough to undo it: the registers and halted flag from before the
+/// step and the previous value of every byte it wrote, in write order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepRecord {
+    pub registers: [u16; REGISTER_COUNT],
+    pub halted: bool,
+    pub writes: Vec<(u16, u8)>,
+    /// Bytes devices overwrote by DMA after the instruction, at bus addresses.
+    pub dma_writes: Vec<(u16, u8)>,
+}
+
+/// A ring buffer of the most recent steps; once `capacity` is reached the oldest is dropped
+/// for each new one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct History {
+    records: VecDeque<StepRecord>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            records: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, record: StepRecord) {
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Takes the most recent step.
+    pub fn pop(&mut self) -> Option<StepRecord> {
+        self.records.pop_back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(a: u16) -> StepRecord {
+        let mut registers = [0; REGISTER_COUNT];
+        registers[0] = a;
+        StepRecord {
+            registers,
+            halted: false,
+            writes: Vec::new(),
+            dma_writes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut history = History::new(2);
+        assert!(history.is_empty());
+        for a in 1..=3 {
+            history.push(record(a));
+        }
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.pop(), Some(record(3)));
+        assert_eq!(history.pop(), Some(record(2)));
+        assert_eq!(history.pop(), None);
+    }
+}
@@ -0,0 +1,243 @@
+use crate::object::{ObjectFile, Segment};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+/// Data bytes per record when writing, the usual choice of EPROM programmers.
+const RECORD_SIZE: usize = 16;
+
+impl ObjectFile {
+    /// Whether `bytes` look like Intel HEX text, i.e. start with a `:` record.
+    pub fn is_intel_hex(bytes: &[u8]) -> bool {
+        bytes.trim_ascii_start().starts_with(b":")
+    }
+
+    /// Writes the segments as Intel HEX data records, followed by a start linear address
+    /// record holding the entry point and the end of file record.
+    pub fn to_intel_hex(&self) -> String {
+        let mut out = String::new();
+        for segment in &self.segments {
+            for (i, chunk) in segment.bytes.chunks(RECORD_SIZE).enumerate() {
+                let address = segment.address as usize + i * RECORD_SIZE;
+                out.push_str(&record(DATA, address as u16, chunk));
+            }
+        }
+        out.push_str(&record(
+            START_LINEAR_ADDRESS,
+            0,
+            &(self.entry as u32).to_be_bytes(),
+        ));
+        out.push_str(&record(END_OF_FILE, 0, &[]));
+        out
+    }
+
+    /// Reads Intel HEX text. Data records that follow on from each other are merged into one
+    /// segment. Without a start address record the entry point is the first segment.
+    pub fn from_intel_hex(text: &str) -> Result<ObjectFile, String> {
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut entry = None;
+        let mut base = 0;
+        let mut ended = false;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: String| format!("line {}: {}", i + 1, message);
+            if ended {
+                return Err(error("Record after the end of file record".to_string()));
+            }
+            let (kind, offset, data) = parse_record(line).map_err(error)?;
+            match kind {
+                DATA => {
+                    let address = base + offset as usize;
+                    if address + data.len() > 0x10000 {
+                        return Err(error(format!(
+                            "Address 0x{:X} is outside the 16-bit address space",
+                            address + data.len() - 1
+                        )));
+                    }
+                    match segments.last_mut() {
+                        Some(last) if last.address as usize + last.bytes.len() == address => {
+                            last.bytes.extend_from_slice(&data)
+                        }
+                        _ => segments.push(Segment {
+                            address: address as u16,
+                            bytes: data,
+                        }),
+                    }
+                }
+                END_OF_FILE => ended = true,
+                EXTENDED_SEGMENT_ADDRESS | EXTENDED_LINEAR_ADDRESS => {
+                    let value = u16::from_be_bytes(fixed(&data).map_err(error)?) as usize;
+                    base = if kind == EXTENDED_SEGMENT_ADDRESS {
+                        value << 4
+                    } else {
+                        value << 16
+                    };
+                }
+                START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS => {
+                    let [a, b, c, d] = fixed(&data).map_err(error)?;
+                    let address = if kind == START_SEGMENT_ADDRESS {
+                        (u16::from_be_bytes([a, b]) as usize) * 16
+                            + u16::from_be_bytes([c, d]) as usize
+                    } else {
+                        u32::from_be_bytes([a, b, c, d]) as usize
+                    };
+                    let address = u16::try_from(address).map_err(|_| {
+                        error(format!(
+                            "Address 0x{:X} is outside the 16-bit address space",
+                            address
+                        ))
+                    })?;
+                    entry = Some(address);
+                }
+                _ => return Err(error(format!("Unsupported record type 0x{:02X}", kind))),
+            }
+        }
+        if !ended {
+            return Err("Missing end of file record".to_string());
+        }
+        let entry = entry.unwrap_or(segments.first().map_or(0, |s| s.address));
+        Ok(ObjectFile { entry, segments })
+    }
+}
+
+fn record(kind: u8, address: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    bytes.push(sum.wrapping_neg());
+    let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    format!(":{}\n", hex)
+}
+
+/// Splits a record into its type, address and data, checking its length and checksum.
+fn parse_record(line: &str) -> Result<(u8, u16, Vec<u8>), String> {
+    let hex = line
+        .strip_prefix(':')
+        .ok_or("Expected ':' at the start of a record")?;
+    if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err("Invalid hex digits in record".to_string());
+    }
+    let bytes: Vec<u8> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect();
+    if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+        return Err("Record length does not match its byte count".to_string());
+    }
+    let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    if sum != 0 {
+        let found = bytes[bytes.len() - 1];
+        return Err(format!(
+            "Checksum mismatch (expected 0x{:02X}, found 0x{:02X})",
+            found.wrapping_sub(sum),
+            found
+        ));
+    }
+    let address = u16::from_be_bytes([bytes[1], bytes[2]]);
+    Ok((bytes[3], address, bytes[4..bytes.len() - 1].to_vec()))
+}
+
+fn fixed<const N: usize>(data: &[u8]) -> Result<[u8; N], String> {
+    data.try_into()
+        .map_err(|_| format!("Expected {} data bytes in address record", N))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let object = ObjectFile {
+            entry: 0x102,
+            segments: vec![
+                Segment {
+                    address: 0x100,
+                    bytes: (0..20).collect(),
+                },
+                Segment {
+                    address: 0xfffe,
+                    bytes: vec![0xaa, 0xbb],
+                },
+            ],
+        };
+        let text = object.to_intel_hex();
+        assert_eq!(
+            text,
+            ":10010000000102030405060708090A0B0C0D0E0F77\n\
+             :0401100010111213A5\n\
+             :02FFFE00AABB9C\n\
+             :0400000500000102F4\n\
+             :00000001FF\n"
+        );
+        assert!(ObjectFile::is_intel_hex(text.as_bytes()));
+        assert_eq!(ObjectFile::from_intel_hex(&text), Ok(object));
+    }
+
+    #[test]
+    fn test_read() {
+        let text = "
+            :020000020100FB
+            :0300300002337A1E
+            :0400000300000000F9
+            :00000001FF
+        ";
+        assert_eq!(
+            ObjectFile::from_intel_hex(text),
+            Ok(ObjectFile {
+                entry: 0,
+                segments: vec![Segment {
+                    address: 0x1030,
+                    bytes: vec![0x02, 0x33, 0x7a],
+                }],
+            })
+        );
+        let object = ObjectFile::from_intel_hex(":0100200001DE\n:00000001FF").unwrap();
+        assert_eq!(object.entry, 0x20);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text| ObjectFile::from_intel_hex(text).map(|_| ());
+        assert_eq!(
+            error("0100200001DE"),
+            Err("line 1: Expected ':' at the start of a record".to_string())
+        );
+        assert_eq!(
+            error(":01002000XXDE"),
+            Err("line 1: Invalid hex digits in record".to_string())
+        );
+        assert_eq!(
+            error(":0200200001DE"),
+            Err("line 1: Record length does not match its byte count".to_string())
+        );
+        assert_eq!(
+            error(":0100200001DF"),
+            Err("line 1: Checksum mismatch (expected 0xDE, found 0xDF)".to_string())
+        );
+        assert_eq!(
+            error(":0100200001DE"),
+            Err("Missing end of file record".to_string())
+        );
+        assert_eq!(
+            error(":00000001FF\n:0100200001DE"),
+            Err("line 2: Record after the end of file record".to_string())
+        );
+        assert_eq!(
+            error(":020000040001F9\n:0100200001DE\n:00000001FF"),
+            Err("line 2: Address 0x10020 is outside the 16-bit address space".to_string())
+        );
+        assert_eq!(
+            error(":00000006FA"),
+            Err("line 1: Unsupported record type 0x06".to_string())
+        );
+    }
+}
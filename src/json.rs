@@ -0,0 +1,243 @@
+use crate::object::{ObjectFile, Segment};
+use crate::symbols::{Symbol, SymbolTable};
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+/// Version of the JSON program format, stored in its `"version"` field.
+pub const JSON_VERSION: u16 = 1;
+
+/// The top-level JSON object. `entry` defaults to the first segment's address.
+#[derive(Serialize, Deserialize)]
+struct Program {
+    version: u16,
+    #[serde(default)]
+    entry: Option<u16>,
+    segments: Vec<Segment>,
+    #[serde(default)]
+    symbols: Vec<Symbol>,
+}
+
+/// Segment bytes as a string of hex digit pairs; an array of byte values also reads.
+pub(crate) mod hex_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_any(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a string of hex digit pairs or an array of bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, hex: &str) -> Result<Vec<u8>, E> {
+            let invalid = || E::custom("'bytes' must be a string of hex digit pairs");
+            if !hex.len().is_multiple_of(2) {
+                return Err(invalid());
+            }
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| {
+                    let pair = hex.get(i..i + 2).ok_or_else(invalid)?;
+                    u8::from_str_radix(pair, 16).map_err(|_| invalid())
+                })
+                .collect()
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut items: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::new();
+            while let Some(byte) = items.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+impl ObjectFile {
+    /// Writes the program and its symbols as JSON:
+    ///
+    /// ```text
+    /// {
+    ///   "version": 1,
+    ///   "entry": 256,
+    ///   "segments": [{"address": 256, "bytes": "0105F000"}],
+    ///   "symbols": [{"name": "main", "value": 256, "kind": "label"}]
+    /// }
+    /// ```
+    pub fn to_json(&self, symbols: &SymbolTable) -> String {
+        let program = Program {
+            version: JSON_VERSION,
+            entry: Some(self.entry),
+            segments: self.segments.clone(),
+            symbols: symbols.iter().cloned().collect(),
+        };
+        let mut json = serde_json::to_string_pretty(&program).expect("a program is valid JSON");
+        json.push('\n');
+        json
+    }
+
+    /// Reads a program written by `to_json` or by hand. `"symbols"` is optional, and
+    /// `"entry"` defaults to the first segment's address.
+    pub fn from_json(text: &str) -> Result<(ObjectFile, SymbolTable), String> {
+        let program: Program =
+            serde_json::from_str(text).map_err(|e| format!("Invalid JSON program: {}", e))?;
+        if program.version != JSON_VERSION {
+            return Err(format!(
+                "Unsupported JSON program version {} (expected version {})",
+                program.version, JSON_VERSION
+            ));
+        }
+        for (i, segment) in program.segments.iter().enumerate() {
+            if segment.address as usize + segment.bytes.len() > 0x10000 {
+                return Err(format!(
+                    "segments[{}]: Segment at 0x{:X} of {} bytes runs past the end of memory",
+                    i,
+                    segment.address,
+                    segment.bytes.len()
+                ));
+            }
+        }
+        let entry = program
+            .entry
+            .unwrap_or_else(|| program.segments.first().map_or(0, |s| s.address));
+        let mut symbols = SymbolTable::new();
+        for symbol in program.symbols {
+            symbols.insert(&symbol.name, symbol.value, symbol.kind);
+        }
+        let object = ObjectFile {
+            entry,
+            segments: program.segments,
+        };
+        Ok((object, symbols))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbols::SymbolKind;
+
+    #[test]
+    fn test_round_trip() {
+        let object = ObjectFile {
+            entry: 0x102,
+            segments: vec![
+                Segment {
+                    address: 0x100,
+                    bytes: vec![0x01, 0x05, 0x22, 0x00],
+                },
+                Segment {
+                    address: 0x1000,
+                    bytes: vec![0xaa],
+                },
+            ],
+        };
+        let mut symbols = SymbolTable::new();
+        symbols.insert("main", 0x102, SymbolKind::Label);
+        symbols.insert("UART", 0xff00, SymbolKind::Constant);
+        let json = object.to_json(&symbols);
+        assert_eq!(
+            json,
+            r#"{
+  "version": 1,
+  "entry": 258,
+  "segments": [
+    {
+      "address": 256,
+      "bytes": "01052200"
+    },
+    {
+      "address": 4096,
+      "bytes": "AA"
+    }
+  ],
+  "symbols": [
+    {
+      "name": "main",
+      "value": 258,
+      "kind": "label"
+    },
+    {
+      "name": "UART",
+      "value": 65280,
+      "kind": "const"
+    }
+  ]
+}
+"#
+        );
+        assert_eq!(ObjectFile::from_json(&json), Ok((object, symbols)));
+    }
+
+    #[test]
+    fn test_from_json() {
+        let (object, symbols) = ObjectFile::from_json(
+            r#"{"version": 1, "segments": [{"address": 16, "bytes": [1, 5, 34, 0]}]}"#,
+        )
+        .unwrap();
+        assert_eq!(object.entry, 16);
+        assert_eq!(object.segments[0].bytes, vec![1, 5, 34, 0]);
+        assert!(symbols.is_empty());
+
+        let error = |text| ObjectFile::from_json(text).map(|_| ());
+        assert_eq!(
+            error("[]"),
+            Err(
+                "Invalid JSON program: invalid length 0, expected struct Program with 4 elements \
+                 at line 1 column 2"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            error(r#"{"version": 2, "segments": []}"#),
+            Err("Unsupported JSON program version 2 (expected version 1)".to_string())
+        );
+        assert_eq!(
+            error(r#"{"version": 1}"#),
+            Err("Invalid JSON program: missing field `segments` at line 1 column 14".to_string())
+        );
+        assert_eq!(
+            error(r#"{"version": 1, "segments": [{"address": -1, "bytes": ""}]}"#),
+            Err(
+                "Invalid JSON program: invalid value: integer `-1`, expected u16 at line 1 \
+                 column 42"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            error(r#"{"version": 1, "segments": [{"address": 0, "bytes": "ABC"}]}"#),
+            Err(
+                "Invalid JSON program: 'bytes' must be a string of hex digit pairs at line 1 \
+                 column 57"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            error(r#"{"version": 1, "segments": [{"address": 65535, "bytes": [1, 2]}]}"#),
+            Err(
+                "segments[0]: Segment at 0xFFFF of 2 bytes runs past the end of memory".to_string()
+            )
+        );
+        assert_eq!(
+            error(
+                r#"{"version": 1, "segments": [], "symbols": [{"name": "x", "value": 1, "kind": "data"}]}"#
+            ),
+            Err(
+                "Invalid JSON program: unknown variant `data`, expected `label` or `const` at \
+                 line 1 column 83"
+                    .to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use crate::memory::Addressable;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+/// Where `vm --keyboard` maps its `Keyboard`.
+pub const KEYBOARD_ADDRESS: u16 = 0xFF40;
+/// Offset of the data register: reading takes the oldest buffered key code, or 0.
+pub const KEYBOARD_DATA: u16 = 0;
+/// Offset of the status register, `KEYBOARD_READY` or 0.
+pub const KEYBOARD_STATUS: u16 = 1;
+/// Status bit: a key code is waiting in the data register.
+pub const KEYBOARD_READY: u8 = 0x01;
+/// How many key codes the keyboard holds; presses beyond that are dropped.
+pub const KEYBOARD_BUFFER: usize = 16;
+
+/// An input device with a data and a status register over a small buffer of key codes. The
+/// host presses keys with `press` and `type_text`, or `from_reader` feeds the bytes of a
+/// reader, like a terminal in raw mode, from a thread of their own.
+#[derive(Default)]
+pub struct Keyboard {
+    buffer: RefCell<VecDeque<u8>>,
+    source: Option<Receiver<u8>>,
+    dropped: usize,
+}
+
+impl Keyboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A keyboard on the host's standard input.
+    pub fn stdin() -> Self {
+        Self::from_reader(io::stdin())
+    }
+
+    pub fn from_reader(mut input: impl Read + Send + 'static) -> Self {
+        let (sender, source) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 16];
+            while let Ok(n @ 1..) = input.read(&mut buf) {
+                if buf[..n].iter().any(|&byte| sender.send(byte).is_err()) {
+                    break;
+                }
+            }
+        });
+        Self {
+            source: Some(source),
+            ..Self::default()
+        }
+    }
+
+    /// Buffers `code`; returns false, and drops it, if the buffer is full.
+    pub fn press(&mut self, code: u8) -> bool {
+        let mut buffer = self.buffer.borrow_mut();
+        if buffer.len() == KEYBOARD_BUFFER {
+            self.dropped += 1;
+            return false;
+        }
+        buffer.push_back(code);
+        true
+    }
+
+    /// Presses the bytes of `text` in order; returns how many fit in the buffer.
+    pub fn type_text(&mut self, text: &str) -> usize {
+        text.bytes().take_while(|&code| self.press(code)).count()
+    }
+
+    /// Key codes waiting to be read.
+    pub fn pending(&self) -> usize {
+        self.buffer.borrow().len()
+    }
+
+    /// How many presses a full buffer has dropped.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Moves what the reader has sent into the buffer, as far as it fits; the rest waits.
+    fn poll(&self) {
+        let Some(source) = &self.source else {
+            return;
+        };
+        let mut buffer = self.buffer.borrow_mut();
+        while buffer.len() < KEYBOARD_BUFFER {
+            match source.try_recv() {
+                Ok(code) => buffer.push_back(code),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl Addressable for Keyboard {
+    fn read(&self, address: u16) -> Option<u8> {
+        self.poll();
+        match address {
+            KEYBOARD_DATA => Some(self.buffer.borrow_mut().pop_front().unwrap_or(0)),
+            KEYBOARD_STATUS if self.pending() > 0 => Some(KEYBOARD_READY),
+            KEYBOARD_STATUS => Some(0),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+
+    fn peek(&self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    fn poke(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::vm::{Machine, Register};
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_keyboard() {
+        let mut keyboard = Keyboard::new();
+        assert_eq!(keyboard.read(KEYBOARD_STATUS), Some(0));
+        assert_eq!(keyboard.read(KEYBOARD_DATA), Some(0));
+        assert_eq!(keyboard.type_text("hello, world!...."), KEYBOARD_BUFFER);
+        assert_eq!(keyboard.dropped(), 1);
+        assert_eq!(keyboard.read(KEYBOARD_STATUS), Some(KEYBOARD_READY));
+        assert_eq!(keyboard.read(KEYBOARD_DATA), Some(b'h'));
+        assert_eq!(keyboard.pending(), KEYBOARD_BUFFER - 1);
+        assert!(keyboard.press(b'x'));
+        assert_eq!(keyboard.read(2), None);
+        assert!(!keyboard.write(KEYBOARD_DATA, 1));
+
+        let keyboard = Keyboard::from_reader(Cursor::new(b"q".to_vec()));
+        while keyboard.read(KEYBOARD_STATUS) != Some(KEYBOARD_READY) {
+            thread::yield_now();
+        }
+        assert_eq!(keyboard.read(KEYBOARD_DATA), Some(b'q'));
+    }
+
+    #[test]
+    fn test_read_keys() {
+        let keyboard = Rc::new(RefCell::new(Keyboard::new()));
+        let mut m = Machine::new();
+        m.map(KEYBOARD_ADDRESS, 2, keyboard.clone()).unwrap();
+        let program = assemble(
+            "
+            LDIW M, 0xFF41
+            LDIW D, 0xFF40
+    wait:   LOADB A, M
+            BTST A, 0
+            JZ wait
+            LOADB B, D
+            HALT
+            ",
+        )
+        .unwrap();
+        m.load_program(&program, 0).unwrap();
+        for _ in 0..10 {
+            m.step().unwrap();
+        }
+        assert!(!m.is_halted());
+        keyboard.borrow_mut().press(b'k');
+        m.run().unwrap();
+        assert_eq!(m.get_register(Register::B), b'k' as u16);
+    }
+}
@@ -1,4 +1,76 @@
+mod assembler;
+mod backtrace;
+mod cache;
+mod console;
+mod core_dump;
+#[cfg(feature = "json")]
+mod dap;
+mod debug_info;
+mod debugger;
+mod device;
+mod diagnostic;
+mod disassembler;
+mod disk;
+mod expression;
+#[cfg(all(
+    unix,
+    target_pointer_width = "64",
+    any(target_os = "linux", target_os = "macos")
+))]
+mod file_memory;
+mod framebuffer;
+mod gdb;
+mod history;
+mod intel_hex;
+#[cfg(feature = "json")]
+mod json;
+mod keyboard;
+mod link;
 mod memory;
+mod mmu;
+mod object;
+mod rng;
+mod srecord;
+mod symbols;
+mod timer;
+#[cfg(feature = "tui")]
+mod tui;
+mod uart;
 mod vm;
 
-pub use crate::vm::*;
\ No newline at end of file
+pub use crate::assembler::*;
+pub use crate::backtrace::Frame;
+pub use crate::cache::*;
+pub use crate::console::*;
+pub use crate::core_dump::*;
+#[cfg(feature = "json")]
+pub use crate::dap::DapServer;
+pub use crate::debug_info::*;
+pub use crate::debugger::*;
+pub use crate::device::Device;
+pub use crate::diagnostic::Diagnostic;
+pub use crate::disassembler::*;
+pub use crate::disk::*;
+#[cfg(all(
+    unix,
+    target_pointer_width = "64",
+    any(target_os = "linux", target_os = "macos")
+))]
+pub use crate::file_memory::FileBackedMemory;
+pub use crate::framebuffer::*;
+pub use crate::gdb::GdbStub;
+pub use crate::history::*;
+#[cfg(feature = "json")]
+pub use crate::json::JSON_VERSION;
+pub use crate::keyboard::*;
+pub use crate::link::link;
+pub use crate::memory::*;
+pub use crate::mmu::*;
+pub use crate::object::*;
+pub use crate::rng::*;
+pub use crate::symbols::*;
+pub use crate::timer::*;
+#[cfg(feature = "tui")]
+pub use crate::tui::Tui;
+pub use crate::uart::*;
+pub use crate::vm::*;
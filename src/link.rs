@@ -0,0 +1,138 @@
+use crate::object::{ObjectFile, RelocatableObject, Segment};
+use crate::symbols::{SymbolKind, SymbolTable};
+use std::collections::HashMap;
+
+/// Links relocatable modules into one program loaded at `base`. Modules are placed one after
+/// another in the order given, each on an even address, then every relocation is patched
+/// with the final address of its module or of the global symbol it names.
+///
+/// Execution starts at the `.entry` of the first module that has one, or at `base`. The
+/// returned symbol table holds the final address of every global symbol.
+pub fn link(modules: &[RelocatableObject], base: u16) -> Result<(ObjectFile, SymbolTable), String> {
+    let mut bases = Vec::new();
+    let mut end = base as usize;
+    for module in modules {
+        end += end % 2;
+        bases.push(end);
+        end += module.code.len();
+    }
+    if end > 0x10000 {
+        return Err("Linked program does not fit in memory".to_string());
+    }
+
+    let mut globals: HashMap<&str, u16> = HashMap::new();
+    for (module, &module_base) in modules.iter().zip(&bases) {
+        for (name, offset) in &module.symbols {
+            let address = (module_base + *offset as usize) as u16;
+            if globals.insert(name, address).is_some() {
+                return Err(format!("Duplicate symbol '{}'", name));
+            }
+        }
+    }
+
+    let mut code = Vec::new();
+    for (module, &module_base) in modules.iter().zip(&bases) {
+        let start = module_base - base as usize;
+        code.resize(start, 0);
+        code.extend_from_slice(&module.code);
+        for relocation in &module.relocations {
+            let target = match &relocation.symbol {
+                Some(name) => *globals
+                    .get(name.as_str())
+                    .ok_or(format!("Undefined symbol '{}'", name))?,
+                None => module_base as u16,
+            };
+            let value = target.wrapping_add(relocation.addend);
+            let at = start + relocation.offset as usize;
+            code[at..at + 2].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    let entry = modules
+        .iter()
+        .zip(&bases)
+        .find_map(|(module, &module_base)| Some(module_base as u16 + module.entry?))
+        .unwrap_or(base);
+    let mut symbols = SymbolTable::new();
+    for (name, address) in globals {
+        symbols.insert(name, address, SymbolKind::Label);
+    }
+    let object = ObjectFile {
+        entry,
+        segments: vec![Segment {
+            address: base,
+            bytes: code,
+        }],
+    };
+    Ok((object, symbols))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembly;
+    use crate::vm::{Machine, Register};
+
+    fn module(source: &str) -> RelocatableObject {
+        Assembly::from_source_relocatable(source)
+            .unwrap()
+            .to_relocatable()
+    }
+
+    #[test]
+    fn test_link_and_run() {
+        let main = module(
+            "
+                .entry start
+                .global start
+            start:  LDIW M, table + 2
+                    LOAD A, M
+                    CALL double
+                    STORE M, A
+                    HALT
+            table:  .word 1, 20, 300
+            ",
+        );
+        let library = module(
+            "
+                .global double
+            pad:    .byte 0xff
+            double: ADD A, A
+                    JR .done
+            .done:  RET
+            ",
+        );
+        assert_eq!(main.relocations.len(), 2);
+        assert_eq!(library.symbols, vec![("double".to_string(), 1)]);
+
+        let (object, symbols) = link(&[main, library], 0x100).unwrap();
+        assert_eq!(object.entry, 0x100);
+        assert_eq!(symbols.get("double").map(|s| s.value), Some(0x115));
+
+        let mut m = Machine::new();
+        m.set_register(Register::SP, 0x1000);
+        m.load_object(&object).unwrap();
+        m.run().unwrap();
+        assert_eq!(m.get_register(Register::A), 40);
+        assert_eq!(m.memory.read2(0x110), Some(40));
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let caller = module("CALL missing");
+        assert_eq!(
+            link(&[caller], 0).map(|_| ()),
+            Err("Undefined symbol 'missing'".to_string())
+        );
+        let twice = module(".global init\ninit: RET");
+        assert_eq!(
+            link(&[twice.clone(), twice], 0).map(|_| ()),
+            Err("Duplicate symbol 'init'".to_string())
+        );
+        let big = module(".org 0xfff0\nRET");
+        assert_eq!(
+            link(&[big], 0x100).map(|_| ()),
+            Err("Linked program does not fit in memory".to_string())
+        );
+    }
+}
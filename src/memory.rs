@@ -1,32 +1,147 @@
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+
 pub trait Addressable {
     fn read(&self, address: u16) -> Option<u8>;
     fn write(&mut self, address: u16, value: u8) -> bool;
     fn read2(&self, address: u16) -> Option<u16> {
-        if let Some(x0) = self.read(address) {
-            if let Some(x1) = self.read(address + 1) {
-                return Some((x0 as u16) | ((x1 as u16) << 8));
-            }
-        };
-        None
+        let x0 = self.read(address)?;
+        let x1 = self.read(address.checked_add(1)?)?;
+        Some((x0 as u16) | ((x1 as u16) << 8))
     }
     fn write2(&mut self, address: u16, value: u16) -> bool {
         let lower = value & 0xff;
         let upper = (value & 0xff00) >> 8;
-        self.write(address, lower as u8) && self.write(address + 1, upper as u8)
+        self.write(address, lower as u8)
+            && address
+                .checked_add(1)
+                .is_some_and(|a| self.write(a, upper as u8))
+    }
+
+    /// `read2` with the high byte first.
+    fn read2_be(&self, address: u16) -> Option<u16> {
+        let high = self.read(address)?;
+        let low = self.read(address.checked_add(1)?)?;
+        Some(u16::from_be_bytes([high, low]))
+    }
+
+    /// `write2` with the high byte first.
+    fn write2_be(&mut self, address: u16, value: u16) -> bool {
+        let [high, low] = value.to_be_bytes();
+        self.write(address, high) && address.checked_add(1).is_some_and(|a| self.write(a, low))
     }
 
+    /// Copies `n` bytes a byte at a time, from the lowest address up; returns false if any
+    /// of them, or the range itself, runs off the end of the address space.
     fn copy(&mut self, from: u16, to: u16, n: usize) -> bool {
         for i in 0..n {
-            if let Some(x) = self.read(from + i as u16) {
-                if !self.write(to + i as u16, x) {
-                    return false;
-                }
-            } else {
+            let (Ok(from), Ok(to)) = (
+                u16::try_from(from as usize + i),
+                u16::try_from(to as usize + i),
+            ) else {
                 return false;
+            };
+            match self.read(from) {
+                Some(x) if self.write(to, x) => {}
+                _ => return false,
             }
         }
         true
     }
+
+    fn fill(&mut self, address: u16, n: usize, value: u8) -> bool {
+        (0..n).all(|i| u16::try_from(address as usize + i).is_ok_and(|a| self.write(a, value)))
+    }
+
+    /// Fills `buf` with the bytes from `address` on; returns false if any of them cannot be
+    /// read, leaving `buf` partly filled.
+    fn read_slice(&self, address: u16, buf: &mut [u8]) -> bool {
+        read_bytes(self, address, buf)
+    }
+
+    /// Writes `bytes` from `address` on; returns false if any of them cannot be written,
+    /// possibly after writing the ones before.
+    fn write_slice(&mut self, address: u16, bytes: &[u8]) -> bool {
+        write_bytes(self, address, bytes)
+    }
+
+    /// Reads a byte of an instruction. Memory that tracks execute permission checks it here;
+    /// everything else reads as usual, and wrappers pass fetches on as fetches.
+    fn fetch(&self, address: u16) -> Option<u8> {
+        self.read(address)
+    }
+
+    /// Reads a byte for the host, for history, snapshots and core dumps, without side
+    /// effects: wrappers pass it on without recording it, and the bus without checking
+    /// permissions or telling observers. Devices, whose registers do more than hold a byte,
+    /// return `None` so the host leaves them alone.
+    fn peek(&self, address: u16) -> Option<u8> {
+        self.read(address)
+    }
+
+    /// Writes a byte for the host, the way `peek` reads one; devices return false.
+    fn poke(&mut self, address: u16, value: u8) -> bool {
+        self.write(address, value)
+    }
+}
+
+/// Which byte of a 16-bit word comes first in memory.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    /// Low byte first, as `read2` and `write2` expect.
+    #[default]
+    Little,
+    /// High byte first, as `read2_be` and `write2_be` expect.
+    Big,
+}
+
+impl Endianness {
+    pub fn from_bytes(self, bytes: [u8; 2]) -> u16 {
+        match self {
+            Endianness::Little => u16::from_le_bytes(bytes),
+            Endianness::Big => u16::from_be_bytes(bytes),
+        }
+    }
+
+    pub fn to_bytes(self, value: u16) -> [u8; 2] {
+        match self {
+            Endianness::Little => value.to_le_bytes(),
+            Endianness::Big => value.to_be_bytes(),
+        }
+    }
+}
+
+/// `read_slice` a byte at a time, for memory that cannot do better.
+pub(crate) fn read_bytes<A: Addressable + ?Sized>(
+    memory: &A,
+    address: u16,
+    buf: &mut [u8],
+) -> bool {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        match u16::try_from(address as usize + i)
+            .ok()
+            .and_then(|a| memory.read(a))
+        {
+            Some(value) => *byte = value,
+            None => return false,
+        }
+    }
+    true
+}
+
+/// `write_slice` a byte at a time, for memory that cannot do better.
+pub(crate) fn write_bytes<A: Addressable + ?Sized>(
+    memory: &mut A,
+    address: u16,
+    bytes: &[u8],
+) -> bool {
+    bytes.iter().enumerate().all(|(i, &value)| {
+        u16::try_from(address as usize + i).is_ok_and(|a| memory.write(a, value))
+    })
 }
 
 pub struct LinearMemory {
@@ -60,4 +175,1449 @@ impl Addressable for LinearMemory {
             false
         }
     }
+
+    fn read_slice(&self, address: u16, buf: &mut [u8]) -> bool {
+        let start = address as usize;
+        match self.bytes.get(start..start + buf.len()) {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn write_slice(&mut self, address: u16, bytes: &[u8]) -> bool {
+        let start = address as usize;
+        match self.bytes.get_mut(start..start + bytes.len()) {
+            Some(target) => {
+                target.copy_from_slice(bytes);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Read-only memory holding a fixed image. Every write fails, so an instruction that writes
+/// to it faults instead of changing it.
+pub struct Rom {
+    bytes: Vec<u8>,
+}
+
+impl Rom {
+    pub fn new(bytes: &[u8]) -> Self {
+        Self {
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+impl Addressable for Rom {
+    fn read(&self, address: u16) -> Option<u8> {
+        self.bytes.get(address as usize).copied()
+    }
+
+    fn write(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+}
+
+/// The whole 64K address space, allocated a 256-byte page at a time as it is written.
+/// Addresses that were never written read as zero. Map it over `0x10000` addresses from 0.
+#[derive(Default)]
+pub struct SparseMemory {
+    pages: HashMap<u8, Box<[u8; 256]>>,
+}
+
+impl SparseMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many pages have been allocated.
+    pub fn pages(&self) -> usize {
+        self.pages.len()
+    }
+}
+
+impl Addressable for SparseMemory {
+    fn read(&self, address: u16) -> Option<u8> {
+        let [offset, page] = address.to_le_bytes();
+        Some(self.pages.get(&page).map_or(0, |p| p[offset as usize]))
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        let [offset, page] = address.to_le_bytes();
+        match self.pages.get_mut(&page) {
+            Some(p) => p[offset as usize] = value,
+            None if value == 0 => {}
+            None => {
+                let mut p = Box::new([0; 256]);
+                p[offset as usize] = value;
+                self.pages.insert(page, p);
+            }
+        }
+        true
+    }
+}
+
+/// A private, writable view of a shared image: reads come from the image until a 256-byte
+/// page is first written, which copies just that page. Any number of machines can start
+/// from one loaded program this way, on any thread, without each holding a copy of it.
+pub struct CowMemory {
+    image: Arc<[u8]>,
+    pages: HashMap<u8, Box<[u8; 256]>>,
+}
+
+impl CowMemory {
+    pub fn new(image: Arc<[u8]>) -> Self {
+        Self {
+            image,
+            pages: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.image.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.image.is_empty()
+    }
+
+    /// How many pages have been copied from the image.
+    pub fn copied_pages(&self) -> usize {
+        self.pages.len()
+    }
+}
+
+impl Addressable for CowMemory {
+    fn read(&self, address: u16) -> Option<u8> {
+        let [offset, page] = address.to_le_bytes();
+        match self.pages.get(&page) {
+            Some(p) => Some(p[offset as usize]),
+            None => self.image.get(address as usize).copied(),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        if address as usize >= self.image.len() {
+            return false;
+        }
+        let [offset, page] = address.to_le_bytes();
+        let image = &self.image;
+        let p = self.pages.entry(page).or_insert_with(|| {
+            let start = page as usize * 256;
+            let end = image.len().min(start + 256);
+            let mut p = Box::new([0; 256]);
+            p[..end - start].copy_from_slice(&image[start..end]);
+            p
+        });
+        p[offset as usize] = value;
+        true
+    }
+}
+
+/// Memory that several machines can map at once, on the same thread or on different ones:
+/// every clone sees the same bytes, and each access locks them for just that byte.
+#[derive(Clone)]
+pub struct SharedMemory {
+    bytes: Arc<RwLock<Vec<u8>>>,
+}
+
+impl SharedMemory {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: Arc::new(RwLock::new(vec![0; len])),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Addressable for SharedMemory {
+    fn read(&self, address: u16) -> Option<u8> {
+        self.bytes.read().unwrap().get(address as usize).copied()
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        match self.bytes.write().unwrap().get_mut(address as usize) {
+            Some(byte) => {
+                *byte = value;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Bytes of physical memory behind a `SegmentedMemory`: 20-bit addresses.
+pub const SEGMENTED_MEMORY_SIZE: usize = 1 << 20;
+
+/// The segments a `SegmentedMemory` adds to the addresses it is given.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Segments {
+    /// Used for instruction fetches.
+    pub code: u16,
+    /// Used for every other access.
+    pub data: u16,
+}
+
+/// 1 MiB of memory seen through 16-bit offsets: the physical address is the segment times
+/// 16 plus the offset, wrapping at `SEGMENTED_MEMORY_SIZE`, with the code segment used for
+/// instruction fetches and the data segment for everything else.
+pub struct SegmentedMemory {
+    bytes: Vec<u8>,
+    segments: Rc<Cell<Segments>>,
+}
+
+impl SegmentedMemory {
+    pub fn new(segments: Rc<Cell<Segments>>) -> Self {
+        Self {
+            bytes: vec![0; SEGMENTED_MEMORY_SIZE],
+            segments,
+        }
+    }
+
+    /// All of physical memory.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+
+    fn physical(segment: u16, offset: u16) -> usize {
+        ((segment as usize) * 16 + offset as usize) % SEGMENTED_MEMORY_SIZE
+    }
+}
+
+impl Addressable for SegmentedMemory {
+    fn read(&self, address: u16) -> Option<u8> {
+        let segment = self.segments.get().data;
+        Some(self.bytes[Self::physical(segment, address)])
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        let segment = self.segments.get().data;
+        self.bytes[Self::physical(segment, address)] = value;
+        true
+    }
+
+    fn fetch(&self, address: u16) -> Option<u8> {
+        let segment = self.segments.get().code;
+        Some(self.bytes[Self::physical(segment, address)])
+    }
+}
+
+/// A `window` of addresses onto one of several equally sized banks, for more data than fits in
+/// the address space. The byte after the window is the bank select register: reading it gives
+/// the current bank and writing it switches banks, failing for a bank that does not exist.
+/// Map it over `len()` addresses; to switch banks from the host as well, map an
+/// `Rc<RefCell<BankedMemory>>` and keep a clone.
+pub struct BankedMemory {
+    banks: Vec<Vec<u8>>,
+    window: u16,
+    current: u8,
+}
+
+impl BankedMemory {
+    /// `count` zeroed banks of `window` bytes each, with bank 0 selected. At most 256 banks
+    /// can be selected.
+    pub fn new(window: u16, count: usize) -> Self {
+        Self {
+            banks: vec![vec![0; window as usize]; count.min(256)],
+            window,
+            current: 0,
+        }
+    }
+
+    /// The window and the select register.
+    pub fn len(&self) -> usize {
+        self.window as usize + 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    pub fn bank(&self) -> u8 {
+        self.current
+    }
+
+    /// Returns false if there is no bank `n`.
+    pub fn select(&mut self, n: u8) -> bool {
+        let exists = (n as usize) < self.banks.len();
+        if exists {
+            self.current = n;
+        }
+        exists
+    }
+
+    /// The contents of bank `n`, for loading data into banks that are not selected.
+    pub fn bank_mut(&mut self, n: u8) -> Option<&mut [u8]> {
+        self.banks.get_mut(n as usize).map(Vec::as_mut_slice)
+    }
+}
+
+impl Addressable for BankedMemory {
+    fn read(&self, address: u16) -> Option<u8> {
+        match address.cmp(&self.window) {
+            Ordering::Less => self
+                .banks
+                .get(self.current as usize)?
+                .get(address as usize)
+                .copied(),
+            Ordering::Equal => Some(self.current),
+            Ordering::Greater => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        match address.cmp(&self.window) {
+            Ordering::Less => match self.banks.get_mut(self.current as usize) {
+                Some(bank) => {
+                    bank[address as usize] = value;
+                    true
+                }
+                None => false,
+            },
+            Ordering::Equal => self.select(value),
+            Ordering::Greater => false,
+        }
+    }
+
+    /// The bank register switches banks, so only the window is plain memory.
+    fn peek(&self, address: u16) -> Option<u8> {
+        (address < self.window).then(|| self.read(address))?
+    }
+
+    fn poke(&mut self, address: u16, value: u8) -> bool {
+        address < self.window && self.write(address, value)
+    }
+}
+
+/// A plain buffer as memory, such as one owned by a host application: the address is the
+/// index, and the buffer's length is the memory's size.
+impl Addressable for [u8] {
+    fn read(&self, address: u16) -> Option<u8> {
+        self.get(address as usize).copied()
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        match self.get_mut(address as usize) {
+            Some(byte) => {
+                *byte = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn read_slice(&self, address: u16, buf: &mut [u8]) -> bool {
+        let start = address as usize;
+        match self.get(start..start + buf.len()) {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn write_slice(&mut self, address: u16, bytes: &[u8]) -> bool {
+        let start = address as usize;
+        match self.get_mut(start..start + bytes.len()) {
+            Some(target) => {
+                target.copy_from_slice(bytes);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Addressable for Vec<u8> {
+    fn read(&self, address: u16) -> Option<u8> {
+        self.as_slice().read(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        self.as_mut_slice().write(address, value)
+    }
+
+    fn read_slice(&self, address: u16, buf: &mut [u8]) -> bool {
+        self.as_slice().read_slice(address, buf)
+    }
+
+    fn write_slice(&mut self, address: u16, bytes: &[u8]) -> bool {
+        self.as_mut_slice().write_slice(address, bytes)
+    }
+}
+
+/// Borrowed memory, such as `&mut [u8]`, for use without giving it up.
+impl<A: Addressable + ?Sized> Addressable for &mut A {
+    fn read(&self, address: u16) -> Option<u8> {
+        (**self).read(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        (**self).write(address, value)
+    }
+
+    fn read_slice(&self, address: u16, buf: &mut [u8]) -> bool {
+        (**self).read_slice(address, buf)
+    }
+
+    fn write_slice(&mut self, address: u16, bytes: &[u8]) -> bool {
+        (**self).write_slice(address, bytes)
+    }
+
+    fn fetch(&self, address: u16) -> Option<u8> {
+        (**self).fetch(address)
+    }
+
+    fn peek(&self, address: u16) -> Option<u8> {
+        (**self).peek(address)
+    }
+
+    fn poke(&mut self, address: u16, value: u8) -> bool {
+        (**self).poke(address, value)
+    }
+}
+
+impl<A: Addressable + ?Sized> Addressable for Box<A> {
+    fn read(&self, address: u16) -> Option<u8> {
+        (**self).read(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        (**self).write(address, value)
+    }
+
+    fn read_slice(&self, address: u16, buf: &mut [u8]) -> bool {
+        (**self).read_slice(address, buf)
+    }
+
+    fn write_slice(&mut self, address: u16, bytes: &[u8]) -> bool {
+        (**self).write_slice(address, bytes)
+    }
+
+    fn fetch(&self, address: u16) -> Option<u8> {
+        (**self).fetch(address)
+    }
+
+    fn peek(&self, address: u16) -> Option<u8> {
+        (**self).peek(address)
+    }
+
+    fn poke(&mut self, address: u16, value: u8) -> bool {
+        (**self).poke(address, value)
+    }
+}
+
+/// Memory shared between owners, such as a `Machine` and the code that maps regions into it.
+/// Accesses to memory already in use, like a device reaching its own registers by DMA, fail.
+impl<A: Addressable> Addressable for Rc<RefCell<A>> {
+    fn read(&self, address: u16) -> Option<u8> {
+        self.try_borrow().ok()?.read(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        self.try_borrow_mut()
+            .is_ok_and(|mut inner| inner.write(address, value))
+    }
+
+    fn read_slice(&self, address: u16, buf: &mut [u8]) -> bool {
+        self.try_borrow()
+            .is_ok_and(|inner| inner.read_slice(address, buf))
+    }
+
+    fn write_slice(&mut self, address: u16, bytes: &[u8]) -> bool {
+        self.try_borrow_mut()
+            .is_ok_and(|mut inner| inner.write_slice(address, bytes))
+    }
+
+    fn fetch(&self, address: u16) -> Option<u8> {
+        self.try_borrow().ok()?.fetch(address)
+    }
+
+    fn peek(&self, address: u16) -> Option<u8> {
+        self.try_borrow().ok()?.peek(address)
+    }
+
+    fn poke(&mut self, address: u16, value: u8) -> bool {
+        self.try_borrow_mut()
+            .is_ok_and(|mut inner| inner.poke(address, value))
+    }
+}
+
+/// What a region of the memory map may be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub const RWX: Self = Self::new(true, true, true);
+    pub const RW: Self = Self::new(true, true, false);
+    pub const RX: Self = Self::new(true, false, true);
+    pub const R: Self = Self::new(true, false, false);
+
+    pub const fn new(read: bool, write: bool, execute: bool) -> Self {
+        Self {
+            read,
+            write,
+            execute,
+        }
+    }
+
+    fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        }
+    }
+}
+
+/// `rwx` with a `-` for each missing permission, like `ls -l`.
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |allowed, c| if allowed { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(self.read, 'r'),
+            flag(self.write, 'w'),
+            flag(self.execute, 'x')
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// An access to a mapped region that its permissions do not allow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Violation {
+    pub address: u16,
+    pub access: Access,
+    pub start: u16,
+    pub len: usize,
+    pub permissions: Permissions,
+}
+
+/// `Cannot write 0x1004: region 0x1000-0x1FFF is r-x`
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let verb = match self.access {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Execute => "execute",
+        };
+        write!(
+            f,
+            "Cannot {} 0x{:04X}: region 0x{:04X}-0x{:04X} is {}",
+            verb,
+            self.address,
+            self.start,
+            self.start as usize + self.len - 1,
+            self.permissions
+        )
+    }
+}
+
+/// A backend mapped over `len` addresses from `start`.
+pub struct Region {
+    pub start: u16,
+    pub len: usize,
+    pub permissions: Permissions,
+    pub backend: Box<dyn Addressable>,
+    /// Shared by the regions `map_mirrored` made from one backend, which hold the same bytes.
+    pub mirror: Option<usize>,
+}
+
+impl Region {
+    fn contains(&self, address: u16) -> bool {
+        (address as usize).wrapping_sub(self.start as usize) < self.len
+    }
+}
+
+/// A memory bus: each access goes to the backend of the region that contains it, at its
+/// offset from the region's start, so a backend works wherever it is mapped. Unmapped
+/// addresses cannot be read or written, and neither can mapped ones in ways their region's
+/// `Permissions` do not allow; the first such access is kept as a `Violation`.
+#[derive(Default)]
+pub struct MemoryMapper {
+    /// Sorted by start, never overlapping.
+    regions: Vec<Region>,
+    violation: Cell<Option<Violation>>,
+    /// Reads go through `&self`, so the callbacks live in a `RefCell`.
+    observers: RefCell<Vec<Observer>>,
+    next_observer: usize,
+    mirrors: usize,
+}
+
+/// A callback for the accesses from `start` to `end` (inclusive) that match `kind`.
+struct Observer {
+    id: usize,
+    start: u16,
+    end: u16,
+    kind: WatchKind,
+    callback: Box<dyn FnMut(MemoryAccess)>,
+}
+
+impl MemoryMapper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maps `backend` over `start..start + len`, which must fit in the address space and be
+    /// free. The region can be read, written and executed until `protect` says otherwise.
+    pub fn map(
+        &mut self,
+        start: u16,
+        len: usize,
+        backend: impl Addressable + 'static,
+    ) -> Result<(), String> {
+        let index = self.free(start, len)?;
+        self.regions.insert(
+            index,
+            Region {
+                start,
+                len,
+                permissions: Permissions::RWX,
+                backend: Box::new(backend),
+                mirror: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Maps one `backend` over `len` addresses at each of `starts`, so every mirror shows the
+    /// same bytes, like a device that decodes only some address lines. Each mirror is a
+    /// region of its own as far as `protect` and `unmap` are concerned. Nothing is mapped
+    /// unless all of them fit.
+    pub fn map_mirrored(
+        &mut self,
+        starts: &[u16],
+        len: usize,
+        backend: impl Addressable + 'static,
+    ) -> Result<(), String> {
+        let mut sorted = starts.to_vec();
+        sorted.sort_unstable();
+        for (i, &start) in sorted.iter().enumerate() {
+            self.free(start, len)?;
+            if let Some(&next) = sorted
+                .get(i + 1)
+                .filter(|&&s| (s as usize) < start as usize + len)
+            {
+                return Err(format!(
+                    "Mirror at 0x{:04X} overlaps the one at 0x{:04X}",
+                    next, start
+                ));
+            }
+        }
+        let shared: Rc<RefCell<Box<dyn Addressable>>> = Rc::new(RefCell::new(Box::new(backend)));
+        self.mirrors += 1;
+        for &start in starts {
+            self.map(start, len, shared.clone())?;
+            let index = self.regions.partition_point(|r| r.start < start);
+            self.regions[index].mirror = Some(self.mirrors);
+        }
+        Ok(())
+    }
+
+    /// Where a region over `start..start + len` goes in `regions`, if it fits and is free.
+    fn free(&self, start: u16, len: usize) -> Result<usize, String> {
+        let end = start as usize + len;
+        if len == 0 || end > 1 << 16 {
+            return Err(format!(
+                "Region of 0x{:X} bytes at 0x{:04X} does not fit in the address space",
+                len, start
+            ));
+        }
+        let index = self.regions.partition_point(|r| r.start < start);
+        let previous = index.checked_sub(1).map(|i| &self.regions[i]);
+        let overlapping = previous
+            .filter(|r| r.start as usize + r.len > start as usize)
+            .or(self.regions.get(index).filter(|r| (r.start as usize) < end));
+        if let Some(region) = overlapping {
+            return Err(format!(
+                "Region 0x{:04X}-0x{:04X} overlaps 0x{:04X}-0x{:04X}",
+                start,
+                end - 1,
+                region.start,
+                region.start as usize + region.len - 1
+            ));
+        }
+        Ok(index)
+    }
+
+    /// Removes the region starting at `start` and returns its backend.
+    pub fn unmap(&mut self, start: u16) -> Option<Box<dyn Addressable>> {
+        let index = self.regions.iter().position(|r| r.start == start)?;
+        Some(self.regions.remove(index).backend)
+    }
+
+    /// Sets the permissions of the region starting at `start`; returns false if there is
+    /// none.
+    pub fn protect(&mut self, start: u16, permissions: Permissions) -> bool {
+        match self.regions.iter_mut().find(|r| r.start == start) {
+            Some(region) => {
+                region.permissions = permissions;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter()
+    }
+
+    /// Takes the first access denied by permissions since the last call.
+    pub fn take_violation(&self) -> Option<Violation> {
+        self.violation.take()
+    }
+
+    fn index(&self, address: u16) -> Option<usize> {
+        let index = self
+            .regions
+            .partition_point(|r| r.start <= address)
+            .checked_sub(1)?;
+        self.regions[index].contains(address).then_some(index)
+    }
+
+    /// Calls `callback` after each successful access from `start` to `end` (inclusive)
+    /// that matches `kind`, with the address as seen on the bus; instruction fetches count as
+    /// reads. Execution carries on as usual. The callback must not access this bus. Returns
+    /// an id for `unobserve`.
+    pub fn observe(
+        &mut self,
+        start: u16,
+        end: u16,
+        kind: WatchKind,
+        callback: impl FnMut(MemoryAccess) + 'static,
+    ) -> usize {
+        self.next_observer += 1;
+        self.observers.get_mut().push(Observer {
+            id: self.next_observer,
+            start,
+            end,
+            kind,
+            callback: Box::new(callback),
+        });
+        self.next_observer
+    }
+
+    pub fn unobserve(&mut self, id: usize) -> bool {
+        let observers = self.observers.get_mut();
+        let count = observers.len();
+        observers.retain(|o| o.id != id);
+        observers.len() != count
+    }
+
+    fn notify(&self, address: u16, value: u8, access: Access) {
+        for observer in self.observers.borrow_mut().iter_mut() {
+            let kind_matches = match observer.kind {
+                WatchKind::Read => access != Access::Write,
+                WatchKind::Write => access == Access::Write,
+                WatchKind::ReadWrite => true,
+            };
+            if kind_matches && (observer.start..=observer.end).contains(&address) {
+                (observer.callback)(MemoryAccess {
+                    address,
+                    value,
+                    access,
+                    pc: None,
+                });
+            }
+        }
+    }
+
+    /// The one region holding all of `len` bytes from `address`, if they are not empty and
+    /// it allows `access`; anything else goes a byte at a time, as does everything while
+    /// there are observers.
+    fn within(&self, address: u16, len: usize, access: Access) -> Option<usize> {
+        if !self.observers.borrow().is_empty() {
+            return None;
+        }
+        let index = self.index(address)?;
+        let region = &self.regions[index];
+        let fits = len > 0 && address as usize + len <= region.start as usize + region.len;
+        (fits && region.permissions.allows(access)).then_some(index)
+    }
+
+    /// The region for an access to `address`, if it is mapped and allows `access`.
+    fn allowed(&self, address: u16, access: Access) -> Option<usize> {
+        let index = self.index(address)?;
+        let region = &self.regions[index];
+        if region.permissions.allows(access) {
+            return Some(index);
+        }
+        if self.violation.get().is_none() {
+            self.violation.set(Some(Violation {
+                address,
+                access,
+                start: region.start,
+                len: region.len,
+                permissions: region.permissions,
+            }));
+        }
+        None
+    }
+}
+
+impl Addressable for MemoryMapper {
+    fn read(&self, address: u16) -> Option<u8> {
+        let region = &self.regions[self.allowed(address, Access::Read)?];
+        let value = region.backend.read(address - region.start)?;
+        self.notify(address, value, Access::Read);
+        Some(value)
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        let written = match self.allowed(address, Access::Write) {
+            Some(index) => {
+                let region = &mut self.regions[index];
+                region.backend.write(address - region.start, value)
+            }
+            None => false,
+        };
+        if written {
+            self.notify(address, value, Access::Write);
+        }
+        written
+    }
+
+    fn read_slice(&self, address: u16, buf: &mut [u8]) -> bool {
+        match self.within(address, buf.len(), Access::Read) {
+            Some(index) => {
+                let region = &self.regions[index];
+                region.backend.read_slice(address - region.start, buf)
+            }
+            None => read_bytes(self, address, buf),
+        }
+    }
+
+    fn write_slice(&mut self, address: u16, bytes: &[u8]) -> bool {
+        match self.within(address, bytes.len(), Access::Write) {
+            Some(index) => {
+                let region = &mut self.regions[index];
+                region.backend.write_slice(address - region.start, bytes)
+            }
+            None => write_bytes(self, address, bytes),
+        }
+    }
+
+    fn fetch(&self, address: u16) -> Option<u8> {
+        let region = &self.regions[self.allowed(address, Access::Execute)?];
+        let value = region.backend.fetch(address - region.start)?;
+        self.notify(address, value, Access::Execute);
+        Some(value)
+    }
+
+    fn peek(&self, address: u16) -> Option<u8> {
+        let region = &self.regions[self.index(address)?];
+        region.backend.peek(address - region.start)
+    }
+
+    fn poke(&mut self, address: u16, value: u8) -> bool {
+        match self.index(address) {
+            Some(index) => {
+                let region = &mut self.regions[index];
+                region.backend.poke(address - region.start, value)
+            }
+            None => false,
+        }
+    }
+}
+
+/// Classic 16 bytes per row hex view with an ASCII column, each row ending in a newline:
+///
+/// ```text
+/// 0200  48 69 00                                         |Hi.|
+/// ```
+pub fn hex_dump(bytes: &[u8], base: u16) -> String {
+    let mut out = String::new();
+    for (row, chunk) in bytes.chunks(16).enumerate() {
+        let address = base.wrapping_add((row * 16) as u16);
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        out.push_str(&format!(
+            "{:04X}  {:47}  |{}|\n",
+            address,
+            hex.join(" "),
+            ascii
+        ));
+    }
+    out
+}
+
+/// Which accesses a watchpoint stops on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// Stops execution when an address from `start` to `end` (inclusive) is accessed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub id: usize,
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+}
+
+/// An access that matched watchpoint `id`. `value` is the byte read or written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub id: usize,
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+/// Watchpoints shared between a `WatchedMemory` and whoever sets them, together with the
+/// hits recorded since they were last cleared.
+#[derive(Debug, Default)]
+pub struct Watchpoints {
+    pub list: Vec<Watchpoint>,
+    pub hits: Vec<WatchHit>,
+    next_id: usize,
+}
+
+impl Watchpoints {
+    pub fn add(&mut self, start: u16, end: u16, kind: WatchKind) -> usize {
+        self.next_id += 1;
+        self.list.push(Watchpoint {
+            id: self.next_id,
+            start,
+            end,
+            kind,
+        });
+        self.next_id
+    }
+
+    pub fn remove(&mut self, id: usize) -> bool {
+        let len = self.list.len();
+        self.list.retain(|w| w.id != id);
+        self.list.len() != len
+    }
+
+    fn record(&mut self, address: u16, value: u8, write: bool) {
+        for watchpoint in &self.list {
+            let kind_matches = match watchpoint.kind {
+                WatchKind::Read => !write,
+                WatchKind::Write => write,
+                WatchKind::ReadWrite => true,
+            };
+            if kind_matches && (watchpoint.start..=watchpoint.end).contains(&address) {
+                self.hits.push(WatchHit {
+                    id: watchpoint.id,
+                    address,
+                    value,
+                    write,
+                });
+            }
+        }
+    }
+}
+
+/// Wraps any memory and records the accesses that match its watchpoints. Reads go through
+/// `&self`, so the watchpoints live in a `RefCell`.
+pub struct WatchedMemory {
+    inner: Box<dyn Addressable>,
+    watchpoints: Rc<RefCell<Watchpoints>>,
+}
+
+impl WatchedMemory {
+    pub fn new(inner: Box<dyn Addressable>, watchpoints: Rc<RefCell<Watchpoints>>) -> Self {
+        Self { inner, watchpoints }
+    }
+
+    pub fn into_inner(self) -> Box<dyn Addressable> {
+        self.inner
+    }
+}
+
+impl Addressable for WatchedMemory {
+    fn read(&self, address: u16) -> Option<u8> {
+        let value = self.inner.read(address)?;
+        self.watchpoints.borrow_mut().record(address, value, false);
+        Some(value)
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        let written = self.inner.write(address, value);
+        if written {
+            self.watchpoints.borrow_mut().record(address, value, true);
+        }
+        written
+    }
+
+    fn fetch(&self, address: u16) -> Option<u8> {
+        let value = self.inner.fetch(address)?;
+        self.watchpoints.borrow_mut().record(address, value, false);
+        Some(value)
+    }
+
+    fn peek(&self, address: u16) -> Option<u8> {
+        self.inner.peek(address)
+    }
+
+    fn poke(&mut self, address: u16, value: u8) -> bool {
+        self.inner.poke(address, value)
+    }
+}
+
+/// A successful access seen by a `TracingMemory`. `pc` is the address of the instruction that
+/// made it, when the tracer was given one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub address: u16,
+    pub value: u8,
+    pub access: Access,
+    pub pc: Option<u16>,
+}
+
+/// Wraps any memory and passes every successful read, write and fetch to a callback, which can
+/// log them, count them or check them.
+pub struct TracingMemory<A: Addressable> {
+    inner: A,
+    sink: RefCell<Box<dyn FnMut(MemoryAccess)>>,
+    pc: Option<Rc<Cell<u16>>>,
+}
+
+impl<A: Addressable> TracingMemory<A> {
+    pub fn new(inner: A, sink: impl FnMut(MemoryAccess) + 'static) -> Self {
+        Self {
+            inner,
+            sink: RefCell::new(Box::new(sink)),
+            pc: None,
+        }
+    }
+
+    /// Tags each access with the value of `pc` at the time, kept up to date by its owner.
+    pub fn with_pc(mut self, pc: Rc<Cell<u16>>) -> Self {
+        self.pc = Some(pc);
+        self
+    }
+
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+
+    fn record(&self, address: u16, value: u8, access: Access) {
+        let pc = self.pc.as_ref().map(|pc| pc.get());
+        (self.sink.borrow_mut())(MemoryAccess {
+            address,
+            value,
+            access,
+            pc,
+        });
+    }
+}
+
+impl<A: Addressable> Addressable for TracingMemory<A> {
+    fn read(&self, address: u16) -> Option<u8> {
+        let value = self.inner.read(address)?;
+        self.record(address, value, Access::Read);
+        Some(value)
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        let written = self.inner.write(address, value);
+        if written {
+            self.record(address, value, Access::Write);
+        }
+        written
+    }
+
+    fn fetch(&self, address: u16) -> Option<u8> {
+        let value = self.inner.fetch(address)?;
+        self.record(address, value, Access::Execute);
+        Some(value)
+    }
+
+    fn peek(&self, address: u16) -> Option<u8> {
+        self.inner.peek(address)
+    }
+
+    fn poke(&mut self, address: u16, value: u8) -> bool {
+        self.inner.poke(address, value)
+    }
+}
+
+/// Wraps any memory and journals the previous value of every byte written, in write order, so
+/// the writes can be undone. Only bytes `peek` can read are journaled, so stores to devices
+/// are left out.
+pub struct JournaledMemory {
+    inner: Box<dyn Addressable>,
+    journal: Rc<RefCell<Vec<(u16, u8)>>>,
+}
+
+impl JournaledMemory {
+    pub fn new(inner: Box<dyn Addressable>, journal: Rc<RefCell<Vec<(u16, u8)>>>) -> Self {
+        Self { inner, journal }
+    }
+
+    pub fn into_inner(self) -> Box<dyn Addressable> {
+        self.inner
+    }
+}
+
+impl Addressable for JournaledMemory {
+    fn read(&self, address: u16) -> Option<u8> {
+        self.inner.read(address)
+    }
+
+    fn fetch(&self, address: u16) -> Option<u8> {
+        self.inner.fetch(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        let old = self.inner.peek(address);
+        let written = self.inner.write(address, value);
+        if let (true, Some(old)) = (written, old) {
+            self.journal.borrow_mut().push((address, old));
+        }
+        written
+    }
+
+    fn peek(&self, address: u16) -> Option<u8> {
+        self.inner.peek(address)
+    }
+
+    fn poke(&mut self, address: u16, value: u8) -> bool {
+        self.inner.poke(address, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rom() {
+        let mut rom = Rom::new(&[1, 2, 3]);
+        assert_eq!(rom.len(), 3);
+        assert_eq!(rom.read2(1), Some(0x0302));
+        assert_eq!(rom.read(3), None);
+        assert!(!rom.write(0, 9));
+        assert!(!rom.fill(0, 2, 0));
+        assert_eq!(rom.read(0), Some(1));
+    }
+
+    #[test]
+    fn test_sparse_memory() {
+        let mut memory = SparseMemory::new();
+        assert_eq!(memory.read(0xFFFF), Some(0));
+        assert!(memory.write(0x1234, 0));
+        assert_eq!(memory.pages(), 0);
+        assert!(memory.write2(0x12FF, 0xBEEF));
+        assert!(memory.write(0xFFFF, 7));
+        assert_eq!(memory.pages(), 3);
+        assert_eq!(memory.read2(0x12FF), Some(0xBEEF));
+        assert_eq!(memory.read(0xFFFF), Some(7));
+        assert_eq!(memory.read(0x1200), Some(0));
+    }
+
+    #[test]
+    fn test_cow_memory() {
+        let image: Arc<[u8]> = (0..=0x1FFu16).map(|i| i as u8).collect();
+        let mut first = CowMemory::new(image.clone());
+        let second = CowMemory::new(image.clone());
+        assert!(first.write(0x1FF, 0xAA));
+        assert!(!first.write(0x200, 0xAA));
+        assert_eq!(first.copied_pages(), 1);
+        assert_eq!(first.read(0x1FF), Some(0xAA));
+        assert_eq!(first.read(0x1FE), Some(0xFE));
+        assert_eq!(first.read(0x0FF), Some(0xFF));
+        assert_eq!(second.read(0x1FF), Some(0xFF));
+        assert_eq!(image[0x1FF], 0xFF);
+    }
+
+    #[test]
+    fn test_shared_memory() {
+        let mut producer = SharedMemory::new(0x10);
+        let consumer = producer.clone();
+        std::thread::spawn(move || assert!(producer.write2(0x0E, 0x1234)))
+            .join()
+            .unwrap();
+        assert_eq!(consumer.read2(0x0E), Some(0x1234));
+        assert_eq!(consumer.read(0x10), None);
+        assert_eq!(consumer.len(), 0x10);
+    }
+
+    #[test]
+    fn test_tracing_memory() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let pc = Rc::new(Cell::new(0x40));
+        let sink = log.clone();
+        let mut memory = TracingMemory::new(LinearMemory::new(0x10), move |access| {
+            sink.borrow_mut().push(access)
+        })
+        .with_pc(pc.clone());
+        assert!(memory.write(2, 7));
+        assert!(!memory.write(0x10, 7));
+        pc.set(0x42);
+        assert_eq!(memory.fetch(2), Some(7));
+        let access = |address, value, access, pc| MemoryAccess {
+            address,
+            value,
+            access,
+            pc: Some(pc),
+        };
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                access(2, 7, Access::Write, 0x40),
+                access(2, 7, Access::Execute, 0x42)
+            ]
+        );
+        assert_eq!(memory.into_inner().read(2), Some(7));
+    }
+
+    #[test]
+    fn test_observers() {
+        let mut bus = MemoryMapper::new();
+        bus.map(0x100, 0x100, LinearMemory::new(0x100)).unwrap();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let sink = log.clone();
+        let id = bus.observe(0x110, 0x11F, WatchKind::Write, move |a| {
+            sink.borrow_mut().push((a.address, a.value))
+        });
+        assert!(bus.write_slice(0x10F, &[1, 2, 3]));
+        assert_eq!(bus.read(0x110), Some(2));
+        assert_eq!(*log.borrow(), vec![(0x110, 2), (0x111, 3)]);
+        assert!(bus.unobserve(id));
+        assert!(!bus.unobserve(id));
+        assert!(bus.write(0x112, 4));
+        assert_eq!(log.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_mirrors() {
+        let mut bus = MemoryMapper::new();
+        bus.map(0, 0x100, LinearMemory::new(0x100)).unwrap();
+        bus.map_mirrored(&[0x1000, 0x1010, 0x1020], 0x10, LinearMemory::new(0x10))
+            .unwrap();
+        assert!(bus.write(0x1013, 7));
+        assert_eq!(bus.read(0x1003), Some(7));
+        assert_eq!(bus.read(0x1023), Some(7));
+        assert_eq!(bus.regions().count(), 4);
+        let mirrors: Vec<Option<usize>> = bus.regions().map(|r| r.mirror).collect();
+        assert_eq!(mirrors, [None, Some(1), Some(1), Some(1)]);
+        assert_eq!(
+            bus.map_mirrored(&[0x2000, 0x2008], 0x10, LinearMemory::new(0x10)),
+            Err("Mirror at 0x2008 overlaps the one at 0x2000".to_string())
+        );
+        assert!(bus
+            .map_mirrored(&[0x3000, 0x0080], 0x10, LinearMemory::new(0x10))
+            .is_err());
+        assert_eq!(bus.regions().count(), 4);
+    }
+
+    #[test]
+    fn test_buffers() {
+        fn poke(mut memory: impl Addressable) {
+            assert!(memory.write2(2, 0xBEEF));
+            assert!(!memory.write(4, 1));
+            assert!(memory.write_slice(0, &[1]));
+            assert!(!memory.write_slice(3, &[1, 2]));
+        }
+        let mut buffer = [0u8; 4];
+        poke(&mut buffer[..]);
+        assert_eq!(buffer, [1, 0, 0xEF, 0xBE]);
+
+        let mut memory = vec![0u8; 0x10];
+        assert!(memory.write(0xF, 7));
+        assert_eq!(memory.read(0xF), Some(7));
+        assert_eq!(memory.read(0x10), None);
+        let mut bus = MemoryMapper::new();
+        bus.map(0x100, memory.len(), memory).unwrap();
+        assert_eq!(bus.read(0x10F), Some(7));
+    }
+
+    #[test]
+    fn test_endianness() {
+        let mut memory = LinearMemory::new(4);
+        assert!(memory.write2_be(0, 0x1234));
+        assert_eq!(memory.read(0), Some(0x12));
+        assert_eq!(memory.read2(0), Some(0x3412));
+        assert_eq!(memory.read2_be(0), Some(0x1234));
+        assert!(!memory.write2_be(3, 1));
+        assert_eq!(memory.read2_be(0xFFFF), None);
+        assert_eq!(Endianness::Big.to_bytes(0x1234), [0x12, 0x34]);
+        assert_eq!(Endianness::Little.from_bytes([0x12, 0x34]), 0x3412);
+    }
+
+    #[test]
+    fn test_top_of_memory() {
+        let mut memory = LinearMemory::new(0x10000);
+        assert!(memory.write(0xFFFF, 1));
+        assert_eq!(memory.read2(0xFFFF), None);
+        assert!(!memory.write2(0xFFFF, 1));
+        assert!(memory.write2(0xFFFE, 0x0201));
+        assert!(!memory.copy(0xFFFF, 0, 2));
+        assert!(!memory.copy(0, 0xFFFF, 2));
+        assert!(memory.copy(0xFFFE, 0, 2));
+        assert_eq!(memory.read2(0), Some(0x0201));
+        assert!(!memory.fill(0xFFFF, 2, 0));
+        assert!(memory.fill(0xFFFE, 2, 0));
+        assert_eq!(memory.read2(0xFFFE), Some(0));
+    }
+
+    #[test]
+    fn test_slices() {
+        let mut memory = LinearMemory::new(0x10);
+        assert!(memory.write_slice(0x0C, &[1, 2, 3, 4]));
+        assert!(!memory.write_slice(0x0E, &[5, 6, 7]));
+        let mut buf = [0; 3];
+        assert!(memory.read_slice(0x0D, &mut buf));
+        assert_eq!(buf, [2, 3, 4]);
+        assert!(!memory.read_slice(0x0E, &mut buf));
+
+        let mut bus = MemoryMapper::new();
+        bus.map(0, 0x10, memory).unwrap();
+        bus.map(0x10, 0x10, LinearMemory::new(0x10)).unwrap();
+        assert!(bus.write_slice(0x0F, &[8, 9]));
+        assert!(bus.read_slice(0x0E, &mut buf));
+        assert_eq!(buf, [3, 8, 9]);
+        assert!(!bus.write_slice(0x1F, &[1, 2]));
+        assert!(!bus.write_slice(0xFFFF, &[1, 2]));
+        bus.protect(0x10, Permissions::R);
+        assert!(!bus.write_slice(0x10, &[1]));
+        assert_eq!(bus.read(0x10), Some(9));
+    }
+
+    #[test]
+    fn test_segmented_memory() {
+        let segments = Rc::new(Cell::new(Segments {
+            code: 0x1234,
+            data: 0xFFFF,
+        }));
+        let mut memory = SegmentedMemory::new(segments.clone());
+        assert!(memory.write(0x20, 7));
+        assert_eq!(memory.bytes()[0x10], 7);
+        memory.bytes_mut()[0x12345] = 9;
+        assert_eq!(memory.fetch(5), Some(9));
+        segments.set(Segments::default());
+        assert_eq!(memory.read(0x10), Some(7));
+    }
+
+    #[test]
+    fn test_banked_memory() {
+        let mut banked = BankedMemory::new(0x10, 3);
+        assert_eq!(banked.len(), 0x11);
+        banked.bank_mut(2).unwrap()[4] = 0xAB;
+        assert!(banked.write(4, 1));
+        assert_eq!(banked.read(0x10), Some(0));
+        assert!(banked.write(0x10, 2));
+        assert_eq!(banked.bank(), 2);
+        assert_eq!(banked.read(4), Some(0xAB));
+        assert!(!banked.write(0x10, 3));
+        assert_eq!(banked.read(0x11), None);
+        assert!(banked.select(0));
+        assert_eq!(banked.read(4), Some(1));
+        assert!(banked.bank_mut(3).is_none());
+    }
+
+    #[test]
+    fn test_mapper() {
+        let mut bus = MemoryMapper::new();
+        bus.map(0, 0x100, LinearMemory::new(0x100)).unwrap();
+        bus.map(0xFF00, 0x100, LinearMemory::new(0x10)).unwrap();
+        assert_eq!(
+            bus.map(0x80, 0x100, LinearMemory::new(0x100)),
+            Err("Region 0x0080-0x017F overlaps 0x0000-0x00FF".to_string())
+        );
+        assert_eq!(
+            bus.map(0xFE00, 0x200, LinearMemory::new(0x100)),
+            Err("Region 0xFE00-0xFFFF overlaps 0xFF00-0xFFFF".to_string())
+        );
+        assert_eq!(
+            bus.map(0xFF00, 0x101, LinearMemory::new(0x100)),
+            Err("Region of 0x101 bytes at 0xFF00 does not fit in the address space".to_string())
+        );
+
+        assert!(bus.write2(0xFF02, 0x1234));
+        assert_eq!(bus.read2(0xFF02), Some(0x1234));
+        // Past the end of the small backend, and between regions.
+        assert_eq!(bus.read(0xFF10), None);
+        assert!(!bus.write(0x100, 1));
+        assert!(bus.write(0xFF, 7));
+
+        let high = bus.unmap(0xFF00).unwrap();
+        assert_eq!(high.read2(2), Some(0x1234));
+        assert_eq!(bus.read(0xFF02), None);
+        let regions: Vec<(u16, usize)> = bus.regions().map(|r| (r.start, r.len)).collect();
+        assert_eq!(regions, vec![(0, 0x100)]);
+        assert!(bus.unmap(0xFF00).is_none());
+    }
+
+    #[test]
+    fn test_permissions() {
+        let mut bus = MemoryMapper::new();
+        bus.map(0x100, 0x100, LinearMemory::new(0x100)).unwrap();
+        assert!(bus.write(0x100, 0x22));
+        assert!(bus.protect(0x100, Permissions::RX));
+        assert!(!bus.protect(0, Permissions::RX));
+        assert!(!bus.write(0x104, 1));
+        assert!(!bus.write(0x105, 1));
+        assert_eq!(
+            bus.take_violation().map(|v| v.to_string()),
+            Some("Cannot write 0x0104: region 0x0100-0x01FF is r-x".to_string())
+        );
+        assert_eq!(bus.take_violation(), None);
+        assert_eq!(bus.fetch(0x100), Some(0x22));
+
+        bus.protect(0x100, Permissions::RW);
+        assert_eq!(bus.read(0x100), Some(0x22));
+        assert_eq!(bus.fetch(0x100), None);
+        assert_eq!(
+            bus.take_violation().map(|v| v.access),
+            Some(Access::Execute)
+        );
+        bus.protect(0x100, Permissions::new(false, false, true));
+        assert_eq!(bus.read(0x100), None);
+        assert_eq!(bus.fetch(0x100), Some(0x22));
+        assert_eq!(Permissions::new(false, false, true).to_string(), "--x");
+    }
 }
@@ -0,0 +1,183 @@
+use crate::memory::{read_bytes, write_bytes, Addressable};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Bytes per page; a 16-bit address is a page number in the high byte and an offset in the
+/// low byte.
+pub const PAGE_SIZE: usize = 256;
+/// Page table entry bit: the page is mapped.
+pub const PAGE_PRESENT: u16 = 0x01;
+/// Page table entry bit: the page may be written.
+pub const PAGE_WRITABLE: u16 = 0x02;
+/// The interrupt raised by a page fault.
+pub const PAGE_FAULT: u8 = 0x0E;
+
+/// An access the page table did not allow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFault {
+    /// The guest address that was accessed.
+    pub address: u16,
+    pub write: bool,
+    /// Whether the page was mapped, i.e. the access broke its protection.
+    pub present: bool,
+}
+
+impl PageFault {
+    /// The cause word pushed for the handler: bit 0 set if the page was present, bit 1 if
+    /// the access was a write.
+    pub fn cause(&self) -> u16 {
+        self.present as u16 | ((self.write as u16) << 1)
+    }
+}
+
+/// Shared between an `Mmu` and the machine, which turns a recorded fault into a trap.
+#[derive(Debug, Default)]
+pub struct MmuState {
+    /// Physical address of the page table, `None` while translation is off.
+    pub page_table: Option<u16>,
+    /// The first fault since it was last taken.
+    pub fault: Option<PageFault>,
+}
+
+/// Wraps physical memory and translates every access through a page table kept in it: 256
+/// little-endian words, one per page, holding the physical page number in the high byte and
+/// `PAGE_PRESENT` and `PAGE_WRITABLE` in the low one. An access to a page that is not present,
+/// or a write to one that is not writable, fails and is recorded in the shared `MmuState`.
+pub struct Mmu {
+    inner: Box<dyn Addressable>,
+    state: Rc<RefCell<MmuState>>,
+}
+
+impl Mmu {
+    pub fn new(inner: Box<dyn Addressable>, state: Rc<RefCell<MmuState>>) -> Self {
+        Self { inner, state }
+    }
+
+    pub fn into_inner(self) -> Box<dyn Addressable> {
+        self.inner
+    }
+
+    /// The physical address for `address`, recording a fault if there is none.
+    fn translate(&self, address: u16, write: bool) -> Option<u16> {
+        match self.walk(address, write, |at| self.inner.read2(at)) {
+            Ok(physical) => Some(physical),
+            Err(fault) => {
+                self.state.borrow_mut().fault.get_or_insert(fault);
+                None
+            }
+        }
+    }
+
+    /// The physical address for `address`, or the fault an access would take, reading page
+    /// table entries with `entry`.
+    fn walk(
+        &self,
+        address: u16,
+        write: bool,
+        entry: impl FnOnce(u16) -> Option<u16>,
+    ) -> Result<u16, PageFault> {
+        let Some(table) = self.state.borrow().page_table else {
+            return Ok(address);
+        };
+        let page = address / PAGE_SIZE as u16;
+        let entry = entry(table.wrapping_add(page * 2)).unwrap_or(0);
+        let present = entry & PAGE_PRESENT != 0;
+        if present && (!write || entry & PAGE_WRITABLE != 0) {
+            return Ok((entry & 0xFF00) | (address % PAGE_SIZE as u16));
+        }
+        Err(PageFault {
+            address,
+            write,
+            present,
+        })
+    }
+
+    /// `translate` for `peek` and `poke`: the page table is peeked and faults are not kept.
+    fn translate_quietly(&self, address: u16, write: bool) -> Option<u16> {
+        let entry = |at: u16| {
+            let low = self.inner.peek(at)?;
+            let high = self.inner.peek(at.checked_add(1)?)?;
+            Some(u16::from_le_bytes([low, high]))
+        };
+        self.walk(address, write, entry).ok()
+    }
+}
+
+impl Addressable for Mmu {
+    fn read(&self, address: u16) -> Option<u8> {
+        self.inner.read(self.translate(address, false)?)
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        match self.translate(address, true) {
+            Some(physical) => self.inner.write(physical, value),
+            None => false,
+        }
+    }
+
+    fn read_slice(&self, address: u16, buf: &mut [u8]) -> bool {
+        if self.state.borrow().page_table.is_some() {
+            return read_bytes(self, address, buf);
+        }
+        self.inner.read_slice(address, buf)
+    }
+
+    fn write_slice(&mut self, address: u16, bytes: &[u8]) -> bool {
+        if self.state.borrow().page_table.is_some() {
+            return write_bytes(self, address, bytes);
+        }
+        self.inner.write_slice(address, bytes)
+    }
+
+    fn fetch(&self, address: u16) -> Option<u8> {
+        self.inner.fetch(self.translate(address, false)?)
+    }
+
+    fn peek(&self, address: u16) -> Option<u8> {
+        self.inner.peek(self.translate_quietly(address, false)?)
+    }
+
+    fn poke(&mut self, address: u16, value: u8) -> bool {
+        match self.translate_quietly(address, true) {
+            Some(physical) => self.inner.poke(physical, value),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::LinearMemory;
+
+    #[test]
+    fn test_translate() {
+        let mut physical = LinearMemory::new(0x1000);
+        // Page 0 is identity mapped read-only, page 1 maps writable to 0x0800.
+        physical.write2(0x0F00, 0x0001);
+        physical.write2(0x0F02, 0x0803);
+        physical.write(0x0805, 0x42);
+        let state = Rc::new(RefCell::new(MmuState::default()));
+        let mut mmu = Mmu::new(Box::new(physical), state.clone());
+        assert_eq!(mmu.read2(0x0F02), Some(0x0803));
+
+        state.borrow_mut().page_table = Some(0x0F00);
+        assert_eq!(mmu.read(0x0105), Some(0x42));
+        assert!(mmu.write(0x01FF, 7));
+        assert_eq!(mmu.read2(0x0F02), None);
+        assert_eq!(
+            state.borrow_mut().fault.take(),
+            Some(PageFault {
+                address: 0x0F02,
+                write: false,
+                present: false,
+            })
+        );
+        assert!(!mmu.write(0x0010, 1));
+        let fault = state.borrow_mut().fault.take().unwrap();
+        assert_eq!((fault.address, fault.cause()), (0x0010, 3));
+
+        state.borrow_mut().page_table = None;
+        assert_eq!(mmu.read(0x08FF), Some(7));
+    }
+}
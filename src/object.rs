@@ -0,0 +1,545 @@
+use crate::symbols::SymbolTable;
+use std::fmt;
+use std::path::Path;
+
+/// Magic bytes at the start of every object file.
+pub const OBJECT_MAGIC: [u8; 4] = *b"TVMO";
+/// Version 2 added the CRC-32.
+pub const OBJECT_VERSION: u16 = 2;
+/// Magic bytes at the start of a relocatable object, the linker's input.
+pub const RELOCATABLE_MAGIC: [u8; 4] = *b"TVMR";
+pub const RELOCATABLE_VERSION: u16 = 1;
+
+/// A block of bytes to be loaded at `address`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize, serde::Deserialize))]
+pub struct Segment {
+    pub address: u16,
+    #[cfg_attr(feature = "json", serde(with = "crate::json::hex_bytes"))]
+    pub bytes: Vec<u8>,
+}
+
+/// A program together with where it goes in memory and where execution starts.
+///
+/// Layout, all numbers little endian:
+///
+/// ```text
+/// magic "TVMO" | version: u16 | crc: u32 | entry: u16 | segment count: u16
+/// per segment:   address: u16 | length: u16 | length bytes
+/// ```
+///
+/// `crc` is the CRC-32 (as used by zip and PNG) of everything after it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectFile {
+    pub entry: u16,
+    pub segments: Vec<Segment>,
+}
+
+impl ObjectFile {
+    /// Whether `bytes` start with the object file magic.
+    pub fn is_object(bytes: &[u8]) -> bool {
+        bytes.starts_with(&OBJECT_MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = self.entry.to_le_bytes().to_vec();
+        body.extend_from_slice(&(self.segments.len() as u16).to_le_bytes());
+        for segment in &self.segments {
+            body.extend_from_slice(&segment.address.to_le_bytes());
+            body.extend_from_slice(&(segment.bytes.len() as u16).to_le_bytes());
+            body.extend_from_slice(&segment.bytes);
+        }
+        let mut bytes = OBJECT_MAGIC.to_vec();
+        bytes.extend_from_slice(&OBJECT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&crc32(&body).to_le_bytes());
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ObjectFile, String> {
+        if !Self::is_object(bytes) {
+            return Err("Not a TinyVM object file".to_string());
+        }
+        let mut reader = Reader {
+            bytes,
+            offset: OBJECT_MAGIC.len(),
+            what: "object file",
+        };
+        let version = reader.u16()?;
+        if version != OBJECT_VERSION {
+            return Err(format!(
+                "Unsupported object file version {} (expected version {})",
+                version, OBJECT_VERSION
+            ));
+        }
+        let crc = reader.take(4)?;
+        let crc = u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]);
+        let actual = crc32(&bytes[reader.offset..]);
+        if crc != actual {
+            return Err(format!(
+                "Object file is corrupted: CRC 0x{:08X} does not match its contents (0x{:08X})",
+                crc, actual
+            ));
+        }
+        let entry = reader.u16()?;
+        let count = reader.u16()?;
+        let mut segments = Vec::new();
+        for _ in 0..count {
+            let address = reader.u16()?;
+            let len = reader.u16()? as usize;
+            let bytes = reader.take(len)?.to_vec();
+            if address as usize + len > 0x10000 {
+                return Err(format!(
+                    "Segment at 0x{:X} of {} bytes runs past the end of memory",
+                    address, len
+                ));
+            }
+            segments.push(Segment { address, bytes });
+        }
+        if reader.offset != bytes.len() {
+            return Err("Trailing bytes after the last segment".to_string());
+        }
+        Ok(ObjectFile { entry, segments })
+    }
+}
+
+/// The file formats a program image can be stored in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Raw,
+    Object,
+    IntelHex,
+    SRecord,
+    Json,
+}
+
+impl ImageFormat {
+    /// Parses a format name as given on the command line: `raw`, `tvm`, `ihex`, `srec` or
+    /// `json`.
+    pub fn from_name(name: &str) -> Result<ImageFormat, String> {
+        match name {
+            "raw" | "bin" => Ok(ImageFormat::Raw),
+            "tvm" | "object" => Ok(ImageFormat::Object),
+            "ihex" | "hex" => Ok(ImageFormat::IntelHex),
+            "srec" | "s19" => Ok(ImageFormat::SRecord),
+            "json" => Ok(ImageFormat::Json),
+            _ => Err(format!("Unknown image format '{}'", name)),
+        }
+    }
+
+    /// The format implied by a file extension, if it names one.
+    pub fn from_extension(path: &Path) -> Option<ImageFormat> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "bin" => Some(ImageFormat::Raw),
+            "tvm" => Some(ImageFormat::Object),
+            "hex" | "ihex" => Some(ImageFormat::IntelHex),
+            "s19" | "srec" | "mot" => Some(ImageFormat::SRecord),
+            "json" => Some(ImageFormat::Json),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format from the contents of a file, falling back to a raw image.
+    pub fn detect(bytes: &[u8]) -> ImageFormat {
+        if ObjectFile::is_object(bytes) {
+            ImageFormat::Object
+        } else if ObjectFile::is_intel_hex(bytes) {
+            ImageFormat::IntelHex
+        } else if ObjectFile::is_srecord(bytes) {
+            ImageFormat::SRecord
+        } else if bytes.trim_ascii_start().starts_with(b"{") {
+            ImageFormat::Json
+        } else {
+            ImageFormat::Raw
+        }
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ImageFormat::Raw => "raw image",
+            ImageFormat::Object => "object file",
+            ImageFormat::IntelHex => "Intel HEX",
+            ImageFormat::SRecord => "S-record",
+            ImageFormat::Json => "JSON program",
+        })
+    }
+}
+
+impl ObjectFile {
+    /// Reads an image stored in `format`. A raw image becomes a single segment at `base`, which
+    /// is also its entry point. Symbols in a JSON program are dropped; use
+    /// `from_image_with_symbols` to keep them.
+    pub fn from_image(bytes: &[u8], format: ImageFormat, base: u16) -> Result<ObjectFile, String> {
+        let text = || String::from_utf8_lossy(bytes);
+        match format {
+            ImageFormat::Raw => {
+                if base as usize + bytes.len() > 0x10000 {
+                    return Err(format!(
+                        "Program of {} bytes does not fit in memory at 0x{:X}",
+                        bytes.len(),
+                        base
+                    ));
+                }
+                Ok(ObjectFile {
+                    entry: base,
+                    segments: vec![Segment {
+                        address: base,
+                        bytes: bytes.to_vec(),
+                    }],
+                })
+            }
+            ImageFormat::Object => ObjectFile::from_bytes(bytes),
+            ImageFormat::IntelHex => ObjectFile::from_intel_hex(&text()),
+            ImageFormat::SRecord => ObjectFile::from_srecord(&text()),
+            #[cfg(feature = "json")]
+            ImageFormat::Json => Ok(ObjectFile::from_json(&text())?.0),
+            #[cfg(not(feature = "json"))]
+            ImageFormat::Json => Err("JSON programs need the json feature".to_string()),
+        }
+    }
+
+    /// Reads an image like `from_image`, along with the symbols it carries; only a JSON
+    /// program has any.
+    pub fn from_image_with_symbols(
+        bytes: &[u8],
+        format: ImageFormat,
+        base: u16,
+    ) -> Result<(ObjectFile, SymbolTable), String> {
+        match format {
+            #[cfg(feature = "json")]
+            ImageFormat::Json => ObjectFile::from_json(&String::from_utf8_lossy(bytes)),
+            _ => Ok((
+                ObjectFile::from_image(bytes, format, base)?,
+                SymbolTable::new(),
+            )),
+        }
+    }
+}
+
+/// A 16-bit word at `offset` in a module's code that must be patched at link time with the
+/// address of `symbol` (or of the module itself when `symbol` is `None`) plus `addend`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub offset: u16,
+    pub symbol: Option<String>,
+    pub addend: u16,
+}
+
+/// One assembled module before linking: code assembled as if loaded at address 0, the
+/// symbols it exports with `.global`, and the words that depend on where it ends up.
+///
+/// Layout, all numbers little endian, names prefixed by their length as a byte:
+///
+/// ```text
+/// magic "TVMR" | version: u16 | has entry: u8 | entry: u16 | code length: u16 | code
+/// symbol count: u16     | per symbol:     offset: u16 | name
+/// relocation count: u16 | per relocation: offset: u16 | addend: u16 | symbol name or ""
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelocatableObject {
+    pub code: Vec<u8>,
+    pub entry: Option<u16>,
+    pub symbols: Vec<(String, u16)>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl RelocatableObject {
+    pub fn is_relocatable(bytes: &[u8]) -> bool {
+        bytes.starts_with(&RELOCATABLE_MAGIC)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = RELOCATABLE_MAGIC.to_vec();
+        bytes.extend_from_slice(&RELOCATABLE_VERSION.to_le_bytes());
+        bytes.push(self.entry.is_some() as u8);
+        bytes.extend_from_slice(&self.entry.unwrap_or(0).to_le_bytes());
+        bytes.extend_from_slice(&(self.code.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&self.code);
+        bytes.extend_from_slice(&(self.symbols.len() as u16).to_le_bytes());
+        for (name, offset) in &self.symbols {
+            bytes.extend_from_slice(&offset.to_le_bytes());
+            push_name(&mut bytes, name);
+        }
+        bytes.extend_from_slice(&(self.relocations.len() as u16).to_le_bytes());
+        for relocation in &self.relocations {
+            bytes.extend_from_slice(&relocation.offset.to_le_bytes());
+            bytes.extend_from_slice(&relocation.addend.to_le_bytes());
+            push_name(&mut bytes, relocation.symbol.as_deref().unwrap_or(""));
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<RelocatableObject, String> {
+        if !Self::is_relocatable(bytes) {
+            return Err("Not a TinyVM relocatable object".to_string());
+        }
+        let mut reader = Reader {
+            bytes,
+            offset: RELOCATABLE_MAGIC.len(),
+            what: "object file",
+        };
+        let version = reader.u16()?;
+        if version != RELOCATABLE_VERSION {
+            return Err(format!(
+                "Unsupported object file version {} (expected version {})",
+                version, RELOCATABLE_VERSION
+            ));
+        }
+        let has_entry = reader.take(1)?[0] != 0;
+        let entry = reader.u16()?;
+        let len = reader.u16()? as usize;
+        let code = reader.take(len)?.to_vec();
+        let mut symbols = Vec::new();
+        for _ in 0..reader.u16()? {
+            let offset = reader.u16()?;
+            symbols.push((reader.name()?, offset));
+        }
+        let mut relocations = Vec::new();
+        for _ in 0..reader.u16()? {
+            let offset = reader.u16()?;
+            let addend = reader.u16()?;
+            let name = reader.name()?;
+            if offset as usize + 2 > code.len() {
+                return Err(format!("Relocation at 0x{:X} is outside the code", offset));
+            }
+            relocations.push(Relocation {
+                offset,
+                symbol: (!name.is_empty()).then_some(name),
+                addend,
+            });
+        }
+        if reader.offset != bytes.len() {
+            return Err("Trailing bytes after the last relocation".to_string());
+        }
+        Ok(RelocatableObject {
+            code,
+            entry: has_entry.then_some(entry),
+            symbols,
+            relocations,
+        })
+    }
+}
+
+/// CRC-32 with the reflected polynomial 0xEDB88320, computed bit by bit.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn push_name(bytes: &mut Vec<u8>, name: &str) {
+    bytes.push(name.len() as u8);
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+/// Little endian cursor over a binary file; `what` names the file in the truncation error.
+pub(crate) struct Reader<'a> {
+    pub(crate) bytes: &'a [u8],
+    pub(crate) offset: usize,
+    pub(crate) what: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let slice = self
+            .bytes
+            .get(self.offset..self.offset + n)
+            .ok_or(format!("Truncated {}", self.what))?;
+        self.offset += n;
+        Ok(slice)
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let len = self.take(1)?[0] as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| "Invalid symbol name".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object() -> ObjectFile {
+        ObjectFile {
+            entry: 0x102,
+            segments: vec![
+                Segment {
+                    address: 0x100,
+                    bytes: vec![1, 2, 3, 4],
+                },
+                Segment {
+                    address: 0x1000,
+                    bytes: vec![0xaa],
+                },
+            ],
+        }
+    }
+
+    /// An object file around `body` with a valid header.
+    fn with_header(body: &[u8]) -> Vec<u8> {
+        let mut bytes = b"TVMO\x02\x00".to_vec();
+        bytes.extend_from_slice(&crc32(body).to_le_bytes());
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = object().to_bytes();
+        assert_eq!(&bytes[..6], b"TVMO\x02\x00");
+        assert_eq!(&bytes[6..10], &crc32(&bytes[10..]).to_le_bytes());
+        assert_eq!(&bytes[10..14], &[0x02, 0x01, 2, 0]);
+        assert_eq!(&bytes[14..22], &[0x00, 0x01, 4, 0, 1, 2, 3, 4]);
+        assert_eq!(ObjectFile::from_bytes(&bytes), Ok(object()));
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_errors() {
+        let bytes = object().to_bytes();
+        assert_eq!(
+            ObjectFile::from_bytes(&[1, 2, 3]),
+            Err("Not a TinyVM object file".to_string())
+        );
+        assert_eq!(
+            ObjectFile::from_bytes(&bytes[..8]),
+            Err("Truncated object file".to_string())
+        );
+        assert_eq!(
+            ObjectFile::from_bytes(&with_header(&bytes[10..bytes.len() - 1])),
+            Err("Truncated object file".to_string())
+        );
+        let mut extra = bytes[10..].to_vec();
+        extra.push(0);
+        assert_eq!(
+            ObjectFile::from_bytes(&with_header(&extra)),
+            Err("Trailing bytes after the last segment".to_string())
+        );
+        let mut corrupted = bytes.clone();
+        corrupted[20] ^= 0x40;
+        assert_eq!(
+            ObjectFile::from_bytes(&corrupted),
+            Err(format!(
+                "Object file is corrupted: CRC 0x{:08X} does not match its contents (0x{:08X})",
+                crc32(&bytes[10..]),
+                crc32(&corrupted[10..])
+            ))
+        );
+        let mut version = bytes;
+        version[4] = 1;
+        assert_eq!(
+            ObjectFile::from_bytes(&version),
+            Err("Unsupported object file version 1 (expected version 2)".to_string())
+        );
+        let past_end = with_header(&[0, 0, 1, 0, 0xff, 0xff, 2, 0, 0, 0]);
+        assert_eq!(
+            ObjectFile::from_bytes(&past_end),
+            Err("Segment at 0xFFFF of 2 bytes runs past the end of memory".to_string())
+        );
+    }
+
+    #[test]
+    fn test_image_format() {
+        assert_eq!(ImageFormat::from_name("srec"), Ok(ImageFormat::SRecord));
+        assert_eq!(
+            ImageFormat::from_name("elf"),
+            Err("Unknown image format 'elf'".to_string())
+        );
+        assert_eq!(
+            ImageFormat::from_extension(Path::new("rom.S19")),
+            Some(ImageFormat::SRecord)
+        );
+        assert_eq!(
+            ImageFormat::from_extension(Path::new("rom.hex")),
+            Some(ImageFormat::IntelHex)
+        );
+        assert_eq!(ImageFormat::from_extension(Path::new("rom")), None);
+
+        let hex = object().to_intel_hex();
+        let srec = object().to_srecord();
+        assert_eq!(
+            ImageFormat::detect(&object().to_bytes()),
+            ImageFormat::Object
+        );
+        assert_eq!(ImageFormat::detect(hex.as_bytes()), ImageFormat::IntelHex);
+        assert_eq!(ImageFormat::detect(srec.as_bytes()), ImageFormat::SRecord);
+        assert_eq!(ImageFormat::detect(&[0x01, 0x02]), ImageFormat::Raw);
+
+        for (bytes, format) in [
+            (hex.as_bytes(), ImageFormat::IntelHex),
+            (srec.as_bytes(), ImageFormat::SRecord),
+        ] {
+            assert_eq!(ObjectFile::from_image(bytes, format, 0), Ok(object()));
+        }
+        assert_eq!(
+            ObjectFile::from_image(&[1, 2], ImageFormat::Raw, 0x10).map(|o| o.segments),
+            Ok(vec![Segment {
+                address: 0x10,
+                bytes: vec![1, 2],
+            }])
+        );
+        assert_eq!(
+            ObjectFile::from_image(&[1, 2], ImageFormat::Raw, 0xffff),
+            Err("Program of 2 bytes does not fit in memory at 0xFFFF".to_string())
+        );
+    }
+
+    #[test]
+    fn test_relocatable_round_trip() {
+        let object = RelocatableObject {
+            code: vec![0x29, 0x00, 0x00, 0x00, 0x13, 0x00, 0x04, 0x00],
+            entry: Some(4),
+            symbols: vec![("main".to_string(), 4)],
+            relocations: vec![
+                Relocation {
+                    offset: 2,
+                    symbol: Some("print".to_string()),
+                    addend: 0,
+                },
+                Relocation {
+                    offset: 6,
+                    symbol: None,
+                    addend: 4,
+                },
+            ],
+        };
+        let bytes = object.to_bytes();
+        assert_eq!(&bytes[..9], &[b'T', b'V', b'M', b'R', 1, 0, 1, 4, 0]);
+        assert_eq!(RelocatableObject::from_bytes(&bytes), Ok(object));
+        assert_eq!(
+            RelocatableObject::from_bytes(&bytes[..bytes.len() - 2]),
+            Err("Truncated object file".to_string())
+        );
+        assert_eq!(
+            RelocatableObject::from_bytes(b"TVMO"),
+            Err("Not a TinyVM relocatable object".to_string())
+        );
+    }
+}
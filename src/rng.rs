@@ -0,0 +1,122 @@
+use crate::memory::Addressable;
+use std::cell::Cell;
+
+/// Where `vm` maps its `Rng`.
+pub const RNG_ADDRESS: u16 = 0xFF30;
+/// Offset of the data register, a word: every byte read from it is a new random one.
+pub const RNG_DATA: u16 = 0;
+/// Offset of the seed, a little-endian word: writing either byte starts the sequence over
+/// from the new seed.
+pub const RNG_SEED: u16 = 2;
+
+/// A pseudo-random number generator (SplitMix64). The same seed gives the same bytes every
+/// run, so programs that use it can be tested.
+pub struct Rng {
+    state: Cell<u64>,
+    /// The seed as guest code last wrote it.
+    seed: u16,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Cell::new(seed),
+            seed: seed as u16,
+        }
+    }
+
+    /// Starts the sequence over from `seed`.
+    pub fn reseed(&mut self, seed: u64) {
+        self.state.set(seed);
+        self.seed = seed as u16;
+    }
+
+    pub fn next_u64(&self) -> u64 {
+        let state = self.state.get().wrapping_add(0x9E37_79B9_7F4A_7C15);
+        self.state.set(state);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl Addressable for Rng {
+    fn read(&self, address: u16) -> Option<u8> {
+        match address {
+            0..=1 => Some((self.next_u64() >> 56) as u8),
+            2..=3 => Some(self.seed.to_le_bytes()[address as usize - 2]),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        if !(2..=3).contains(&address) {
+            return false;
+        }
+        let mut bytes = self.seed.to_le_bytes();
+        bytes[address as usize - 2] = value;
+        self.reseed(u16::from_le_bytes(bytes).into());
+        true
+    }
+
+    fn peek(&self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    fn poke(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::vm::{Machine, Register};
+
+    #[test]
+    fn test_rng() {
+        let rng = Rng::new(42);
+        let first: Vec<_> = (0..8).map(|_| rng.read(RNG_DATA)).collect();
+        let again = Rng::new(42);
+        assert_eq!(
+            (0..8).map(|_| again.read(RNG_DATA)).collect::<Vec<_>>(),
+            first
+        );
+        assert_ne!(Rng::new(43).next_u64(), Rng::new(42).next_u64());
+        assert_eq!(rng.read(4), None);
+
+        let mut rng = rng;
+        assert!(rng.write2(RNG_SEED, 42));
+        assert_eq!(rng.read2(RNG_SEED), Some(42));
+        assert_eq!(rng.read(RNG_DATA), first[0]);
+        assert!(!rng.write(RNG_DATA, 0));
+    }
+
+    #[test]
+    fn test_seeded_run() {
+        let program = assemble(
+            "
+            LDIW M, 0xFF32
+            LDIW A, 7
+            STORE M, A          ; seed
+            LDIW M, 0xFF30
+            LOAD B, M
+            LOAD C, M
+            HALT
+            ",
+        )
+        .unwrap();
+        let run = || {
+            let mut m = Machine::new();
+            m.map(RNG_ADDRESS, 4, Rng::new(1)).unwrap();
+            m.load_program(&program, 0).unwrap();
+            m.run().unwrap();
+            (m.get_register(Register::B), m.get_register(Register::C))
+        };
+        let (b, c) = run();
+        assert_eq!(run(), (b, c));
+        assert_ne!(b, c);
+    }
+}
@@ -0,0 +1,248 @@
+use crate::object::{ObjectFile, Segment};
+
+/// Data bytes per S1 record when writing.
+const RECORD_SIZE: usize = 16;
+/// Module name stored in the S0 header record.
+const HEADER: &[u8] = b"tinyvm";
+
+impl ObjectFile {
+    /// Whether `bytes` look like Motorola S-records, i.e. start with an `S0`-`S9` record.
+    pub fn is_srecord(bytes: &[u8]) -> bool {
+        match bytes.trim_ascii_start() {
+            [b'S', digit, ..] => digit.is_ascii_digit(),
+            _ => false,
+        }
+    }
+
+    /// Writes the segments as S19: an S0 header, S1 data records, an S5 record count and an S9
+    /// record holding the entry point.
+    pub fn to_srecord(&self) -> String {
+        let mut out = record(0, 0, HEADER);
+        let mut count = 0;
+        for segment in &self.segments {
+            for (i, chunk) in segment.bytes.chunks(RECORD_SIZE).enumerate() {
+                let address = segment.address as usize + i * RECORD_SIZE;
+                out.push_str(&record(1, address as u32, chunk));
+                count += 1;
+            }
+        }
+        if count <= 0xffff {
+            out.push_str(&record(5, count, &[]));
+        }
+        out.push_str(&record(9, self.entry as u32, &[]));
+        out
+    }
+
+    /// Reads S-records. S2/S3 and S7/S8 records with wider addresses are accepted as long as
+    /// every address fits in 16 bits. Data records that follow on from each other are merged
+    /// into one segment.
+    pub fn from_srecord(text: &str) -> Result<ObjectFile, String> {
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut entry = None;
+        let mut data_records = 0;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: String| format!("line {}: {}", i + 1, message);
+            if entry.is_some() {
+                return Err(error("Record after the termination record".to_string()));
+            }
+            let (kind, address, data) = parse_record(line).map_err(error)?;
+            match kind {
+                0 => {}
+                1..=3 => {
+                    let address = address as usize;
+                    if address + data.len() > 0x10000 {
+                        return Err(error(format!(
+                            "Address 0x{:X} is outside the 16-bit address space",
+                            address + data.len() - 1
+                        )));
+                    }
+                    data_records += 1;
+                    match segments.last_mut() {
+                        Some(last) if last.address as usize + last.bytes.len() == address => {
+                            last.bytes.extend_from_slice(&data)
+                        }
+                        _ => segments.push(Segment {
+                            address: address as u16,
+                            bytes: data,
+                        }),
+                    }
+                }
+                5 | 6 => {
+                    if address != data_records {
+                        return Err(error(format!(
+                            "Record count {} does not match the {} data records",
+                            address, data_records
+                        )));
+                    }
+                }
+                7..=9 => {
+                    let address = u16::try_from(address).map_err(|_| {
+                        error(format!(
+                            "Address 0x{:X} is outside the 16-bit address space",
+                            address
+                        ))
+                    })?;
+                    entry = Some(address);
+                }
+                _ => return Err(error(format!("Unsupported record type S{}", kind))),
+            }
+        }
+        let entry = entry.ok_or("Missing termination record")?;
+        Ok(ObjectFile { entry, segments })
+    }
+}
+
+/// Width in bytes of the address field of each record type.
+fn address_size(kind: u8) -> Option<usize> {
+    match kind {
+        0 | 1 | 5 | 9 => Some(2),
+        2 | 6 | 8 => Some(3),
+        3 | 7 => Some(4),
+        _ => None,
+    }
+}
+
+fn record(kind: u8, address: u32, data: &[u8]) -> String {
+    let size = address_size(kind).unwrap();
+    let mut bytes = vec![(size + data.len() + 1) as u8];
+    bytes.extend_from_slice(&address.to_be_bytes()[4 - size..]);
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    bytes.push(!sum);
+    let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    format!("S{}{}\n", kind, hex)
+}
+
+/// Splits a record into its type, address and data, checking its length and checksum.
+fn parse_record(line: &str) -> Result<(u8, u32, Vec<u8>), String> {
+    let hex = line
+        .strip_prefix('S')
+        .ok_or("Expected 'S' at the start of a record")?;
+    let kind = hex
+        .bytes()
+        .next()
+        .filter(u8::is_ascii_digit)
+        .ok_or("Missing record type")?
+        - b'0';
+    let size = address_size(kind).ok_or(format!("Unsupported record type S{}", kind))?;
+    let hex = &hex[1..];
+    if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err("Invalid hex digits in record".to_string());
+    }
+    let bytes: Vec<u8> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect();
+    if bytes.len() < size + 2 || bytes.len() != bytes[0] as usize + 1 {
+        return Err("Record length does not match its byte count".to_string());
+    }
+    let (checksum, body) = bytes.split_last().unwrap();
+    let expected = !body.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    if *checksum != expected {
+        return Err(format!(
+            "Checksum mismatch (expected 0x{:02X}, found 0x{:02X})",
+            expected, checksum
+        ));
+    }
+    let address = body[1..=size]
+        .iter()
+        .fold(0u32, |address, b| address << 8 | *b as u32);
+    Ok((kind, address, body[size + 1..].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let object = ObjectFile {
+            entry: 0x102,
+            segments: vec![
+                Segment {
+                    address: 0x100,
+                    bytes: (0..20).collect(),
+                },
+                Segment {
+                    address: 0xfffe,
+                    bytes: vec![0xaa, 0xbb],
+                },
+            ],
+        };
+        let text = object.to_srecord();
+        assert_eq!(
+            text,
+            "S009000074696E79766D4F\n\
+             S1130100000102030405060708090A0B0C0D0E0F73\n\
+             S107011010111213A1\n\
+             S105FFFEAABB98\n\
+             S5030003F9\n\
+             S9030102F9\n"
+        );
+        assert!(ObjectFile::is_srecord(text.as_bytes()));
+        assert_eq!(ObjectFile::from_srecord(&text), Ok(object));
+    }
+
+    #[test]
+    fn test_read() {
+        let text = "
+            S20700103002337A09
+            S804000000FB
+        ";
+        assert_eq!(
+            ObjectFile::from_srecord(text),
+            Ok(ObjectFile {
+                entry: 0,
+                segments: vec![Segment {
+                    address: 0x1030,
+                    bytes: vec![0x02, 0x33, 0x7a],
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text| ObjectFile::from_srecord(text).map(|_| ());
+        assert_eq!(
+            error(":0100200001DE"),
+            Err("line 1: Expected 'S' at the start of a record".to_string())
+        );
+        assert_eq!(
+            error("S4030000FC"),
+            Err("line 1: Unsupported record type S4".to_string())
+        );
+        assert_eq!(
+            error("S1040020XXDE"),
+            Err("line 1: Invalid hex digits in record".to_string())
+        );
+        assert_eq!(
+            error("S105002001D9"),
+            Err("line 1: Record length does not match its byte count".to_string())
+        );
+        assert_eq!(
+            error("S104002001DB"),
+            Err("line 1: Checksum mismatch (expected 0xDA, found 0xDB)".to_string())
+        );
+        assert_eq!(
+            error("S104002001DA"),
+            Err("Missing termination record".to_string())
+        );
+        assert_eq!(
+            error("S9030000FC\nS104002001DA"),
+            Err("line 2: Record after the termination record".to_string())
+        );
+        assert_eq!(
+            error("S104002001DA\nS5030002FA"),
+            Err("line 2: Record count 2 does not match the 1 data records".to_string())
+        );
+        assert_eq!(
+            error("S3090001002001020304CB"),
+            Err("line 1: Address 0x10023 is outside the 16-bit address space".to_string())
+        );
+    }
+}
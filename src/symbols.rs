@@ -0,0 +1,160 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize, serde::Deserialize))]
+pub enum SymbolKind {
+    #[default]
+    #[cfg_attr(feature = "json", serde(rename = "label"))]
+    Label,
+    #[cfg_attr(feature = "json", serde(rename = "const"))]
+    Constant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize, serde::Deserialize))]
+pub struct Symbol {
+    pub name: String,
+    pub value: u16,
+    #[cfg_attr(feature = "json", serde(default))]
+    pub kind: SymbolKind,
+}
+
+/// Names produced by the assembler, kept sorted by value so addresses can be looked up.
+///
+/// The text form has one `label 0x0010 loop` or `const 0xFF00 UART` entry per line; it is
+/// what `to_string` writes and `parse` reads back.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, name: &str, value: u16, kind: SymbolKind) {
+        let symbol = Symbol {
+            name: name.to_string(),
+            value,
+            kind,
+        };
+        let index = self
+            .symbols
+            .partition_point(|s| (s.value, &s.name) < (value, &symbol.name));
+        self.symbols.insert(index, symbol);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    /// The label defined at exactly `address`, if any. Constants are never returned.
+    pub fn label_at(&self, address: u16) -> Option<&str> {
+        self.symbols
+            .iter()
+            .find(|s| s.kind == SymbolKind::Label && s.value == address)
+            .map(|s| s.name.as_str())
+    }
+
+    /// The closest non-local label at or below `address`, i.e. the routine it is likely in.
+    pub fn label_before(&self, address: u16) -> Option<&Symbol> {
+        self.symbols
+            .iter()
+            .rev()
+            .skip_while(|s| s.value > address)
+            .find(|s| s.kind == SymbolKind::Label && !s.name.contains('.'))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn parse(text: &str) -> Result<SymbolTable, String> {
+        let mut table = SymbolTable::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let invalid = || format!("line {}: Invalid symbol entry '{}'", n + 1, line);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [kind, value, name] = fields.as_slice() else {
+                return Err(invalid());
+            };
+            let kind = match *kind {
+                "label" => SymbolKind::Label,
+                "const" => SymbolKind::Constant,
+                _ => return Err(invalid()),
+            };
+            let value = value
+                .strip_prefix("0x")
+                .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+                .ok_or_else(invalid)?;
+            table.insert(name, value, kind);
+        }
+        Ok(table)
+    }
+}
+
+impl fmt::Display for SymbolTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for symbol in &self.symbols {
+            let kind = match symbol.kind {
+                SymbolKind::Label => "label",
+                SymbolKind::Constant => "const",
+            };
+            writeln!(f, "{} 0x{:04X} {}", kind, symbol.value, symbol.name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let mut table = SymbolTable::new();
+        table.insert("end", 0x20, SymbolKind::Label);
+        table.insert("ZERO", 0, SymbolKind::Constant);
+        table.insert("start", 0, SymbolKind::Label);
+        assert_eq!(table.label_at(0), Some("start"));
+        assert_eq!(table.label_at(0x20), Some("end"));
+        assert_eq!(table.label_at(0x10), None);
+        assert_eq!(
+            table.get("ZERO").map(|s| s.kind),
+            Some(SymbolKind::Constant)
+        );
+        let names: Vec<&str> = table.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["ZERO", "start", "end"]);
+
+        table.insert("start.loop", 0x8, SymbolKind::Label);
+        assert_eq!(table.label_before(0x10).map(|s| s.value), Some(0));
+        assert_eq!(
+            table.label_before(0x20).map(|s| s.name.as_str()),
+            Some("end")
+        );
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let text = "const 0x0000 ZERO\nlabel 0x0000 start\nlabel 0x0020 end\n";
+        let table = SymbolTable::parse(text).unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.to_string(), text);
+        assert_eq!(
+            SymbolTable::parse("label 20 end"),
+            Err("line 1: Invalid symbol entry 'label 20 end'".to_string())
+        );
+    }
+}
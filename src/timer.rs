@@ -0,0 +1,187 @@
+use crate::device::Device;
+use crate::memory::Addressable;
+use std::time::Instant;
+
+/// Where `vm` maps its `Timer`.
+pub const TIMER_ADDRESS: u16 = 0xFF20;
+/// The interrupt a new `Timer` raises.
+pub const TIMER_INTERRUPT: u8 = 0x08;
+/// Offset of the period, a little-endian word: the count the timer starts from.
+pub const TIMER_PERIOD: u16 = 0;
+/// Offset of the current count, a little-endian word.
+pub const TIMER_COUNT: u16 = 2;
+/// Offset of the control register, a combination of the `TIMER_` bits below.
+pub const TIMER_CONTROL: u16 = 4;
+/// Offset of the number of the interrupt to raise.
+pub const TIMER_VECTOR: u16 = 5;
+/// Control bit: the timer is counting. Setting it loads the count from the period.
+pub const TIMER_ENABLE: u8 = 0x01;
+/// Control bit: reload the period and carry on after expiring instead of stopping.
+pub const TIMER_PERIODIC: u8 = 0x02;
+/// Control bit: count microseconds of host time instead of instructions.
+pub const TIMER_HOST_TIME: u8 = 0x04;
+
+/// A countdown timer that raises an interrupt when it reaches zero, for time slices and
+/// timeouts in guest code.
+pub struct Timer {
+    period: u16,
+    count: u16,
+    control: u8,
+    vector: u8,
+    /// When the host time last counted down, in host time mode.
+    since: Instant,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self {
+            period: 0,
+            count: 0,
+            control: 0,
+            vector: TIMER_INTERRUPT,
+            since: Instant::now(),
+        }
+    }
+
+    /// How far the count goes down this tick.
+    fn elapsed(&mut self) -> u16 {
+        if self.control & TIMER_HOST_TIME == 0 {
+            return 1;
+        }
+        let micros = self.since.elapsed().as_micros();
+        if micros > 0 {
+            self.since = Instant::now();
+        }
+        micros.min(u16::MAX as u128) as u16
+    }
+}
+
+impl Addressable for Timer {
+    fn read(&self, address: u16) -> Option<u8> {
+        match address {
+            0..=1 => Some(self.period.to_le_bytes()[address as usize]),
+            2..=3 => Some(self.count.to_le_bytes()[address as usize - 2]),
+            TIMER_CONTROL => Some(self.control),
+            TIMER_VECTOR => Some(self.vector),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        let set_byte = |word: &mut u16, i: usize| {
+            let mut bytes = word.to_le_bytes();
+            bytes[i] = value;
+            *word = u16::from_le_bytes(bytes);
+        };
+        match address {
+            0..=1 => set_byte(&mut self.period, address as usize),
+            2..=3 => set_byte(&mut self.count, address as usize - 2),
+            TIMER_CONTROL => {
+                if value & TIMER_ENABLE != 0 && self.control & TIMER_ENABLE == 0 {
+                    self.count = self.period;
+                    self.since = Instant::now();
+                }
+                self.control = value;
+            }
+            TIMER_VECTOR => self.vector = value,
+            _ => return false,
+        }
+        true
+    }
+
+    fn peek(&self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    fn poke(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+}
+
+impl Device for Timer {
+    fn tick(&mut self, _memory: &mut dyn Addressable) -> Option<u8> {
+        if self.control & TIMER_ENABLE == 0 {
+            return None;
+        }
+        let elapsed = self.elapsed();
+        if self.count > elapsed {
+            self.count -= elapsed;
+            return None;
+        }
+        if self.control & TIMER_PERIODIC != 0 && self.period > 0 {
+            self.count = self.period;
+        } else {
+            self.count = 0;
+            self.control &= !TIMER_ENABLE;
+        }
+        Some(self.vector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::memory::LinearMemory;
+    use crate::vm::{Flag, Machine, Register};
+
+    #[test]
+    fn test_timer() {
+        let mut timer = Timer::new();
+        let bus = &mut LinearMemory::new(0);
+        assert!(timer.write2(TIMER_PERIOD, 3));
+        assert_eq!(timer.tick(bus), None);
+        assert!(timer.write(TIMER_CONTROL, TIMER_ENABLE));
+        assert_eq!(timer.read2(TIMER_COUNT), Some(3));
+        assert_eq!(timer.tick(bus), None);
+        assert_eq!(timer.tick(bus), None);
+        assert_eq!(timer.tick(bus), Some(TIMER_INTERRUPT));
+        assert_eq!(timer.read(TIMER_CONTROL), Some(0));
+        assert_eq!(timer.tick(bus), None);
+
+        timer.write(TIMER_VECTOR, 3);
+        timer.write(TIMER_CONTROL, TIMER_ENABLE | TIMER_PERIODIC);
+        let fired: Vec<_> = (0..9).filter_map(|_| timer.tick(bus)).collect();
+        assert_eq!(fired, vec![3, 3, 3]);
+        assert!(!timer.write(6, 0));
+    }
+
+    #[test]
+    fn test_preemption() {
+        let mut m = Machine::new();
+        m.add_device(TIMER_ADDRESS, 6, Timer::new()).unwrap();
+        let program = assemble(
+            "
+            LDIW M, 0xFF20
+            LDIW A, 10
+            STORE M, A          ; period
+            LDIW M, 0xFF24
+            LDI A, 3            ; enable, periodic
+            STOREB M, A
+            BSET FLAGS, 4       ; interrupts on
+            LDIW C, 100
+    spin:   LOOP C, spin
+            HALT
+            ",
+        )
+        .unwrap();
+        m.load_program(&assemble("INC D\nIRET").unwrap(), 0x200)
+            .unwrap();
+        m.set_interrupt_table(0x100);
+        m.memory.write2(0x100 + TIMER_INTERRUPT as u16 * 2, 0x200);
+        m.load_program(&program, 0).unwrap();
+        m.set_register(Register::SP, 0x1000);
+        m.run().unwrap();
+        // Some 100 loop iterations and 3 steps per interrupt (entering it, INC and IRET),
+        // with the timer expiring every 10 steps.
+        assert_eq!(m.get_register(Register::D), 14);
+        assert!(m.get_flag(Flag::Interrupt));
+        assert_eq!(m.get_register(Register::SP), 0x1000);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::debugger::{Debugger, StopReason};
+use crate::vm::Register;
+
+/// Instructions `c` runs before handing control back, so a program that never stops does not
+/// freeze the screen.
+const CONTINUE_LIMIT: usize = 100_000;
+const LEFT_WIDTH: usize = 46;
+const RIGHT_WIDTH: usize = 40;
+const MEMORY_ROWS: usize = 8;
+
+const KEYS: &str =
+    "s step  c continue  r/R back  b breakpoint  j/k memory  m/p memory at M/PC  q quit";
+
+/// A full-screen debugger: registers, the stack, disassembly around PC and a memory view,
+/// redrawn after every key. `render` builds the frame as text with ANSI highlighting; the
+/// terminal handling is left to `tvm-tui`.
+pub struct Tui {
+    pub debugger: Debugger,
+    /// First address of the memory pane.
+    pub memory_view: u16,
+    status: String,
+}
+
+impl Tui {
+    pub fn new(debugger: Debugger) -> Self {
+        let memory_view = debugger.machine.get_register(Register::M);
+        Self {
+            debugger,
+            memory_view,
+            status: String::new(),
+        }
+    }
+
+    /// Handles one key press and returns false when the user quits.
+    pub fn key(&mut self, key: char) -> bool {
+        self.status.clear();
+        match key {
+            's' | ' ' => self.run(|d| d.step().map(Some)),
+            'c' => self.run(|d| d.resume_for(CONTINUE_LIMIT)),
+            'r' => self.run(|d| d.step_back().map(Some)),
+            'R' => self.run(|d| d.reverse().map(Some)),
+            'b' => {
+                let pc = self.debugger.machine.get_register(Register::PC);
+                let label = self.debugger.describe(pc);
+                self.status = if self.debugger.remove_breakpoint(pc) {
+                    format!("Deleted breakpoint at {}", label)
+                } else {
+                    self.debugger.add_breakpoint(pc);
+                    format!("Breakpoint at {}", label)
+                };
+            }
+            'j' => self.memory_view = self.memory_view.wrapping_add(16),
+            'k' => self.memory_view = self.memory_view.wrapping_sub(16),
+            'm' => self.memory_view = self.debugger.machine.get_register(Register::M),
+            'p' => self.memory_view = self.debugger.machine.get_register(Register::PC),
+            'q' => return false,
+            _ => self.status = KEYS.to_string(),
+        }
+        true
+    }
+
+    fn run(&mut self, run: impl FnOnce(&mut Debugger) -> Result<Option<StopReason>, String>) {
+        self.status = match run(&mut self.debugger) {
+            Ok(Some(StopReason::Step)) => String::new(),
+            Ok(Some(StopReason::Breakpoint(address))) => {
+                format!("Breakpoint at {}", self.debugger.describe(address))
+            }
+            Ok(Some(StopReason::Watchpoint(hits))) => {
+                format!("Watchpoint {} at 0x{:04X}", hits[0].id, hits[0].address)
+            }
+            Ok(Some(StopReason::Halted)) => "Program halted".to_string(),
+            Ok(Some(StopReason::StartOfHistory)) => "Start of recorded history".to_string(),
+            Ok(None) => format!(
+                "Still running after {} instructions, press c to go on",
+                CONTINUE_LIMIT
+            ),
+            Err(error) => format!("error: {}", error),
+        };
+    }
+
+    /// The whole screen, lines separated by `\r\n` so it also draws right in raw mode.
+    pub fn render(&self) -> String {
+        let debugger = &self.debugger;
+        let mut left = boxed("Registers", &lines(&debugger.registers()), LEFT_WIDTH, 4);
+        left.extend(boxed("Stack", &self.stack(), LEFT_WIDTH, 8));
+        let right = boxed(
+            "Disassembly",
+            &lines(&debugger.disassembly(None, 12)),
+            RIGHT_WIDTH,
+            left.len() - 2,
+        );
+        let mut screen: Vec<String> = left
+            .into_iter()
+            .zip(right)
+            .map(|(left, right)| left + &right)
+            .collect();
+        let end = self
+            .memory_view
+            .saturating_add((MEMORY_ROWS * 16 - 1) as u16);
+        screen.extend(boxed(
+            "Memory",
+            &lines(&debugger.machine.dump_memory(self.memory_view..=end)),
+            LEFT_WIDTH + RIGHT_WIDTH,
+            MEMORY_ROWS,
+        ));
+        screen.push(if self.status.is_empty() {
+            KEYS.to_string()
+        } else {
+            self.status.clone()
+        });
+        screen.join("\r\n")
+    }
+
+    /// The most recently pushed words, from SP-2 downwards since the stack grows upwards.
+    /// Read with `peek_word`, so redrawing does not touch watchpoints or devices.
+    fn stack(&self) -> Vec<String> {
+        let machine = &self.debugger.machine;
+        let sp = machine.get_register(Register::SP);
+        (1..=8u16)
+            .map_while(|i| {
+                let address = sp.checked_sub(i * 2)?;
+                let value = machine.peek_word(address)?;
+                let offset = format!("SP-{}", i * 2);
+                Some(format!("{:5} 0x{:04X}  0x{:04X}", offset, address, value))
+            })
+            .collect()
+    }
+}
+
+fn lines(text: &str) -> Vec<String> {
+    text.lines().map(str::to_string).collect()
+}
+
+/// Draws `lines` in a box `width` columns wide with `height` rows inside, cutting off what
+/// does not fit. The line with PC on it is shown in reverse video.
+fn boxed(title: &str, lines: &[String], width: usize, height: usize) -> Vec<String> {
+    let inner = width - 2;
+    let mut out = vec![format!(
+        "┌ {} {}┐",
+        title,
+        "─".repeat(inner - title.len() - 2)
+    )];
+    for i in 0..height {
+        let line: String = lines
+            .get(i)
+            .map_or("", String::as_str)
+            .chars()
+            .take(inner)
+            .collect();
+        let padded = format!("{:inner$}", line);
+        if line.starts_with("=>") {
+            out.push(format!("│\x1b[7m{}\x1b[0m│", padded));
+        } else {
+            out.push(format!("│{}│", padded));
+        }
+    }
+    out.push(format!("└{}┘", "─".repeat(inner)));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembly;
+    use crate::vm::Machine;
+
+    fn tui() -> Tui {
+        let assembly = Assembly::from_source(
+            "
+            main:   LDI A, 3
+                    LDIW M, 0x200
+            loop:   STORE M, A
+                    DEC A
+                    JNZ loop
+                    HALT
+            ",
+        )
+        .unwrap();
+        let mut machine = Machine::new();
+        machine.load_program(&assembly.bytes, 0).unwrap();
+        Tui::new(Debugger::new(machine, assembly.symbols))
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut tui = tui();
+        assert!(tui.key('s'));
+        assert!(tui.key(' '));
+        assert_eq!(tui.debugger.machine.get_register(Register::PC), 6);
+        tui.key('b');
+        assert_eq!(tui.status, "Breakpoint at 0x0006 <loop>");
+        tui.key('c');
+        assert_eq!(tui.status, "Breakpoint at 0x0006 <loop>");
+        assert_eq!(tui.debugger.machine.get_register(Register::A), 2);
+        tui.key('b');
+        tui.key('c');
+        assert_eq!(tui.status, "Program halted");
+        tui.key('s');
+        assert_eq!(tui.status, "error: The program has halted");
+
+        tui.key('m');
+        assert_eq!(tui.memory_view, 0x200);
+        tui.key('k');
+        assert_eq!(tui.memory_view, 0x1F0);
+        tui.key('r');
+        assert!(!tui.debugger.machine.is_halted());
+        tui.key('R');
+        assert_eq!(tui.status, "Start of recorded history");
+        assert_eq!(tui.debugger.machine.get_register(Register::PC), 0);
+        tui.key('?');
+        assert_eq!(tui.status, KEYS);
+        assert!(!tui.key('q'));
+    }
+
+    #[test]
+    fn test_render() {
+        let mut tui = tui();
+        tui.key('s');
+        tui.key('s');
+        tui.key('m');
+        let machine = &mut tui.debugger.machine;
+        machine.set_register(Register::SP, 0x400);
+        machine.push(0x1111).unwrap();
+        machine.push(0x2222).unwrap();
+        let screen = tui.render();
+        let rows: Vec<&str> = screen.split("\r\n").collect();
+        assert_eq!(rows.len(), 6 + 10 + MEMORY_ROWS + 2 + 1);
+        assert!(rows[0].starts_with("┌ Registers ─"));
+        assert!(rows[0].contains("┐┌ Disassembly ─"));
+        assert!(rows[1].starts_with("│A  0x0003  B  0x0000  C  0x0000  M  0x0200  │"));
+        assert!(rows[7].starts_with("│SP-2  0x0402  0x2222"));
+        assert!(rows[8].starts_with("│SP-4  0x0400  0x1111"));
+        assert!(screen.contains("│\x1b[7m=> 0006  20 03        STORE M, A      \x1b[0m│"));
+        assert!(rows[17].starts_with("│0200  00 00"));
+        assert_eq!(rows[rows.len() - 1], KEYS);
+        for row in &rows[..rows.len() - 1] {
+            let width = row
+                .replace("\x1b[7m", "")
+                .replace("\x1b[0m", "")
+                .chars()
+                .count();
+            assert_eq!(width, LEFT_WIDTH + RIGHT_WIDTH, "{}", row);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use crate::memory::Addressable;
+use std::cell::Cell;
+use std::io::{self, Read, Stdout, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+/// Where `vm` maps its `Uart`.
+pub const UART_ADDRESS: u16 = 0xFF10;
+/// Offset of the data register: reading takes the next received byte, writing sends one.
+pub const UART_DATA: u16 = 0;
+/// Offset of the status register, a combination of `UART_RX_READY` and `UART_TX_READY`.
+pub const UART_STATUS: u16 = 1;
+/// Status bit: a received byte is waiting in the data register.
+pub const UART_RX_READY: u8 = 0x01;
+/// Status bit: the data register can take a byte to send.
+pub const UART_TX_READY: u8 = 0x02;
+
+/// A serial port with a data and a status register. Received bytes come from a host reader on
+/// a thread of their own, so guest code polls the status register instead of blocking the
+/// machine; sent bytes go to a host writer. Reading data with nothing received gives 0.
+pub struct Uart<W: Write> {
+    received: Receiver<u8>,
+    /// A byte taken off `received` by a status read and not yet read as data.
+    pending: Cell<Option<u8>>,
+    output: W,
+}
+
+impl Uart<Stdout> {
+    /// A UART on the host's standard input and output.
+    pub fn stdio() -> Self {
+        Self::new(io::stdin(), io::stdout())
+    }
+}
+
+impl<W: Write> Uart<W> {
+    pub fn new(mut input: impl Read + Send + 'static, output: W) -> Self {
+        let (sender, received) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 256];
+            while let Ok(n @ 1..) = input.read(&mut buf) {
+                if buf[..n].iter().any(|&byte| sender.send(byte).is_err()) {
+                    break;
+                }
+            }
+        });
+        Self {
+            received,
+            pending: Cell::new(None),
+            output,
+        }
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    fn waiting(&self) -> Option<u8> {
+        if self.pending.get().is_none() {
+            self.pending.set(self.received.try_recv().ok());
+        }
+        self.pending.get()
+    }
+}
+
+impl<W: Write> Addressable for Uart<W> {
+    fn read(&self, address: u16) -> Option<u8> {
+        match address {
+            UART_DATA => {
+                let byte = self.waiting();
+                self.pending.set(None);
+                Some(byte.unwrap_or(0))
+            }
+            UART_STATUS => {
+                let rx = if self.waiting().is_some() {
+                    UART_RX_READY
+                } else {
+                    0
+                };
+                Some(rx | UART_TX_READY)
+            }
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        address == UART_DATA
+            && self
+                .output
+                .write_all(&[value])
+                .and_then(|_| self.output.flush())
+                .is_ok()
+    }
+
+    fn peek(&self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    fn poke(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::vm::Machine;
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_uart() {
+        let uart = Uart::new(Cursor::new(b"x".to_vec()), Vec::new());
+        while uart.read(UART_STATUS) != Some(UART_RX_READY | UART_TX_READY) {
+            thread::yield_now();
+        }
+        assert_eq!(uart.read(UART_DATA), Some(b'x'));
+        assert_eq!(uart.read(UART_STATUS), Some(UART_TX_READY));
+        assert_eq!(uart.read(UART_DATA), Some(0));
+        assert_eq!(uart.read(2), None);
+    }
+
+    #[test]
+    fn test_echo() {
+        let uart = Rc::new(RefCell::new(Uart::new(
+            Cursor::new(b"ok".to_vec()),
+            Vec::new(),
+        )));
+        let mut m = Machine::new();
+        m.map(UART_ADDRESS, 2, uart.clone()).unwrap();
+        let program = assemble(
+            "
+            LDIW M, 0xFF11
+            LDIW D, 0xFF10
+            LDIW C, 2
+    wait:   LOADB A, M
+            BTST A, 0
+            JZ wait
+            LOADB B, D
+            STOREB D, B
+            LOOP C, wait
+            HALT
+            ",
+        )
+        .unwrap();
+        m.load_program(&program, 0).unwrap();
+        m.run().unwrap();
+        assert_eq!(uart.borrow().output(), b"ok");
+    }
+}
@@ -1,6 +1,18 @@
+use crate::cache::{Cache, CacheConfig, CachedMemory};
+use crate::core_dump::CoreDump;
+use crate::device::Device;
+use crate::history::{History, StepRecord};
 use crate::memory::*;
+use crate::mmu::{Mmu, MmuState, PageFault, PAGE_FAULT};
+use crate::object::{ObjectFile, Reader};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Register {
     A,
@@ -11,8 +23,26 @@ pub enum Register {
     PC,
     BP,
     FLAGS,
+    // Added after the original register file so existing encodings keep their numbers.
+    D,
+    E,
+    F,
+    G,
+    H,
 }
 
+/// Magic bytes at the start of a machine snapshot.
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"TVMS";
+pub const SNAPSHOT_VERSION: u16 = 2;
+
+/// Number of registers. Register operands are encoded as 4-bit fields, so this can grow
+/// up to 16.
+pub const REGISTER_COUNT: usize = 13;
+
+/// Where `Machine::map_registers` puts the register file: one little-endian word per
+/// register, in register number order.
+pub const REGISTER_WINDOW: u16 = 0xFFE0;
+
 impl Register {
     pub fn from_u8(value: u8) -> Option<Self> {
         match value {
@@ -24,28 +54,340 @@ impl Register {
             x if x == Register::PC as u8 => Some(Register::PC),
             x if x == Register::BP as u8 => Some(Register::BP),
             x if x == Register::FLAGS as u8 => Some(Register::FLAGS),
+            x if x == Register::D as u8 => Some(Register::D),
+            x if x == Register::E as u8 => Some(Register::E),
+            x if x == Register::F as u8 => Some(Register::F),
+            x if x == Register::G as u8 => Some(Register::G),
+            x if x == Register::H as u8 => Some(Register::H),
             _ => None,
         }
     }
 }
 
+/// Bits of the FLAGS register, numbered from the least significant bit.
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum Flag {
+    Zero,
+    Carry,
+    Negative,
+    Overflow,
+    /// Set to let devices interrupt; cleared on entering their handler, restored by `IRET`.
+    Interrupt,
+}
+
+impl Flag {
+    pub fn mask(self) -> u16 {
+        1 << self as u8
+    }
+}
+
 #[repr(u8)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Op {
     Nop,
     Push(u8),
     PopRegister(Register),
+    /// Pops two words and pushes their sum, wrapping around and setting flags like
+    /// `AddRegister`.
     AddStack,
+    /// `r1 = r1 + r2`, wrapping around on overflow and setting zero, negative, carry and
+    /// overflow. The first register is encoded in bits 8-11 and the second in 12-15.
     AddRegister(Register, Register),
+    /// `r1 = r2`, encoded like `AddRegister`.
     Mov(Register, Register),
+    /// Pops `b` then `a` and pushes `a - b`, wrapping around and setting flags like
+    /// `SubRegister`.
+    SubStack,
+    /// `r1 = r1 - r2`, wrapping around on underflow and setting flags like `Cmp`: carry on a
+    /// borrow, overflow when the signed result does not fit.
+    SubRegister(Register, Register),
+    /// Pops `b` then `a` and pushes the low 16 bits of `a * b`.
+    MulStack,
+    /// `r1 = r1 * r2`, truncated to the low 16 bits.
+    MulRegister(Register, Register),
+    /// Pops `b` then `a` and pushes `a / b`. Fails on division by zero.
+    DivStack,
+    /// `r1 = r1 / r2`. Fails on division by zero.
+    DivRegister(Register, Register),
+    /// Logical shift left of a register by an immediate amount (0-15).
+    Shl(Register, u8),
+    /// Logical shift right of a register by an immediate amount (0-15).
+    Shr(Register, u8),
+    /// Arithmetic shift right of a register by an immediate amount (0-15).
+    Sar(Register, u8),
+    /// `r1 = r1 << r2`. Shifting by 16 or more clears the register.
+    ShlRegister(Register, Register),
+    /// `r1 = r1 >> r2`. Shifting by 16 or more clears the register.
+    ShrRegister(Register, Register),
+    /// Arithmetic `r1 = r1 >> r2`. Shifting by 16 or more fills with the sign bit.
+    SarRegister(Register, Register),
+    /// Computes `r1 - r2` and updates FLAGS without storing the result.
+    Cmp(Register, Register),
+    /// Jumps to the absolute address held in the following instruction word.
+    Jmp(u16),
+    /// Jumps by a signed byte offset relative to the next instruction.
+    JmpRelative(i8),
+    /// Jumps to the following word if the zero flag is set.
+    Jz(u16),
+    /// Jumps to the following word if the zero flag is clear.
+    Jnz(u16),
+    /// Jumps if the last comparison was signed less than.
+    Jlt(u16),
+    /// Jumps if the last comparison was signed greater than.
+    Jgt(u16),
+    /// Jumps if the last comparison was signed less than or equal.
+    Jle(u16),
+    /// Jumps if the last comparison was signed greater than or equal.
+    Jge(u16),
+    /// Pushes the address of the next instruction and jumps to the following word.
+    Call(u16),
+    /// Pops a return address off the stack and jumps to it.
+    Ret,
+    /// Pushes the full 16-bit value of a register.
+    PushRegister(Register),
+    /// Pushes the 16-bit immediate held in the following instruction word.
+    Push16(u16),
+    /// `dst = memory[addr]`, reading a 16-bit word.
+    Load(Register, Register),
+    /// `memory[addr] = src`, writing a 16-bit word.
+    Store(Register, Register),
+    /// Loads the 16-bit immediate held in the following instruction word into a register.
+    LoadImm16(Register, u16),
+    /// Stops the machine; `Machine::run` returns once it is executed.
+    Halt,
+    /// Increments a register, updating zero, negative and overflow. Carry is preserved.
+    Inc(Register),
+    /// Decrements a register, updating zero, negative and overflow. Carry is preserved.
+    Dec(Register),
+    /// Two's-complement negation, setting FLAGS as for `0 - r`.
+    Neg(Register),
+    /// Rotates a register left by an immediate amount; carry receives the last bit rotated out.
+    Rol(Register, u8),
+    /// Rotates a register right by an immediate amount; carry receives the last bit rotated out.
+    Ror(Register, u8),
+    /// Rotates a register left through the carry flag, as a 17-bit value.
+    Rcl(Register, u8),
+    /// Rotates a register right through the carry flag, as a 17-bit value.
+    Rcr(Register, u8),
+    /// `dst = src` if the zero flag is set.
+    CmovZ(Register, Register),
+    /// `dst = src` if the zero flag is clear.
+    CmovNz(Register, Register),
+    /// Calls the host handler registered for the given number.
+    Syscall(u8),
+    /// Exchanges the values of two registers.
+    Swap(Register, Register),
+    /// `dst = memory[addr]`, reading a single byte and zero-extending it.
+    LoadByte(Register, Register),
+    /// `memory[addr] = src`, writing the low byte of `src`.
+    StoreByte(Register, Register),
+    /// Unsigned 16x16 -> 32-bit multiply: `r1` receives the low half and `r2` the high half,
+    /// so the two must differ; the same register twice does not decode.
+    MulWide(Register, Register),
+    /// `r1 = r1 + r2 + carry`, setting carry on unsigned overflow.
+    Adc(Register, Register),
+    /// `r1 = r1 - r2 - carry`, setting carry on unsigned borrow.
+    Sbb(Register, Register),
+    /// Computes `r1 & r2` to set zero and negative, clearing carry and overflow.
+    Test(Register, Register),
+    /// Pushes FLAGS and the return address, then jumps through entry `n` of the
+    /// interrupt table.
+    Int(u8),
+    /// Returns from an interrupt handler, restoring PC and FLAGS.
+    Iret,
+    /// Jumps to the address held in a register.
+    JmpRegister(Register),
+    /// Pushes every register except SP and PC, in register number order.
+    PushAll,
+    /// Pops the registers saved by `PushAll`, in reverse order.
+    PopAll,
+    /// Sign-extends the low byte of a register to 16 bits.
+    Sext8(Register),
+    /// Zero-extends the low byte of a register to 16 bits.
+    Zext8(Register),
+    /// Sets the zero flag if the given bit (0-15) of a register is clear.
+    BitTest(Register, u8),
+    /// Sets the given bit of a register.
+    BitSet(Register, u8),
+    /// Clears the given bit of a register.
+    BitClear(Register, u8),
+    /// Flips the given bit of a register.
+    BitToggle(Register, u8),
+    /// Replaces a register with its number of leading zero bits (16 for zero).
+    Clz(Register),
+    /// Replaces a register with its number of set bits.
+    Popcnt(Register),
+    /// `dst = min(dst, src)`, comparing as unsigned values.
+    Min(Register, Register),
+    /// `dst = max(dst, src)`, comparing as unsigned values.
+    Max(Register, Register),
+    /// `dst = min(dst, src)`, comparing as signed values.
+    MinSigned(Register, Register),
+    /// `dst = max(dst, src)`, comparing as signed values.
+    MaxSigned(Register, Register),
+    /// Copies `len` bytes from the address in `src` to the address in `dst`, lowest address
+    /// first. The `len` register is held in the following word. If either range faults,
+    /// memory is left as it was.
+    MemCpy(Register, Register, Register),
+    /// Fills `len` bytes at the address in `dst` with the low byte of `value`, or none if the
+    /// range faults. The `len` register is held in the following word.
+    MemSet(Register, Register, Register),
+    /// Decrements a counter register and jumps to the following word if it is nonzero.
+    /// FLAGS are not modified.
+    Loop(Register, u16),
+    /// Compare-and-swap on the word at `addr`: if it equals `expected` it is replaced with
+    /// `new` and the zero flag is set, otherwise `expected` receives the current value and
+    /// the zero flag is cleared. The `new` register is held in the following word.
+    Cas(Register, Register, Register),
+    /// `r1 = r1 + r2`, clamping at 0xFFFF instead of wrapping.
+    AddSat(Register, Register),
+    /// `r1 = r1 - r2`, clamping at 0 instead of wrapping.
+    SubSat(Register, Register),
+    /// Multiply-accumulate `acc = acc + a * b`, keeping the low 16 bits. Carry is set when
+    /// the full result does not fit. The `b` register is held in the following word.
+    Mac(Register, Register, Register),
+    /// Pushes the FLAGS register.
+    PushFlags,
+    /// Pops a value into the FLAGS register.
+    PopFlags,
+    /// Loads the code segment from a register, from the next instruction on.
+    LoadCodeSegment(Register),
+    /// Loads the data segment from a register.
+    LoadDataSegment(Register),
+    // The instructions below carry a register and an 8-bit immediate in a single word by
+    // encoding the register in the low nibble of the opcode. Each one takes a block of 16
+    // opcodes, so new instructions must be declared above them.
+    /// `reg = reg - imm`, setting FLAGS like `Sbb` with no borrow in.
+    SubImm(Register, u8) = 0xd0,
+    /// `reg = reg + imm`, setting FLAGS like `Adc` with no carry in.
+    AddImm(Register, u8) = 0xe0,
+    /// Loads an 8-bit immediate into a register.
+    LoadImm(Register, u8) = 0xf0,
 }
 impl Op {
     pub fn value(&self) -> u8 {
         unsafe { *<*const _>::from(self).cast::<u8>() }
     }
+
+    /// Encodes the instruction as the words `parse_instruction` expects: the instruction
+    /// word followed by an optional extension word.
+    pub fn encode(&self) -> Vec<u16> {
+        let op = self.value() as u16;
+        let reg = |r: Register| (r as u16) << 8;
+        let pair = |r1: Register, r2: Register| (r1 as u16) << 8 | (r2 as u16) << 12;
+        let shift = |r: Register, n: u8| (r as u16) << 8 | (n as u16 & 0xf) << 12;
+        match *self {
+            Op::Nop
+            | Op::AddStack
+            | Op::SubStack
+            | Op::MulStack
+            | Op::DivStack
+            | Op::Ret
+            | Op::Halt
+            | Op::Iret
+            | Op::PushAll
+            | Op::PopAll
+            | Op::PushFlags
+            | Op::PopFlags => vec![op],
+            Op::Push(imm) | Op::Syscall(imm) | Op::Int(imm) => vec![op | (imm as u16) << 8],
+            Op::JmpRelative(offset) => vec![op | (offset as u8 as u16) << 8],
+            Op::PopRegister(r)
+            | Op::PushRegister(r)
+            | Op::Inc(r)
+            | Op::Dec(r)
+            | Op::Neg(r)
+            | Op::JmpRegister(r)
+            | Op::Sext8(r)
+            | Op::Zext8(r)
+            | Op::Clz(r)
+            | Op::Popcnt(r)
+            | Op::LoadCodeSegment(r)
+            | Op::LoadDataSegment(r) => vec![op | reg(r)],
+            Op::AddRegister(r1, r2)
+            | Op::Mov(r1, r2)
+            | Op::SubRegister(r1, r2)
+            | Op::MulRegister(r1, r2)
+            | Op::DivRegister(r1, r2)
+            | Op::ShlRegister(r1, r2)
+            | Op::ShrRegister(r1, r2)
+            | Op::SarRegister(r1, r2)
+            | Op::Cmp(r1, r2)
+            | Op::Load(r1, r2)
+            | Op::Store(r1, r2)
+            | Op::CmovZ(r1, r2)
+            | Op::CmovNz(r1, r2)
+            | Op::Swap(r1, r2)
+            | Op::LoadByte(r1, r2)
+            | Op::StoreByte(r1, r2)
+            | Op::MulWide(r1, r2)
+            | Op::Adc(r1, r2)
+            | Op::Sbb(r1, r2)
+            | Op::Test(r1, r2)
+            | Op::Min(r1, r2)
+            | Op::Max(r1, r2)
+            | Op::MinSigned(r1, r2)
+            | Op::MaxSigned(r1, r2)
+            | Op::AddSat(r1, r2)
+            | Op::SubSat(r1, r2) => vec![op | pair(r1, r2)],
+            Op::Shl(r, n)
+            | Op::Shr(r, n)
+            | Op::Sar(r, n)
+            | Op::Rol(r, n)
+            | Op::Ror(r, n)
+            | Op::Rcl(r, n)
+            | Op::Rcr(r, n)
+            | Op::BitTest(r, n)
+            | Op::BitSet(r, n)
+            | Op::BitClear(r, n)
+            | Op::BitToggle(r, n) => vec![op | shift(r, n)],
+            Op::Jmp(address)
+            | Op::Jz(address)
+            | Op::Jnz(address)
+            | Op::Jlt(address)
+            | Op::Jgt(address)
+            | Op::Jle(address)
+            | Op::Jge(address)
+            | Op::Call(address)
+            | Op::Push16(address) => vec![op, address],
+            Op::LoadImm16(r, value) | Op::Loop(r, value) => vec![op | reg(r), value],
+            Op::Cas(r1, r2, r3)
+            | Op::Mac(r1, r2, r3)
+            | Op::MemCpy(r1, r2, r3)
+            | Op::MemSet(r1, r2, r3) => vec![op | pair(r1, r2), r3 as u16],
+            Op::SubImm(r, imm) | Op::AddImm(r, imm) | Op::LoadImm(r, imm) => {
+                vec![op | r as u16 | (imm as u16) << 8]
+            }
+        }
+    }
+}
+
+fn parse_register(bits: u16) -> Result<Register, String> {
+    Register::from_u8(bits as u8).ok_or(format!("Unknown register 0x{:X}", bits))
+}
+
+fn parse_register_pair(ins: u16) -> Result<(Register, Register), String> {
+    let r1 = parse_register((ins & 0xf00) >> 8)?;
+    let r2 = parse_register((ins & 0xf000) >> 12)?;
+    Ok((r1, r2))
+}
+
+fn parse_register_shift(ins: u16) -> Result<(Register, u8), String> {
+    let reg = parse_register((ins & 0xf00) >> 8)?;
+    let amount = (ins & 0xf000) >> 12;
+    Ok((reg, amount as u8))
+}
+
+fn parse_register_immediate(ins: u16) -> Result<(Register, u8), String> {
+    let reg = parse_register(ins & 0xf)?;
+    let imm = (ins & 0xff00) >> 8;
+    Ok((reg, imm as u8))
 }
 
-fn parse_instruction(ins: u16) -> Result<Op, String> {
+pub(crate) fn parse_instruction<F>(ins: u16, mut next_word: F) -> Result<Op, String>
+where
+    F: FnMut() -> Result<u16, String>,
+{
     let op = (ins & 0xff) as u8;
     match op {
         x if x == Op::Nop.value() => Ok(Op::Nop),
@@ -54,45 +396,665 @@ fn parse_instruction(ins: u16) -> Result<Op, String> {
             Ok(Op::Push(arg as u8))
         }
         x if x == Op::PopRegister(Register::A).value() => {
-            let reg = (ins & 0xf00) >> 8;
-            if let Some(r) = Register::from_u8(reg as u8) {
-                Ok(Op::PopRegister(r))
-            } else {
-                Err(format!("Unknown register 0x{:X}", reg))
-            }
+            let reg = parse_register((ins & 0xf00) >> 8)?;
+            Ok(Op::PopRegister(reg))
         }
         x if x == Op::AddStack.value() => Ok(Op::AddStack),
         x if x == Op::AddRegister(Register::A, Register::B).value() => {
-            Ok(Op::AddRegister(Register::A, Register::B))
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::AddRegister(r1, r2))
         }
         x if x == Op::Mov(Register::A, Register::B).value() => {
-            Ok(Op::Mov(Register::A, Register::B))
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::Mov(r1, r2))
+        }
+        x if x == Op::SubStack.value() => Ok(Op::SubStack),
+        x if x == Op::SubRegister(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::SubRegister(r1, r2))
+        }
+        x if x == Op::MulStack.value() => Ok(Op::MulStack),
+        x if x == Op::MulRegister(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::MulRegister(r1, r2))
+        }
+        x if x == Op::DivStack.value() => Ok(Op::DivStack),
+        x if x == Op::DivRegister(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::DivRegister(r1, r2))
+        }
+        x if x == Op::Shl(Register::A, 0).value() => {
+            let (reg, amount) = parse_register_shift(ins)?;
+            Ok(Op::Shl(reg, amount))
+        }
+        x if x == Op::Shr(Register::A, 0).value() => {
+            let (reg, amount) = parse_register_shift(ins)?;
+            Ok(Op::Shr(reg, amount))
+        }
+        x if x == Op::Sar(Register::A, 0).value() => {
+            let (reg, amount) = parse_register_shift(ins)?;
+            Ok(Op::Sar(reg, amount))
+        }
+        x if x == Op::ShlRegister(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::ShlRegister(r1, r2))
+        }
+        x if x == Op::ShrRegister(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::ShrRegister(r1, r2))
+        }
+        x if x == Op::SarRegister(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::SarRegister(r1, r2))
+        }
+        x if x == Op::Cmp(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::Cmp(r1, r2))
+        }
+        x if x == Op::Jmp(0).value() => Ok(Op::Jmp(next_word()?)),
+        x if x == Op::JmpRelative(0).value() => {
+            let offset = (ins & 0xff00) >> 8;
+            Ok(Op::JmpRelative(offset as u8 as i8))
+        }
+        x if x == Op::Jz(0).value() => Ok(Op::Jz(next_word()?)),
+        x if x == Op::Jnz(0).value() => Ok(Op::Jnz(next_word()?)),
+        x if x == Op::Jlt(0).value() => Ok(Op::Jlt(next_word()?)),
+        x if x == Op::Jgt(0).value() => Ok(Op::Jgt(next_word()?)),
+        x if x == Op::Jle(0).value() => Ok(Op::Jle(next_word()?)),
+        x if x == Op::Jge(0).value() => Ok(Op::Jge(next_word()?)),
+        x if x == Op::Call(0).value() => Ok(Op::Call(next_word()?)),
+        x if x == Op::Ret.value() => Ok(Op::Ret),
+        x if x == Op::PushRegister(Register::A).value() => {
+            let reg = parse_register((ins & 0xf00) >> 8)?;
+            Ok(Op::PushRegister(reg))
+        }
+        x if x == Op::Push16(0).value() => Ok(Op::Push16(next_word()?)),
+        x if x == Op::Load(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::Load(r1, r2))
+        }
+        x if x == Op::Store(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::Store(r1, r2))
+        }
+        x if x == Op::LoadImm16(Register::A, 0).value() => {
+            let reg = parse_register((ins & 0xf00) >> 8)?;
+            Ok(Op::LoadImm16(reg, next_word()?))
+        }
+        x if x == Op::Halt.value() => Ok(Op::Halt),
+        x if x == Op::Inc(Register::A).value() => {
+            let reg = parse_register((ins & 0xf00) >> 8)?;
+            Ok(Op::Inc(reg))
+        }
+        x if x == Op::Dec(Register::A).value() => {
+            let reg = parse_register((ins & 0xf00) >> 8)?;
+            Ok(Op::Dec(reg))
+        }
+        x if x == Op::Neg(Register::A).value() => {
+            let reg = parse_register((ins & 0xf00) >> 8)?;
+            Ok(Op::Neg(reg))
+        }
+        x if x == Op::Rol(Register::A, 0).value() => {
+            let (reg, amount) = parse_register_shift(ins)?;
+            Ok(Op::Rol(reg, amount))
+        }
+        x if x == Op::Ror(Register::A, 0).value() => {
+            let (reg, amount) = parse_register_shift(ins)?;
+            Ok(Op::Ror(reg, amount))
+        }
+        x if x == Op::Rcl(Register::A, 0).value() => {
+            let (reg, amount) = parse_register_shift(ins)?;
+            Ok(Op::Rcl(reg, amount))
+        }
+        x if x == Op::Rcr(Register::A, 0).value() => {
+            let (reg, amount) = parse_register_shift(ins)?;
+            Ok(Op::Rcr(reg, amount))
+        }
+        x if x == Op::CmovZ(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::CmovZ(r1, r2))
+        }
+        x if x == Op::CmovNz(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::CmovNz(r1, r2))
+        }
+        x if x == Op::Syscall(0).value() => {
+            let n = (ins & 0xff00) >> 8;
+            Ok(Op::Syscall(n as u8))
+        }
+        x if x == Op::Swap(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::Swap(r1, r2))
+        }
+        x if x == Op::LoadByte(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::LoadByte(r1, r2))
+        }
+        x if x == Op::StoreByte(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::StoreByte(r1, r2))
+        }
+        x if x == Op::MulWide(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            if r1 == r2 {
+                return Err(format!(
+                    "MULW needs two different registers, not {} twice",
+                    r1
+                ));
+            }
+            Ok(Op::MulWide(r1, r2))
+        }
+        x if x == Op::Adc(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::Adc(r1, r2))
+        }
+        x if x == Op::Sbb(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::Sbb(r1, r2))
+        }
+        x if x == Op::Test(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::Test(r1, r2))
+        }
+        x if x == Op::Int(0).value() => {
+            let n = (ins & 0xff00) >> 8;
+            Ok(Op::Int(n as u8))
+        }
+        x if x == Op::Iret.value() => Ok(Op::Iret),
+        x if x == Op::JmpRegister(Register::A).value() => {
+            let reg = parse_register((ins & 0xf00) >> 8)?;
+            Ok(Op::JmpRegister(reg))
+        }
+        x if x == Op::PushAll.value() => Ok(Op::PushAll),
+        x if x == Op::PopAll.value() => Ok(Op::PopAll),
+        x if x == Op::Sext8(Register::A).value() => {
+            let reg = parse_register((ins & 0xf00) >> 8)?;
+            Ok(Op::Sext8(reg))
+        }
+        x if x == Op::Zext8(Register::A).value() => {
+            let reg = parse_register((ins & 0xf00) >> 8)?;
+            Ok(Op::Zext8(reg))
+        }
+        x if x == Op::BitTest(Register::A, 0).value() => {
+            let (reg, bit) = parse_register_shift(ins)?;
+            Ok(Op::BitTest(reg, bit))
+        }
+        x if x == Op::BitSet(Register::A, 0).value() => {
+            let (reg, bit) = parse_register_shift(ins)?;
+            Ok(Op::BitSet(reg, bit))
+        }
+        x if x == Op::BitClear(Register::A, 0).value() => {
+            let (reg, bit) = parse_register_shift(ins)?;
+            Ok(Op::BitClear(reg, bit))
+        }
+        x if x == Op::BitToggle(Register::A, 0).value() => {
+            let (reg, bit) = parse_register_shift(ins)?;
+            Ok(Op::BitToggle(reg, bit))
+        }
+        x if x == Op::Clz(Register::A).value() => {
+            let reg = parse_register((ins & 0xf00) >> 8)?;
+            Ok(Op::Clz(reg))
+        }
+        x if x == Op::Popcnt(Register::A).value() => {
+            let reg = parse_register((ins & 0xf00) >> 8)?;
+            Ok(Op::Popcnt(reg))
+        }
+        x if x == Op::Min(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::Min(r1, r2))
+        }
+        x if x == Op::Max(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::Max(r1, r2))
+        }
+        x if x == Op::MinSigned(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::MinSigned(r1, r2))
+        }
+        x if x == Op::MaxSigned(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::MaxSigned(r1, r2))
+        }
+        x if x == Op::MemCpy(Register::A, Register::B, Register::C).value() => {
+            let (dst, src) = parse_register_pair(ins)?;
+            let len = parse_register(next_word()? & 0xf)?;
+            Ok(Op::MemCpy(dst, src, len))
+        }
+        x if x == Op::MemSet(Register::A, Register::B, Register::C).value() => {
+            let (dst, value) = parse_register_pair(ins)?;
+            let len = parse_register(next_word()? & 0xf)?;
+            Ok(Op::MemSet(dst, value, len))
+        }
+        x if x == Op::Loop(Register::A, 0).value() => {
+            let reg = parse_register((ins & 0xf00) >> 8)?;
+            Ok(Op::Loop(reg, next_word()?))
+        }
+        x if x == Op::Cas(Register::A, Register::B, Register::C).value() => {
+            let (addr, expected) = parse_register_pair(ins)?;
+            let new = parse_register(next_word()? & 0xf)?;
+            Ok(Op::Cas(addr, expected, new))
+        }
+        x if x == Op::AddSat(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::AddSat(r1, r2))
+        }
+        x if x == Op::SubSat(Register::A, Register::B).value() => {
+            let (r1, r2) = parse_register_pair(ins)?;
+            Ok(Op::SubSat(r1, r2))
+        }
+        x if x == Op::Mac(Register::A, Register::B, Register::C).value() => {
+            let (acc, a) = parse_register_pair(ins)?;
+            let b = parse_register(next_word()? & 0xf)?;
+            Ok(Op::Mac(acc, a, b))
+        }
+        x if x == Op::PushFlags.value() => Ok(Op::PushFlags),
+        x if x == Op::PopFlags.value() => Ok(Op::PopFlags),
+        x if x == Op::LoadCodeSegment(Register::A).value() => {
+            let reg = parse_register((ins & 0xf00) >> 8)?;
+            Ok(Op::LoadCodeSegment(reg))
+        }
+        x if x == Op::LoadDataSegment(Register::A).value() => {
+            let reg = parse_register((ins & 0xf00) >> 8)?;
+            Ok(Op::LoadDataSegment(reg))
+        }
+        x if x & 0xf0 == Op::SubImm(Register::A, 0).value() => {
+            let (reg, imm) = parse_register_immediate(ins)?;
+            Ok(Op::SubImm(reg, imm))
+        }
+        x if x & 0xf0 == Op::AddImm(Register::A, 0).value() => {
+            let (reg, imm) = parse_register_immediate(ins)?;
+            Ok(Op::AddImm(reg, imm))
+        }
+        x if x & 0xf0 == Op::LoadImm(Register::A, 0).value() => {
+            let (reg, imm) = parse_register_immediate(ins)?;
+            Ok(Op::LoadImm(reg, imm))
         }
         _ => Err(format!("Unknown instruction 0x{:X}", op)),
     }
 }
 
+fn divide(a: u16, b: u16) -> Result<u16, String> {
+    a.checked_div(b).ok_or("Division by zero".to_string())
+}
+
+fn shift_left(value: u16, amount: u16) -> u16 {
+    value.checked_shl(amount.into()).unwrap_or(0)
+}
+
+fn shift_right(value: u16, amount: u16) -> u16 {
+    value.checked_shr(amount.into()).unwrap_or(0)
+}
+
+fn shift_right_arithmetic(value: u16, amount: u16) -> u16 {
+    (value as i16)
+        .checked_shr(amount.into())
+        .unwrap_or((value as i16) >> 15) as u16
+}
+
+/// Host-side implementation of a `Syscall` instruction.
+pub trait SyscallHandler {
+    fn handle(&mut self, machine: &mut Machine) -> Result<(), String>;
+}
+
+impl<F> SyscallHandler for F
+where
+    F: FnMut(&mut Machine) -> Result<(), String>,
+{
+    fn handle(&mut self, machine: &mut Machine) -> Result<(), String> {
+        self(machine)
+    }
+}
+
 pub struct Machine {
-    registers: [u16; 8],
+    registers: [u16; REGISTER_COUNT],
+    /// What instructions read and write: the bus, unless replaced, possibly wrapped to record
+    /// watchpoint hits and history.
     pub memory: Box<dyn Addressable>,
+    /// Regions mapped with `map`; 8 KiB of RAM at 0 to start with.
+    bus: Rc<RefCell<MemoryMapper>>,
+    /// Address translation between `memory` and the bus, off until `enable_mmu`.
+    mmu: Rc<RefCell<MmuState>>,
+    halted: bool,
+    interrupt_table: u16,
+    syscalls: HashMap<u8, Box<dyn SyscallHandler>>,
+    core_dump_path: Option<PathBuf>,
+    watchpoints: Rc<RefCell<Watchpoints>>,
+    /// Whether `memory` has been wrapped in a `WatchedMemory` yet.
+    watching: bool,
+    history: Option<History>,
+    /// Bytes overwritten by the current step, filled in by a `JournaledMemory`.
+    journal: Rc<RefCell<Vec<(u16, u8)>>>,
+    /// Bytes overwritten by device DMA in the current step, journaled while `journaling`.
+    dma_journal: Rc<RefCell<Vec<(u16, u8)>>>,
+    /// Whether `memory` has been wrapped in a `JournaledMemory` yet.
+    journaling: bool,
+    /// The PC of the current step, for tagging traced memory accesses.
+    trace_pc: Rc<Cell<u16>>,
+    /// Where the stack starts on a machine built with `with_config`; it grows up to
+    /// `stack_end`, and programs may not be loaded there.
+    stack: Option<u16>,
+    stack_end: usize,
+    /// Whether word accesses and instructions at odd addresses fault.
+    check_alignment: bool,
+    /// The byte order of instruction words and data words in memory.
+    endianness: Endianness,
+    /// Loaded by `LCS` and `LDS`, used by a `SegmentedMemory` from `enable_segments`.
+    segments: Rc<Cell<Segments>>,
+    /// The memory `enable_segments` mapped, saved whole by snapshots.
+    segmented: Option<Rc<RefCell<SegmentedMemory>>>,
+    /// The registers as mapped by `map_registers`.
+    register_window: Option<Rc<RefCell<RegisterWindow>>>,
+    /// Ticked after every instruction by `step`.
+    devices: Vec<Rc<RefCell<dyn Device>>>,
+    /// Interrupts raised by devices and `raise_interrupt`, waiting for `Flag::Interrupt`.
+    pending_interrupts: BTreeSet<u8>,
+}
+
+/// A copy of the registers that memory accesses see, with the ones written through memory
+/// since the machine last synchronized it.
+#[derive(Default)]
+struct RegisterWindow {
+    values: [u16; REGISTER_COUNT],
+    /// One bit per register.
+    written: u16,
+    /// The machine's, so that word loads and stores see whole registers.
+    endianness: Endianness,
+}
+
+impl Addressable for RegisterWindow {
+    fn read(&self, address: u16) -> Option<u8> {
+        let value = self.values.get(address as usize / 2)?;
+        Some(self.endianness.to_bytes(*value)[address as usize % 2])
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> bool {
+        let index = address as usize / 2;
+        let Some(register) = self.values.get_mut(index) else {
+            return false;
+        };
+        let mut bytes = self.endianness.to_bytes(*register);
+        bytes[address as usize % 2] = value;
+        *register = self.endianness.from_bytes(bytes);
+        self.written |= 1 << index;
+        true
+    }
+
+    fn peek(&self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    fn poke(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+}
+
+/// The layout of a new `Machine`: `memory_size` bytes of RAM at 0, a stack growing up from
+/// `stack` to the end of RAM, and PC at `entry`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MachineConfig {
+    pub memory_size: usize,
+    pub stack: u16,
+    pub entry: u16,
+}
+
+impl Default for MachineConfig {
+    /// 8 KiB of RAM, with code from 0 and the stack in the upper 4 KiB.
+    fn default() -> Self {
+        Self {
+            memory_size: 8 * 1024,
+            stack: 0x1000,
+            entry: 0,
+        }
+    }
+}
+
+impl Default for Machine {
+    /// The same machine as `new`.
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Machine {
+    /// A machine with 8 KiB of RAM and every register 0, so the stack starts at 0 where
+    /// programs are usually loaded; `with_config` separates them.
     pub fn new() -> Self {
+        Self::with_ram(8 * 1024)
+    }
+
+    /// A machine laid out by `config`, with SP and PC set. Fails if RAM is empty or larger
+    /// than the address space, or if the stack or entry point are outside it.
+    pub fn with_config(config: MachineConfig) -> Result<Self, String> {
+        let size = config.memory_size;
+        if size == 0 || size > 0x10000 {
+            return Err(format!(
+                "Memory size 0x{:X} is not 1 to 0x10000 bytes",
+                size
+            ));
+        }
+        for (name, address) in [("Stack", config.stack), ("Entry point", config.entry)] {
+            if address as usize >= size {
+                return Err(format!(
+                    "{} 0x{:04X} is outside 0x{:X} bytes of memory",
+                    name, address, size
+                ));
+            }
+        }
+        let mut machine = Self::with_ram(size);
+        machine.stack = Some(config.stack);
+        machine.stack_end = size;
+        machine.set_register(Register::SP, config.stack);
+        machine.set_register(Register::PC, config.entry);
+        Ok(machine)
+    }
+
+    fn with_ram(size: usize) -> Self {
+        let mut bus = MemoryMapper::new();
+        bus.map(0, size, LinearMemory::new(size))
+            .expect("RAM fits an empty bus");
+        let bus = Rc::new(RefCell::new(bus));
+        let mmu = Rc::new(RefCell::new(MmuState::default()));
         Self {
-            registers: [0; 8],
-            memory: Box::new(LinearMemory::new(8 * 1024)),
+            registers: [0; REGISTER_COUNT],
+            memory: Box::new(Mmu::new(Box::new(bus.clone()), mmu.clone())),
+            bus,
+            mmu,
+            halted: false,
+            interrupt_table: 0,
+            syscalls: HashMap::new(),
+            core_dump_path: None,
+            watchpoints: Rc::default(),
+            watching: false,
+            history: None,
+            journal: Rc::default(),
+            dma_journal: Rc::default(),
+            journaling: false,
+            trace_pc: Rc::default(),
+            stack: None,
+            stack_end: 0,
+            check_alignment: false,
+            endianness: Endianness::Little,
+            segments: Rc::default(),
+            segmented: None,
+            register_window: None,
+            devices: Vec::new(),
+            pending_interrupts: BTreeSet::new(),
         }
     }
 
     pub fn get_register(&self, reg: Register) -> u16 {
+        if let Some(window) = &self.register_window {
+            let window = window.borrow();
+            if window.written & (1 << reg as u16) != 0 {
+                return window.values[reg as usize];
+            }
+        }
         self.registers[reg as usize]
     }
 
+    pub fn set_register(&mut self, reg: Register, value: u16) {
+        self.sync_register_window(true);
+        self.registers[reg as usize] = value;
+        self.sync_register_window(false);
+    }
+
+    /// Maps the registers at `REGISTER_WINDOW`, so guest code and the debugger can read and
+    /// write them with ordinary loads and stores. Reads during an instruction see the
+    /// registers as they were before it; a register written through memory takes the new
+    /// value once the instruction is done, or before the next step for writes from the host.
+    pub fn map_registers(&mut self) -> Result<(), String> {
+        let window = Rc::new(RefCell::new(RegisterWindow::default()));
+        self.map(REGISTER_WINDOW, REGISTER_COUNT * 2, window.clone())?;
+        self.register_window = Some(window);
+        self.sync_register_window(false);
+        Ok(())
+    }
+
+    /// Copies the registers to the register window, first taking any written through it
+    /// if `apply` is set.
+    fn sync_register_window(&mut self, apply: bool) {
+        let Some(window) = &self.register_window else {
+            return;
+        };
+        let mut window = window.borrow_mut();
+        for (i, register) in self.registers.iter_mut().enumerate() {
+            if apply && window.written & (1 << i) != 0 {
+                *register = window.values[i];
+            }
+        }
+        window.values = self.registers;
+        window.written = 0;
+        window.endianness = self.endianness;
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn halt(&mut self) {
+        self.halted = true;
+    }
+
+    /// Sets the address of the interrupt table, an array of 16-bit handler addresses
+    /// indexed by interrupt number.
+    pub fn set_interrupt_table(&mut self, address: u16) {
+        self.interrupt_table = address;
+    }
+
+    pub fn interrupt(&mut self, n: u8) -> Result<(), String> {
+        let entry = self.interrupt_table.wrapping_add(n as u16 * 2);
+        let handler = self.load(entry)?;
+        self.push(self.registers[Register::FLAGS as usize])?;
+        self.push(self.registers[Register::PC as usize])?;
+        self.jump(handler);
+        Ok(())
+    }
+
+    /// Queues interrupt `n` to be taken before the next instruction once `Flag::Interrupt`
+    /// is set. Pending interrupts are taken lowest number first.
+    pub fn raise_interrupt(&mut self, n: u8) {
+        self.pending_interrupts.insert(n);
+    }
+
+    pub fn register_syscall<H>(&mut self, n: u8, handler: H)
+    where
+        H: SyscallHandler + 'static,
+    {
+        self.syscalls.insert(n, Box::new(handler));
+    }
+
+    fn syscall(&mut self, n: u8) -> Result<(), String> {
+        let mut handler = self
+            .syscalls
+            .remove(&n)
+            .ok_or(format!("Unknown syscall 0x{:X}", n))?;
+        let result = handler.handle(self);
+        self.syscalls.entry(n).or_insert(handler);
+        result
+    }
+
+    pub fn get_flag(&self, flag: Flag) -> bool {
+        self.registers[Register::FLAGS as usize] & flag.mask() != 0
+    }
+
+    pub fn set_flag(&mut self, flag: Flag, value: bool) {
+        if value {
+            self.registers[Register::FLAGS as usize] |= flag.mask();
+        } else {
+            self.registers[Register::FLAGS as usize] &= !flag.mask();
+        }
+    }
+
+    fn less_than(&self) -> bool {
+        self.get_flag(Flag::Negative) != self.get_flag(Flag::Overflow)
+    }
+
+    fn set_result_flags(&mut self, result: u16) {
+        self.set_flag(Flag::Zero, result == 0);
+        self.set_flag(Flag::Negative, result & 0x8000 != 0);
+    }
+
+    fn rotate_left(&mut self, mut value: u16, amount: u8, through_carry: bool) -> u16 {
+        for _ in 0..amount {
+            let out = value >> 15;
+            let fill = if through_carry {
+                self.get_flag(Flag::Carry) as u16
+            } else {
+                out
+            };
+            value = (value << 1) | fill;
+            self.set_flag(Flag::Carry, out != 0);
+        }
+        value
+    }
+
+    fn rotate_right(&mut self, mut value: u16, amount: u8, through_carry: bool) -> u16 {
+        for _ in 0..amount {
+            let out = value & 1;
+            let fill = if through_carry {
+                self.get_flag(Flag::Carry) as u16
+            } else {
+                out
+            };
+            value = (value >> 1) | (fill << 15);
+            self.set_flag(Flag::Carry, out != 0);
+        }
+        value
+    }
+
+    fn add_with_carry(&mut self, a: u16, b: u16, carry: bool) -> u16 {
+        let sum = a as u32 + b as u32 + carry as u32;
+        let result = sum as u16;
+        self.set_result_flags(result);
+        self.set_flag(Flag::Carry, sum > 0xffff);
+        self.set_flag(Flag::Overflow, !(a ^ b) & (a ^ result) & 0x8000 != 0);
+        result
+    }
+
+    fn sub_with_borrow(&mut self, a: u16, b: u16, borrow: bool) -> u16 {
+        let subtrahend = b as u32 + borrow as u32;
+        let result = (a as u32).wrapping_sub(subtrahend) as u16;
+        self.set_result_flags(result);
+        self.set_flag(Flag::Carry, (a as u32) < subtrahend);
+        self.set_flag(Flag::Overflow, (a ^ b) & (a ^ result) & 0x8000 != 0);
+        result
+    }
+
+    fn compare(&mut self, a: u16, b: u16) -> u16 {
+        self.sub_with_borrow(a, b, false)
+    }
+
+    fn saved_registers() -> impl DoubleEndedIterator<Item = usize> {
+        (0..REGISTER_COUNT).filter(|&r| r != Register::SP as usize && r != Register::PC as usize)
+    }
+
     pub fn pop(&mut self) -> Result<u16, String> {
-        let sp = self.registers[Register::SP as usize] - 2;
-        if let Some(v) = self.memory.read2(sp) {
-            self.registers[Register::SP as usize] -= 2;
+        let sp = self.registers[Register::SP as usize]
+            .checked_sub(2)
+            .ok_or("Stack underflow")?;
+        self.check_aligned(sp)?;
+        if let Some(v) = self.read_word(sp) {
+            self.registers[Register::SP as usize] = sp;
             Ok(v)
         } else {
             Err("Stack underflow".to_string())
@@ -101,106 +1063,3103 @@ impl Machine {
 
     pub fn push(&mut self, value: u16) -> Result<(), String> {
         let sp = self.registers[Register::SP as usize];
-        if !self.memory.write2(sp, value) {
+        self.check_aligned(sp)?;
+        let top = sp.checked_add(2).ok_or("Stack overflow")?;
+        if !self.write_word(sp, value) {
             return Err("Stack overflow".to_string());
         }
-        self.registers[Register::SP as usize] += 2;
+        self.registers[Register::SP as usize] = top;
         Ok(())
     }
 
-    pub fn step(&mut self) -> Result<(), String> {
-        let pc = self.registers[Register::PC as usize];
-        let instruction = self.memory.read2(pc).unwrap();
-        self.registers[Register::PC as usize] += 2;
+    /// Makes word loads, stores, pushes and pops at odd addresses fail, and instructions at
+    /// odd addresses too, instead of splitting the word across the two bytes. Off by default.
+    pub fn set_alignment_checks(&mut self, on: bool) {
+        self.check_alignment = on;
+    }
 
-        let op = parse_instruction(instruction)?;
-        match op {
-            Op::Nop => Ok(()),
-            Op::Push(arg) => self.push(arg.into()),
-            Op::PopRegister(reg) => {
-                let value = self.pop()?;
-                self.registers[reg as usize] = value;
-                Ok(())
-            }
-            Op::AddStack => {
-                let reg1 = self.pop()?;
-                let reg2 = self.pop()?;
-                self.push(reg1 + reg2)
-            }
-            Op::AddRegister(reg1, reg2) => {
-                self.registers[reg1 as usize] += self.registers[reg2 as usize];
-                Ok(())
-            }
-            Op::Mov(reg1, reg2) => {
-                self.registers[reg1 as usize] = self.registers[reg2 as usize];
-                Ok(())
+    fn check_aligned(&self, address: u16) -> Result<(), String> {
+        if self.check_alignment && !address.is_multiple_of(2) {
+            return Err(format!("Misaligned word access at 0x{:X}", address));
+        }
+        Ok(())
+    }
+
+    /// Writes `n` bytes from `to` on for MEMCPY and MEMSET, lowest address first: each is
+    /// read from `from` when copying, so an overlapping copy sees its own writes, or else is
+    /// `value`. Both ranges must fit in the address space, and on a fault the bytes already
+    /// written are put back, so the instruction leaves memory as it was. The error names the
+    /// range that failed.
+    fn write_block(&mut self, to: u16, n: u16, from: Option<u16>, value: u8) -> Result<(), String> {
+        let invalid = |address: u16| format!("Invalid memory range 0x{:X}+0x{:X}", address, n);
+        let fits = |address: u16| address as usize + n as usize <= 0x10000;
+        if let Some(from) = from.filter(|&from| !fits(from)) {
+            return Err(invalid(from));
+        }
+        if !fits(to) {
+            return Err(invalid(to));
+        }
+        let mut saved = Vec::with_capacity(n as usize);
+        for i in 0..n {
+            let byte = match from {
+                Some(from) => self.memory.read(from + i).ok_or_else(|| invalid(from)),
+                None => Ok(value),
+            };
+            let previous = self.memory.peek(to + i);
+            let result = byte.and_then(|byte| match self.memory.write(to + i, byte) {
+                true => Ok(()),
+                false => Err(invalid(to)),
+            });
+            if let Err(error) = result {
+                for (i, previous) in saved.into_iter().enumerate().rev() {
+                    if let Some(previous) = previous {
+                        self.memory.poke(to + i as u16, previous);
+                    }
+                }
+                return Err(error);
             }
+            saved.push(previous);
         }
-        // Ok(())
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Sets the byte order of words in memory: instructions, their operands, loads, stores
+    /// and the stack. Little-endian by default.
+    pub fn set_endianness(&mut self, endianness: Endianness) {
+        self.endianness = endianness;
+        self.sync_register_window(true);
+    }
 
-    #[test]
-    fn test_parse_instruction() {
-        assert!(matches!(parse_instruction(0x0), Ok(Op::Nop)));
-        assert!(matches!(parse_instruction(0x1), Ok(Op::Push(0))));
-        assert!(matches!(
-            parse_instruction(0x2),
-            Ok(Op::PopRegister(Register::A))
-        ));
-        assert!(matches!(parse_instruction(0x3), Ok(Op::AddStack)));
-        assert!(matches!(
-            parse_instruction(0x4),
-            Ok(Op::AddRegister(Register::A, Register::B))
-        ));
-        assert!(matches!(
-            parse_instruction(0x5),
-            Ok(Op::Mov(Register::A, Register::B))
-        ));
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
     }
 
-    #[test]
-    fn test_push_pop() {
-        let mut m = Machine::new();
-        m.push(0x1234).unwrap();
-        m.push(0x5678).unwrap();
-        assert_eq!(m.pop().unwrap(), 0x5678);
-        assert_eq!(m.pop().unwrap(), 0x1234);
+    /// The word at `address` in the machine's byte order, read with `peek` so that nothing
+    /// notices: no watchpoint, trace or cache sees it and no device is touched. For host
+    /// tools such as backtraces and debugger views.
+    pub fn peek_word(&self, address: u16) -> Option<u16> {
+        let first = self.memory.peek(address)?;
+        let second = self.memory.peek(address.checked_add(1)?)?;
+        Some(self.endianness.from_bytes([first, second]))
     }
 
-    #[test]
-    fn test_add_stack() {
-        let mut m = Machine::new();
-        m.push((0x8 << 8) + 0x1).unwrap();
-        m.push((0x9 << 8) + 0x1).unwrap();
-        m.push(0x3).unwrap();
-        m.step().unwrap();
-        m.step().unwrap();
-        m.step().unwrap();
-        assert_eq!(m.pop().unwrap(), 8 + 9);
+    fn read_word(&self, address: u16) -> Option<u16> {
+        match self.endianness {
+            Endianness::Little => self.memory.read2(address),
+            Endianness::Big => self.memory.read2_be(address),
+        }
     }
 
-    #[test]
-    fn test_add_register() {
-        let mut m = Machine::new();
-        m.registers[Register::A as usize] = 0x9;
-        m.registers[Register::B as usize] = 0x8;
-        m.memory.write(0, 0x4);
-        m.step().unwrap();
-        assert_eq!(m.get_register(Register::A), 0x8 + 0x9);
+    fn write_word(&mut self, address: u16, value: u16) -> bool {
+        match self.endianness {
+            Endianness::Little => self.memory.write2(address, value),
+            Endianness::Big => self.memory.write2_be(address, value),
+        }
     }
 
-    #[test]
-    fn test_mov() {
-        let mut m = Machine::new();
-        m.registers[Register::A as usize] = 0x1234;
-        m.registers[Register::B as usize] = 0x5678;
-        m.memory.write(0, 0x5);
-        m.step().unwrap();
-        assert_eq!(m.get_register(Register::A), 0x5678);
+    fn load(&self, address: u16) -> Result<u16, String> {
+        self.check_aligned(address)?;
+        self.read_word(address)
+            .ok_or(format!("Invalid memory address 0x{:X}", address))
+    }
+
+    fn store(&mut self, address: u16, value: u16) -> Result<(), String> {
+        self.check_aligned(address)?;
+        if self.write_word(address, value) {
+            Ok(())
+        } else {
+            Err(format!("Invalid memory address 0x{:X}", address))
+        }
+    }
+
+    fn load_byte(&self, address: u16) -> Result<u8, String> {
+        self.memory
+            .read(address)
+            .ok_or(format!("Invalid memory address 0x{:X}", address))
+    }
+
+    fn store_byte(&mut self, address: u16, value: u8) -> Result<(), String> {
+        if self.memory.write(address, value) {
+            Ok(())
+        } else {
+            Err(format!("Invalid memory address 0x{:X}", address))
+        }
+    }
+
+    fn fetch(&mut self) -> Result<u16, String> {
+        let pc = self.registers[Register::PC as usize];
+        if self.check_alignment && !pc.is_multiple_of(2) {
+            return Err(format!("Misaligned instruction at 0x{:X}", pc));
+        }
+        let byte = |address| self.memory.fetch(address);
+        let word = byte(pc)
+            .zip(byte(pc.wrapping_add(1)))
+            .map(|(first, second)| self.endianness.from_bytes([first, second]))
+            .ok_or(format!("Invalid instruction address 0x{:X}", pc))?;
+        self.registers[Register::PC as usize] = pc.wrapping_add(2);
+        Ok(word)
+    }
+
+    fn jump(&mut self, address: u16) {
+        self.registers[Register::PC as usize] = address;
+    }
+
+    fn jump_if(&mut self, condition: bool, address: u16) {
+        if condition {
+            self.jump(address);
+        }
+    }
+
+    /// Makes `step` write a `CoreDump` to `path` whenever an instruction fails; `None` turns
+    /// this off again.
+    pub fn set_core_dump_path(&mut self, path: Option<PathBuf>) {
+        self.core_dump_path = path;
+    }
+
+    /// The core dump of the machine as it is now, blaming the instruction at `pc`. Like a
+    /// snapshot it is taken with `peek`, so devices are not touched; an instruction in a
+    /// device has no bytes.
+    pub fn core_dump(&self, pc: u16, error: &str) -> CoreDump {
+        CoreDump {
+            pc,
+            instruction: (0..4)
+                .map_while(|i| self.memory.peek(pc.checked_add(i)?))
+                .collect(),
+            error: error.to_string(),
+            snapshot: self.save_snapshot(),
+        }
+    }
+
+    /// Restores the registers and memory of a core dump for post-mortem inspection.
+    pub fn load_core(&mut self, core: &CoreDump) -> Result<(), String> {
+        self.load_snapshot(&core.snapshot)
+    }
+
+    /// Maps `backend` into the address space over `start..start + len`, which must not
+    /// overlap another region; the backend sees addresses relative to `start`. Has no effect
+    /// on what runs if `memory` was replaced.
+    pub fn map(
+        &mut self,
+        start: u16,
+        len: usize,
+        backend: impl Addressable + 'static,
+    ) -> Result<(), String> {
+        self.bus.borrow_mut().map(start, len, backend)
+    }
+
+    /// Maps `device` like `map` and ticks it after every instruction, raising the interrupts
+    /// it returns. Returns the device, to look at or drive from the host.
+    pub fn add_device<D: Device + 'static>(
+        &mut self,
+        start: u16,
+        len: usize,
+        device: D,
+    ) -> Result<Rc<RefCell<D>>, String> {
+        let device = Rc::new(RefCell::new(device));
+        self.map(start, len, device.clone())?;
+        self.devices.push(device.clone());
+        Ok(device)
+    }
+
+    /// Maps one `backend` at each of `starts`, so the same bytes show up in every mirror.
+    pub fn map_mirrored(
+        &mut self,
+        starts: &[u16],
+        len: usize,
+        backend: impl Addressable + 'static,
+    ) -> Result<(), String> {
+        self.bus.borrow_mut().map_mirrored(starts, len, backend)
+    }
+
+    /// Calls `callback` after each access from `start` to `end` (inclusive) matching `kind`,
+    /// tagged with the PC of the instruction, without stopping execution: enough for logging
+    /// or a simple device that reacts to a register being written. The callback must not use
+    /// the machine. Returns an id for `unobserve`.
+    pub fn observe(
+        &mut self,
+        start: u16,
+        end: u16,
+        kind: WatchKind,
+        mut callback: impl FnMut(MemoryAccess) + 'static,
+    ) -> usize {
+        let pc = self.trace_pc.clone();
+        self.bus
+            .borrow_mut()
+            .observe(start, end, kind, move |access| {
+                callback(MemoryAccess {
+                    pc: Some(pc.get()),
+                    ..access
+                })
+            })
+    }
+
+    pub fn unobserve(&mut self, id: usize) -> bool {
+        self.bus.borrow_mut().unobserve(id)
+    }
+
+    /// Removes the region mapped at `start`, such as the RAM at 0, and returns its backend.
+    pub fn unmap(&mut self, start: u16) -> Option<Box<dyn Addressable>> {
+        self.bus.borrow_mut().unmap(start)
+    }
+
+    /// Sets what the region mapped at `start` may be used for; returns false if there is
+    /// none. Fetching an instruction from a region needs `execute`, loads need `read` and
+    /// stores need `write`, and an instruction that breaks this fails with the reason.
+    pub fn protect(&mut self, start: u16, permissions: Permissions) -> bool {
+        self.bus.borrow_mut().protect(start, permissions)
+    }
+
+    /// The mapped regions as `(start, len)`, in address order.
+    pub fn memory_map(&self) -> Vec<(u16, usize)> {
+        self.bus
+            .borrow()
+            .regions()
+            .map(|r| (r.start, r.len))
+            .collect()
+    }
+
+    /// Translates every access from now on through the page table at physical address
+    /// `page_table` (see `Mmu`). An access the table does not allow raises interrupt
+    /// `PAGE_FAULT` with the instruction restarted; its handler finds the fault's cause and
+    /// address pushed after FLAGS and PC, and pops them before `IRET`. Without a handler
+    /// installed the fault is an error.
+    pub fn enable_mmu(&mut self, page_table: u16) {
+        self.mmu.borrow_mut().page_table = Some(page_table);
+    }
+
+    pub fn disable_mmu(&mut self) {
+        self.mmu.borrow_mut().page_table = None;
+    }
+
+    /// Replaces the RAM at 0 with a `SegmentedMemory` holding its contents at the start of
+    /// physical memory, so `LCS` and `LDS` can reach up to 1 MiB. Returns a handle for the
+    /// host to reach all of it.
+    pub fn enable_segments(&mut self) -> Result<Rc<RefCell<SegmentedMemory>>, String> {
+        let len = self
+            .memory_map()
+            .first()
+            .filter(|&&(start, _)| start == 0)
+            .map(|&(_, len)| len)
+            .ok_or("Segments replace the RAM at 0, and none is mapped")?;
+        let ram = self.unmap(0).expect("region at 0 is mapped");
+        let mut memory = SegmentedMemory::new(self.segments.clone());
+        ram.read_slice(0, &mut memory.bytes_mut()[..len]);
+        let memory = Rc::new(RefCell::new(memory));
+        self.map(0, len, memory.clone())?;
+        self.segmented = Some(memory.clone());
+        Ok(memory)
+    }
+
+    pub fn segments(&self) -> Segments {
+        self.segments.get()
+    }
+
+    pub fn set_segments(&mut self, segments: Segments) {
+        self.segments.set(segments);
+    }
+
+    /// Makes `run` stop after an instruction that accesses memory from `start` to `end`
+    /// (inclusive) in a way that matches `kind`. The first watchpoint wraps `memory` in a
+    /// `WatchedMemory`, so replace `memory` before adding any. Returns the watchpoint's id.
+    pub fn add_watchpoint(&mut self, start: u16, end: u16, kind: WatchKind) -> usize {
+        if !self.watching {
+            let inner = std::mem::replace(&mut self.memory, Box::new(LinearMemory::new(0)));
+            self.memory = Box::new(WatchedMemory::new(inner, self.watchpoints.clone()));
+            self.watching = true;
+        }
+        self.watchpoints.borrow_mut().add(start, end, kind)
+    }
+
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        self.watchpoints.borrow_mut().remove(id)
+    }
+
+    pub fn watchpoints(&self) -> Vec<Watchpoint> {
+        self.watchpoints.borrow().list.clone()
+    }
+
+    /// The watchpoint hits of the last `step`, instruction fetches included.
+    pub fn watch_hits(&self) -> Vec<WatchHit> {
+        self.watchpoints.borrow().hits.clone()
+    }
+
+    /// Passes every memory access from now on to `sink`, tagged with the PC of the step that
+    /// made it. Wraps `memory` in a `TracingMemory` each call, so replace `memory` before.
+    pub fn trace_memory(&mut self, sink: impl FnMut(MemoryAccess) + 'static) {
+        let inner = std::mem::replace(&mut self.memory, Box::new(LinearMemory::new(0)));
+        self.memory = Box::new(TracingMemory::new(inner, sink).with_pc(self.trace_pc.clone()));
+    }
+
+    /// Runs every memory access from now on through a simulated cache and returns it for its
+    /// statistics. Wraps `memory` in a `CachedMemory` each call, so replace `memory` before.
+    pub fn enable_cache(&mut self, config: CacheConfig) -> Result<Rc<RefCell<Cache>>, String> {
+        let cache = Rc::new(RefCell::new(Cache::new(config)?));
+        let inner = std::mem::replace(&mut self.memory, Box::new(LinearMemory::new(0)));
+        self.memory = Box::new(CachedMemory::new(inner, cache.clone()));
+        Ok(cache)
+    }
+
+    /// Records the last `capacity` steps so `step_back` can undo them; 0 stops recording.
+    /// The first call wraps `memory` in a `JournaledMemory`, so replace `memory` before.
+    pub fn record_history(&mut self, capacity: usize) {
+        if capacity == 0 {
+            self.history = None;
+            return;
+        }
+        if !self.journaling {
+            let inner = std::mem::replace(&mut self.memory, Box::new(LinearMemory::new(0)));
+            self.memory = Box::new(JournaledMemory::new(inner, self.journal.clone()));
+            self.journaling = true;
+        }
+        self.history = Some(History::new(capacity));
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Undoes the most recent recorded step: registers, the halted flag and every byte it or
+    /// a device's DMA wrote to memory go back to what they were before it. Device I/O cannot
+    /// be undone: stores to devices, device state such as a timer's count and pending
+    /// interrupts stay.
+    pub fn step_back(&mut self) -> Result<(), String> {
+        let record = self
+            .history
+            .as_mut()
+            .and_then(History::pop)
+            .ok_or("No recorded steps to undo")?;
+        for &(address, value) in record.dma_writes.iter().rev() {
+            self.bus.borrow_mut().poke(address, value);
+        }
+        for &(address, value) in record.writes.iter().rev() {
+            self.memory.poke(address, value);
+        }
+        self.journal.borrow_mut().clear();
+        self.dma_journal.borrow_mut().clear();
+        self.watchpoints.borrow_mut().hits.clear();
+        self.registers = record.registers;
+        self.halted = record.halted;
+        self.sync_register_window(false);
+        Ok(())
+    }
+
+    pub fn step(&mut self) -> Result<(), String> {
+        self.watchpoints.borrow_mut().hits.clear();
+        self.journal.borrow_mut().clear();
+        self.dma_journal.borrow_mut().clear();
+        self.sync_register_window(true);
+        let pc = self.registers[Register::PC as usize];
+        self.trace_pc.set(pc);
+        self.mmu.borrow_mut().fault = None;
+        self.bus.borrow().take_violation();
+        let (registers, halted) = (self.registers, self.halted);
+        let mut result = self.execute();
+        for device in &self.devices {
+            let interrupt = if self.journaling {
+                let bus = Box::new(self.bus.clone());
+                let mut bus = JournaledMemory::new(bus, self.dma_journal.clone());
+                device.borrow_mut().tick(&mut bus)
+            } else {
+                device.borrow_mut().tick(&mut *self.bus.borrow_mut())
+            };
+            if let Some(n) = interrupt {
+                self.pending_interrupts.insert(n);
+            }
+        }
+        if result.is_err() {
+            let fault = self.mmu.borrow_mut().fault.take();
+            if let Some(fault) = fault {
+                result = self.page_fault(fault, registers);
+            } else if let Some(violation) = self.bus.borrow().take_violation() {
+                result = Err(violation.to_string());
+            }
+        }
+        self.sync_register_window(true);
+        if let Some(history) = &mut self.history {
+            history.push(StepRecord {
+                registers,
+                halted,
+                writes: self.journal.take(),
+                dma_writes: self.dma_journal.take(),
+            });
+        }
+        if let (Err(error), Some(path)) = (&result, &self.core_dump_path) {
+            if let Err(e) = self.core_dump(pc, error).save(path) {
+                return Err(format!("{} (no core dump: {})", error, e));
+            }
+        }
+        result
+    }
+
+    /// Enters the `PAGE_FAULT` handler as though the faulting instruction, whose registers
+    /// were `registers`, had not run yet.
+    fn page_fault(
+        &mut self,
+        fault: PageFault,
+        registers: [u16; REGISTER_COUNT],
+    ) -> Result<(), String> {
+        let reason = if fault.present {
+            "page is read-only"
+        } else {
+            "page not present"
+        };
+        let error = format!("Page fault at 0x{:04X} ({})", fault.address, reason);
+        let entry = self.interrupt_table.wrapping_add(PAGE_FAULT as u16 * 2);
+        if self.load(entry).unwrap_or(0) == 0 {
+            return Err(error);
+        }
+        self.registers = registers;
+        self.interrupt(PAGE_FAULT)
+            .and_then(|_| self.push(fault.address))
+            .and_then(|_| self.push(fault.cause()))
+            .map_err(|e| format!("{} while handling a {}", e, error.to_lowercase()))
+    }
+
+    fn execute(&mut self) -> Result<(), String> {
+        if self.get_flag(Flag::Interrupt) {
+            if let Some(n) = self.pending_interrupts.pop_first() {
+                let result = self.interrupt(n);
+                self.set_flag(Flag::Interrupt, false);
+                return result;
+            }
+        }
+        let instruction = self.fetch()?;
+        let op = parse_instruction(instruction, || self.fetch())?;
+        match op {
+            Op::Nop => Ok(()),
+            Op::Push(arg) => self.push(arg.into()),
+            Op::PopRegister(reg) => {
+                let value = self.pop()?;
+                self.registers[reg as usize] = value;
+                Ok(())
+            }
+            Op::AddStack => {
+                let reg1 = self.pop()?;
+                let reg2 = self.pop()?;
+                let sum = self.add_with_carry(reg1, reg2, false);
+                self.push(sum)
+            }
+            Op::AddRegister(reg1, reg2) => {
+                let (r1, r2) = (reg1 as usize, reg2 as usize);
+                self.registers[r1] =
+                    self.add_with_carry(self.registers[r1], self.registers[r2], false);
+                Ok(())
+            }
+            Op::Mov(reg1, reg2) => {
+                self.registers[reg1 as usize] = self.registers[reg2 as usize];
+                Ok(())
+            }
+            Op::SubStack => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let difference = self.sub_with_borrow(a, b, false);
+                self.push(difference)
+            }
+            Op::SubRegister(reg1, reg2) => {
+                let (r1, r2) = (reg1 as usize, reg2 as usize);
+                self.registers[r1] =
+                    self.sub_with_borrow(self.registers[r1], self.registers[r2], false);
+                Ok(())
+            }
+            Op::MulStack => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(a.wrapping_mul(b))
+            }
+            Op::MulRegister(reg1, reg2) => {
+                let r1 = reg1 as usize;
+                self.registers[r1] = self.registers[r1].wrapping_mul(self.registers[reg2 as usize]);
+                Ok(())
+            }
+            Op::DivStack => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(divide(a, b)?)
+            }
+            Op::DivRegister(reg1, reg2) => {
+                let r1 = reg1 as usize;
+                self.registers[r1] = divide(self.registers[r1], self.registers[reg2 as usize])?;
+                Ok(())
+            }
+            Op::Shl(reg, amount) => {
+                let r = reg as usize;
+                self.registers[r] = shift_left(self.registers[r], amount.into());
+                Ok(())
+            }
+            Op::Shr(reg, amount) => {
+                let r = reg as usize;
+                self.registers[r] = shift_right(self.registers[r], amount.into());
+                Ok(())
+            }
+            Op::Sar(reg, amount) => {
+                let r = reg as usize;
+                self.registers[r] = shift_right_arithmetic(self.registers[r], amount.into());
+                Ok(())
+            }
+            Op::ShlRegister(reg1, reg2) => {
+                let r1 = reg1 as usize;
+                self.registers[r1] = shift_left(self.registers[r1], self.registers[reg2 as usize]);
+                Ok(())
+            }
+            Op::ShrRegister(reg1, reg2) => {
+                let r1 = reg1 as usize;
+                self.registers[r1] = shift_right(self.registers[r1], self.registers[reg2 as usize]);
+                Ok(())
+            }
+            Op::SarRegister(reg1, reg2) => {
+                let r1 = reg1 as usize;
+                self.registers[r1] =
+                    shift_right_arithmetic(self.registers[r1], self.registers[reg2 as usize]);
+                Ok(())
+            }
+            Op::Cmp(reg1, reg2) => {
+                self.compare(self.registers[reg1 as usize], self.registers[reg2 as usize]);
+                Ok(())
+            }
+            Op::Jmp(address) => {
+                self.jump(address);
+                Ok(())
+            }
+            Op::JmpRelative(offset) => {
+                let pc = self.registers[Register::PC as usize];
+                self.jump(pc.wrapping_add_signed(offset.into()));
+                Ok(())
+            }
+            Op::Jz(address) => {
+                self.jump_if(self.get_flag(Flag::Zero), address);
+                Ok(())
+            }
+            Op::Jnz(address) => {
+                self.jump_if(!self.get_flag(Flag::Zero), address);
+                Ok(())
+            }
+            Op::Jlt(address) => {
+                self.jump_if(self.less_than(), address);
+                Ok(())
+            }
+            Op::Jgt(address) => {
+                self.jump_if(!self.get_flag(Flag::Zero) && !self.less_than(), address);
+                Ok(())
+            }
+            Op::Jle(address) => {
+                self.jump_if(self.get_flag(Flag::Zero) || self.less_than(), address);
+                Ok(())
+            }
+            Op::Jge(address) => {
+                self.jump_if(!self.less_than(), address);
+                Ok(())
+            }
+            Op::Call(address) => {
+                self.push(self.registers[Register::PC as usize])?;
+                self.jump(address);
+                Ok(())
+            }
+            Op::Ret => {
+                let address = self.pop()?;
+                self.jump(address);
+                Ok(())
+            }
+            Op::PushRegister(reg) => self.push(self.registers[reg as usize]),
+            Op::Push16(value) => self.push(value),
+            Op::Load(dst, addr) => {
+                self.registers[dst as usize] = self.load(self.registers[addr as usize])?;
+                Ok(())
+            }
+            Op::Store(addr, src) => {
+                self.store(self.registers[addr as usize], self.registers[src as usize])
+            }
+            Op::LoadImm16(reg, value) => {
+                self.registers[reg as usize] = value;
+                Ok(())
+            }
+            Op::LoadImm(reg, value) => {
+                self.registers[reg as usize] = value.into();
+                Ok(())
+            }
+            Op::Halt => {
+                self.halted = true;
+                Ok(())
+            }
+            Op::Inc(reg) => {
+                let r = reg as usize;
+                let result = self.registers[r].wrapping_add(1);
+                self.set_result_flags(result);
+                self.set_flag(Flag::Overflow, result == 0x8000);
+                self.registers[r] = result;
+                Ok(())
+            }
+            Op::Dec(reg) => {
+                let r = reg as usize;
+                let result = self.registers[r].wrapping_sub(1);
+                self.set_result_flags(result);
+                self.set_flag(Flag::Overflow, result == 0x7fff);
+                self.registers[r] = result;
+                Ok(())
+            }
+            Op::Neg(reg) => {
+                let r = reg as usize;
+                self.registers[r] = self.compare(0, self.registers[r]);
+                Ok(())
+            }
+            Op::Rol(reg, amount) => {
+                let r = reg as usize;
+                self.registers[r] = self.rotate_left(self.registers[r], amount, false);
+                Ok(())
+            }
+            Op::Ror(reg, amount) => {
+                let r = reg as usize;
+                self.registers[r] = self.rotate_right(self.registers[r], amount, false);
+                Ok(())
+            }
+            Op::Rcl(reg, amount) => {
+                let r = reg as usize;
+                self.registers[r] = self.rotate_left(self.registers[r], amount, true);
+                Ok(())
+            }
+            Op::Rcr(reg, amount) => {
+                let r = reg as usize;
+                self.registers[r] = self.rotate_right(self.registers[r], amount, true);
+                Ok(())
+            }
+            Op::CmovZ(dst, src) => {
+                if self.get_flag(Flag::Zero) {
+                    self.registers[dst as usize] = self.registers[src as usize];
+                }
+                Ok(())
+            }
+            Op::CmovNz(dst, src) => {
+                if !self.get_flag(Flag::Zero) {
+                    self.registers[dst as usize] = self.registers[src as usize];
+                }
+                Ok(())
+            }
+            Op::Syscall(n) => self.syscall(n),
+            Op::Swap(reg1, reg2) => {
+                self.registers.swap(reg1 as usize, reg2 as usize);
+                Ok(())
+            }
+            Op::LoadByte(dst, addr) => {
+                self.registers[dst as usize] =
+                    self.load_byte(self.registers[addr as usize])?.into();
+                Ok(())
+            }
+            Op::StoreByte(addr, src) => {
+                let value = self.registers[src as usize] as u8;
+                self.store_byte(self.registers[addr as usize], value)
+            }
+            Op::MulWide(reg1, reg2) => {
+                let (r1, r2) = (reg1 as usize, reg2 as usize);
+                let product = self.registers[r1] as u32 * self.registers[r2] as u32;
+                self.registers[r1] = product as u16;
+                self.registers[r2] = (product >> 16) as u16;
+                Ok(())
+            }
+            Op::Adc(reg1, reg2) => {
+                let (r1, r2) = (reg1 as usize, reg2 as usize);
+                let carry = self.get_flag(Flag::Carry);
+                self.registers[r1] =
+                    self.add_with_carry(self.registers[r1], self.registers[r2], carry);
+                Ok(())
+            }
+            Op::Sbb(reg1, reg2) => {
+                let (r1, r2) = (reg1 as usize, reg2 as usize);
+                let borrow = self.get_flag(Flag::Carry);
+                self.registers[r1] =
+                    self.sub_with_borrow(self.registers[r1], self.registers[r2], borrow);
+                Ok(())
+            }
+            Op::Test(reg1, reg2) => {
+                self.set_result_flags(
+                    self.registers[reg1 as usize] & self.registers[reg2 as usize],
+                );
+                self.set_flag(Flag::Carry, false);
+                self.set_flag(Flag::Overflow, false);
+                Ok(())
+            }
+            Op::Int(n) => self.interrupt(n),
+            Op::Iret => {
+                let address = self.pop()?;
+                self.registers[Register::FLAGS as usize] = self.pop()?;
+                self.jump(address);
+                Ok(())
+            }
+            Op::JmpRegister(reg) => {
+                self.jump(self.registers[reg as usize]);
+                Ok(())
+            }
+            Op::SubImm(reg, imm) => {
+                let r = reg as usize;
+                self.registers[r] = self.sub_with_borrow(self.registers[r], imm.into(), false);
+                Ok(())
+            }
+            Op::AddImm(reg, imm) => {
+                let r = reg as usize;
+                self.registers[r] = self.add_with_carry(self.registers[r], imm.into(), false);
+                Ok(())
+            }
+            Op::PushAll => {
+                for r in Self::saved_registers() {
+                    self.push(self.registers[r])?;
+                }
+                Ok(())
+            }
+            Op::PopAll => {
+                for r in Self::saved_registers().rev() {
+                    self.registers[r] = self.pop()?;
+                }
+                Ok(())
+            }
+            Op::Sext8(reg) => {
+                let r = reg as usize;
+                self.registers[r] = self.registers[r] as u8 as i8 as u16;
+                Ok(())
+            }
+            Op::Zext8(reg) => {
+                self.registers[reg as usize] &= 0xff;
+                Ok(())
+            }
+            Op::BitTest(reg, bit) => {
+                let set = self.registers[reg as usize] & (1 << bit) != 0;
+                self.set_flag(Flag::Zero, !set);
+                Ok(())
+            }
+            Op::BitSet(reg, bit) => {
+                self.registers[reg as usize] |= 1 << bit;
+                Ok(())
+            }
+            Op::BitClear(reg, bit) => {
+                self.registers[reg as usize] &= !(1 << bit);
+                Ok(())
+            }
+            Op::BitToggle(reg, bit) => {
+                self.registers[reg as usize] ^= 1 << bit;
+                Ok(())
+            }
+            Op::Clz(reg) => {
+                let r = reg as usize;
+                self.registers[r] = self.registers[r].leading_zeros() as u16;
+                Ok(())
+            }
+            Op::Popcnt(reg) => {
+                let r = reg as usize;
+                self.registers[r] = self.registers[r].count_ones() as u16;
+                Ok(())
+            }
+            Op::Min(dst, src) => {
+                let (d, s) = (dst as usize, src as usize);
+                self.registers[d] = self.registers[d].min(self.registers[s]);
+                Ok(())
+            }
+            Op::Max(dst, src) => {
+                let (d, s) = (dst as usize, src as usize);
+                self.registers[d] = self.registers[d].max(self.registers[s]);
+                Ok(())
+            }
+            Op::MinSigned(dst, src) => {
+                let (d, s) = (dst as usize, src as usize);
+                self.registers[d] = (self.registers[d] as i16).min(self.registers[s] as i16) as u16;
+                Ok(())
+            }
+            Op::MaxSigned(dst, src) => {
+                let (d, s) = (dst as usize, src as usize);
+                self.registers[d] = (self.registers[d] as i16).max(self.registers[s] as i16) as u16;
+                Ok(())
+            }
+            Op::MemCpy(dst, src, len) => {
+                let to = self.registers[dst as usize];
+                let from = self.registers[src as usize];
+                let n = self.registers[len as usize];
+                self.write_block(to, n, Some(from), 0)
+            }
+            Op::MemSet(dst, value, len) => {
+                let to = self.registers[dst as usize];
+                let value = self.registers[value as usize] as u8;
+                let n = self.registers[len as usize];
+                self.write_block(to, n, None, value)
+            }
+            Op::Loop(reg, address) => {
+                let r = reg as usize;
+                self.registers[r] = self.registers[r].wrapping_sub(1);
+                self.jump_if(self.registers[r] != 0, address);
+                Ok(())
+            }
+            Op::Cas(addr, expected, new) => {
+                let address = self.registers[addr as usize];
+                let current = self.load(address)?;
+                let swapped = current == self.registers[expected as usize];
+                if swapped {
+                    self.store(address, self.registers[new as usize])?;
+                } else {
+                    self.registers[expected as usize] = current;
+                }
+                self.set_flag(Flag::Zero, swapped);
+                Ok(())
+            }
+            Op::AddSat(reg1, reg2) => {
+                let r1 = reg1 as usize;
+                self.registers[r1] =
+                    self.registers[r1].saturating_add(self.registers[reg2 as usize]);
+                Ok(())
+            }
+            Op::SubSat(reg1, reg2) => {
+                let r1 = reg1 as usize;
+                self.registers[r1] =
+                    self.registers[r1].saturating_sub(self.registers[reg2 as usize]);
+                Ok(())
+            }
+            Op::Mac(acc, a, b) => {
+                let product = self.registers[a as usize] as u32 * self.registers[b as usize] as u32;
+                let sum = self.registers[acc as usize] as u32 + product;
+                self.registers[acc as usize] = sum as u16;
+                self.set_flag(Flag::Carry, sum > 0xffff);
+                Ok(())
+            }
+            Op::PushFlags => self.push(self.registers[Register::FLAGS as usize]),
+            Op::PopFlags => {
+                self.registers[Register::FLAGS as usize] = self.pop()?;
+                Ok(())
+            }
+            Op::LoadCodeSegment(reg) => {
+                let code = self.registers[reg as usize];
+                self.segments.set(Segments {
+                    code,
+                    ..self.segments.get()
+                });
+                Ok(())
+            }
+            Op::LoadDataSegment(reg) => {
+                let data = self.registers[reg as usize];
+                self.segments.set(Segments {
+                    data,
+                    ..self.segments.get()
+                });
+                Ok(())
+            }
+        }
+        // Ok(())
+    }
+
+    /// Steps until the machine halts or an instruction hits a watchpoint.
+    pub fn run(&mut self) -> Result<(), String> {
+        while !self.halted {
+            self.step()?;
+            if !self.watchpoints.borrow().hits.is_empty() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// The bytes in `range`, stopping early where memory ends or is not readable. Memory is
+    /// read with `peek`, so watchpoints, caches and devices are not touched and a device
+    /// register ends the bytes like unmapped memory does.
+    pub fn read_memory(&self, range: impl RangeBounds<u16>) -> Vec<u8> {
+        let (start, end) = bounds(range);
+        self.read_span(start, end)
+    }
+
+    /// A `hex_dump` of the bytes in `range`.
+    pub fn dump_memory(&self, range: impl RangeBounds<u16>) -> String {
+        let (start, end) = bounds(range);
+        hex_dump(&self.read_span(start, end), start as u16)
+    }
+
+    fn read_span(&self, start: usize, end: usize) -> Vec<u8> {
+        (start..end)
+            .map_while(|address| self.memory.peek(address as u16))
+            .collect()
+    }
+
+    /// Copies `program` into memory starting at `at` and points PC at its first byte. On a
+    /// machine built with `with_config` the program must end below the stack.
+    pub fn load_program(&mut self, program: &[u8], at: u16) -> Result<(), String> {
+        if let Some(stack) = self.stack {
+            let end = at as usize + program.len();
+            if !program.is_empty() && end > stack as usize && (at as usize) < self.stack_end {
+                return Err(format!(
+                    "Program at 0x{:04X}-0x{:04X} overlaps the stack at 0x{:04X}",
+                    at,
+                    end - 1,
+                    stack
+                ));
+            }
+        }
+        if !self.memory.write_slice(at, program) {
+            return Err(format!(
+                "Program of {} bytes does not fit in memory at 0x{:X}",
+                program.len(),
+                at
+            ));
+        }
+        self.set_register(Register::PC, at);
+        Ok(())
+    }
+
+    /// Copies every segment of `object` to its address and points PC at the entry point.
+    pub fn load_object(&mut self, object: &ObjectFile) -> Result<(), String> {
+        for segment in &object.segments {
+            self.load_program(&segment.bytes, segment.address)?;
+        }
+        self.set_register(Register::PC, object.entry);
+        Ok(())
+    }
+
+    /// Serializes the registers, the halted flag, the interrupt table address, the segments
+    /// and every byte of memory mapped on the bus, at its physical address: mirrors once, and
+    /// with segments enabled all of the physical memory behind them. Memory is read with
+    /// `peek`, past the MMU and wrappers such as watchpoints; devices are left out and see no
+    /// accesses. If `memory` was replaced, the bus is not what runs, and the address space
+    /// is saved as `memory` shows it instead. Syscall handlers are not part of a snapshot;
+    /// register them again on the machine the snapshot is restored into.
+    ///
+    /// Layout, all numbers little endian:
+    ///
+    /// ```text
+    /// magic "TVMS" | version: u16 | register count: u16 | registers: u16 each
+    /// halted: u8   | interrupt table: u16 | code segment: u16 | data segment: u16
+    /// run count: u16 | per run of readable memory: address: u16 | length: u32 | length bytes
+    /// segmented memory length: u32 (0 or 1 MiB) | length bytes
+    /// ```
+    pub fn save_snapshot(&self) -> Vec<u8> {
+        let mut runs: Vec<(u16, Vec<u8>)> = Vec::new();
+        let mut add = |address: u16, byte: Option<u8>| match (byte, runs.last_mut()) {
+            (None, _) => {}
+            (Some(byte), Some((start, bytes)))
+                if *start as usize + bytes.len() == address as usize =>
+            {
+                bytes.push(byte)
+            }
+            (Some(byte), _) => runs.push((address, vec![byte])),
+        };
+        if self.memory_on_bus() {
+            let bus = self.bus.borrow();
+            let mut mirrors = BTreeSet::new();
+            for region in bus.regions() {
+                if region.mirror.is_some_and(|mirror| !mirrors.insert(mirror)) {
+                    continue;
+                }
+                for offset in 0..region.len {
+                    add(
+                        region.start + offset as u16,
+                        region.backend.peek(offset as u16),
+                    );
+                }
+            }
+        } else {
+            for address in 0..=u16::MAX {
+                add(address, self.memory.peek(address));
+            }
+        }
+
+        let mut bytes = SNAPSHOT_MAGIC.to_vec();
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(REGISTER_COUNT as u16).to_le_bytes());
+        for register in self.registers {
+            bytes.extend_from_slice(&register.to_le_bytes());
+        }
+        bytes.push(self.halted as u8);
+        bytes.extend_from_slice(&self.interrupt_table.to_le_bytes());
+        let segments = self.segments.get();
+        bytes.extend_from_slice(&segments.code.to_le_bytes());
+        bytes.extend_from_slice(&segments.data.to_le_bytes());
+        bytes.extend_from_slice(&(runs.len() as u16).to_le_bytes());
+        for (address, run) in runs {
+            bytes.extend_from_slice(&address.to_le_bytes());
+            bytes.extend_from_slice(&(run.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&run);
+        }
+        match &self.segmented {
+            Some(segmented) => {
+                let segmented = segmented.borrow();
+                bytes.extend_from_slice(&(segmented.bytes().len() as u32).to_le_bytes());
+                bytes.extend_from_slice(segmented.bytes());
+            }
+            None => bytes.extend_from_slice(&0u32.to_le_bytes()),
+        }
+        bytes
+    }
+
+    /// Restores a snapshot written by `save_snapshot`, writing memory with `poke` the way it
+    /// was read. The snapshot is checked before anything changes, except that memory is left
+    /// partly written if it does not fit this machine's bus.
+    pub fn load_snapshot(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if !bytes.starts_with(&SNAPSHOT_MAGIC) {
+            return Err("Not a TinyVM snapshot".to_string());
+        }
+        let mut reader = Reader {
+            bytes,
+            offset: SNAPSHOT_MAGIC.len(),
+            what: "snapshot",
+        };
+        let version = reader.u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(format!("Unsupported snapshot version {}", version));
+        }
+        let count = reader.u16()? as usize;
+        if count != REGISTER_COUNT {
+            return Err(format!(
+                "Snapshot has {} registers, expected {}",
+                count, REGISTER_COUNT
+            ));
+        }
+        let mut registers = [0; REGISTER_COUNT];
+        for register in &mut registers {
+            *register = reader.u16()?;
+        }
+        let halted = reader.take(1)?[0] != 0;
+        let interrupt_table = reader.u16()?;
+        let segments = Segments {
+            code: reader.u16()?,
+            data: reader.u16()?,
+        };
+        let mut runs = Vec::new();
+        for _ in 0..reader.u16()? {
+            let address = reader.u16()?;
+            let len = reader.u32()? as usize;
+            runs.push((address, reader.take(len)?));
+        }
+        let len = reader.u32()? as usize;
+        let physical = reader.take(len)?;
+        if reader.offset != bytes.len() {
+            return Err("Trailing bytes after the last memory run".to_string());
+        }
+        let segmented = match (&self.segmented, len) {
+            (_, 0) => None,
+            (Some(segmented), SEGMENTED_MEMORY_SIZE) => Some(segmented.clone()),
+            (Some(_), _) => {
+                return Err(format!(
+                    "Snapshot has 0x{:X} bytes of segmented memory, expected 0x{:X}",
+                    len, SEGMENTED_MEMORY_SIZE
+                ))
+            }
+            (None, _) => {
+                return Err("Snapshot has segmented memory; enable segments first".to_string())
+            }
+        };
+
+        let on_bus = self.memory_on_bus();
+        for (address, run) in runs {
+            for (i, byte) in run.iter().enumerate() {
+                let at = address as usize + i;
+                let written = at <= u16::MAX as usize
+                    && match on_bus {
+                        true => self.bus.borrow_mut().poke(at as u16, *byte),
+                        false => self.memory.poke(at as u16, *byte),
+                    };
+                if !written {
+                    return Err(format!(
+                        "Snapshot memory at 0x{:X} does not fit in this machine's memory",
+                        at
+                    ));
+                }
+            }
+        }
+        if let Some(segmented) = segmented {
+            segmented.borrow_mut().bytes_mut().copy_from_slice(physical);
+        }
+        self.segments.set(segments);
+        self.registers = registers;
+        self.halted = halted;
+        self.interrupt_table = interrupt_table;
+        self.sync_register_window(false);
+        if let Some(history) = &mut self.history {
+            // The recorded steps led to the old state, not this one.
+            *history = History::new(history.capacity());
+        }
+        Ok(())
+    }
+
+    /// Whether `memory` still leads to the bus, rather than having been replaced: the bus is
+    /// then shared with the `Mmu` at the bottom of `memory`.
+    fn memory_on_bus(&self) -> bool {
+        Rc::strong_count(&self.bus) > 1
+    }
+
+    /// Writes `save_snapshot` to `path`.
+    pub fn save_snapshot_file(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        fs::write(path, self.save_snapshot())
+            .map_err(|e| format!("Cannot write '{}': {}", path.display(), e))
+    }
+
+    /// Reads a snapshot from `path` and restores it with `load_snapshot`.
+    pub fn load_snapshot_file(&mut self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        let bytes =
+            fs::read(path).map_err(|e| format!("Cannot read '{}': {}", path.display(), e))?;
+        self.load_snapshot(&bytes)
+    }
+
+    /// Reads a raw binary image from `path` and loads it with `load_program`.
+    pub fn load_program_file(&mut self, path: impl AsRef<Path>, at: u16) -> Result<(), String> {
+        let path = path.as_ref();
+        let program =
+            fs::read(path).map_err(|e| format!("Cannot read '{}': {}", path.display(), e))?;
+        self.load_program(&program, at)
+    }
+}
+
+/// The addresses in `range` as `start..end`, where `end` may be 0x10000.
+fn bounds(range: impl RangeBounds<u16>) -> (usize, usize) {
+    let start = match range.start_bound() {
+        Bound::Included(&start) => start as usize,
+        Bound::Excluded(&start) => start as usize + 1,
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(&end) => end as usize + 1,
+        Bound::Excluded(&end) => end as usize,
+        Bound::Unbounded => 1 << 16,
+    };
+    (start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn parse(words: &[u16]) -> Result<Op, String> {
+        let mut rest = words[1..].iter();
+        parse_instruction(words[0], || {
+            rest.next().copied().ok_or("Missing operand".to_string())
+        })
+    }
+
+    #[test]
+    fn test_parse_instruction() {
+        assert!(matches!(parse(&[0x0]), Ok(Op::Nop)));
+        assert!(matches!(parse(&[0x1]), Ok(Op::Push(0))));
+        assert!(matches!(parse(&[0x2]), Ok(Op::PopRegister(Register::A))));
+        assert!(matches!(parse(&[0x3]), Ok(Op::AddStack)));
+        assert!(matches!(
+            parse(&[0x1004]),
+            Ok(Op::AddRegister(Register::A, Register::B))
+        ));
+        assert!(matches!(
+            parse(&[0x4]),
+            Ok(Op::AddRegister(Register::A, Register::A))
+        ));
+        assert!(matches!(
+            parse(&[0x1005]),
+            Ok(Op::Mov(Register::A, Register::B))
+        ));
+        assert!(matches!(
+            parse(&[0x3205]),
+            Ok(Op::Mov(Register::C, Register::M))
+        ));
+        assert!(matches!(
+            parse(&[0x8c05]),
+            Ok(Op::Mov(Register::H, Register::D))
+        ));
+        assert!(parse(&[0xd005]).is_err());
+        assert!(parse(&[0xf005]).is_err());
+        assert!(matches!(parse(&[0x6]), Ok(Op::SubStack)));
+        assert!(matches!(
+            parse(&[0x1207]),
+            Ok(Op::SubRegister(Register::C, Register::B))
+        ));
+        assert!(matches!(parse(&[0x8]), Ok(Op::MulStack)));
+        assert!(matches!(
+            parse(&[0x1009]),
+            Ok(Op::MulRegister(Register::A, Register::B))
+        ));
+        assert!(matches!(parse(&[0xa]), Ok(Op::DivStack)));
+        assert!(matches!(
+            parse(&[0x100b]),
+            Ok(Op::DivRegister(Register::A, Register::B))
+        ));
+        assert!(matches!(parse(&[0x420c]), Ok(Op::Shl(Register::C, 4))));
+        assert!(matches!(parse(&[0xf00d]), Ok(Op::Shr(Register::A, 15))));
+        assert!(matches!(parse(&[0x110e]), Ok(Op::Sar(Register::B, 1))));
+        assert!(matches!(
+            parse(&[0x100f]),
+            Ok(Op::ShlRegister(Register::A, Register::B))
+        ));
+        assert!(matches!(
+            parse(&[0x1010]),
+            Ok(Op::ShrRegister(Register::A, Register::B))
+        ));
+        assert!(matches!(
+            parse(&[0x1011]),
+            Ok(Op::SarRegister(Register::A, Register::B))
+        ));
+        assert!(matches!(
+            parse(&[0x1012]),
+            Ok(Op::Cmp(Register::A, Register::B))
+        ));
+        assert!(matches!(parse(&[0x13, 0x1234]), Ok(Op::Jmp(0x1234))));
+        assert!(parse(&[0x13]).is_err());
+        assert!(matches!(parse(&[0xfe14]), Ok(Op::JmpRelative(-2))));
+        assert!(matches!(parse(&[0x15, 0x10]), Ok(Op::Jz(0x10))));
+        assert!(matches!(parse(&[0x16, 0x10]), Ok(Op::Jnz(0x10))));
+        assert!(matches!(parse(&[0x17, 0x10]), Ok(Op::Jlt(0x10))));
+        assert!(matches!(parse(&[0x18, 0x10]), Ok(Op::Jgt(0x10))));
+        assert!(matches!(parse(&[0x19, 0x10]), Ok(Op::Jle(0x10))));
+        assert!(matches!(parse(&[0x1a, 0x10]), Ok(Op::Jge(0x10))));
+        assert!(matches!(parse(&[0x1b, 0x10]), Ok(Op::Call(0x10))));
+        assert!(matches!(parse(&[0x1c]), Ok(Op::Ret)));
+        assert!(matches!(parse(&[0x21d]), Ok(Op::PushRegister(Register::C))));
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let mut m = Machine::new();
+        m.push(0x1234).unwrap();
+        m.push(0x5678).unwrap();
+        assert_eq!(m.pop().unwrap(), 0x5678);
+        assert_eq!(m.pop().unwrap(), 0x1234);
+    }
+
+    #[test]
+    fn test_add_stack() {
+        let mut m = Machine::new();
+        m.push((0x8 << 8) + 0x1).unwrap();
+        m.push((0x9 << 8) + 0x1).unwrap();
+        m.push(0x3).unwrap();
+        m.step().unwrap();
+        m.step().unwrap();
+        m.step().unwrap();
+        assert_eq!(m.pop().unwrap(), 8 + 9);
+    }
+
+    #[test]
+    fn test_add_stack_flags() {
+        let adds = |a: u16, b: u16| {
+            let mut m = Machine::new();
+            m.registers[Register::SP as usize] = 0x1000;
+            m.push(a).unwrap();
+            m.push(b).unwrap();
+            m.memory.write(0, 0x3);
+            m.step().unwrap();
+            m
+        };
+
+        let mut m = adds(0xffff, 1);
+        assert_eq!(m.pop().unwrap(), 0);
+        assert!(m.get_flag(Flag::Zero));
+        assert!(m.get_flag(Flag::Carry));
+        assert!(!m.get_flag(Flag::Overflow));
+
+        let mut m = adds(0x7fff, 1);
+        assert_eq!(m.pop().unwrap(), 0x8000);
+        assert!(!m.get_flag(Flag::Carry));
+        assert!(m.get_flag(Flag::Overflow));
+        assert!(m.get_flag(Flag::Negative));
+
+        let mut m = adds(0x8000, 0x8000);
+        assert_eq!(m.pop().unwrap(), 0);
+        assert!(m.get_flag(Flag::Carry));
+        assert!(m.get_flag(Flag::Overflow));
+    }
+
+    #[test]
+    fn test_add_register() {
+        let mut m = Machine::new();
+        m.registers[Register::A as usize] = 0x9;
+        m.registers[Register::B as usize] = 0x8;
+        m.memory.write2(0, 0x1004);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0x8 + 0x9);
+    }
+
+    #[test]
+    fn test_mov() {
+        let mut m = Machine::new();
+        m.registers[Register::A as usize] = 0x1234;
+        m.registers[Register::B as usize] = 0x5678;
+        m.memory.write2(0, 0x1005);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0x5678);
+    }
+
+    #[test]
+    fn test_mov_c_m() {
+        let mut m = Machine::new();
+        m.registers[Register::C as usize] = 0x1234;
+        m.registers[Register::M as usize] = 0x5678;
+        m.memory.write2(0, 0x3205);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::C), 0x5678);
+        assert_eq!(m.get_register(Register::A), 0);
+    }
+
+    #[test]
+    fn test_default() {
+        assert_eq!(
+            Machine::default().save_snapshot(),
+            Machine::new().save_snapshot()
+        );
+    }
+
+    #[test]
+    fn test_sub_stack() {
+        let mut m = Machine::new();
+        m.push((0x9 << 8) + 0x1).unwrap();
+        m.push((0x4 << 8) + 0x1).unwrap();
+        m.push(0x6).unwrap();
+        m.step().unwrap();
+        m.step().unwrap();
+        m.step().unwrap();
+        assert_eq!(m.pop().unwrap(), 9 - 4);
+    }
+
+    #[test]
+    fn test_sub_register_wraps() {
+        let mut m = Machine::new();
+        m.registers[Register::A as usize] = 0x1;
+        m.registers[Register::B as usize] = 0x2;
+        m.memory.write(0, 0x7);
+        m.memory.write(1, 0x10);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0xffff);
+    }
+
+    #[test]
+    fn test_sub_register_flags() {
+        let sub = |a: u16, b: u16| {
+            let mut m = Machine::new();
+            m.registers[Register::A as usize] = a;
+            m.registers[Register::B as usize] = b;
+            m.memory.write2(0, 0x1007);
+            m.step().unwrap();
+            m
+        };
+
+        let m = sub(5, 5);
+        assert_eq!(m.get_register(Register::A), 0);
+        assert!(m.get_flag(Flag::Zero));
+        assert!(!m.get_flag(Flag::Carry));
+        assert!(!m.get_flag(Flag::Negative));
+
+        let m = sub(1, 2);
+        assert_eq!(m.get_register(Register::A), 0xffff);
+        assert!(m.get_flag(Flag::Carry));
+        assert!(m.get_flag(Flag::Negative));
+        assert!(!m.get_flag(Flag::Overflow));
+
+        let m = sub(0x8000, 1);
+        assert_eq!(m.get_register(Register::A), 0x7fff);
+        assert!(!m.get_flag(Flag::Carry));
+        assert!(!m.get_flag(Flag::Negative));
+        assert!(m.get_flag(Flag::Overflow));
+    }
+
+    #[test]
+    fn test_sub_stack_flags() {
+        let mut m = Machine::new();
+        m.registers[Register::SP as usize] = 0x1000;
+        m.push(0x7fff).unwrap();
+        m.push(0xffff).unwrap();
+        m.memory.write(0, 0x6);
+        m.step().unwrap();
+        assert_eq!(m.pop().unwrap(), 0x8000);
+        assert!(m.get_flag(Flag::Carry));
+        assert!(m.get_flag(Flag::Negative));
+        assert!(m.get_flag(Flag::Overflow));
+        assert!(!m.get_flag(Flag::Zero));
+    }
+
+    #[test]
+    fn test_mul_register_truncates() {
+        let mut m = Machine::new();
+        m.registers[Register::A as usize] = 0x1234;
+        m.registers[Register::B as usize] = 0x100;
+        m.memory.write(0, 0x9);
+        m.memory.write(1, 0x10);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0x3400);
+    }
+
+    #[test]
+    fn test_div_stack() {
+        let mut m = Machine::new();
+        m.push((0x9 << 8) + 0x1).unwrap();
+        m.push((0x2 << 8) + 0x1).unwrap();
+        m.push(0xa).unwrap();
+        m.step().unwrap();
+        m.step().unwrap();
+        m.step().unwrap();
+        assert_eq!(m.pop().unwrap(), 9 / 2);
+    }
+
+    #[test]
+    fn test_div_by_zero() {
+        let mut m = Machine::new();
+        m.registers[Register::A as usize] = 0x9;
+        m.memory.write(0, 0xb);
+        m.memory.write(1, 0x10);
+        assert_eq!(m.step(), Err("Division by zero".to_string()));
+    }
+
+    #[test]
+    fn test_shift_immediate() {
+        let mut m = Machine::new();
+        m.registers[Register::A as usize] = 0x8001;
+        m.memory.write2(0, 0x400c);
+        m.memory.write2(2, 0x400d);
+        m.memory.write2(4, 0x400e);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0x0010);
+        m.registers[Register::A as usize] = 0x8001;
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0x0800);
+        m.registers[Register::A as usize] = 0x8001;
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0xf800);
+    }
+
+    #[test]
+    fn test_shift_register_out_of_range() {
+        let mut m = Machine::new();
+        m.registers[Register::B as usize] = 16;
+        m.memory.write2(0, 0x100f);
+        m.memory.write2(2, 0x1011);
+        m.registers[Register::A as usize] = 0x1234;
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0);
+        m.registers[Register::A as usize] = 0x8000;
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0xffff);
+    }
+
+    #[test]
+    fn test_cmp_flags() {
+        let mut m = Machine::new();
+        m.memory.write2(0, 0x1012);
+        m.memory.write2(2, 0x1012);
+        m.memory.write2(4, 0x1012);
+        m.registers[Register::A as usize] = 5;
+        m.registers[Register::B as usize] = 5;
+        m.step().unwrap();
+        assert!(m.get_flag(Flag::Zero));
+        assert!(!m.get_flag(Flag::Carry));
+        assert!(!m.get_flag(Flag::Negative));
+        assert_eq!(m.get_register(Register::A), 5);
+
+        m.registers[Register::A as usize] = 3;
+        m.step().unwrap();
+        assert!(!m.get_flag(Flag::Zero));
+        assert!(m.get_flag(Flag::Carry));
+        assert!(m.get_flag(Flag::Negative));
+        assert!(!m.get_flag(Flag::Overflow));
+
+        m.registers[Register::A as usize] = 0x8000;
+        m.registers[Register::B as usize] = 1;
+        m.step().unwrap();
+        assert!(!m.get_flag(Flag::Carry));
+        assert!(!m.get_flag(Flag::Negative));
+        assert!(m.get_flag(Flag::Overflow));
+    }
+
+    #[test]
+    fn test_jmp() {
+        let mut m = Machine::new();
+        m.memory.write2(0, 0x13);
+        m.memory.write2(2, 0x100);
+        m.memory.write2(0x100, 0x0414);
+        m.memory.write2(0x106, 0xf814);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::PC), 0x100);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::PC), 0x106);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::PC), 0x100);
+    }
+
+    fn branch_taken(jump: u16, a: u16, b: u16) -> bool {
+        let mut m = Machine::new();
+        m.registers[Register::A as usize] = a;
+        m.registers[Register::B as usize] = b;
+        m.memory.write2(0, 0x1012);
+        m.memory.write2(2, jump);
+        m.memory.write2(4, 0x100);
+        m.step().unwrap();
+        m.step().unwrap();
+        m.get_register(Register::PC) == 0x100
+    }
+
+    #[test]
+    fn test_conditional_jumps() {
+        let neg = (-3i16) as u16;
+        assert!(branch_taken(0x15, 4, 4));
+        assert!(!branch_taken(0x15, 4, 5));
+        assert!(branch_taken(0x16, 4, 5));
+        assert!(!branch_taken(0x16, 4, 4));
+        assert!(branch_taken(0x17, neg, 2));
+        assert!(!branch_taken(0x17, 2, neg));
+        assert!(!branch_taken(0x17, 2, 2));
+        assert!(branch_taken(0x18, 2, neg));
+        assert!(!branch_taken(0x18, 2, 2));
+        assert!(branch_taken(0x19, 2, 2));
+        assert!(branch_taken(0x19, neg, 2));
+        assert!(!branch_taken(0x19, 3, 2));
+        assert!(branch_taken(0x1a, 2, 2));
+        assert!(branch_taken(0x1a, 0x7fff, neg));
+        assert!(!branch_taken(0x1a, neg, 0x7fff));
+    }
+
+    #[test]
+    fn test_call_ret() {
+        let mut m = Machine::new();
+        m.registers[Register::SP as usize] = 0x1000;
+        m.memory.write2(0, 0x1b);
+        m.memory.write2(2, 0x100);
+        m.memory.write2(0x100, 0x1c);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::PC), 0x100);
+        assert_eq!(m.get_register(Register::SP), 0x1002);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::PC), 0x4);
+        assert_eq!(m.get_register(Register::SP), 0x1000);
+    }
+
+    #[test]
+    fn test_push_register() {
+        let mut m = Machine::new();
+        m.registers[Register::SP as usize] = 0x1000;
+        m.registers[Register::B as usize] = 0xbeef;
+        m.memory.write2(0, 0x11d);
+        m.step().unwrap();
+        assert_eq!(m.pop().unwrap(), 0xbeef);
+    }
+
+    #[test]
+    fn test_push16() {
+        let mut m = Machine::new();
+        m.registers[Register::SP as usize] = 0x1000;
+        m.memory.write2(0, 0x1e);
+        m.memory.write2(2, 0x1234);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::PC), 4);
+        assert_eq!(m.pop().unwrap(), 0x1234);
+    }
+
+    #[test]
+    fn test_load_store() {
+        let mut m = Machine::new();
+        m.registers[Register::M as usize] = 0x200;
+        m.registers[Register::B as usize] = 0xcafe;
+        m.memory.write2(0, 0x1320);
+        m.memory.write2(2, 0x301f);
+        m.step().unwrap();
+        assert_eq!(m.memory.read2(0x200), Some(0xcafe));
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0xcafe);
+    }
+
+    #[test]
+    fn test_load_out_of_range() {
+        let mut m = Machine::new();
+        m.registers[Register::M as usize] = 0xfff0;
+        m.memory.write2(0, 0x301f);
+        assert_eq!(m.step(), Err("Invalid memory address 0xFFF0".to_string()));
+    }
+
+    #[test]
+    fn test_load_imm() {
+        let mut m = Machine::new();
+        m.registers[Register::B as usize] = 0xffff;
+        m.memory.write2(0, 0x2af1);
+        m.memory.write2(2, 0x221);
+        m.memory.write2(4, 0x1234);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::B), 0x2a);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::C), 0x1234);
+        assert_eq!(m.get_register(Register::PC), 6);
+    }
+
+    #[test]
+    fn test_run_until_halt() {
+        let mut m = Machine::new();
+        m.registers[Register::SP as usize] = 0x1000;
+        m.memory.write2(0, 0x0201);
+        m.memory.write2(2, 0x0601);
+        m.memory.write2(4, 0x3);
+        m.memory.write2(6, 0x2);
+        m.memory.write2(8, 0x22);
+        m.run().unwrap();
+        assert!(m.is_halted());
+        assert_eq!(m.get_register(Register::A), 8);
+        assert_eq!(m.get_register(Register::PC), 10);
+    }
+
+    #[test]
+    fn test_run_stops_on_error() {
+        let mut m = Machine::new();
+        m.memory.write2(0, 0x50);
+        assert_eq!(m.run(), Err("Unknown instruction 0x50".to_string()));
+        assert!(!m.is_halted());
+        assert_eq!(m.get_register(Register::PC), 2);
+    }
+
+    #[test]
+    fn test_inc_dec_flags() {
+        let mut m = Machine::new();
+        m.registers[Register::C as usize] = 0xffff;
+        m.set_flag(Flag::Carry, true);
+        m.memory.write2(0, 0x223);
+        m.memory.write2(2, 0x224);
+        m.memory.write2(4, 0x224);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::C), 0);
+        assert!(m.get_flag(Flag::Zero));
+        assert!(m.get_flag(Flag::Carry));
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::C), 0xffff);
+        assert!(m.get_flag(Flag::Negative));
+        assert!(!m.get_flag(Flag::Zero));
+        m.registers[Register::C as usize] = 0x8000;
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::C), 0x7fff);
+        assert!(m.get_flag(Flag::Overflow));
+    }
+
+    #[test]
+    fn test_neg() {
+        let mut m = Machine::new();
+        m.memory.write2(0, 0x25);
+        m.memory.write2(2, 0x25);
+        m.memory.write2(4, 0x25);
+        m.registers[Register::A as usize] = 5;
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A) as i16, -5);
+        assert!(m.get_flag(Flag::Negative));
+        assert!(m.get_flag(Flag::Carry));
+        m.registers[Register::A as usize] = 0;
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0);
+        assert!(m.get_flag(Flag::Zero));
+        assert!(!m.get_flag(Flag::Carry));
+        m.registers[Register::A as usize] = 0x8000;
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0x8000);
+        assert!(m.get_flag(Flag::Overflow));
+    }
+
+    #[test]
+    fn test_rotate() {
+        let mut m = Machine::new();
+        m.registers[Register::A as usize] = 0x8001;
+        m.memory.write2(0, 0x4026);
+        m.memory.write2(2, 0x4027);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0x0018);
+        assert!(!m.get_flag(Flag::Carry));
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0x8001);
+        assert!(m.get_flag(Flag::Carry));
+    }
+
+    #[test]
+    fn test_rotate_through_carry() {
+        let mut m = Machine::new();
+        m.registers[Register::A as usize] = 0x8000;
+        m.memory.write2(0, 0x1028);
+        m.memory.write2(2, 0x1028);
+        m.memory.write2(4, 0x1029);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0);
+        assert!(m.get_flag(Flag::Carry));
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 1);
+        assert!(!m.get_flag(Flag::Carry));
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0);
+        assert!(m.get_flag(Flag::Carry));
+    }
+
+    #[test]
+    fn test_cmov() {
+        let mut m = Machine::new();
+        m.registers[Register::C as usize] = 0x42;
+        m.memory.write2(0, 0x202a);
+        m.memory.write2(2, 0x202b);
+        m.set_flag(Flag::Zero, false);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0x42);
+
+        m.registers[Register::PC as usize] = 0;
+        m.registers[Register::A as usize] = 0;
+        m.set_flag(Flag::Zero, true);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0x42);
+    }
+
+    #[test]
+    fn test_syscall() {
+        let mut m = Machine::new();
+        m.register_syscall(1, |m: &mut Machine| {
+            let a = m.get_register(Register::A);
+            m.set_register(Register::B, a * 2);
+            Ok(())
+        });
+        m.register_syscall(2, |m: &mut Machine| {
+            m.halt();
+            Ok(())
+        });
+        m.registers[Register::A as usize] = 21;
+        m.memory.write2(0, 0x12c);
+        m.memory.write2(2, 0x12c);
+        m.memory.write2(4, 0x22c);
+        m.run().unwrap();
+        assert_eq!(m.get_register(Register::B), 42);
+        assert_eq!(m.get_register(Register::PC), 6);
+    }
+
+    #[test]
+    fn test_unknown_syscall() {
+        let mut m = Machine::new();
+        m.memory.write2(0, 0x32c);
+        assert_eq!(m.step(), Err("Unknown syscall 0x3".to_string()));
+    }
+
+    #[test]
+    fn test_swap() {
+        let mut m = Machine::new();
+        m.registers[Register::B as usize] = 0x1111;
+        m.registers[Register::M as usize] = 0x2222;
+        m.memory.write2(0, 0x312d);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::B), 0x2222);
+        assert_eq!(m.get_register(Register::M), 0x1111);
+    }
+
+    #[test]
+    fn test_load_store_byte() {
+        let mut m = Machine::new();
+        m.registers[Register::M as usize] = 0x201;
+        m.registers[Register::B as usize] = 0xcafe;
+        m.registers[Register::A as usize] = 0xffff;
+        m.memory.write2(0, 0x132f);
+        m.memory.write2(2, 0x302e);
+        m.step().unwrap();
+        assert_eq!(m.memory.read2(0x200), Some(0xfe00));
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0x00fe);
+    }
+
+    #[test]
+    fn test_mul_wide() {
+        let mut m = Machine::new();
+        m.registers[Register::A as usize] = 0xffff;
+        m.registers[Register::B as usize] = 0x1234;
+        m.memory.write2(0, 0x1030);
+        m.step().unwrap();
+        let product = 0xffffu32 * 0x1234;
+        assert_eq!(m.get_register(Register::A), product as u16);
+        assert_eq!(m.get_register(Register::B), (product >> 16) as u16);
+
+        // MULW A, A
+        m.memory.write2(2, 0x0030);
+        assert_eq!(
+            m.step(),
+            Err("MULW needs two different registers, not A twice".to_string())
+        );
+    }
+
+    #[test]
+    fn test_adc_32bit_addition() {
+        // (B:A) += (M:C), low words first.
+        let x = 0x0001_ffffu32;
+        let y = 0x0002_0003u32;
+        let mut m = Machine::new();
+        m.registers[Register::A as usize] = x as u16;
+        m.registers[Register::B as usize] = (x >> 16) as u16;
+        m.registers[Register::C as usize] = y as u16;
+        m.registers[Register::M as usize] = (y >> 16) as u16;
+        m.memory.write2(0, 0x2031);
+        m.memory.write2(2, 0x3131);
+        m.step().unwrap();
+        assert!(m.get_flag(Flag::Carry));
+        m.step().unwrap();
+        assert!(!m.get_flag(Flag::Carry));
+        let sum = (m.get_register(Register::B) as u32) << 16 | m.get_register(Register::A) as u32;
+        assert_eq!(sum, x + y);
+    }
+
+    #[test]
+    fn test_sbb_32bit_subtraction() {
+        let x = 0x0003_0001u32;
+        let y = 0x0001_0002u32;
+        let mut m = Machine::new();
+        m.registers[Register::A as usize] = x as u16;
+        m.registers[Register::B as usize] = (x >> 16) as u16;
+        m.registers[Register::C as usize] = y as u16;
+        m.registers[Register::M as usize] = (y >> 16) as u16;
+        m.memory.write2(0, 0x2032);
+        m.memory.write2(2, 0x3132);
+        m.step().unwrap();
+        assert!(m.get_flag(Flag::Carry));
+        m.step().unwrap();
+        assert!(!m.get_flag(Flag::Carry));
+        let diff = (m.get_register(Register::B) as u32) << 16 | m.get_register(Register::A) as u32;
+        assert_eq!(diff, x - y);
+    }
+
+    #[test]
+    fn test_test_sets_flags_only() {
+        let mut m = Machine::new();
+        m.registers[Register::A as usize] = 0x8004;
+        m.registers[Register::B as usize] = 0x0004;
+        m.set_flag(Flag::Carry, true);
+        m.memory.write2(0, 0x1033);
+        m.memory.write2(2, 0x1033);
+        m.step().unwrap();
+        assert!(!m.get_flag(Flag::Zero));
+        assert!(!m.get_flag(Flag::Carry));
+        assert_eq!(m.get_register(Register::A), 0x8004);
+        m.registers[Register::B as usize] = 0x0002;
+        m.step().unwrap();
+        assert!(m.get_flag(Flag::Zero));
+        assert!(!m.get_flag(Flag::Negative));
+    }
+
+    #[test]
+    fn test_int_iret() {
+        let mut m = Machine::new();
+        m.set_interrupt_table(0x100);
+        m.registers[Register::SP as usize] = 0x1000;
+        m.memory.write2(0x106, 0x200);
+        m.memory.write2(0, 0x334);
+        m.memory.write2(0x200, 0x1033);
+        m.memory.write2(0x202, 0x35);
+        m.set_flag(Flag::Carry, true);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::PC), 0x200);
+        assert_eq!(m.get_register(Register::SP), 0x1004);
+        m.step().unwrap();
+        assert!(!m.get_flag(Flag::Carry));
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::PC), 2);
+        assert_eq!(m.get_register(Register::SP), 0x1000);
+        assert!(m.get_flag(Flag::Carry));
+    }
+
+    #[test]
+    fn test_raise_interrupt() {
+        let mut m = Machine::new();
+        m.set_interrupt_table(0x100);
+        m.registers[Register::SP as usize] = 0x1000;
+        m.memory.write2(0x104, 0x200);
+        m.memory.write2(0x106, 0x300);
+        m.raise_interrupt(3);
+        m.raise_interrupt(2);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::PC), 2);
+        m.set_flag(Flag::Interrupt, true);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::PC), 0x200);
+        assert!(!m.get_flag(Flag::Interrupt));
+        m.memory.write2(0x200, 0x35);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::PC), 2);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::PC), 0x300);
+    }
+
+    #[test]
+    fn test_jmp_register() {
+        let mut m = Machine::new();
+        m.registers[Register::M as usize] = 0x300;
+        m.memory.write2(0, 0x336);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::PC), 0x300);
+    }
+
+    #[test]
+    fn test_add_sub_imm() {
+        let mut m = Machine::new();
+        m.registers[Register::B as usize] = 0xfffe;
+        m.memory.write2(0, 0x03e1);
+        m.memory.write2(2, 0x02d1);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::B), 1);
+        assert!(m.get_flag(Flag::Carry));
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::B), 0xffff);
+        assert!(m.get_flag(Flag::Carry));
+        assert!(m.get_flag(Flag::Negative));
+    }
+
+    #[test]
+    fn test_push_pop_all() {
+        let mut m = Machine::new();
+        m.registers[Register::SP as usize] = 0x1000;
+        m.memory.write2(0, 0x37);
+        m.memory.write2(2, 0x38);
+        for (i, r) in Machine::saved_registers().enumerate() {
+            m.registers[r] = 0x100 + i as u16;
+        }
+        m.step().unwrap();
+        let saved = Machine::saved_registers().count() as u16;
+        assert_eq!(saved, REGISTER_COUNT as u16 - 2);
+        assert_eq!(m.get_register(Register::SP), 0x1000 + saved * 2);
+        assert_eq!(m.memory.read2(0x1000), Some(0x100));
+        for r in Machine::saved_registers() {
+            m.registers[r] = 0;
+        }
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::SP), 0x1000);
+        assert_eq!(m.get_register(Register::PC), 4);
+        for (i, r) in Machine::saved_registers().enumerate() {
+            assert_eq!(m.registers[r], 0x100 + i as u16);
+        }
+    }
+
+    #[test]
+    fn test_sign_zero_extend() {
+        let mut m = Machine::new();
+        m.memory.write2(0, 0x39);
+        m.memory.write2(2, 0x39);
+        m.memory.write2(4, 0x3a);
+        m.registers[Register::A as usize] = 0x1280;
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0xff80);
+        m.registers[Register::A as usize] = 0xff7f;
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0x007f);
+        m.registers[Register::A as usize] = 0xff80;
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0x0080);
+    }
+
+    #[test]
+    fn test_bit_ops() {
+        let mut m = Machine::new();
+        m.memory.write2(0, 0xf03c);
+        m.memory.write2(2, 0xf03b);
+        m.memory.write2(4, 0xf03d);
+        m.memory.write2(6, 0xf03b);
+        m.memory.write2(8, 0x003e);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0x8000);
+        m.step().unwrap();
+        assert!(!m.get_flag(Flag::Zero));
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0);
+        m.step().unwrap();
+        assert!(m.get_flag(Flag::Zero));
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 1);
+    }
+
+    #[test]
+    fn test_clz_popcnt() {
+        let mut m = Machine::new();
+        m.memory.write2(0, 0x3f);
+        m.memory.write2(2, 0x3f);
+        m.memory.write2(4, 0x40);
+        m.registers[Register::A as usize] = 0x00f0;
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 8);
+        m.registers[Register::A as usize] = 0;
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 16);
+        m.registers[Register::A as usize] = 0xf0f1;
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 9);
+    }
+
+    #[test]
+    fn test_min_max() {
+        let minus_one = 0xffff;
+        let run = |opcode: u16| {
+            let mut m = Machine::new();
+            m.registers[Register::A as usize] = 1;
+            m.registers[Register::B as usize] = minus_one;
+            m.memory.write2(0, 0x1000 | opcode);
+            m.step().unwrap();
+            m.get_register(Register::A)
+        };
+        assert_eq!(run(0x41), 1);
+        assert_eq!(run(0x42), minus_one);
+        assert_eq!(run(0x43), minus_one);
+        assert_eq!(run(0x44), 1);
+    }
+
+    #[test]
+    fn test_memset_memcpy() {
+        let mut m = Machine::new();
+        m.registers[Register::A as usize] = 0x200;
+        m.registers[Register::B as usize] = 0x1aa;
+        m.registers[Register::C as usize] = 4;
+        m.registers[Register::M as usize] = 0x300;
+        m.memory.write2(0, 0x1046);
+        m.memory.write2(2, 0x0002);
+        m.memory.write2(4, 0x0345);
+        m.memory.write2(6, 0x0002);
+        m.step().unwrap();
+        assert_eq!(m.read_memory(0x200..=0x204), [0xaa, 0xaa, 0xaa, 0xaa, 0]);
+        m.step().unwrap();
+        assert_eq!(m.read_memory(0x300..0x305), [0xaa, 0xaa, 0xaa, 0xaa, 0]);
+    }
+
+    #[test]
+    fn test_dump_memory() {
+        let mut m = Machine::new();
+        for (i, b) in b"Hello, world!\n\0\x7f tinyvm".iter().enumerate() {
+            m.memory.write(0x1FF0 + i as u16, *b);
+        }
+        assert_eq!(
+            m.dump_memory(0x1FF0..0x1FF5),
+            "1FF0  48 65 6C 6C 6F                                   |Hello|\n"
+        );
+        assert_eq!(
+            m.dump_memory(0x1FF0..),
+            "1FF0  48 65 6C 6C 6F 2C 20 77 6F 72 6C 64 21 0A 00 7F  |Hello, world!...|\n"
+        );
+        assert_eq!(m.read_memory(0x1FFE..), [0, 0x7f]);
+        assert!(m.read_memory(0x2000..=0xFFFF).is_empty());
+        assert_eq!(m.dump_memory(0x10..0x10), "");
+    }
+
+    #[test]
+    fn test_read_memory_quietly() {
+        use crate::uart::{Uart, UART_ADDRESS, UART_DATA, UART_RX_READY, UART_STATUS};
+        let uart = Rc::new(RefCell::new(Uart::new(
+            std::io::Cursor::new(b"x".to_vec()),
+            Vec::new(),
+        )));
+        while uart.borrow().read(UART_STATUS).unwrap() & UART_RX_READY == 0 {
+            std::thread::yield_now();
+        }
+        let mut m = Machine::new();
+        m.map(UART_ADDRESS - 2, 2, LinearMemory::new(2)).unwrap();
+        m.map(UART_ADDRESS, 2, uart.clone()).unwrap();
+        m.memory.write(UART_ADDRESS - 1, 0x42);
+        m.add_watchpoint(0x100, 0x10F, WatchKind::Read);
+
+        // The read stops at the UART instead of taking its received byte.
+        assert_eq!(m.read_memory(UART_ADDRESS - 2..UART_ADDRESS + 2), [0, 0x42]);
+        assert_eq!(uart.borrow().read(UART_DATA), Some(b'x'));
+        assert_eq!(m.read_memory(0x100..0x104), [0; 4]);
+        assert!(m.watch_hits().is_empty());
+    }
+
+    #[test]
+    fn test_memcpy_out_of_range() {
+        let mut m = Machine::new();
+        m.registers[Register::A as usize] = 0x1ffe;
+        m.registers[Register::C as usize] = 4;
+        m.memory.write2(0, 0x1045);
+        m.memory.write2(2, 0x0002);
+        assert_eq!(m.step(), Err("Invalid memory range 0x1FFE+0x4".to_string()));
+        // The bytes that fit were put back.
+        assert_eq!(m.read_memory(0x1FFE..), [0, 0]);
+
+        m.registers[Register::A as usize] = 0;
+        m.registers[Register::B as usize] = 0x1ffe;
+        m.registers[Register::PC as usize] = 0;
+        assert_eq!(m.step(), Err("Invalid memory range 0x1FFE+0x4".to_string()));
+        assert_eq!(m.read_memory(0..4), [0x45, 0x10, 0x02, 0x00]);
+    }
+
+    #[test]
+    fn test_memset_top_of_memory() {
+        let full = MachineConfig {
+            memory_size: 0x10000,
+            stack: 0xF000,
+            ..MachineConfig::default()
+        };
+        let mut m = Machine::with_config(full).unwrap();
+        let program = crate::assembler::assemble(
+            "
+            MEMSET M, A, D
+            MEMCPY B, M, D
+            ",
+        )
+        .unwrap();
+        m.load_program(&program, 0).unwrap();
+        m.set_register(Register::PC, 0);
+        m.set_register(Register::A, 0x5A);
+        m.set_register(Register::B, 0x100);
+        m.set_register(Register::D, 2);
+        m.set_register(Register::M, 0xFFFF);
+        assert_eq!(m.step(), Err("Invalid memory range 0xFFFF+0x2".to_string()));
+        assert_eq!(m.read_memory(0xFFFF..=0xFFFF), [0]);
+        m.set_register(Register::PC, 4);
+        assert_eq!(m.step(), Err("Invalid memory range 0xFFFF+0x2".to_string()));
+        assert_eq!(m.read_memory(0x100..0x102), [0, 0]);
+        m.set_register(Register::M, 0xFFFE);
+        m.set_register(Register::PC, 0);
+        m.step().unwrap();
+        m.step().unwrap();
+        assert_eq!(m.read_memory(0xFFFE..), [0x5A, 0x5A]);
+        assert_eq!(m.read_memory(0x100..0x102), [0x5A, 0x5A]);
+    }
+
+    #[test]
+    fn test_loop() {
+        // A += 3 until C reaches zero.
+        let mut m = Machine::new();
+        m.registers[Register::C as usize] = 5;
+        m.memory.write2(0, 0x03e0);
+        m.memory.write2(2, 0x247);
+        m.memory.write2(4, 0);
+        m.memory.write2(6, 0x22);
+        m.run().unwrap();
+        assert_eq!(m.get_register(Register::A), 15);
+        assert_eq!(m.get_register(Register::C), 0);
+    }
+
+    #[test]
+    fn test_cas() {
+        let mut m = Machine::new();
+        m.memory.write2(0x200, 7);
+        m.registers[Register::M as usize] = 0x200;
+        m.registers[Register::A as usize] = 7;
+        m.registers[Register::C as usize] = 9;
+        m.memory.write2(0, 0x0348);
+        m.memory.write2(2, 0x2);
+        m.memory.write2(4, 0x0348);
+        m.memory.write2(6, 0x2);
+        m.step().unwrap();
+        assert!(m.get_flag(Flag::Zero));
+        assert_eq!(m.memory.read2(0x200), Some(9));
+        m.step().unwrap();
+        assert!(!m.get_flag(Flag::Zero));
+        assert_eq!(m.memory.read2(0x200), Some(9));
+        assert_eq!(m.get_register(Register::A), 9);
+    }
+
+    #[test]
+    fn test_saturating_arithmetic() {
+        let mut m = Machine::new();
+        m.registers[Register::A as usize] = 0xfff0;
+        m.registers[Register::B as usize] = 0x20;
+        m.memory.write2(0, 0x1049);
+        m.memory.write2(2, 0x104a);
+        m.memory.write2(4, 0x104a);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0xffff);
+        m.registers[Register::A as usize] = 0x30;
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0x10);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 0);
+    }
+
+    #[test]
+    fn test_mac() {
+        let mut m = Machine::new();
+        m.registers[Register::A as usize] = 10;
+        m.registers[Register::B as usize] = 3;
+        m.registers[Register::C as usize] = 4;
+        m.memory.write2(0, 0x104b);
+        m.memory.write2(2, 0x2);
+        m.memory.write2(4, 0x104b);
+        m.memory.write2(6, 0x2);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 22);
+        assert!(!m.get_flag(Flag::Carry));
+        m.registers[Register::B as usize] = 0x4000;
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::A), 22);
+        assert!(m.get_flag(Flag::Carry));
+    }
+
+    #[test]
+    fn test_push_pop_flags() {
+        let mut m = Machine::new();
+        m.registers[Register::SP as usize] = 0x1000;
+        m.set_flag(Flag::Carry, true);
+        m.set_flag(Flag::Zero, true);
+        m.memory.write2(0, 0x4c);
+        m.memory.write2(2, 0x4d);
+        m.step().unwrap();
+        m.registers[Register::FLAGS as usize] = 0;
+        m.step().unwrap();
+        assert!(m.get_flag(Flag::Carry));
+        assert!(m.get_flag(Flag::Zero));
+        assert!(!m.get_flag(Flag::Negative));
+        assert_eq!(m.get_register(Register::SP), 0x1000);
+    }
+
+    #[test]
+    fn test_add_register_flags() {
+        let add = |a: u16, b: u16| {
+            let mut m = Machine::new();
+            m.registers[Register::A as usize] = a;
+            m.registers[Register::B as usize] = b;
+            m.memory.write2(0, 0x1004);
+            m.step().unwrap();
+            m
+        };
+
+        let m = add(0xffff, 1);
+        assert_eq!(m.get_register(Register::A), 0);
+        assert!(m.get_flag(Flag::Zero));
+        assert!(m.get_flag(Flag::Carry));
+        assert!(!m.get_flag(Flag::Overflow));
+
+        let m = add(0x7fff, 1);
+        assert_eq!(m.get_register(Register::A), 0x8000);
+        assert!(!m.get_flag(Flag::Carry));
+        assert!(m.get_flag(Flag::Overflow));
+        assert!(m.get_flag(Flag::Negative));
+
+        let m = add(0x8000, 0x8000);
+        assert_eq!(m.get_register(Register::A), 0);
+        assert!(m.get_flag(Flag::Carry));
+        assert!(m.get_flag(Flag::Overflow));
+
+        let m = add(0xfffe, 1);
+        assert_eq!(m.get_register(Register::A), 0xffff);
+        assert!(!m.get_flag(Flag::Carry));
+        assert!(!m.get_flag(Flag::Overflow));
+    }
+
+    #[test]
+    fn test_extended_registers() {
+        let mut m = Machine::new();
+        m.memory.write2(0, 0x42fc);
+        m.memory.write2(2, 0xc805);
+        m.memory.write2(4, 0x82d);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::H), 0x42);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::D), 0x42);
+        m.step().unwrap();
+        assert_eq!(m.get_register(Register::D), 0);
+        assert_eq!(m.get_register(Register::A), 0x42);
+        for n in 0..16u8 {
+            assert_eq!(
+                Register::from_u8(n).is_some(),
+                (n as usize) < REGISTER_COUNT
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let ops = [
+            Op::Nop,
+            Op::Push(0xab),
+            Op::PopRegister(Register::H),
+            Op::Mov(Register::C, Register::M),
+            Op::Shl(Register::B, 15),
+            Op::JmpRelative(-6),
+            Op::Jge(0x1234),
+            Op::Syscall(9),
+            Op::LoadImm16(Register::G, 0xbeef),
+            Op::Loop(Register::C, 0x40),
+            Op::Cas(Register::M, Register::A, Register::D),
+            Op::Mac(Register::A, Register::B, Register::C),
+            Op::SubImm(Register::E, 1),
+            Op::AddImm(Register::H, 0x80),
+            Op::LoadImm(Register::FLAGS, 0xff),
+            Op::PopFlags,
+            Op::LoadCodeSegment(Register::A),
+            Op::LoadDataSegment(Register::H),
+        ];
+        for op in ops {
+            let words = op.encode();
+            assert_eq!(parse(&words), Ok(op));
+        }
+    }
+
+    #[test]
+    fn test_load_program() {
+        let mut m = Machine::new();
+        let program = [Op::Push(7).encode(), Op::Halt.encode()].concat();
+        let bytes: Vec<u8> = program.iter().flat_map(|w| w.to_le_bytes()).collect();
+        m.load_program(&bytes, 0x100).unwrap();
+        assert_eq!(m.get_register(Register::PC), 0x100);
+        m.run().unwrap();
+        assert_eq!(m.pop(), Ok(7));
+
+        assert_eq!(
+            m.load_program(&bytes, 0x1ffe),
+            Err("Program of 4 bytes does not fit in memory at 0x1FFE".to_string())
+        );
+        assert_eq!(
+            m.load_program(&bytes, 0xfffe),
+            Err("Program of 4 bytes does not fit in memory at 0xFFFE".to_string())
+        );
+
+        let path = std::env::temp_dir().join(format!("tinyvm-load-{}.bin", std::process::id()));
+        fs::write(&path, &bytes).unwrap();
+        let mut m = Machine::new();
+        m.load_program_file(&path, 0x40).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(m.memory.read2(0x40), Some(0x0701));
+        assert_eq!(m.get_register(Register::PC), 0x40);
+    }
+
+    #[test]
+    fn test_load_object() {
+        let object = ObjectFile {
+            entry: 0x200,
+            segments: vec![
+                crate::object::Segment {
+                    address: 0x10,
+                    bytes: vec![0x34, 0x12],
+                },
+                crate::object::Segment {
+                    address: 0x200,
+                    bytes: vec![0x01, 0x05, 0x22, 0x00],
+                },
+            ],
+        };
+        let mut m = Machine::new();
+        m.load_object(&object).unwrap();
+        assert_eq!(m.get_register(Register::PC), 0x200);
+        assert_eq!(m.memory.read2(0x10), Some(0x1234));
+        m.run().unwrap();
+        assert_eq!(m.pop(), Ok(5));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let program = crate::assembler::assemble(
+            "
+                    LDI A, 0
+            loop:   INC A
+                    PUSH A
+                    JMP loop
+            ",
+        )
+        .unwrap();
+        let mut m = Machine::new();
+        m.set_register(Register::SP, 0x1000);
+        m.load_program(&program, 0).unwrap();
+        for _ in 0..10 {
+            m.step().unwrap();
+        }
+        let snapshot = m.save_snapshot();
+        assert_eq!(&snapshot[..6], b"TVMS\x02\x00");
+
+        let mut resumed = Machine::new();
+        resumed.load_snapshot(&snapshot).unwrap();
+        assert_eq!(resumed.save_snapshot(), snapshot);
+        for _ in 0..30 {
+            m.step().unwrap();
+            resumed.step().unwrap();
+        }
+        for register in [Register::A, Register::SP, Register::PC] {
+            assert_eq!(resumed.get_register(register), m.get_register(register));
+        }
+        assert_eq!(resumed.save_snapshot(), m.save_snapshot());
+    }
+
+    #[test]
+    fn test_snapshot_devices() {
+        use crate::console::{Console, CONSOLE_ADDRESS};
+        use crate::uart::{Uart, UART_ADDRESS, UART_DATA, UART_RX_READY, UART_STATUS};
+        let console = Rc::new(RefCell::new(Console::new(Vec::new())));
+        let uart = Rc::new(RefCell::new(Uart::new(
+            std::io::Cursor::new(b"x".to_vec()),
+            Vec::new(),
+        )));
+        while uart.borrow().read(UART_STATUS).unwrap() & UART_RX_READY == 0 {
+            std::thread::yield_now();
+        }
+        let mut m = Machine::new();
+        m.map(CONSOLE_ADDRESS, 1, console.clone()).unwrap();
+        m.map(UART_ADDRESS, 2, uart.clone()).unwrap();
+        m.load_program(&[1, 2, 3], 0x100).unwrap();
+        m.add_watchpoint(0, 0xFFFF, WatchKind::ReadWrite);
+
+        let snapshot = m.save_snapshot();
+        m.load_snapshot(&snapshot).unwrap();
+        assert!(m.watch_hits().is_empty());
+        assert_eq!(m.read_memory(0x100..0x103), [1, 2, 3]);
+        // Neither device was read or written: nothing was sent and the byte is still there.
+        assert!(console.borrow().sink().is_empty());
+        assert!(uart.borrow().output().is_empty());
+        assert_eq!(uart.borrow().read(UART_DATA), Some(b'x'));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let program = crate::assembler::assemble(
+            "
+                    LDIW M, 0x200
+                    LDI A, 5
+                    STORE M, A
+                    LDIW M, 0x300
+                    LOAD B, M
+                    STORE M, B
+                    HALT
+            ",
+        )
+        .unwrap();
+        let mut m = Machine::new();
+        m.load_program(&program, 0).unwrap();
+        let write = m.add_watchpoint(0x200, 0x201, WatchKind::Write);
+        let read = m.add_watchpoint(0x300, 0x300, WatchKind::Read);
+        assert_eq!(m.watchpoints().len(), 2);
+
+        m.run().unwrap();
+        assert!(!m.is_halted());
+        assert_eq!(m.get_register(Register::PC), 8);
+        assert_eq!(
+            m.watch_hits(),
+            vec![
+                WatchHit {
+                    id: write,
+                    address: 0x200,
+                    value: 5,
+                    write: true,
+                },
+                WatchHit {
+                    id: write,
+                    address: 0x201,
+                    value: 0,
+                    write: true,
+                },
+            ]
+        );
+
+        m.run().unwrap();
+        assert_eq!(m.get_register(Register::PC), 14);
+        assert_eq!(m.watch_hits().len(), 1);
+        assert_eq!(m.watch_hits()[0].id, read);
+
+        assert!(m.remove_watchpoint(read));
+        assert!(!m.remove_watchpoint(read));
+        m.run().unwrap();
+        assert!(m.is_halted());
+        assert!(m.watch_hits().is_empty());
+    }
+
+    #[test]
+    fn test_step_back() {
+        let program = crate::assembler::assemble(
+            "
+                    LDIW SP, 0x1000
+                    LDI A, 5
+                    PUSH A
+                    LDIW M, 0x200
+                    STORE M, A
+                    HALT
+            ",
+        )
+        .unwrap();
+        let mut m = Machine::new();
+        m.load_program(&program, 0).unwrap();
+        assert_eq!(m.step_back(), Err("No recorded steps to undo".to_string()));
+        m.record_history(3);
+        m.add_watchpoint(0x200, 0x200, WatchKind::Write);
+        m.step().unwrap();
+        m.run().unwrap();
+        m.run().unwrap();
+        assert!(m.is_halted());
+        let end = m.save_snapshot();
+        assert_eq!(m.history().map(History::len), Some(3));
+
+        m.step_back().unwrap();
+        assert!(!m.is_halted());
+        assert_eq!(m.memory.read2(0x200), Some(5));
+        m.step_back().unwrap();
+        assert_eq!(m.memory.read2(0x200), Some(0));
+        assert!(m.watch_hits().is_empty());
+        m.step_back().unwrap();
+        assert_eq!(m.get_register(Register::M), 0);
+        // Only the last three steps were kept.
+        assert_eq!(m.step_back(), Err("No recorded steps to undo".to_string()));
+
+        m.run().unwrap();
+        m.run().unwrap();
+        assert_eq!(m.save_snapshot(), end);
+    }
+
+    #[test]
+    fn test_step_back_device() {
+        use crate::uart::{Uart, UART_ADDRESS, UART_DATA, UART_RX_READY, UART_STATUS};
+        let uart = Rc::new(RefCell::new(Uart::new(
+            std::io::Cursor::new(b"x".to_vec()),
+            Vec::new(),
+        )));
+        while uart.borrow().read(UART_STATUS).unwrap() & UART_RX_READY == 0 {
+            std::thread::yield_now();
+        }
+        let program = crate::assembler::assemble(
+            "
+                    LDIW M, 0xFF10
+                    LDI A, 0x61
+                    STOREB M, A
+                    HALT
+            ",
+        )
+        .unwrap();
+        let mut m = Machine::new();
+        m.map(UART_ADDRESS, 2, uart.clone()).unwrap();
+        m.load_program(&program, 0).unwrap();
+        m.record_history(8);
+        m.run().unwrap();
+        // Journaling the store did not read, and so take, the received byte.
+        assert_eq!(m.history().unwrap().len(), 4);
+        while m.step_back().is_ok() {}
+        assert_eq!(m.get_register(Register::M), 0);
+        // Undoing the store did not send anything either.
+        assert_eq!(uart.borrow().output(), b"a");
+        assert_eq!(uart.borrow().read(UART_DATA), Some(b'x'));
+    }
+
+    #[test]
+    fn test_core_dump() {
+        let path = std::env::temp_dir().join(format!("tinyvm-core-{}", std::process::id()));
+        let program = crate::assembler::assemble("LDI A, 7\nPUSH A\n.word 0xffff").unwrap();
+        let mut m = Machine::new();
+        m.set_register(Register::SP, 0x1000);
+        m.load_program(&program, 0x100).unwrap();
+        m.set_core_dump_path(Some(path.clone()));
+        let error = m.run().unwrap_err();
+
+        let core = CoreDump::from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(core.pc, 0x104);
+        assert_eq!(core.instruction, vec![0xff, 0xff, 0x00, 0x00]);
+        assert_eq!(core.error, error);
+        assert_eq!(core.snapshot, m.save_snapshot());
+
+        let mut post_mortem = Machine::new();
+        post_mortem.load_core(&core).unwrap();
+        assert_eq!(post_mortem.get_register(Register::A), 7);
+        assert_eq!(post_mortem.pop(), Ok(7));
+
+        m.set_core_dump_path(Some(std::env::temp_dir().join("missing-dir/core")));
+        m.set_register(Register::PC, 0x104);
+        assert!(m
+            .step()
+            .unwrap_err()
+            .contains("(no core dump: Cannot write"));
+    }
+
+    #[test]
+    fn test_core_dump_device() {
+        use crate::uart::{Uart, UART_ADDRESS, UART_DATA, UART_RX_READY, UART_STATUS};
+        let path = std::env::temp_dir().join(format!("tinyvm-core-uart-{}", std::process::id()));
+        let uart = Rc::new(RefCell::new(Uart::new(
+            std::io::Cursor::new(b"x".to_vec()),
+            Vec::new(),
+        )));
+        while uart.borrow().read(UART_STATUS).unwrap() & UART_RX_READY == 0 {
+            std::thread::yield_now();
+        }
+        let mut m = Machine::new();
+        m.map(UART_ADDRESS - 2, 2, LinearMemory::new(2)).unwrap();
+        m.map(UART_ADDRESS, 2, uart.clone()).unwrap();
+        // A bad opcode right before the UART, where the rest of the instruction would be.
+        m.load_program(&[0xff, 0xff], UART_ADDRESS - 2).unwrap();
+        m.set_core_dump_path(Some(path.clone()));
+        let error = m.step().unwrap_err();
+
+        let core = CoreDump::from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(core.error, error);
+        assert_eq!(core.instruction, vec![0xff, 0xff]);
+        // Dumping neither took the received byte nor sent one.
+        assert!(uart.borrow().output().is_empty());
+        assert_eq!(uart.borrow().read(UART_DATA), Some(b'x'));
+    }
+
+    #[test]
+    fn test_snapshot_errors() {
+        let snapshot = Machine::new().save_snapshot();
+        let mut m = Machine::new();
+        assert_eq!(
+            m.load_snapshot(b"TVMO"),
+            Err("Not a TinyVM snapshot".to_string())
+        );
+        assert_eq!(
+            m.load_snapshot(&snapshot[..snapshot.len() - 1]),
+            Err("Truncated snapshot".to_string())
+        );
+        let mut version = snapshot.clone();
+        version[4] = 7;
+        assert_eq!(
+            m.load_snapshot(&version),
+            Err("Unsupported snapshot version 7".to_string())
+        );
+        let mut registers = snapshot.clone();
+        registers[6] = 8;
+        assert_eq!(
+            m.load_snapshot(&registers),
+            Err("Snapshot has 8 registers, expected 13".to_string())
+        );
+        let mut big = Machine::new();
+        big.map(0x2000, 0x1000, LinearMemory::new(0x1000)).unwrap();
+        assert_eq!(
+            m.load_snapshot(&big.save_snapshot()),
+            Err("Snapshot memory at 0x2000 does not fit in this machine's memory".to_string())
+        );
+        let mut replaced = Machine::new();
+        replaced.memory = Box::new(LinearMemory::new(0x3000));
+        assert_eq!(
+            m.load_snapshot(&replaced.save_snapshot()),
+            Err("Snapshot memory at 0x2000 does not fit in this machine's memory".to_string())
+        );
+        let mut segmented = Machine::new();
+        segmented.enable_segments().unwrap();
+        assert_eq!(
+            m.load_snapshot(&segmented.save_snapshot()),
+            Err("Snapshot has segmented memory; enable segments first".to_string())
+        );
+    }
+
+    #[test]
+    fn test_snapshot_replaced_memory() {
+        let mut m = Machine::new();
+        m.memory = Box::new(LinearMemory::new(0x3000));
+        m.memory.write(0x2800, 9);
+        let snapshot = m.save_snapshot();
+
+        let mut resumed = Machine::new();
+        resumed.memory = Box::new(LinearMemory::new(0x3000));
+        resumed.load_snapshot(&snapshot).unwrap();
+        assert_eq!(resumed.memory.read(0x2800), Some(9));
+        assert_eq!(resumed.save_snapshot(), snapshot);
+    }
+
+    #[test]
+    fn test_snapshot_mirrors() {
+        let mirrored = || {
+            let mut m = Machine::new();
+            m.map_mirrored(&[0x4000, 0x4010, 0x4020], 0x10, LinearMemory::new(0x10))
+                .unwrap();
+            m
+        };
+        let mut m = mirrored();
+        m.memory.write(0x4013, 7);
+        let snapshot = m.save_snapshot();
+        // One run of the mirrored bytes: a 6-byte header and 16 bytes.
+        assert_eq!(
+            snapshot.len(),
+            Machine::new().save_snapshot().len() + 6 + 0x10
+        );
+
+        let mut resumed = mirrored();
+        resumed.load_snapshot(&snapshot).unwrap();
+        assert_eq!(resumed.read_memory(0x4023..0x4024), [7]);
+        assert_eq!(resumed.save_snapshot(), snapshot);
+    }
+
+    #[test]
+    fn test_snapshot_segments() {
+        let mut m = Machine::new();
+        let segmented = m.enable_segments().unwrap();
+        segmented.borrow_mut().bytes_mut()[0x12345] = 0x42;
+        m.set_segments(Segments {
+            code: 0,
+            data: 0x1000,
+        });
+        let snapshot = m.save_snapshot();
+
+        let mut resumed = Machine::new();
+        let restored = resumed.enable_segments().unwrap();
+        resumed.load_snapshot(&snapshot).unwrap();
+        assert_eq!(restored.borrow().bytes()[0x12345], 0x42);
+        assert_eq!(resumed.segments(), m.segments());
+        assert_eq!(resumed.save_snapshot(), snapshot);
+    }
+
+    #[test]
+    fn test_top_of_memory() {
+        let full = MachineConfig {
+            memory_size: 0x10000,
+            stack: 0xF000,
+            ..MachineConfig::default()
+        };
+        let mut m = Machine::with_config(full).unwrap();
+        // LOAD A, M
+        m.memory.write2(0, 0x301F);
+        m.set_register(Register::M, 0xFFFF);
+        assert_eq!(m.step(), Err("Invalid memory address 0xFFFF".to_string()));
+
+        m.set_register(Register::SP, 0xFFFE);
+        assert_eq!(m.push(1), Err("Stack overflow".to_string()));
+        assert_eq!(m.get_register(Register::SP), 0xFFFE);
+        m.set_register(Register::SP, 0xFFFC);
+        m.push(1).unwrap();
+        assert_eq!(m.get_register(Register::SP), 0xFFFE);
+
+        m.load_program(&crate::assembler::assemble("RET").unwrap(), 0x100)
+            .unwrap();
+        m.set_register(Register::PC, 0x100);
+        m.set_register(Register::SP, 0);
+        assert_eq!(m.step(), Err("Stack underflow".to_string()));
+        assert_eq!(m.get_register(Register::SP), 0);
+    }
+
+    #[test]
+    fn test_config() {
+        let config = MachineConfig {
+            memory_size: 0x4000,
+            stack: 0x3000,
+            entry: 0x0100,
+        };
+        let mut m = Machine::with_config(config).unwrap();
+        assert_eq!(m.get_register(Register::SP), 0x3000);
+        assert_eq!(m.get_register(Register::PC), 0x0100);
+        assert_eq!(m.memory_map(), vec![(0, 0x4000)]);
+        m.load_program(&[0; 0x100], 0x2F00).unwrap();
+        assert_eq!(
+            m.load_program(&[0; 2], 0x2FFF),
+            Err("Program at 0x2FFF-0x3000 overlaps the stack at 0x3000".to_string())
+        );
+        assert!(m.load_program(&[0], 0x3FFF).is_err());
+        // PUSH A; HALT
+        m.load_program(&[0x1D, 0x00, 0x22, 0x00], 0x100).unwrap();
+        m.set_register(Register::A, 0xBEEF);
+        m.run().unwrap();
+        assert_eq!(m.read_memory(0x3000..0x3002), vec![0xEF, 0xBE]);
+
+        let full = MachineConfig {
+            memory_size: 0x10000,
+            stack: 0xF000,
+            ..MachineConfig::default()
+        };
+        assert!(Machine::with_config(full).is_ok());
+        let outside = MachineConfig {
+            stack: 0x2000,
+            ..MachineConfig::default()
+        };
+        assert_eq!(
+            Machine::with_config(outside).err(),
+            Some("Stack 0x2000 is outside 0x2000 bytes of memory".to_string())
+        );
+        let empty = MachineConfig {
+            memory_size: 0,
+            ..MachineConfig::default()
+        };
+        assert!(Machine::with_config(empty).is_err());
+    }
+
+    #[test]
+    fn test_alignment_checks() {
+        // LDIW M, 0x0101; STORE M, A; HALT
+        let program = [0x21, 0x03, 0x01, 0x01, 0x20, 0x03, 0x22, 0x00];
+        let mut m = Machine::new();
+        m.load_program(&program, 0).unwrap();
+        m.run().unwrap();
+
+        let mut m = Machine::new();
+        m.set_alignment_checks(true);
+        m.load_program(&program, 0).unwrap();
+        assert_eq!(m.run(), Err("Misaligned word access at 0x101".to_string()));
+        m.set_register(Register::SP, 0x201);
+        assert!(m.push(1).is_err());
+        m.load_program(&[0x22, 0x00], 0x301).unwrap();
+        assert_eq!(m.step(), Err("Misaligned instruction at 0x301".to_string()));
+    }
+
+    #[test]
+    fn test_segments() {
+        let mut m = Machine::new();
+        let program = crate::assembler::assemble(
+            "LDIW A, 0x1000\nLDS A\nLDI B, 0x42\nSTOREB M, B\nLCS A\nHALT",
+        )
+        .unwrap();
+        m.load_program(&program, 0).unwrap();
+        m.set_register(Register::M, 0x10);
+        let memory = m.enable_segments().unwrap();
+        assert_eq!(m.memory_map(), vec![(0, 8 * 1024)]);
+        // The code segment's HALT is at the same offset as LCS's successor.
+        let end = program.len() - 2;
+        memory.borrow_mut().bytes_mut()[0x10000 + end..0x10000 + end + 2]
+            .copy_from_slice(&[0x22, 0x00]);
+        while m.segments().code == 0 {
+            m.step().unwrap();
+        }
+        m.step().unwrap();
+        assert!(m.halted);
+        assert_eq!(
+            m.segments(),
+            Segments {
+                code: 0x1000,
+                data: 0x1000
+            }
+        );
+        assert_eq!(memory.borrow().bytes()[0x10010], 0x42);
+        assert_eq!(m.read_memory(0x10..0x11), vec![0x42]);
+        m.set_segments(Segments::default());
+        assert_eq!(m.read_memory(0..2), program[..2].to_vec());
+
+        m.unmap(0);
+        assert!(m.enable_segments().is_err());
+    }
+
+    #[test]
+    fn test_register_window() {
+        let mut m = Machine::new();
+        m.map_registers().unwrap();
+        assert!(m.map_registers().is_err());
+        // LDIW M, 0xFFE2; LDI A, 5; STORE M, A; LDIW M, 0xFFEA; LOAD C, M; HALT
+        let program = [
+            0x21, 0x03, 0xE2, 0xFF, 0xF0, 0x05, 0x20, 0x03, 0x21, 0x03, 0xEA, 0xFF, 0x1F, 0x32,
+            0x22, 0x00,
+        ];
+        m.load_program(&program, 0).unwrap();
+        m.run().unwrap();
+        assert_eq!(m.get_register(Register::B), 5);
+        // LOAD saw PC as it was before the instruction.
+        assert_eq!(m.get_register(Register::C), 12);
+        assert_eq!(m.get_register(Register::A), 5);
+        // The window is a device, so host reads do not see it.
+        assert!(m.read_memory(0xFFE0..0xFFE2).is_empty());
+
+        assert!(m.memory.write2(REGISTER_WINDOW, 0x1234));
+        assert_eq!(m.get_register(Register::A), 0x1234);
+        m.set_register(Register::D, 7);
+        assert_eq!(m.get_register(Register::A), 0x1234);
+        assert_eq!(m.memory.read2(REGISTER_WINDOW + 16), Some(7));
+    }
+
+    #[test]
+    fn test_endianness() {
+        let source = "LDIW M, 0x100\nLDIW A, 0x1234\nSTORE M, A\nPUSH A\nHALT";
+        let little = crate::assembler::assemble(source).unwrap();
+        let big: Vec<u8> = little
+            .chunks(2)
+            .flat_map(|word| [word[1], word[0]])
+            .collect();
+        for (endianness, program, stored) in [
+            (Endianness::Little, &little, [0x34, 0x12]),
+            (Endianness::Big, &big, [0x12, 0x34]),
+        ] {
+            let mut m = Machine::new();
+            m.set_endianness(endianness);
+            m.load_program(program, 0).unwrap();
+            m.set_register(Register::SP, 0x200);
+            m.run().unwrap();
+            assert_eq!(m.read_memory(0x100..0x102), stored.to_vec());
+            assert_eq!(m.read_memory(0x200..0x202), stored.to_vec());
+            assert_eq!(m.peek_word(0x100), Some(0x1234));
+            assert_eq!(m.pop(), Ok(0x1234));
+        }
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let mut m = Machine::new();
+        assert_eq!(m.memory_map(), vec![(0, 0x2000)]);
+        m.map(0xF000, 0x100, LinearMemory::new(0x100)).unwrap();
+        // LDIW M, 0xF010; STORE M, A; HALT
+        m.load_program(&[0x21, 0x03, 0x10, 0xF0, 0x20, 0x03, 0x22, 0x00], 0)
+            .unwrap();
+        m.set_register(Register::A, 0xBEEF);
+        m.run().unwrap();
+        assert_eq!(m.read_memory(0xF010..0xF012), vec![0xEF, 0xBE]);
+        assert_eq!(
+            m.map(0x1000, 0x2000, LinearMemory::new(0x2000)),
+            Err("Region 0x1000-0x2FFF overlaps 0x0000-0x1FFF".to_string())
+        );
+
+        let device = m.unmap(0xF000).unwrap();
+        assert_eq!(device.read2(0x10), Some(0xBEEF));
+        assert_eq!(m.memory_map(), vec![(0, 0x2000)]);
+        assert!(m.read_memory(0xF010..0xF012).is_empty());
+    }
+
+    #[test]
+    fn test_rom() {
+        let mut m = Machine::new();
+        // LDIW M, 0xE000; STORE M, A
+        let code = [0x21, 0x03, 0x00, 0xE0, 0x20, 0x03];
+        m.map(0xE000, code.len(), Rom::new(&code)).unwrap();
+        m.set_register(Register::PC, 0xE000);
+        m.step().unwrap();
+        assert_eq!(m.step(), Err("Invalid memory address 0xE000".to_string()));
+        assert_eq!(m.read_memory(0xE000..0xE002), vec![0x21, 0x03]);
+        assert!(m.load_program(&[0], 0xE000).is_err());
+    }
+
+    #[test]
+    fn test_protection() {
+        let mut m = Machine::new();
+        // LDIW M, 0x0100; STORE M, A
+        m.load_program(&[0x21, 0x03, 0x00, 0x01, 0x20, 0x03], 0)
+            .unwrap();
+        assert!(m.protect(0, Permissions::RX));
+        m.step().unwrap();
+        assert_eq!(
+            m.step(),
+            Err("Cannot write 0x0100: region 0x0000-0x1FFF is r-x".to_string())
+        );
+
+        m.map(0x4000, 0x100, LinearMemory::new(0x100)).unwrap();
+        m.load_program(&[0x22, 0x00], 0x4000).unwrap();
+        assert!(m.protect(0x4000, Permissions::RW));
+        assert_eq!(
+            m.step(),
+            Err("Cannot execute 0x4000: region 0x4000-0x40FF is rw-".to_string())
+        );
+        m.protect(0x4000, Permissions::RWX);
+        m.step().unwrap();
+        assert!(m.halted);
+    }
+
+    #[test]
+    fn test_cow_fork() {
+        let mut image = vec![0; 0x2000];
+        // LDI A, 1; LDIW M, 0x1000; STOREB M, A; HALT
+        let program = [0xF0, 0x01, 0x21, 0x03, 0x00, 0x10, 0x2F, 0x03, 0x22, 0x00];
+        image[..program.len()].copy_from_slice(&program);
+        let image: Arc<[u8]> = image.into();
+        let forks: Vec<Machine> = (0..3)
+            .map(|_| {
+                let mut m = Machine::new();
+                m.unmap(0);
+                m.map(0, image.len(), CowMemory::new(image.clone()))
+                    .unwrap();
+                m.run().unwrap();
+                m
+            })
+            .collect();
+        for m in &forks {
+            assert_eq!(m.read_memory(0x1000..0x1001), vec![1]);
+        }
+        assert_eq!(image[0x1000], 0);
+    }
+
+    #[test]
+    fn test_shared_memory() {
+        let shared = SharedMemory::new(0x100);
+        let mut producer = Machine::new();
+        let mut consumer = Machine::new();
+        producer.map(0x8000, 0x100, shared.clone()).unwrap();
+        consumer.map(0x4000, 0x100, shared).unwrap();
+        // LDIW M, 0x8000; LDI A, 1; STOREB M, A; HALT
+        let program = [0x21, 0x03, 0x00, 0x80, 0xF0, 0x01, 0x2F, 0x03, 0x22, 0x00];
+        producer.load_program(&program, 0).unwrap();
+        producer.run().unwrap();
+        // LDIW M, 0x4000; LOADB B, M; HALT
+        let program = [0x21, 0x03, 0x00, 0x40, 0x2E, 0x31, 0x22, 0x00];
+        consumer.load_program(&program, 0).unwrap();
+        consumer.run().unwrap();
+        assert_eq!(consumer.get_register(Register::B), 1);
+    }
+
+    #[test]
+    fn test_cache() {
+        let mut m = Machine::new();
+        let program = crate::assembler::assemble(
+            "
+            LDIW C, 64
+            LDIW M, 0x1000
+    loop:   LOADB A, M
+            INC M
+            LOOP C, loop
+            HALT
+            ",
+        )
+        .unwrap();
+        m.load_program(&program, 0).unwrap();
+        let cache = m.enable_cache(CacheConfig::default()).unwrap();
+        m.run().unwrap();
+        let stats = cache.borrow().stats();
+        // 64 sequential bytes fill four 16-byte lines.
+        assert_eq!((stats.read.hits, stats.read.misses), (60, 4));
+        assert_eq!(stats.fetch.misses, 2);
+    }
+
+    #[test]
+    fn test_observe() {
+        let mut m = Machine::new();
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let sink = output.clone();
+        m.observe(0x1F00, 0x1F00, WatchKind::Write, move |access| {
+            sink.borrow_mut().push((access.value, access.pc))
+        });
+        // LDIW M, 0x1F00; LDI A, 0x48; STOREB M, A; LDI A, 0x69; STOREB M, A; HALT
+        let program = [
+            0x21, 0x03, 0x00, 0x1F, 0xF0, 0x48, 0x2F, 0x03, 0xF0, 0x69, 0x2F, 0x03, 0x22, 0x00,
+        ];
+        m.load_program(&program, 0).unwrap();
+        m.run().unwrap();
+        assert_eq!(*output.borrow(), vec![(0x48, Some(6)), (0x69, Some(10))]);
+    }
+
+    #[test]
+    fn test_trace_memory() {
+        let mut m = Machine::new();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let sink = log.clone();
+        m.trace_memory(move |access| sink.borrow_mut().push(access));
+        // LDIW M, 0x0100; STORE M, A; HALT
+        m.load_program(&[0x21, 0x03, 0x00, 0x01, 0x20, 0x03, 0x22, 0x00], 0)
+            .unwrap();
+        m.set_register(Register::A, 0x1234);
+        log.borrow_mut().clear();
+        m.run().unwrap();
+        let data: Vec<_> = log
+            .borrow()
+            .iter()
+            .filter(|a| a.access != Access::Execute)
+            .map(|a| (a.address, a.value, a.access, a.pc))
+            .collect();
+        assert_eq!(
+            data,
+            vec![
+                (0x100, 0x34, Access::Write, Some(4)),
+                (0x101, 0x12, Access::Write, Some(4))
+            ]
+        );
+        assert_eq!(log.borrow()[0].pc, Some(0));
+        assert_eq!(log.borrow()[0].access, Access::Execute);
+    }
+
+    #[test]
+    fn test_bank_switching() {
+        let mut m = Machine::new();
+        let banked = Rc::new(RefCell::new(BankedMemory::new(0x1000, 4)));
+        m.map(0x8000, banked.borrow().len(), banked.clone())
+            .unwrap();
+        banked.borrow_mut().bank_mut(1).unwrap()[0] = 0x42;
+        // LDIW M, 0x9000; LDI A, 1; STOREB M, A; LDIW M, 0x8000; LOADB B, M; HALT
+        let program = [
+            0x21, 0x03, 0x00, 0x90, 0xF0, 0x01, 0x2F, 0x03, 0x21, 0x03, 0x00, 0x80, 0x2E, 0x31,
+            0x22, 0x00,
+        ];
+        m.load_program(&program, 0).unwrap();
+        m.run().unwrap();
+        assert_eq!(banked.borrow().bank(), 1);
+        assert_eq!(m.get_register(Register::B), 0x42);
+    }
+
+    #[test]
+    fn test_page_fault() {
+        let mut m = Machine::new();
+        // LDIW M, 0x4000; STORE M, A; HALT, and at 0x40 a handler that maps page 0x40 to
+        // 0x0200: POP B; POP C; LDIW E, 0x1E80; LDIW D, 0x0203; STORE E, D; IRET
+        m.load_program(&[0x21, 0x03, 0x00, 0x40, 0x20, 0x03, 0x22, 0x00], 0)
+            .unwrap();
+        m.load_program(
+            &[
+                0x02, 0x01, 0x02, 0x02, 0x21, 0x09, 0x80, 0x1E, 0x21, 0x08, 0x03, 0x02, 0x20, 0x89,
+                0x35, 0x00,
+            ],
+            0x40,
+        )
+        .unwrap();
+        // Code read-only, the stack page and the page table itself writable.
+        m.memory.write2(0x1E00, 0x0001);
+        m.memory.write2(0x1E02, 0x0103);
+        m.memory.write2(0x1E3C, 0x1E03);
+        m.memory.write2(0x80 + PAGE_FAULT as u16 * 2, 0x40);
+        m.set_register(Register::PC, 0);
+        m.set_register(Register::SP, 0x100);
+        m.set_register(Register::A, 0x1234);
+        m.enable_mmu(0x1E00);
+        assert_eq!(
+            m.run(),
+            Err("Page fault at 0x4000 (page not present)".to_string())
+        );
+
+        m.set_interrupt_table(0x80);
+        m.set_register(Register::PC, 0);
+        m.run().unwrap();
+        assert_eq!(m.get_register(Register::B), 0x0002);
+        assert_eq!(m.get_register(Register::C), 0x4000);
+        assert_eq!(m.get_register(Register::SP), 0x100);
+        assert_eq!(m.read_memory(0x4000..0x4002), vec![0x34, 0x12]);
+        m.disable_mmu();
+        assert_eq!(m.read_memory(0x0200..0x0202), vec![0x34, 0x12]);
     }
 }